@@ -0,0 +1,601 @@
+use crate::error::{SandboxError, SandboxResult};
+use crate::sandbox::CancellationToken;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Runs the process behind a live execution
+///
+/// The sandbox takes care of permissions, approvals and change tracking; a
+/// backend only has to run `invocation` and report what it printed and how it
+/// ended. Backend errors become `Failed` results.
+pub trait ExecutionBackend: Send + Sync {
+    fn run(&self, invocation: &Invocation) -> SandboxResult<BackendOutput>;
+}
+
+/// A single command for a backend to run
+#[derive(Debug, Clone, Default)]
+pub struct Invocation {
+    pub tool: String,
+    pub args: Vec<String>,
+    /// Variables set in the command's environment on top of the sandbox's own
+    pub env: Vec<(String, String)>,
+    /// Directory to run in
+    pub cwd: PathBuf,
+    /// Written to the command's stdin, which is then closed; `None` gives it no stdin
+    pub stdin: Option<Vec<u8>>,
+    /// Kill the command once it has run this long
+    pub timeout: Option<Duration>,
+    /// Keep at most this many bytes of each output stream
+    pub max_output_bytes: Option<usize>,
+    /// Kill the command as soon as this is cancelled
+    pub cancel: Option<CancellationToken>,
+    /// Address-space limit (`RLIMIT_AS`) applied to the command
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub max_memory_bytes: Option<u64>,
+    /// CPU time limit (`RLIMIT_CPU`) applied to the command, in whole seconds
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub cpu_time_limit: Option<Duration>,
+}
+
+impl Invocation {
+    /// The program followed by its arguments
+    pub fn argv(&self) -> Vec<String> {
+        std::iter::once(self.tool.clone()).chain(self.args.iter().cloned()).collect()
+    }
+}
+
+/// What a backend run produced
+#[derive(Debug, Clone)]
+pub struct BackendOutput {
+    /// Exit code, or `None` if the command was killed
+    pub exit_code: Option<i32>,
+    /// Why the backend killed the command, if it did
+    pub killed: Option<KillReason>,
+    pub stdout: CapturedStream,
+    pub stderr: CapturedStream,
+    pub duration: Duration,
+    /// Program and arguments the backend actually started, if it can tell
+    pub argv: Option<Vec<String>>,
+}
+
+/// Why a backend stopped a command early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillReason {
+    TimedOut,
+    Cancelled,
+}
+
+/// Runs tools as native child processes, each in its own process group
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeBackend;
+
+impl ExecutionBackend for NativeBackend {
+    fn run(&self, invocation: &Invocation) -> SandboxResult<BackendOutput> {
+        let mut cmd = native_command(invocation);
+        run_with_timeout(&mut cmd, invocation)
+    }
+}
+
+/// Program and arguments `cmd` will start with
+fn command_argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Error for a command that could not be started at all
+fn spawn_error(tool: &str, error: std::io::Error) -> SandboxError {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        SandboxError::CommandNotFound(tool.to_string())
+    } else {
+        SandboxError::SpawnFailed {
+            tool: tool.to_string(),
+            source: error,
+        }
+    }
+}
+
+/// How often a running command is polled while waiting for its timeout or cancellation
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The kept prefix of an output stream and how many bytes were discarded after it
+#[derive(Debug, Clone, Default)]
+pub struct CapturedStream {
+    pub bytes: Vec<u8>,
+    pub dropped: u64,
+}
+
+impl CapturedStream {
+    /// Size of the chunks streams are read in
+    const CHUNK_SIZE: usize = 8192;
+    
+    /// Keep `chunk` up to `limit` bytes in total, counting the rest as dropped
+    pub fn push(&mut self, chunk: &[u8], limit: Option<usize>) {
+        let room = limit.map_or(chunk.len(), |limit| limit.saturating_sub(self.bytes.len()));
+        let kept = room.min(chunk.len());
+        self.bytes.extend_from_slice(&chunk[..kept]);
+        self.dropped += (chunk.len() - kept) as u64;
+    }
+    
+    /// Lossily decode the kept bytes, noting any that were dropped
+    pub fn to_text(&self) -> String {
+        let mut text = String::from_utf8_lossy(&self.bytes).to_string();
+        if self.dropped > 0 {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&format!("... [truncated {} bytes]\n", self.dropped));
+        }
+        text
+    }
+}
+
+/// Build the child process for `invocation`, in its own process group
+pub(crate) fn native_command(invocation: &Invocation) -> Command {
+    let mut cmd = Command::new(&invocation.tool);
+    cmd.args(&invocation.args)
+        .envs(invocation.env.iter().map(|(name, value)| (name, value)))
+        .current_dir(&invocation.cwd)
+        .stdin(if invocation.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    
+    // Run in its own process group so a timeout can kill any children too
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    
+    #[cfg(all(unix, feature = "rlimits"))]
+    {
+        use std::os::unix::process::CommandExt;
+        let memory = invocation.max_memory_bytes;
+        let cpu = invocation.cpu_time_limit;
+        if memory.is_some() || cpu.is_some() {
+            // SAFETY: the closure only calls setrlimit(2), which is async-signal-safe
+            unsafe {
+                cmd.pre_exec(move || rlimits::apply(memory, cpu));
+            }
+        }
+    }
+    
+    cmd
+}
+
+/// Spawn `cmd` and wait for it, killing it once the timeout elapses or it is cancelled
+///
+/// Stdin is written and output read on separate threads so a command that
+/// fills its pipes cannot stall, and whatever was written before a timeout is
+/// still returned.
+fn run_with_timeout(cmd: &mut Command, invocation: &Invocation) -> SandboxResult<BackendOutput> {
+    let argv = command_argv(cmd);
+    let started = Instant::now();
+    let mut child = cmd.spawn().map_err(|e| spawn_error(&invocation.tool, e))?;
+    
+    // Dropping the handle once everything is written closes the child's stdin
+    let writer = match (child.stdin.take(), &invocation.stdin) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.clone();
+            Some(std::thread::spawn(move || {
+                let _ = stdin.write_all(&input);
+            }))
+        }
+        _ => None,
+    };
+    
+    let stdout = read_in_background(child.stdout.take(), invocation.max_output_bytes);
+    let stderr = read_in_background(child.stderr.take(), invocation.max_output_bytes);
+    
+    let mut killed = None;
+    let status = if invocation.timeout.is_none() && invocation.cancel.is_none() {
+        Some(child.wait()?)
+    } else {
+        let deadline = invocation.timeout.map(|timeout| started + timeout);
+        loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if invocation.cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                killed = Some(KillReason::Cancelled);
+            } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                killed = Some(KillReason::TimedOut);
+            }
+            if killed.is_some() {
+                if !kill_process_group(child.id()) {
+                    child.kill()?;
+                }
+                child.wait()?;
+                break None;
+            }
+            std::thread::sleep(TIMEOUT_POLL_INTERVAL);
+        }
+    };
+    let duration = started.elapsed();
+    
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    
+    #[allow(unused_mut)]
+    let mut stderr = stderr.join().unwrap_or_default();
+    #[cfg(all(unix, feature = "rlimits"))]
+    rlimits::annotate(&mut stderr, status, invocation);
+    
+    Ok(BackendOutput {
+        exit_code: status.and_then(|status| status.code()),
+        killed,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr,
+        duration,
+        argv: Some(argv),
+    })
+}
+
+/// Read a pipe to the end on its own thread, keeping at most `limit` bytes
+fn read_in_background<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    limit: Option<usize>,
+) -> JoinHandle<CapturedStream> {
+    std::thread::spawn(move || {
+        let mut stream = CapturedStream::default();
+        let mut chunk = [0; CapturedStream::CHUNK_SIZE];
+        if let Some(mut pipe) = pipe {
+            loop {
+                match pipe.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => stream.push(&chunk[..n], limit),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+        stream
+    })
+}
+
+/// Async counterpart of `run_with_timeout` built on `tokio::process`
+#[cfg(feature = "async")]
+pub(crate) async fn run_with_timeout_async(
+    cmd: Command,
+    invocation: &Invocation,
+) -> SandboxResult<BackendOutput> {
+    use tokio::io::AsyncWriteExt;
+    
+    let argv = command_argv(&cmd);
+    let started = Instant::now();
+    let mut child = tokio::process::Command::from(cmd)
+        .spawn()
+        .map_err(|e| spawn_error(&invocation.tool, e))?;
+    
+    let writer = match (child.stdin.take(), &invocation.stdin) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.clone();
+            Some(tokio::spawn(async move {
+                let _ = stdin.write_all(&input).await;
+            }))
+        }
+        _ => None,
+    };
+    
+    let stdout = read_in_task(child.stdout.take(), invocation.max_output_bytes);
+    let stderr = read_in_task(child.stderr.take(), invocation.max_output_bytes);
+    
+    let status = match invocation.timeout {
+        None => Some(child.wait().await?),
+        Some(timeout) => match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => Some(status?),
+            Err(_) => {
+                if !child.id().is_some_and(kill_process_group) {
+                    child.start_kill()?;
+                }
+                child.wait().await?;
+                None
+            }
+        },
+    };
+    let duration = started.elapsed();
+    
+    if let Some(writer) = writer {
+        let _ = writer.await;
+    }
+    
+    #[allow(unused_mut)]
+    let mut stderr = stderr.await.unwrap_or_default();
+    #[cfg(all(unix, feature = "rlimits"))]
+    rlimits::annotate(&mut stderr, status, invocation);
+    
+    Ok(BackendOutput {
+        exit_code: status.and_then(|status| status.code()),
+        killed: status.is_none().then_some(KillReason::TimedOut),
+        stdout: stdout.await.unwrap_or_default(),
+        stderr,
+        duration,
+        argv: Some(argv),
+    })
+}
+
+/// Read a pipe to the end on its own task, keeping at most `limit` bytes
+#[cfg(feature = "async")]
+fn read_in_task<R>(pipe: Option<R>, limit: Option<usize>) -> tokio::task::JoinHandle<CapturedStream>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    use tokio::io::AsyncReadExt;
+    
+    tokio::spawn(async move {
+        let mut stream = CapturedStream::default();
+        let mut chunk = [0; CapturedStream::CHUNK_SIZE];
+        if let Some(mut pipe) = pipe {
+            while let Ok(n @ 1..) = pipe.read(&mut chunk).await {
+                stream.push(&chunk[..n], limit);
+            }
+        }
+        stream
+    })
+}
+
+/// Kill the process group led by `pid`; returns false if that was not possible
+#[cfg(unix)]
+fn kill_process_group(pid: u32) -> bool {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) == 0 }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(_pid: u32) -> bool {
+    false
+}
+
+/// Resource limits applied between fork and exec
+#[cfg(all(unix, feature = "rlimits"))]
+mod rlimits {
+    use super::{CapturedStream, Invocation};
+    use std::process::ExitStatus;
+    use std::time::Duration;
+    
+    /// Apply the limits to the calling (child) process
+    pub fn apply(memory: Option<u64>, cpu: Option<Duration>) -> std::io::Result<()> {
+        if let Some(bytes) = memory {
+            set(libc::RLIMIT_AS, bytes)?;
+        }
+        if let Some(cpu) = cpu {
+            // Round up so a sub-second limit does not become "unlimited"
+            set(libc::RLIMIT_CPU, cpu.as_secs() + u64::from(cpu.subsec_nanos() > 0))?;
+        }
+        Ok(())
+    }
+    
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    type Resource = libc::c_int;
+    
+    fn set(resource: Resource, value: u64) -> std::io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        // SAFETY: `limit` is a valid rlimit for the duration of the call
+        if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+    
+    /// What commands commonly print when an allocation fails
+    const OUT_OF_MEMORY_MESSAGES: &[&str] = &[
+        "cannot allocate memory",
+        "out of memory",
+        "memory exhausted",
+        "memoryerror",
+        "bad_alloc",
+        "memory allocation of",
+    ];
+    
+    /// Explain a failure that was likely caused by one of the limits
+    ///
+    /// A failure is only put down to the memory limit when the command was
+    /// killed by a signal a failed allocation typically ends in, or reported
+    /// running out of memory on stderr; other exit codes are left alone.
+    pub fn annotate(stderr: &mut CapturedStream, status: Option<ExitStatus>, invocation: &Invocation) {
+        use std::os::unix::process::ExitStatusExt;
+        
+        let Some(status) = status.filter(|status| !status.success()) else {
+            return;
+        };
+        
+        let out_of_memory = || {
+            let text = String::from_utf8_lossy(&stderr.bytes).to_lowercase();
+            let crashed = matches!(
+                status.signal(),
+                Some(libc::SIGSEGV | libc::SIGABRT | libc::SIGBUS | libc::SIGKILL)
+            );
+            crashed || OUT_OF_MEMORY_MESSAGES.iter().any(|message| text.contains(message))
+        };
+        
+        let note = match (status.signal(), invocation.cpu_time_limit, invocation.max_memory_bytes) {
+            (Some(libc::SIGXCPU), Some(cpu), _) => {
+                format!("CPU time limit of {:?} exceeded", cpu)
+            }
+            (_, _, Some(bytes)) if out_of_memory() => format!("command failed under a memory limit of {} bytes", bytes),
+            _ => return,
+        };
+        
+        if !stderr.bytes.is_empty() && !stderr.bytes.ends_with(b"\n") {
+            stderr.bytes.push(b'\n');
+        }
+        stderr.bytes.extend_from_slice(format!("[sandbox] {}\n", note).as_bytes());
+    }
+}
+
+#[cfg(feature = "wasi")]
+pub use wasi::WasiBackend;
+
+/// WebAssembly tools run under wasmtime
+#[cfg(feature = "wasi")]
+mod wasi {
+    use super::{BackendOutput, CapturedStream, ExecutionBackend, Invocation, KillReason, TIMEOUT_POLL_INTERVAL};
+    use crate::error::{SandboxError, SandboxResult};
+    use crate::sandbox::CancellationToken;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Instant;
+    use wasmtime::{Config, Engine, Linker, Module, Store, Trap};
+    use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+    use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+    use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+    
+    /// Runs `.wasm` tools under wasmtime with WASI preview 1
+    ///
+    /// A tool runs the module registered for it with `with_module`; otherwise
+    /// a tool ending in `.wasm` is loaded from that path, relative to the
+    /// command's directory. The command's directory, which is where the
+    /// sandbox's virtual filesystem is mounted from, is preopened as `.` and
+    /// is the only part of the host the module can reach. Stdin, timeouts and
+    /// cancellation behave as for `NativeBackend`. Output is captured only up
+    /// to the output limit: once it is reached the module's writes stop
+    /// succeeding, so the truncation notice does not count every byte the
+    /// module tried to write. Failures of the runtime itself, including traps,
+    /// are reported as `WasiError`.
+    #[derive(Debug, Clone, Default)]
+    pub struct WasiBackend {
+        modules: HashMap<String, PathBuf>,
+    }
+    
+    impl WasiBackend {
+        pub fn new() -> Self {
+            Self::default()
+        }
+        
+        /// Run `tool` from the module at `path`
+        pub fn with_module(mut self, tool: &str, path: impl Into<PathBuf>) -> Self {
+            self.modules.insert(tool.to_string(), path.into());
+            self
+        }
+        
+        fn module_path(&self, invocation: &Invocation) -> Option<PathBuf> {
+            match self.modules.get(&invocation.tool) {
+                Some(path) => Some(invocation.cwd.join(path)),
+                None if invocation.tool.ends_with(".wasm") => Some(invocation.cwd.join(&invocation.tool)),
+                None => None,
+            }
+        }
+    }
+    
+    impl ExecutionBackend for WasiBackend {
+        fn run(&self, invocation: &Invocation) -> SandboxResult<BackendOutput> {
+            let started = Instant::now();
+            let path = self
+                .module_path(invocation)
+                .filter(|path| path.is_file())
+                .ok_or_else(|| SandboxError::CommandNotFound(invocation.tool.clone()))?;
+            
+            // Epoch interruption lets the watcher stop a module on timeout or cancellation
+            let mut config = Config::new();
+            config.epoch_interruption(true);
+            let engine = Engine::new(&config).map_err(wasi_error)?;
+            let module = Module::from_file(&engine, &path).map_err(wasi_error)?;
+            
+            // One byte past the limit is kept so `captured` can tell output was cut off
+            let capacity = invocation.max_output_bytes.map_or(usize::MAX, |limit| limit.saturating_add(1));
+            let stdout = MemoryOutputPipe::new(capacity);
+            let stderr = MemoryOutputPipe::new(capacity);
+            let mut builder = WasiCtxBuilder::new();
+            builder
+                .args(&invocation.argv())
+                .envs(&invocation.env)
+                .stdout(stdout.clone())
+                .stderr(stderr.clone())
+                .preopened_dir(&invocation.cwd, ".", DirPerms::all(), FilePerms::all())
+                .map_err(wasi_error)?;
+            if let Some(input) = &invocation.stdin {
+                builder.stdin(MemoryInputPipe::new(input.clone()));
+            }
+            
+            let mut store = Store::new(&engine, builder.build_p1());
+            store.set_epoch_deadline(1);
+            let mut linker: Linker<WasiP1Ctx> = Linker::new(&engine);
+            preview1::add_to_linker_sync(&mut linker, |ctx| ctx).map_err(wasi_error)?;
+            let start = linker
+                .instantiate(&mut store, &module)
+                .and_then(|instance| instance.get_typed_func::<(), ()>(&mut store, "_start"))
+                .map_err(wasi_error)?;
+            
+            let (done, watcher) = watch(&engine, invocation, started);
+            let outcome = start.call(&mut store, ());
+            drop(done);
+            let killed = watcher.and_then(|watcher| watcher.join().ok()).flatten();
+            
+            let exit_code = match outcome {
+                Ok(()) => Some(0),
+                Err(e) => match e.downcast_ref::<I32Exit>() {
+                    Some(exit) => Some(exit.0),
+                    None if killed.is_some() && e.downcast_ref::<Trap>() == Some(&Trap::Interrupt) => None,
+                    None => return Err(wasi_error(e)),
+                },
+            };
+            
+            Ok(BackendOutput {
+                exit_code,
+                killed,
+                stdout: captured(&stdout, invocation.max_output_bytes),
+                stderr: captured(&stderr, invocation.max_output_bytes),
+                duration: started.elapsed(),
+                argv: None,
+            })
+        }
+    }
+    
+    /// Interrupt `engine` once the invocation times out or is cancelled
+    ///
+    /// The watcher stops when the returned sender is dropped and reports why it
+    /// interrupted the module, if it did.
+    fn watch(
+        engine: &Engine,
+        invocation: &Invocation,
+        started: Instant,
+    ) -> (mpsc::Sender<()>, Option<std::thread::JoinHandle<Option<KillReason>>>) {
+        let (done, finished) = mpsc::channel();
+        if invocation.timeout.is_none() && invocation.cancel.is_none() {
+            return (done, None);
+        }
+        
+        let engine = engine.clone();
+        let deadline = invocation.timeout.map(|timeout| started + timeout);
+        let cancel = invocation.cancel.clone();
+        let watcher = std::thread::spawn(move || loop {
+            if finished.recv_timeout(TIMEOUT_POLL_INTERVAL) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return None;
+            }
+            let reason = if cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                Some(KillReason::Cancelled)
+            } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                Some(KillReason::TimedOut)
+            } else {
+                None
+            };
+            if reason.is_some() {
+                engine.increment_epoch();
+                return reason;
+            }
+        });
+        (done, Some(watcher))
+    }
+    
+    fn captured(pipe: &MemoryOutputPipe, limit: Option<usize>) -> CapturedStream {
+        let mut stream = CapturedStream::default();
+        stream.push(&pipe.contents(), limit);
+        stream
+    }
+    
+    fn wasi_error(error: impl std::fmt::Display) -> SandboxError {
+        SandboxError::WasiError(format!("{:#}", error))
+    }
+}
@@ -1,18 +1,24 @@
-use crate::error::SandboxResult;
+use crate::error::{SandboxError, SandboxResult};
+use crate::virtual_fs::{DiffOperation, FileDiff};
 use serde::{Deserialize, Serialize};
-use similar::{ChangeTag, TextDiff};
-use std::path::PathBuf;
+use similar::{Algorithm, ChangeTag, TextDiff};
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 /// Represents a change in the diff
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiffChange {
     pub line_number: usize,
     pub content: String,
     pub change_type: DiffChangeType,
+    /// Byte ranges of `content` that differ from the paired line on the other side
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub highlights: Vec<Range<usize>>,
 }
 
 /// Type of diff change
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DiffChangeType {
     Equal,
     Insert,
@@ -20,7 +26,7 @@ pub enum DiffChangeType {
 }
 
 /// A unified diff representation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UnifiedDiff {
     pub old_path: PathBuf,
     pub new_path: PathBuf,
@@ -28,127 +34,716 @@ pub struct UnifiedDiff {
 }
 
 /// A diff hunk (group of changes)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiffHunk {
     pub old_start: usize,
     pub old_lines: usize,
     pub new_start: usize,
     pub new_lines: usize,
     pub changes: Vec<DiffChange>,
+    /// Section heading that follows the `@@` ranges, e.g. the enclosing function
+    #[serde(default)]
+    pub heading: Option<String>,
+}
+
+const ANSI_RED: &str = "31";
+const ANSI_GREEN: &str = "32";
+const ANSI_CYAN: &str = "36";
+const ANSI_REVERSE: &str = "\x1b[7m";
+const ANSI_NO_REVERSE: &str = "\x1b[27m";
+
+/// Options for `DiffEngine::format_unified_diff_with_options`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Color inserts, deletes and hunk headers with ANSI escapes
+    pub color: bool,
+    /// Highlight the changed words within replaced lines (requires `color`)
+    pub word_diff: bool,
+}
+
+/// Marker line emitted after a line that ends the file without a newline
+const NO_NEWLINE_MARKER: &str = "\\ No newline at end of file\n";
+
+/// Number of unchanged lines kept around each change by default
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+/// Line matching algorithm used to compute a diff
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffAlgorithm {
+    /// Myers' O(ND) algorithm, the default used by `diff` and `git diff`
+    #[default]
+    Myers,
+    /// Patience diff, which anchors on unique lines and often reads better for moved blocks
+    Patience,
+    /// Classic longest common subsequence
+    Lcs,
+}
+
+impl From<DiffAlgorithm> for Algorithm {
+    fn from(algorithm: DiffAlgorithm) -> Self {
+        match algorithm {
+            DiffAlgorithm::Myers => Algorithm::Myers,
+            DiffAlgorithm::Patience => Algorithm::Patience,
+            DiffAlgorithm::Lcs => Algorithm::Lcs,
+        }
+    }
+}
+
+/// Options for `DiffEngine::unified_diff_with_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Unchanged lines kept around each change
+    pub context: usize,
+    pub algorithm: DiffAlgorithm,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            context: DEFAULT_CONTEXT_LINES,
+            algorithm: DiffAlgorithm::default(),
+        }
+    }
+}
+
+/// Path prefixes replaced by aliases when diffs are formatted for sharing
+///
+/// `/home/alice/project/src/x` renders as `$PROJECT/src/x` under a rule mapping
+/// `/home/alice/project` to `$PROJECT`. Prefixes match whole components, and
+/// the longest matching prefix wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathMasker {
+    rules: Vec<(PathBuf, String)>,
+}
+
+impl PathMasker {
+    /// A masker with no rules, which shows paths unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Render paths under `prefix` with `alias` in its place
+    pub fn with_rule(mut self, prefix: impl Into<PathBuf>, alias: &str) -> Self {
+        self.rules.push((prefix.into(), alias.to_string()));
+        self
+    }
+    
+    /// `path` for display, with its longest matching prefix replaced
+    pub fn mask(&self, path: &Path) -> String {
+        let matched = self
+            .rules
+            .iter()
+            .filter_map(|(prefix, alias)| path.strip_prefix(prefix).ok().map(|rest| (prefix, alias, rest)))
+            .max_by_key(|(prefix, _, _)| prefix.components().count());
+        
+        match matched {
+            Some((_, alias, rest)) if rest.as_os_str().is_empty() => alias.clone(),
+            Some((_, alias, rest)) => format!("{}/{}", alias, rest.display()),
+            None => path.display().to_string(),
+        }
+    }
+}
+
+/// 1-based start line for a hunk header; empty ranges point at the preceding line
+fn hunk_start(index: usize, len: usize) -> usize {
+    if len == 0 {
+        index
+    } else {
+        index + 1
+    }
 }
 
 /// Diff engine for computing file differences
 pub struct DiffEngine;
 
 impl DiffEngine {
-    /// Compute a unified diff between two strings
+    /// Compute a unified diff between two strings with the default context
     pub fn unified_diff(
         old: &str,
         new: &str,
-        old_path: &PathBuf,
-        new_path: &PathBuf,
+        old_path: &Path,
+        new_path: &Path,
     ) -> UnifiedDiff {
-        let diff = TextDiff::from_lines(old, new);
+        Self::unified_diff_with_context(old, new, old_path, new_path, DEFAULT_CONTEXT_LINES)
+    }
+    
+    /// Compute a unified diff keeping `context` unchanged lines around each change
+    ///
+    /// Changes separated by more than `2 * context` unchanged lines end up in
+    /// separate hunks, matching the output of `diff -U`.
+    pub fn unified_diff_with_context(
+        old: &str,
+        new: &str,
+        old_path: &Path,
+        new_path: &Path,
+        context: usize,
+    ) -> UnifiedDiff {
+        Self::unified_diff_with_options(
+            old,
+            new,
+            old_path,
+            new_path,
+            &DiffOptions {
+                context,
+                ..DiffOptions::default()
+            },
+        )
+    }
+    
+    /// Compute a unified diff with the given context size and algorithm
+    ///
+    /// Line contents are kept verbatim, so a final line without a trailing
+    /// newline stays that way and `apply` reproduces the input byte for byte.
+    pub fn unified_diff_with_options(
+        old: &str,
+        new: &str,
+        old_path: &Path,
+        new_path: &Path,
+        options: &DiffOptions,
+    ) -> UnifiedDiff {
+        let diff = TextDiff::configure()
+            .algorithm(options.algorithm.into())
+            .diff_lines(old, new);
         let mut hunks = Vec::new();
-        let mut current_hunk: Option<DiffHunk> = None;
-        let mut old_line = 0;
-        let mut new_line = 0;
         
-        for change in diff.iter_all_changes() {
-            match change.tag() {
-                ChangeTag::Delete => {
-                    if current_hunk.is_none() {
-                        current_hunk = Some(DiffHunk {
-                            old_start: old_line + 1,
-                            old_lines: 0,
-                            new_start: new_line + 1,
-                            new_lines: 0,
-                            changes: Vec::new(),
-                        });
-                    }
-                    
-                    let hunk = current_hunk.as_mut().unwrap();
-                    hunk.old_lines += 1;
-                    hunk.changes.push(DiffChange {
-                        line_number: old_line,
-                        content: change.to_string(),
-                        change_type: DiffChangeType::Delete,
-                    });
-                    old_line += 1;
-                }
-                ChangeTag::Insert => {
-                    if current_hunk.is_none() {
-                        current_hunk = Some(DiffHunk {
-                            old_start: old_line + 1,
-                            old_lines: 0,
-                            new_start: new_line + 1,
-                            new_lines: 0,
-                            changes: Vec::new(),
-                        });
-                    }
+        for group in diff.grouped_ops(options.context) {
+            let (first, last) = match (group.first(), group.last()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => continue,
+            };
+            
+            let old_lines = last.old_range().end - first.old_range().start;
+            let new_lines = last.new_range().end - first.new_range().start;
+            
+            let mut hunk = DiffHunk {
+                old_start: hunk_start(first.old_range().start, old_lines),
+                old_lines,
+                new_start: hunk_start(first.new_range().start, new_lines),
+                new_lines,
+                changes: Vec::new(),
+                heading: None,
+            };
+            
+            for op in &group {
+                for change in diff.iter_changes(op) {
+                    let (line_number, change_type) = match change.tag() {
+                        ChangeTag::Equal => (change.old_index().unwrap_or(0), DiffChangeType::Equal),
+                        ChangeTag::Delete => (change.old_index().unwrap_or(0), DiffChangeType::Delete),
+                        ChangeTag::Insert => (change.new_index().unwrap_or(0), DiffChangeType::Insert),
+                    };
                     
-                    let hunk = current_hunk.as_mut().unwrap();
-                    hunk.new_lines += 1;
                     hunk.changes.push(DiffChange {
-                        line_number: new_line,
-                        content: change.to_string(),
-                        change_type: DiffChangeType::Insert,
+                        line_number,
+                        content: change.value().to_string(),
+                        change_type,
+                        highlights: Vec::new(),
                     });
-                    new_line += 1;
-                }
-                ChangeTag::Equal => {
-                    if let Some(hunk) = current_hunk.take() {
-                        hunks.push(hunk);
-                    }
-                    old_line += 1;
-                    new_line += 1;
                 }
             }
-        }
-        
-        if let Some(hunk) = current_hunk {
+            
             hunks.push(hunk);
         }
         
         UnifiedDiff {
-            old_path: old_path.clone(),
-            new_path: new_path.clone(),
+            old_path: old_path.to_path_buf(),
+            new_path: new_path.to_path_buf(),
             hunks,
         }
     }
     
     /// Format a unified diff for display
     pub fn format_unified_diff(diff: &UnifiedDiff) -> String {
+        Self::format_unified_diff_with_color(diff, false)
+    }
+    
+    /// Format a unified diff with ANSI colors, unless `NO_COLOR` is set
+    pub fn format_unified_diff_colored(diff: &UnifiedDiff) -> String {
+        let color = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+        Self::format_unified_diff_with_color(diff, color)
+    }
+    
+    /// Format a unified diff, coloring inserts green, deletes red and hunk headers cyan
+    pub fn format_unified_diff_with_color(diff: &UnifiedDiff, color: bool) -> String {
+        Self::format_unified_diff_with_options(
+            diff,
+            &FormatOptions {
+                color,
+                ..FormatOptions::default()
+            },
+        )
+    }
+    
+    /// Format a unified diff according to `options`
+    ///
+    /// With `word_diff` set, changed spans within replaced lines are shown in
+    /// reverse video on top of the line color.
+    pub fn format_unified_diff_with_options(diff: &UnifiedDiff, options: &FormatOptions) -> String {
+        Self::format_unified_diff_masked(diff, options, &PathMasker::default())
+    }
+    
+    /// Format a unified diff according to `options`, with header paths passed through `masker`
+    pub fn format_unified_diff_masked(
+        diff: &UnifiedDiff,
+        options: &FormatOptions,
+        masker: &PathMasker,
+    ) -> String {
+        format!(
+            "--- a/{}\n+++ b/{}\n{}",
+            masker.mask(&diff.old_path),
+            masker.mask(&diff.new_path),
+            Self::format_hunks(&diff.hunks, options)
+        )
+    }
+    
+    /// Format hunks without the `---`/`+++` file header
+    fn format_hunks(hunks: &[DiffHunk], options: &FormatOptions) -> String {
+        let color = options.color;
+        let paint = |code: &str, line: &str| -> String {
+            if !color {
+                return line.to_string();
+            }
+            match line.strip_suffix('\n') {
+                Some(body) => format!("\x1b[{}m{}\x1b[0m\n", code, body),
+                None => format!("\x1b[{}m{}\x1b[0m", code, line),
+            }
+        };
+        
+        let mut output = String::new();
+        
+        for hunk in hunks {
+            let heading = hunk
+                .heading
+                .as_ref()
+                .map(|h| format!(" {}", h))
+                .unwrap_or_default();
+            output.push_str(&paint(
+                ANSI_CYAN,
+                &format!(
+                    "@@ -{},{} +{},{} @@{}\n",
+                    hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines, heading
+                ),
+            ));
+            
+            let changes = if options.word_diff {
+                let mut changes = hunk.changes.clone();
+                mark_inline_changes(&mut changes);
+                Cow::Owned(changes)
+            } else {
+                Cow::Borrowed(&hunk.changes)
+            };
+            
+            for change in changes.iter() {
+                let content = if color {
+                    Cow::Owned(emphasize(&change.content, &change.highlights))
+                } else {
+                    Cow::Borrowed(&change.content)
+                };
+                let line = match change.change_type {
+                    DiffChangeType::Equal => format!(" {}", content),
+                    DiffChangeType::Insert => paint(ANSI_GREEN, &format!("+{}", content)),
+                    DiffChangeType::Delete => paint(ANSI_RED, &format!("-{}", content)),
+                };
+                output.push_str(&line);
+                
+                if !change.content.ends_with('\n') {
+                    output.push('\n');
+                    output.push_str(NO_NEWLINE_MARKER);
+                }
+            }
+        }
+        
+        output
+    }
+    
+    /// Render a unified diff as an HTML table
+    ///
+    /// Rows carry the `diff-add`, `diff-del`, `diff-ctx` and `diff-hunk` classes so
+    /// styling can live in an external stylesheet. Paths and content are escaped.
+    pub fn to_html(diff: &UnifiedDiff) -> String {
         let mut output = String::new();
         
+        output.push_str("<table class=\"diff\">\n");
         output.push_str(&format!(
-            "--- a/{}\n+++ b/{}\n",
-            diff.old_path.display(),
-            diff.new_path.display()
+            "<thead><tr><th colspan=\"3\">--- a/{}<br>+++ b/{}</th></tr></thead>\n",
+            escape_html(&diff.old_path.display().to_string()),
+            escape_html(&diff.new_path.display().to_string())
         ));
+        output.push_str("<tbody>\n");
         
         for hunk in &diff.hunks {
             output.push_str(&format!(
-                "@@ -{},{} +{},{} @@\n",
+                "<tr class=\"diff-hunk\"><td colspan=\"3\">@@ -{},{} +{},{} @@</td></tr>\n",
                 hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
             ));
             
+            let mut old_line = hunk.old_start;
+            let mut new_line = hunk.new_start;
+            
+            for change in &hunk.changes {
+                let (class, old_number, new_number) = match change.change_type {
+                    DiffChangeType::Equal => ("diff-ctx", old_line.to_string(), new_line.to_string()),
+                    DiffChangeType::Delete => ("diff-del", old_line.to_string(), String::new()),
+                    DiffChangeType::Insert => ("diff-add", String::new(), new_line.to_string()),
+                };
+                
+                if change.change_type != DiffChangeType::Insert {
+                    old_line += 1;
+                }
+                if change.change_type != DiffChangeType::Delete {
+                    new_line += 1;
+                }
+                
+                output.push_str(&format!(
+                    "<tr class=\"{}\"><td class=\"diff-line-old\">{}</td><td class=\"diff-line-new\">{}</td><td><pre>{}</pre></td></tr>\n",
+                    class,
+                    old_number,
+                    new_number,
+                    escape_html(change.content.trim_end_matches('\n'))
+                ));
+            }
+        }
+        
+        output.push_str("</tbody>\n</table>\n");
+        output
+    }
+    
+    /// Apply a unified diff to `original`, returning the patched text
+    ///
+    /// Context and deleted lines must match `original` at the offsets given by
+    /// the hunk headers; the first mismatch is reported as a `PatchError`.
+    pub fn apply(original: &str, diff: &UnifiedDiff) -> SandboxResult<String> {
+        let lines: Vec<&str> = original.split_inclusive('\n').collect();
+        let mut output = String::with_capacity(original.len());
+        let mut cursor = 0;
+        
+        for (index, hunk) in diff.hunks.iter().enumerate() {
+            let header = format!(
+                "hunk #{} (@@ -{},{} +{},{} @@)",
+                index + 1,
+                hunk.old_start,
+                hunk.old_lines,
+                hunk.new_start,
+                hunk.new_lines
+            );
+            
+            let start = if hunk.old_lines == 0 {
+                hunk.old_start
+            } else {
+                hunk.old_start.saturating_sub(1)
+            };
+            
+            if start < cursor || start > lines.len() {
+                return Err(SandboxError::PatchError(format!(
+                    "{} starts at line {} which is out of order or past the end of the file",
+                    header, hunk.old_start
+                )));
+            }
+            
+            for line in &lines[cursor..start] {
+                output.push_str(line);
+            }
+            
+            let mut position = start;
             for change in &hunk.changes {
-                let prefix = match change.change_type {
-                    DiffChangeType::Equal => " ",
-                    DiffChangeType::Insert => "+",
-                    DiffChangeType::Delete => "-",
+                match change.change_type {
+                    DiffChangeType::Insert => output.push_str(&change.content),
+                    DiffChangeType::Equal | DiffChangeType::Delete => {
+                        let actual = lines.get(position).copied();
+                        if actual != Some(change.content.as_str()) {
+                            return Err(SandboxError::PatchError(format!(
+                                "{} does not apply at line {}: expected {:?}, found {:?}",
+                                header,
+                                position + 1,
+                                change.content,
+                                actual.unwrap_or("<end of file>")
+                            )));
+                        }
+                        
+                        if change.change_type == DiffChangeType::Equal {
+                            output.push_str(&change.content);
+                        }
+                        position += 1;
+                    }
+                }
+            }
+            
+            cursor = position;
+        }
+        
+        for line in &lines[cursor..] {
+            output.push_str(line);
+        }
+        
+        Ok(output)
+    }
+    
+    /// Parse a single-file unified diff, such as the output of `git diff`
+    ///
+    /// Leading `diff --git`/`index` lines are skipped, `a/` and `b/` prefixes are
+    /// stripped from the paths, and `\ No newline at end of file` markers are honored.
+    pub fn parse_unified(text: &str) -> SandboxResult<UnifiedDiff> {
+        let mut lines = text.split_inclusive('\n').peekable();
+        let mut old_path = None;
+        let mut new_path = None;
+        
+        while let Some(line) = lines.next() {
+            if let Some(path) = line.strip_prefix("--- ") {
+                old_path = Some(parse_diff_path(path, "a/"));
+                let next = lines.next().and_then(|l| l.strip_prefix("+++ "));
+                new_path = next.map(|path| parse_diff_path(path, "b/"));
+                break;
+            }
+        }
+        
+        let (old_path, new_path) = match (old_path, new_path) {
+            (Some(old), Some(new)) => (old, new),
+            _ => {
+                return Err(SandboxError::PatchError(
+                    "missing ---/+++ file header".to_string(),
+                ))
+            }
+        };
+        
+        let mut hunks = Vec::new();
+        
+        while let Some(line) = lines.next() {
+            let header = line.trim_end();
+            if !header.starts_with("@@") {
+                // Anything after the hunks (e.g. the next file) ends this diff
+                if hunks.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            
+            let mut hunk = parse_hunk_header(header)?;
+            let mut old_line = hunk.old_start.saturating_sub(1);
+            let mut new_line = hunk.new_start.saturating_sub(1);
+            let mut old_remaining = hunk.old_lines;
+            let mut new_remaining = hunk.new_lines;
+            
+            while old_remaining > 0 || new_remaining > 0 {
+                let line = match lines.next() {
+                    Some(line) => line,
+                    None => {
+                        return Err(SandboxError::PatchError(format!(
+                            "{} ends before all of its lines were read",
+                            header
+                        )))
+                    }
+                };
+                
+                // Some tools strip the single space from empty context lines
+                let (prefix, body) = match line.chars().next() {
+                    Some(c @ (' ' | '+' | '-')) => (c, &line[1..]),
+                    Some('\n') | Some('\r') => (' ', line),
+                    _ => {
+                        return Err(SandboxError::PatchError(format!(
+                            "unexpected line in {}: {:?}",
+                            header, line
+                        )))
+                    }
+                };
+                
+                let mut content = body.to_string();
+                if !content.ends_with('\n') {
+                    content.push('\n');
+                }
+                
+                let (line_number, change_type) = match prefix {
+                    '+' => (new_line, DiffChangeType::Insert),
+                    '-' => (old_line, DiffChangeType::Delete),
+                    _ => (old_line, DiffChangeType::Equal),
+                };
+                
+                if change_type != DiffChangeType::Insert {
+                    old_remaining = old_remaining.saturating_sub(1);
+                    old_line += 1;
+                }
+                if change_type != DiffChangeType::Delete {
+                    new_remaining = new_remaining.saturating_sub(1);
+                    new_line += 1;
+                }
+                
+                hunk.changes.push(DiffChange {
+                    line_number,
+                    content,
+                    change_type,
+                    highlights: Vec::new(),
+                });
+                
+                if lines.peek().is_some_and(|l| l.starts_with('\\')) {
+                    lines.next();
+                    if let Some(last) = hunk.changes.last_mut() {
+                        last.content.pop();
+                    }
+                }
+            }
+            
+            hunks.push(hunk);
+        }
+        
+        Ok(UnifiedDiff {
+            old_path,
+            new_path,
+            hunks,
+        })
+    }
+    
+    /// Compute a line diff whose replaced lines carry word-level highlights
+    ///
+    /// Runs of deleted lines immediately followed by inserted lines are paired
+    /// up in order, and each pair is diffed by words; the spans that differ are
+    /// recorded in `DiffChange::highlights`.
+    pub fn word_diff(old: &str, new: &str) -> Vec<DiffChange> {
+        let diff = TextDiff::from_lines(old, new);
+        let mut changes: Vec<DiffChange> = diff
+            .iter_all_changes()
+            .map(|change| {
+                let (line_number, change_type) = match change.tag() {
+                    ChangeTag::Equal => (change.old_index().unwrap_or(0), DiffChangeType::Equal),
+                    ChangeTag::Delete => (change.old_index().unwrap_or(0), DiffChangeType::Delete),
+                    ChangeTag::Insert => (change.new_index().unwrap_or(0), DiffChangeType::Insert),
                 };
-                output.push_str(&format!("{}{}", prefix, change.content));
+                DiffChange {
+                    line_number,
+                    content: change.value().to_string(),
+                    change_type,
+                    highlights: Vec::new(),
+                }
+            })
+            .collect();
+        
+        mark_inline_changes(&mut changes);
+        changes
+    }
+    
+    /// Render file changes as one multi-file patch with `diff --git` headers
+    ///
+    /// Created and deleted files are diffed against `/dev/null` and renames
+    /// carry `rename from`/`rename to` lines, so the patch applies with
+    /// `git apply`. New and deleted files get mode `100755` when their
+    /// `mode` is executable. Binary changes are listed without content;
+    /// directory and symlink entries are skipped.
+    pub fn format_patch(diffs: &[FileDiff], context: usize) -> String {
+        let mut output = String::new();
+        
+        for diff in diffs {
+            let (old_path, header) = match &diff.operation {
+                DiffOperation::DirectoryAdded
+                | DiffOperation::DirectoryRemoved
+                | DiffOperation::Symlinked { .. } => continue,
+                DiffOperation::Added => (diff.path.clone(), format!("new file mode {}\n", git_mode(diff.mode))),
+                DiffOperation::Deleted => (diff.path.clone(), format!("deleted file mode {}\n", git_mode(diff.mode))),
+                DiffOperation::Modified => (diff.path.clone(), String::new()),
+                DiffOperation::Renamed { from } => (
+                    from.clone(),
+                    format!("rename from {}\nrename to {}\n", from.display(), diff.path.display()),
+                ),
+            };
+            let old_name = match diff.operation {
+                DiffOperation::Added => "/dev/null".to_string(),
+                _ => format!("a/{}", old_path.display()),
+            };
+            let new_name = match diff.operation {
+                DiffOperation::Deleted => "/dev/null".to_string(),
+                _ => format!("b/{}", diff.path.display()),
+            };
+            
+            let body = if diff.is_binary() {
+                format!("Binary files {} and {} differ\n", old_name, new_name)
+            } else {
+                let unified = Self::unified_diff_with_context(
+                    diff.old_content.as_deref().unwrap_or(""),
+                    diff.new_content.as_deref().unwrap_or(""),
+                    &old_path,
+                    &diff.path,
+                    context,
+                );
+                if unified.hunks.is_empty() {
+                    String::new()
+                } else {
+                    format!(
+                        "--- {}\n+++ {}\n{}",
+                        old_name,
+                        new_name,
+                        Self::format_hunks(&unified.hunks, &FormatOptions::default())
+                    )
+                }
+            };
+            if body.is_empty() && header.is_empty() {
+                continue;
             }
+            
+            output.push_str(&format!(
+                "diff --git a/{} b/{}\n{}{}",
+                old_path.display(),
+                diff.path.display(),
+                header,
+                body
+            ));
         }
         
         output
     }
     
+    /// Count inserted and deleted lines per file, like `git diff --stat`
+    ///
+    /// Directory entries are skipped; binary changes are listed by size only.
+    pub fn multi_file_stat(diffs: &[FileDiff]) -> MultiFileStat {
+        let mut stat = MultiFileStat::default();
+        
+        for diff in diffs {
+            let path = match &diff.operation {
+                DiffOperation::DirectoryAdded | DiffOperation::DirectoryRemoved => continue,
+                DiffOperation::Renamed { from } => {
+                    format!("{} => {}", from.display(), diff.path.display())
+                }
+                _ => diff.path.display().to_string(),
+            };
+            
+            if let Some(binary) = &diff.binary {
+                stat.files.push(FileStat {
+                    path,
+                    added: 0,
+                    deleted: 0,
+                    binary: Some((binary.old_len, binary.new_len)),
+                });
+                continue;
+            }
+            
+            let summary = Self::diff_summary(
+                diff.old_content.as_deref().unwrap_or(""),
+                diff.new_content.as_deref().unwrap_or(""),
+            );
+            stat.insertions += summary.added;
+            stat.deletions += summary.deleted;
+            stat.files.push(FileStat {
+                path,
+                added: summary.added,
+                deleted: summary.deleted,
+                binary: None,
+            });
+        }
+        
+        stat
+    }
+    
+    /// Serialize a unified diff, including hunk ranges and per-line changes, as JSON
+    pub fn to_json(diff: &UnifiedDiff) -> SandboxResult<String> {
+        Ok(serde_json::to_string_pretty(diff)?)
+    }
+    
     /// Get a simple summary of changes
     pub fn diff_summary(old: &str, new: &str) -> DiffSummary {
-        let diff = TextDiff::from_lines(old, new);
+        Self::diff_summary_with_algorithm(old, new, DiffAlgorithm::default())
+    }
+    
+    /// Get a summary of changes using the given algorithm
+    pub fn diff_summary_with_algorithm(old: &str, new: &str, algorithm: DiffAlgorithm) -> DiffSummary {
+        let diff = TextDiff::configure()
+            .algorithm(algorithm.into())
+            .diff_lines(old, new);
         let mut added = 0;
         let mut deleted = 0;
         let mut unchanged = 0;
@@ -165,21 +760,291 @@ impl DiffEngine {
             added,
             deleted,
             unchanged,
+            ..DiffSummary::default()
+        }
+    }
+    
+    /// Line and file counts across a set of file changes
+    ///
+    /// Renames count as modified files; directory entries are not counted.
+    pub fn summarize_file_diffs(diffs: &[FileDiff]) -> DiffSummary {
+        let stat = Self::multi_file_stat(diffs);
+        let mut summary = DiffSummary {
+            added: stat.insertions,
+            deleted: stat.deletions,
+            ..DiffSummary::default()
+        };
+        
+        for diff in diffs {
+            match diff.operation {
+                DiffOperation::Added | DiffOperation::Symlinked { .. } => summary.files_added += 1,
+                DiffOperation::Modified | DiffOperation::Renamed { .. } => summary.files_modified += 1,
+                DiffOperation::Deleted => summary.files_deleted += 1,
+                DiffOperation::DirectoryAdded | DiffOperation::DirectoryRemoved => {}
+            }
+        }
+        
+        summary
+    }
+}
+
+/// Widest `+`/`-` bar drawn by `MultiFileStat::format`
+const STAT_GRAPH_WIDTH: usize = 40;
+
+/// Per-file line counts for a `--stat` style summary
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStat {
+    /// Display name, `old => new` for renames
+    pub path: String,
+    pub added: usize,
+    pub deleted: usize,
+    /// Old and new sizes in bytes when the change is binary
+    pub binary: Option<(u64, u64)>,
+}
+
+/// Line counts across several file diffs, like `git diff --stat`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MultiFileStat {
+    pub files: Vec<FileStat>,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+impl MultiFileStat {
+    /// The closing line, e.g. `3 files changed, 12 insertions(+), 4 deletions(-)`
+    pub fn summary_line(&self) -> String {
+        let plural = |n: usize, one: &str, many: &str| {
+            format!("{} {}", n, if n == 1 { one } else { many })
+        };
+        
+        let mut line = plural(self.files.len(), "file changed", "files changed");
+        if self.insertions > 0 || self.deletions == 0 {
+            line.push_str(&format!(", {}", plural(self.insertions, "insertion(+)", "insertions(+)")));
+        }
+        if self.deletions > 0 || self.insertions == 0 {
+            line.push_str(&format!(", {}", plural(self.deletions, "deletion(-)", "deletions(-)")));
+        }
+        line
+    }
+    
+    /// Format one `path | count +++--` line per file followed by the summary line
+    pub fn format(&self) -> String {
+        let name_width = self.files.iter().map(|f| f.path.chars().count()).max().unwrap_or(0);
+        let max_changes = self.files.iter().map(|f| f.added + f.deleted).max().unwrap_or(0);
+        let count_width = max_changes.to_string().len();
+        let mut output = String::new();
+        
+        for file in &self.files {
+            let padding = " ".repeat(name_width - file.path.chars().count());
+            if let Some((old_len, new_len)) = file.binary {
+                output.push_str(&format!(
+                    " {}{} | Bin {} -> {} bytes\n",
+                    file.path, padding, old_len, new_len
+                ));
+                continue;
+            }
+            
+            let total = file.added + file.deleted;
+            let (plus, minus) = if max_changes > STAT_GRAPH_WIDTH {
+                let scale = |n: usize| {
+                    if n == 0 {
+                        0
+                    } else {
+                        (n * STAT_GRAPH_WIDTH / max_changes).max(1)
+                    }
+                };
+                (scale(file.added), scale(file.deleted))
+            } else {
+                (file.added, file.deleted)
+            };
+            
+            output.push_str(&format!(
+                " {}{} | {:>width$} {}{}\n",
+                file.path,
+                padding,
+                total,
+                "+".repeat(plus),
+                "-".repeat(minus),
+                width = count_width
+            ));
+        }
+        
+        output.push_str(&format!(" {}\n", self.summary_line()));
+        output
+    }
+}
+
+/// Fill in `highlights` for each deleted line paired with a following inserted line
+fn mark_inline_changes(changes: &mut [DiffChange]) {
+    let mut index = 0;
+    while index < changes.len() {
+        if changes[index].change_type != DiffChangeType::Delete {
+            index += 1;
+            continue;
+        }
+        
+        let deletes = index;
+        while index < changes.len() && changes[index].change_type == DiffChangeType::Delete {
+            index += 1;
+        }
+        let inserts = index;
+        while index < changes.len() && changes[index].change_type == DiffChangeType::Insert {
+            index += 1;
+        }
+        
+        let pairs = (inserts - deletes).min(index - inserts);
+        for offset in 0..pairs {
+            let (old, new) = inline_ranges(
+                &changes[deletes + offset].content,
+                &changes[inserts + offset].content,
+            );
+            changes[deletes + offset].highlights = old;
+            changes[inserts + offset].highlights = new;
+        }
+    }
+}
+
+/// Byte ranges of the words that differ between two lines, ignoring the line ending
+fn inline_ranges(old: &str, new: &str) -> (Vec<Range<usize>>, Vec<Range<usize>>) {
+    let old = old.trim_end_matches(['\n', '\r']);
+    let new = new.trim_end_matches(['\n', '\r']);
+    let diff = TextDiff::from_words(old, new);
+    let mut old_ranges: Vec<Range<usize>> = Vec::new();
+    let mut new_ranges: Vec<Range<usize>> = Vec::new();
+    let mut old_offset = 0;
+    let mut new_offset = 0;
+    
+    let push = |ranges: &mut Vec<Range<usize>>, range: Range<usize>| match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    };
+    
+    for change in diff.iter_all_changes() {
+        let len = change.value().len();
+        match change.tag() {
+            ChangeTag::Equal => {
+                old_offset += len;
+                new_offset += len;
+            }
+            ChangeTag::Delete => {
+                push(&mut old_ranges, old_offset..old_offset + len);
+                old_offset += len;
+            }
+            ChangeTag::Insert => {
+                push(&mut new_ranges, new_offset..new_offset + len);
+                new_offset += len;
+            }
+        }
+    }
+    
+    (old_ranges, new_ranges)
+}
+
+/// Wrap the highlighted byte ranges of `content` in reverse video
+fn emphasize(content: &str, highlights: &[Range<usize>]) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut last = 0;
+    for range in highlights {
+        if let Some(span) = content.get(range.clone()).filter(|_| range.start >= last) {
+            output.push_str(&content[last..range.start]);
+            output.push_str(ANSI_REVERSE);
+            output.push_str(span);
+            output.push_str(ANSI_NO_REVERSE);
+            last = range.end;
+        }
+    }
+    output.push_str(&content[last..]);
+    output
+}
+
+/// Parse a path from a `---`/`+++` header, dropping timestamps and the `a/`/`b/` prefix
+fn parse_diff_path(raw: &str, prefix: &str) -> PathBuf {
+    let path = raw.trim_end_matches(['\n', '\r']);
+    let path = path.split('\t').next().unwrap_or(path);
+    PathBuf::from(path.strip_prefix(prefix).unwrap_or(path))
+}
+
+/// Parse an `@@ -a,b +c,d @@` header; omitted counts default to 1
+fn parse_hunk_header(header: &str) -> SandboxResult<DiffHunk> {
+    let invalid = || SandboxError::PatchError(format!("invalid hunk header: {}", header));
+    
+    let (ranges, heading) = header
+        .strip_prefix("@@ ")
+        .and_then(|rest| rest.split_once(" @@"))
+        .ok_or_else(invalid)?;
+    let heading = heading.trim();
+    let mut parts = ranges.split_whitespace();
+    
+    let parse_range = |part: Option<&str>, sign: char| -> SandboxResult<(usize, usize)> {
+        let range = part.and_then(|p| p.strip_prefix(sign)).ok_or_else(invalid)?;
+        let (start, len) = match range.split_once(',') {
+            Some((start, len)) => (start, len),
+            None => (range, "1"),
+        };
+        Ok((
+            start.parse().map_err(|_| invalid())?,
+            len.parse().map_err(|_| invalid())?,
+        ))
+    };
+    
+    let (old_start, old_lines) = parse_range(parts.next(), '-')?;
+    let (new_start, new_lines) = parse_range(parts.next(), '+')?;
+    
+    Ok(DiffHunk {
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        changes: Vec::new(),
+        heading: (!heading.is_empty()).then(|| heading.to_string()),
+    })
+}
+
+/// Git's file mode for permission bits: `100755` when executable, else `100644`
+fn git_mode(mode: Option<u32>) -> &'static str {
+    match mode {
+        Some(mode) if mode & 0o111 != 0 => "100755",
+        _ => "100644",
+    }
+}
+
+/// Escape text for inclusion in HTML element content or attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
         }
     }
+    escaped
 }
 
 /// Summary of diff statistics
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct DiffSummary {
     pub added: usize,
     pub deleted: usize,
     pub unchanged: usize,
+    /// Files created, as counted by `DiffEngine::summarize_file_diffs`
+    #[serde(default)]
+    pub files_added: usize,
+    /// Files edited or renamed
+    #[serde(default)]
+    pub files_modified: usize,
+    /// Files removed
+    #[serde(default)]
+    pub files_deleted: usize,
 }
 
 impl DiffSummary {
+    /// Whether any line or file changed; an empty file created or removed counts
     pub fn has_changes(&self) -> bool {
-        self.added > 0 || self.deleted > 0
+        self.added > 0 || self.deleted > 0 || self.files_changed() > 0
     }
     
     pub fn format(&self) -> String {
@@ -188,6 +1053,24 @@ impl DiffSummary {
             self.added, self.deleted
         )
     }
+    
+    /// Total files added, modified or deleted
+    pub fn files_changed(&self) -> usize {
+        self.files_added + self.files_modified + self.files_deleted
+    }
+    
+    /// File-level counts, e.g. `3 files changed (1 added, 1 modified, 1 deleted)`
+    pub fn format_files(&self) -> String {
+        let files = self.files_changed();
+        format!(
+            "{} file{} changed ({} added, {} modified, {} deleted)",
+            files,
+            if files == 1 { "" } else { "s" },
+            self.files_added,
+            self.files_modified,
+            self.files_deleted
+        )
+    }
 }
 
 /// Compute diff between two files
@@ -207,14 +1090,39 @@ pub fn compute_file_diff(old_path: &PathBuf, new_path: &PathBuf) -> SandboxResul
     Ok(DiffEngine::unified_diff(&old_content, &new_content, old_path, new_path))
 }
 
-/// Generate a side-by-side diff view
+/// Terminal width assumed when stdout is not a TTY
+const FALLBACK_TERMINAL_WIDTH: usize = 80;
+
+/// Columns a tab advances to the next multiple of
+const TAB_WIDTH: usize = 4;
+
+/// Generate a side-by-side diff view sized to the terminal
 pub fn side_by_side_diff(old: &str, new: &str) -> String {
+    let width = terminal_size::terminal_size()
+        .map(|(terminal_size::Width(w), _)| w as usize)
+        .unwrap_or(FALLBACK_TERMINAL_WIDTH);
+    
+    side_by_side_diff_with_width(old, new, width)
+}
+
+/// Generate a side-by-side diff view that fits within `width` display columns
+///
+/// Tabs are expanded and wide characters measured by display width, so the
+/// box stays aligned; lines longer than a column are elided with `…`.
+pub fn side_by_side_diff_with_width(old: &str, new: &str, width: usize) -> String {
+    // Each row is "│m│ left │ right │", i.e. two columns plus nine border cells
+    let column = (width.saturating_sub(9) / 2).max(8);
+    let rule = "─".repeat(column + 2);
     let diff = TextDiff::from_lines(old, new);
     let mut output = String::new();
     
-    output.push_str("┌─────────────────────────────────────┬─────────────────────────────────────┐\n");
-    output.push_str("│ OLD                                │ NEW                                │\n");
-    output.push_str("├─────────────────────────────────────┼─────────────────────────────────────┤\n");
+    output.push_str(&format!("┌─┬{}┬{}┐\n", rule, rule));
+    output.push_str(&format!(
+        "│ │ {} │ {} │\n",
+        fit_to_width("OLD", column),
+        fit_to_width("NEW", column)
+    ));
+    output.push_str(&format!("├─┼{}┼{}┤\n", rule, rule));
     
     for change in diff.iter_all_changes() {
         let (left, right, marker) = match change.tag() {
@@ -223,14 +1131,51 @@ pub fn side_by_side_diff(old: &str, new: &str) -> String {
             ChangeTag::Equal => (change.to_string(), change.to_string(), " "),
         };
         
-        // Pad to 35 chars
-        let left = format!("{:<35}", left.trim_end());
-        let right = format!("{:<35}", right.trim_end());
-        
-        output.push_str(&format!("│{}│ {} │ {} │\n", marker, left, right));
+        output.push_str(&format!(
+            "│{}│ {} │ {} │\n",
+            marker,
+            fit_to_width(left.trim_end_matches(['\n', '\r']), column),
+            fit_to_width(right.trim_end_matches(['\n', '\r']), column)
+        ));
     }
     
-    output.push_str("└─────────────────────────────────────┴─────────────────────────────────────┘\n");
+    output.push_str(&format!("└─┴{}┴{}┘\n", rule, rule));
     
     output
 }
+
+/// Expand tabs, then pad or elide `text` to exactly `width` display columns
+fn fit_to_width(text: &str, width: usize) -> String {
+    use unicode_width::UnicodeWidthChar;
+    
+    let mut expanded = String::new();
+    let mut expanded_width = 0;
+    for c in text.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - expanded_width % TAB_WIDTH;
+            expanded.push_str(&" ".repeat(spaces));
+            expanded_width += spaces;
+        } else {
+            expanded.push(c);
+            expanded_width += c.width().unwrap_or(0);
+        }
+    }
+    
+    if expanded_width <= width {
+        return format!("{}{}", expanded, " ".repeat(width - expanded_width));
+    }
+    
+    // Leave one column for the ellipsis
+    let mut fitted = String::new();
+    let mut fitted_width = 0;
+    for c in expanded.chars() {
+        let w = c.width().unwrap_or(0);
+        if fitted_width + w > width - 1 {
+            break;
+        }
+        fitted.push(c);
+        fitted_width += w;
+    }
+    
+    format!("{}…{}", fitted, " ".repeat(width - 1 - fitted_width))
+}
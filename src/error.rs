@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,6 +6,12 @@ pub enum SandboxError {
     #[error("Permission denied: {0}")]
     PermissionDenied(String),
     
+    #[error("Path not allowed: '{tool}' may not access {}", path.display())]
+    PathNotAllowed { tool: String, path: PathBuf },
+    
+    #[error("Failed to spawn '{tool}': {source}")]
+    SpawnFailed { tool: String, source: std::io::Error },
+    
     #[error("Command not found: {0}")]
     CommandNotFound(String),
     
@@ -23,6 +30,15 @@ pub enum SandboxError {
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
     
+    #[error("Patch failed: {0}")]
+    PatchError(String),
+    
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    
+    #[error("Approval expired: {0}")]
+    ApprovalExpired(String),
+    
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     
@@ -30,4 +46,45 @@ pub enum SandboxError {
     SerdeError(#[from] serde_json::Error),
 }
 
+impl SandboxError {
+    /// Stable process exit code for this kind of failure
+    ///
+    /// | Variant               | Code |
+    /// |-----------------------|------|
+    /// | `PermissionDenied`    | 13   |
+    /// | `PathNotAllowed`      | 14   |
+    /// | `InvalidCommand`      | 64   |
+    /// | `PatchError`          | 65   |
+    /// | `VirtualFileNotFound` | 66   |
+    /// | `WasiError`           | 70   |
+    /// | `SimulationError`     | 71   |
+    /// | `FileSystemError`     | 73   |
+    /// | `IoError`             | 74   |
+    /// | `ApprovalExpired`     | 75   |
+    /// | `SerdeError`          | 76   |
+    /// | `InvalidConfig`       | 78   |
+    /// | `SpawnFailed`         | 126  |
+    /// | `CommandNotFound`     | 127  |
+    ///
+    /// The codes are part of the CLI's contract and do not change between releases.
+    pub fn code(&self) -> i32 {
+        match self {
+            SandboxError::PermissionDenied(_) => 13,
+            SandboxError::PathNotAllowed { .. } => 14,
+            SandboxError::InvalidCommand(_) => 64,
+            SandboxError::PatchError(_) => 65,
+            SandboxError::VirtualFileNotFound(_) => 66,
+            SandboxError::WasiError(_) => 70,
+            SandboxError::SimulationError(_) => 71,
+            SandboxError::FileSystemError(_) => 73,
+            SandboxError::IoError(_) => 74,
+            SandboxError::ApprovalExpired(_) => 75,
+            SandboxError::SerdeError(_) => 76,
+            SandboxError::InvalidConfig(_) => 78,
+            SandboxError::SpawnFailed { .. } => 126,
+            SandboxError::CommandNotFound(_) => 127,
+        }
+    }
+}
+
 pub type SandboxResult<T> = Result<T, SandboxError>;
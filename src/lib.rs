@@ -24,17 +24,34 @@
 //!
 //! // Execute a command (won't actually run)
 //! let result = sandbox.execute("git commit -m 'fix: bug'")?;
+//! # Ok::<(), agent_sandbox::SandboxError>(())
 //! ```
 
+pub mod backend;
 pub mod diff_engine;
 pub mod error;
 pub mod permissions;
+pub mod predictor;
 pub mod sandbox;
 pub mod virtual_fs;
 
 // Re-export main types
-pub use diff_engine::{DiffEngine, DiffSummary, UnifiedDiff};
+pub use backend::{BackendOutput, ExecutionBackend, Invocation, NativeBackend};
+#[cfg(feature = "wasi")]
+pub use backend::WasiBackend;
+pub use diff_engine::{
+    DiffAlgorithm, DiffEngine, DiffOptions, DiffSummary, FileStat, FormatOptions, MultiFileStat,
+    PathMasker, UnifiedDiff,
+};
 pub use error::{SandboxError, SandboxResult};
-pub use permissions::{PermissionGate, PermissionLevel, ToolPermission};
-pub use sandbox::{ExecutionMode, ExecutionResult, ExecutionStatus, Sandbox, SandboxStatus};
-pub use virtual_fs::{DiffOperation, FileDiff, VirtualFile, VirtualFilesystem};
+pub use permissions::{ApprovalPolicy, PermissionGate, PermissionLevel, ToolPermission, UnknownToolPolicy};
+pub use predictor::SideEffectPredictor;
+pub use sandbox::{
+    AfterHook, BeforeHook, CancellationToken, ChainOperator, CheckOutcome, ExecutionMode,
+    ExecutionResult, ExecutionStatus, HookDecision, ParsedCommand, RetryPolicy, Sandbox,
+    SandboxBuilder, SandboxEvent, SandboxStatus, SessionReport, SessionSnapshot, VerifiedExecution,
+};
+pub use virtual_fs::{
+    BinaryChange, CompactStats, DiffOperation, FileDiff, FlushConflict, FlushOperation, FlushPlan,
+    FlushStep, FsSnapshot, VirtualFile, VirtualFilesystem,
+};
@@ -1,7 +1,12 @@
-use agent_sandbox::sandbox::{ExecutionMode, Sandbox};
-use clap::{Parser, Subcommand};
+use agent_sandbox::sandbox::{ExecutionMode, ExecutionResult, ExecutionStatus, Sandbox};
+use agent_sandbox::{DiffEngine, DiffOperation, FileDiff, SandboxError};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use tracing::{info, error, Level};
+use std::time::Duration;
+use tracing::{info, warn, error, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::FmtSubscriber;
 
 /// Agent Sandbox - Deterministic Execution Firewall
@@ -13,14 +18,26 @@ use tracing_subscriber::FmtSubscriber;
 #[command(version = "0.1.0")]
 #[command(about = "Sandbox runtime for AI agents", long_about = None)]
 struct Cli {
-    /// Enable verbose output
+    /// Log debug messages, such as permission checks
     #[arg(short, long)]
     verbose: bool,
     
+    /// Only log warnings and errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    
+    /// Log line format; `json` emits one JSON object per event
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    
     /// Set working directory
     #[arg(short, long, default_value = ".")]
     working_dir: PathBuf,
     
+    /// Skip files ignored by .gitignore when mounting the working directory
+    #[arg(long)]
+    gitignore: bool,
+    
     /// Allow all commands (bypass permissions)
     #[arg(long)]
     allow_all: bool,
@@ -33,14 +50,55 @@ struct Cli {
     #[arg(long)]
     diff: bool,
     
+    /// Kill commands that run longer than this many seconds
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    
     /// Allow specific tools (comma-separated)
     #[arg(long)]
     allow: Option<String>,
     
+    /// Load the sandbox from this session file and save it back afterwards
+    #[arg(long, value_name = "FILE")]
+    session: Option<PathBuf>,
+    
+    /// Output format; `json` prints results as JSON for scripts
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    format: OutputFormat,
+    
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// How command output is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable report
+    Text,
+    /// One pretty-printed JSON document
+    Json,
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Plain text lines
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+/// Most detailed log level to emit for the given flags
+fn log_level(verbose: bool, quiet: bool) -> Level {
+    if verbose {
+        Level::DEBUG
+    } else if quiet {
+        Level::WARN
+    } else {
+        Level::INFO
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run a command in the sandbox
@@ -57,6 +115,15 @@ enum Commands {
     Diff {
         /// The command to diff
         command: String,
+        
+        /// Only print per-file line counts, like `git diff --stat`
+        #[arg(long)]
+        stat: bool,
+    },
+    /// Report whether a command would be allowed, without running it
+    Check {
+        /// The command to check
+        command: String,
     },
     /// Show sandbox status
     Status,
@@ -68,37 +135,142 @@ enum Commands {
     Approve {
         /// Execution ID
         execution_id: String,
+        
+        /// Only run if the command still has this content hash, as printed when it was queued
+        #[arg(long, value_name = "SHA256")]
+        hash: Option<String>,
+    },
+    /// Reject a pending execution
+    Reject {
+        /// Execution ID
+        execution_id: String,
+        
+        /// Why the execution was rejected
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// List executions waiting for approval
+    Pending,
+    /// Summarize the session: commands run, blocked and approved, and files touched
+    Report {
+        /// Print the report as Markdown instead of text
+        #[arg(long)]
+        markdown: bool,
+    },
+    /// Write the pending virtual filesystem changes to the working directory
+    Apply {
+        /// Commands to diff first; their predicted changes are staged before writing
+        commands: Vec<String>,
     },
+    /// Save the pending virtual filesystem changes as a patch file
+    ExportDiff {
+        /// Patch file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        
+        /// Unchanged lines kept around each change
+        #[arg(long, default_value_t = agent_sandbox::diff_engine::DEFAULT_CONTEXT_LINES)]
+        context: usize,
+    },
+    /// Read commands line by line from stdin and run them in one session
+    Repl,
     /// Show execution history
-    History,
+    History {
+        /// Only show executions of this tool
+        #[arg(long)]
+        tool: Option<String>,
+        
+        /// Only show executions with this status (e.g. success, failed, blocked)
+        #[arg(long, value_parser = parse_status)]
+        status: Option<ExecutionStatus>,
+        
+        /// Only show executions since this time (Unix seconds or RFC 3339)
+        #[arg(long, value_parser = parse_timestamp)]
+        since: Option<i64>,
+    },
+}
+
+fn parse_status(value: &str) -> Result<ExecutionStatus, String> {
+    let status = match value.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+        "success" => ExecutionStatus::Success,
+        "failed" => ExecutionStatus::Failed,
+        "blocked" => ExecutionStatus::Blocked,
+        "simulated" => ExecutionStatus::Simulated,
+        "pendingapproval" | "pending" => ExecutionStatus::PendingApproval,
+        "timedout" => ExecutionStatus::TimedOut,
+        "skipped" => ExecutionStatus::Skipped,
+        "cancelled" | "canceled" => ExecutionStatus::Cancelled,
+        _ => return Err(format!("unknown status '{}'", value)),
+    };
+    Ok(status)
+}
+
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    if let Ok(secs) = value.parse() {
+        return Ok(secs);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|time| time.timestamp())
+        .map_err(|e| format!("expected Unix seconds or an RFC 3339 time: {}", e))
 }
 
 fn main() {
-    // Initialize logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .finish();
-    
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("setting default subscriber failed");
-    
     let cli = Cli::parse();
+    let format = cli.format;
+    
+    // Initialize logging; keep stdout clean for JSON and Markdown consumers
+    let markdown = matches!(cli.command, Some(Commands::Report { markdown: true }));
+    let writer = match format {
+        OutputFormat::Text if !markdown => BoxMakeWriter::new(std::io::stdout),
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
+    let builder = FmtSubscriber::builder()
+        .with_max_level(log_level(cli.verbose, cli.quiet))
+        .with_target(false)
+        .with_writer(writer);
+    let installed = match cli.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => {
+            tracing::subscriber::set_global_default(builder.json().with_current_span(true).finish())
+        }
+    };
+    installed.expect("setting default subscriber failed");
     
-    // Create sandbox
-    let mut sandbox = match Sandbox::with_working_dir(cli.working_dir) {
+    // Create sandbox, resuming the session if one was saved
+    let created = match &cli.session {
+        Some(path) if path.exists() => Sandbox::load_session(path),
+        _ => Sandbox::with_working_dir(cli.working_dir),
+    };
+    let mut sandbox = match created {
         Ok(s) => s,
         Err(e) => {
             error!("Failed to create sandbox: {}", e);
-            std::process::exit(1);
+            std::process::exit(e.code());
         }
     };
     
+    // Environment configuration first, so explicit flags override it
+    if let Err(e) = sandbox.permissions.apply_env() {
+        error!("Failed to read permissions from the environment: {}", e);
+        std::process::exit(e.code());
+    }
+    
     // Apply CLI options
+    if cli.gitignore {
+        if let Err(e) = sandbox.set_respect_gitignore(true) {
+            error!("Failed to mount working directory: {}", e);
+            std::process::exit(e.code());
+        }
+    }
+    
     if cli.allow_all {
         sandbox.allow_all();
     }
     
+    if let Some(secs) = cli.timeout {
+        sandbox.set_execution_timeout(Some(Duration::from_secs(secs)));
+    }
+    
     if cli.simulate {
         sandbox.set_mode(ExecutionMode::Simulation);
     } else if cli.diff {
@@ -107,27 +279,31 @@ fn main() {
     
     // Handle custom allowed tools
     if let Some(tools) = cli.allow {
-        for tool in tools.split(',') {
+        for tool in tools.split(',').map(str::trim).filter(|tool| !tool.is_empty()) {
             info!("Allowing tool: {}", tool);
+            sandbox.permissions.allow_tool(tool);
         }
     }
     
     // Execute commands
     let result = match &cli.command {
         Some(Commands::Run { command }) => {
-            sandbox.set_mode(ExecutionMode::Live);
-            run_command(&mut sandbox, command)
+            run_command(&mut sandbox, command, ExecutionMode::Live, format)
         }
         Some(Commands::Sim { command }) => {
-            sandbox.set_mode(ExecutionMode::Simulation);
-            run_command(&mut sandbox, command)
+            run_command(&mut sandbox, command, ExecutionMode::Simulation, format)
         }
-        Some(Commands::Diff { command }) => {
-            sandbox.set_mode(ExecutionMode::Diff);
-            run_command(&mut sandbox, command)
+        Some(Commands::Diff { command, stat: false }) => {
+            run_command(&mut sandbox, command, ExecutionMode::Diff, format)
+        }
+        Some(Commands::Diff { command, stat: true }) => {
+            diff_stat(&mut sandbox, command, format)
+        }
+        Some(Commands::Check { command }) => {
+            check_command(&sandbox, command, format)
         }
         Some(Commands::Status) => {
-            show_status(&sandbox)
+            show_status(&sandbox, format)
         }
         Some(Commands::Reset) => {
             sandbox.reset();
@@ -135,13 +311,36 @@ fn main() {
             Ok(())
         }
         Some(Commands::ListTools) => {
-            list_tools(&sandbox)
+            list_tools(&sandbox, format)
+        }
+        Some(Commands::Approve { execution_id, hash }) => {
+            approve_execution(&mut sandbox, execution_id, hash.as_deref(), format)
         }
-        Some(Commands::Approve { execution_id }) => {
-            approve_execution(&mut sandbox, execution_id)
+        Some(Commands::Reject { execution_id, reason }) => {
+            reject_execution(&mut sandbox, execution_id, reason.clone(), format)
         }
-        Some(Commands::History) => {
-            show_history(&sandbox)
+        Some(Commands::Pending) => {
+            list_pending(&sandbox, format)
+        }
+        Some(Commands::Report { markdown }) => {
+            show_report(&sandbox, *markdown, format)
+        }
+        Some(Commands::Apply { commands }) => {
+            apply_changes(&mut sandbox, commands, format)
+        }
+        Some(Commands::ExportDiff { output, context }) => {
+            export_diff(&sandbox, output, *context, format)
+        }
+        Some(Commands::History { tool, status, since }) => {
+            let entries = sandbox.history_filter(|result| {
+                tool.as_ref().is_none_or(|tool| result.tool == *tool)
+                    && status.is_none_or(|status| result.status == status)
+                    && since.is_none_or(|since| result.executed_at >= since)
+            });
+            show_history(&entries, format)
+        }
+        Some(Commands::Repl) => {
+            repl(&mut sandbox, format)
         }
         None => {
             // No subcommand - print help
@@ -150,16 +349,51 @@ fn main() {
         }
     };
     
+    let result = result.and_then(|()| match &cli.session {
+        Some(path) => Ok(sandbox.save_session(path)?),
+        None => Ok(()),
+    });
+    
     if let Err(e) = result {
         error!("Error: {}", e);
-        std::process::exit(1);
+        // Sandbox errors map to their documented codes; anything else is a generic failure
+        let code = e.downcast_ref::<SandboxError>().map_or(1, SandboxError::code);
+        std::process::exit(code);
     }
 }
 
-fn run_command(sandbox: &mut Sandbox, command: &str) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Executing: {} (mode: {:?})", command, sandbox.mode);
-    
-    let result = sandbox.execute(command)?;
+/// Print `value` as pretty JSON on stdout
+fn print_json(value: &impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// How to run a command that is waiting for approval
+fn approval_notice(result: &ExecutionResult) -> String {
+    let hash = result.approval_hash.clone().unwrap_or_else(|| result.content_hash());
+    format!(
+        "This command requires approval. Use 'agent-sandbox approve {} --hash {}' to execute.",
+        result.id, hash
+    )
+}
+
+fn run_command(
+    sandbox: &mut Sandbox,
+    command: &str,
+    mode: ExecutionMode,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Executing: {} (mode: {:?})", command, mode);
+    
+    let result = sandbox.execute_with_mode(command, mode)?;
+    
+    if format == OutputFormat::Json {
+        let mut json = serde_json::to_value(&result)?;
+        if result.status == ExecutionStatus::PendingApproval {
+            json["approval_notice"] = approval_notice(&result).into();
+        }
+        return print_json(&json);
+    }
     
     // Print results
     println!("\n{}", "=".repeat(60));
@@ -179,6 +413,7 @@ fn run_command(sandbox: &mut Sandbox, command: &str) -> Result<(), Box<dyn std::
     
     if let Some(ref summary) = result.diff_summary {
         println!("\nDiff Summary: +{} -{}", summary.added, summary.deleted);
+        println!("{}", summary.format_files());
     }
     
     if !result.file_changes.is_empty() {
@@ -186,18 +421,155 @@ fn run_command(sandbox: &mut Sandbox, command: &str) -> Result<(), Box<dyn std::
         for change in &result.file_changes {
             println!("  {}: {:?}", change.path.display(), change.operation);
         }
+        
+        if result.mode == ExecutionMode::Diff {
+            print_file_diffs(&result.file_changes);
+        }
+    }
+    
+    if result.status == ExecutionStatus::PendingApproval {
+        println!("\n⚠️  {}", approval_notice(&result));
     }
     
-    if result.status == agent_sandbox::sandbox::ExecutionStatus::PendingApproval {
-        println!("\n⚠️  This command requires approval. Use 'agent-sandbox approve {}' to execute.", result.id);
+    Ok(())
+}
+
+fn diff_stat(sandbox: &mut Sandbox, command: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Diffing: {}", command);
+    
+    let result = sandbox.execute_with_mode(command, ExecutionMode::Diff)?;
+    let stat = DiffEngine::multi_file_stat(&result.file_changes);
+    
+    if format == OutputFormat::Json {
+        return print_json(&stat);
     }
     
+    print!("{}", stat.format());
     Ok(())
 }
 
-fn show_status(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
+/// Prompt shown before each REPL line when stdin is a terminal
+const REPL_PROMPT: &str = "sandbox> ";
+
+/// Run each line of stdin in `sandbox` until `:quit` or end of input
+///
+/// Lines starting with `:` are meta-commands; everything else runs in the
+/// sandbox's mode. A failing line is logged and the session carries on.
+fn repl(sandbox: &mut Sandbox, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{BufRead, Write};
+    
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            print!("{}", REPL_PROMPT);
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        
+        let line = line.trim();
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let outcome = match word {
+            "" => Ok(()),
+            ":quit" | ":q" | ":exit" => break,
+            ":help" => {
+                println!(":status  :history  :diff  :approve <id>  :quit");
+                Ok(())
+            }
+            ":status" => show_status(sandbox, format),
+            ":history" => show_history(&sandbox.history_filter(|_| true), format),
+            ":diff" => show_session_diff(sandbox, format),
+            ":approve" if !rest.trim().is_empty() => approve_execution(sandbox, rest.trim(), None, format),
+            ":approve" => Err("usage: :approve <id>".into()),
+            meta if meta.starts_with(':') => Err(format!("unknown meta-command '{}', try :help", meta).into()),
+            _ => run_command(sandbox, line, sandbox.mode, format),
+        };
+        if let Err(e) = outcome {
+            error!("{}", e);
+        }
+    }
+    
+    Ok(())
+}
+
+/// Print the net file changes of the session so far
+fn show_session_diff(sandbox: &Sandbox, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let changes = sandbox.cumulative_diff();
+    if format == OutputFormat::Json {
+        return print_json(&changes);
+    }
+    
+    if changes.is_empty() {
+        println!("No file changes");
+        return Ok(());
+    }
+    for change in &changes {
+        println!("  {}: {:?}", change.path.display(), change.operation);
+    }
+    print_file_diffs(&changes);
+    Ok(())
+}
+
+fn print_file_diffs(changes: &[FileDiff]) {
+    let color = std::io::stdout().is_terminal();
+    
+    for change in changes {
+        if change.old_content.is_none() && change.new_content.is_none() {
+            continue;
+        }
+        
+        let diff = change.unified.clone().unwrap_or_else(|| {
+            DiffEngine::unified_diff(
+                change.old_content.as_deref().unwrap_or(""),
+                change.new_content.as_deref().unwrap_or(""),
+                &change.path,
+                &change.path,
+            )
+        });
+        
+        let formatted = if color {
+            DiffEngine::format_unified_diff_colored(&diff)
+        } else {
+            DiffEngine::format_unified_diff(&diff)
+        };
+        println!("\n{}", formatted);
+    }
+}
+
+fn check_command(sandbox: &Sandbox, command: &str, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let outcome = sandbox.check(command)?;
+    
+    if format == OutputFormat::Json {
+        return print_json(&outcome);
+    }
+    
+    println!("\n{}", "=".repeat(60));
+    println!("Command: {}", command);
+    println!("Allowed: {}", if outcome.is_allowed() { "yes" } else { "no" });
+    println!("Permission Level: {:?}", outcome.permission_level);
+    println!("Requires Approval: {}", if outcome.requires_approval { "yes" } else { "no" });
+    println!("{}", "=".repeat(60));
+    
+    if !outcome.violations.is_empty() {
+        println!("\nViolations:");
+        for violation in &outcome.violations {
+            println!("  {}", violation);
+        }
+    }
+    
+    Ok(())
+}
+
+fn show_status(sandbox: &Sandbox, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
     let status = sandbox.status();
     
+    if format == OutputFormat::Json {
+        return print_json(&status);
+    }
+    
     println!("\n{}", "=".repeat(60));
     println!("Agent Sandbox Status");
     println!("{}", "=".repeat(60));
@@ -207,14 +579,44 @@ fn show_status(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
     println!("Files: {}", status.file_count);
     println!("Executions: {}", status.execution_count);
     println!("Pending Approvals: {}", status.pending_approval_count);
+    println!("Pending Changes: {}", status.pending_changes.format_files());
     println!("{}", "=".repeat(60));
     
     Ok(())
 }
 
-fn list_tools(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
+fn show_report(sandbox: &Sandbox, markdown: bool, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let report = sandbox.report();
+    
+    match format {
+        OutputFormat::Json if markdown => Err("--markdown cannot be combined with --format json".into()),
+        OutputFormat::Json => print_json(&report),
+        OutputFormat::Text if markdown => {
+            print!("{}", report.to_markdown());
+            Ok(())
+        }
+        OutputFormat::Text => {
+            println!("\n{}", "=".repeat(60));
+            println!("Session Report");
+            println!("{}", "=".repeat(60));
+            print!("{}", report);
+            println!("{}", "=".repeat(60));
+            Ok(())
+        }
+    }
+}
+
+fn list_tools(sandbox: &Sandbox, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
     let tools = sandbox.permissions.list_tools();
     
+    if format == OutputFormat::Json {
+        let permissions: Vec<_> = tools
+            .iter()
+            .filter_map(|tool| sandbox.permissions.get_permission(tool))
+            .collect();
+        return print_json(&permissions);
+    }
+    
     println!("\n{}", "=".repeat(60));
     println!("Available Tools");
     println!("{}", "=".repeat(60));
@@ -235,10 +637,22 @@ fn list_tools(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn approve_execution(sandbox: &mut Sandbox, execution_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn approve_execution(
+    sandbox: &mut Sandbox,
+    execution_id: &str,
+    hash: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
     info!("Approving execution: {}", execution_id);
     
-    let result = sandbox.approve(execution_id)?;
+    let result = match hash {
+        Some(hash) => sandbox.approve_checked(execution_id, hash)?,
+        None => sandbox.approve(execution_id)?,
+    };
+    
+    if format == OutputFormat::Json {
+        return print_json(&result);
+    }
     
     println!("\n{}", "=".repeat(60));
     println!("Execution Approved & Executed");
@@ -259,8 +673,231 @@ fn approve_execution(sandbox: &mut Sandbox, execution_id: &str) -> Result<(), Bo
     Ok(())
 }
 
-fn show_history(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
-    let history = sandbox.history();
+fn reject_execution(
+    sandbox: &mut Sandbox,
+    execution_id: &str,
+    reason: Option<String>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Rejecting execution: {}", execution_id);
+    
+    let result = sandbox.reject(execution_id, reason)?;
+    
+    if format == OutputFormat::Json {
+        return print_json(&result);
+    }
+    
+    println!("\n{}", "=".repeat(60));
+    println!("Execution Rejected");
+    println!("{}", "=".repeat(60));
+    println!("Command: {}", result.command);
+    println!("Status: {:?}", result.status);
+    println!("{}", "=".repeat(60));
+    
+    if !result.stderr.is_empty() {
+        println!("\n{}", result.stderr);
+    }
+    
+    Ok(())
+}
+
+/// One execution waiting for approval, as listed by `pending`
+#[derive(Serialize)]
+struct PendingEntry<'a> {
+    id: &'a str,
+    command: &'a str,
+    /// Content hash to pass to `approve --hash`
+    hash: String,
+    /// Seconds since the execution was queued
+    age_secs: i64,
+}
+
+fn list_pending(sandbox: &Sandbox, format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    let entries: Vec<PendingEntry> = sandbox
+        .list_pending()
+        .into_iter()
+        .map(|result| PendingEntry {
+            id: &result.id,
+            command: &result.command,
+            hash: result.content_hash(),
+            age_secs: (now - result.created_at).max(0),
+        })
+        .collect();
+    
+    if format == OutputFormat::Json {
+        return print_json(&entries);
+    }
+    
+    println!("\n{}", "=".repeat(60));
+    println!("Pending Approvals");
+    println!("{}", "=".repeat(60));
+    
+    if entries.is_empty() {
+        println!("No executions waiting for approval.");
+    } else {
+        for entry in &entries {
+            println!("\n{}", entry.id);
+            println!("    Command: {}", entry.command);
+            println!("    Hash: {}", entry.hash);
+            println!("    Age: {}", format_age(entry.age_secs));
+        }
+    }
+    
+    println!("\n{}", "=".repeat(60));
+    
+    Ok(())
+}
+
+/// `secs` as the two largest of hours, minutes and seconds, e.g. `3m 12s`
+fn format_age(secs: i64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Where `export-diff` wrote its patch and which files it covers
+#[derive(Serialize)]
+struct ExportSummary {
+    output: PathBuf,
+    files: Vec<PathBuf>,
+}
+
+fn export_diff(
+    sandbox: &Sandbox,
+    output: &PathBuf,
+    context: usize,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = sandbox.virtual_fs.get_diff();
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    let patch = DiffEngine::format_patch(&changes, context);
+    if patch.is_empty() {
+        return Err("no pending virtual changes to export".into());
+    }
+    
+    std::fs::write(output, &patch)?;
+    info!("Wrote patch to {}", output.display());
+    
+    let summary = ExportSummary {
+        output: output.clone(),
+        files: changes
+            .into_iter()
+            .filter(|c| {
+                !matches!(
+                    c.operation,
+                    DiffOperation::DirectoryAdded | DiffOperation::DirectoryRemoved | DiffOperation::Symlinked { .. }
+                )
+            })
+            .map(|c| c.path)
+            .collect(),
+    };
+    
+    if format == OutputFormat::Json {
+        return print_json(&summary);
+    }
+    
+    println!("Exported {} file(s) to {}", summary.files.len(), summary.output.display());
+    Ok(())
+}
+
+/// Files an `apply` wrote and deleted
+#[derive(Serialize)]
+struct ApplySummary {
+    written: Vec<PathBuf>,
+    deleted: Vec<PathBuf>,
+}
+
+/// Diff `command` and stage its predicted changes in the virtual filesystem
+///
+/// Only changes whose contents are known are staged; a prediction that just
+/// describes what a tool would write has nothing to put on disk. Predicted
+/// deletions of files that do not exist are skipped.
+fn stage_command(sandbox: &mut Sandbox, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let result = sandbox.execute_with_mode(command, ExecutionMode::Diff)?;
+    if result.status != ExecutionStatus::Simulated {
+        return Err(format!("{}: {:?}: {}", command, result.status, result.stderr).into());
+    }
+    
+    let vfs = &mut sandbox.virtual_fs;
+    for change in result.file_changes {
+        match change.operation {
+            DiffOperation::Added | DiffOperation::Modified => match change.new_content {
+                Some(content) if change.unified.is_some() => vfs.write(change.path, content.into_bytes()),
+                _ => warn!("Not staging {}: its contents are not known", change.path.display()),
+            },
+            DiffOperation::Deleted if vfs.is_file(&change.path) => vfs.delete(&change.path)?,
+            DiffOperation::Deleted => warn!("Not staging deletion of {}: no such file", change.path.display()),
+            DiffOperation::Renamed { from } => vfs.rename(&from, &change.path)?,
+            DiffOperation::DirectoryAdded => vfs.mkdir(&change.path)?,
+            DiffOperation::DirectoryRemoved => vfs.rmdir(&change.path)?,
+            DiffOperation::Symlinked { target } => vfs.symlink_force(&change.path, &target)?,
+        }
+    }
+    
+    Ok(())
+}
+
+fn apply_changes(sandbox: &mut Sandbox, commands: &[String], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    for command in commands {
+        stage_command(sandbox, command)?;
+    }
+    
+    let changes = sandbox.virtual_fs.get_diff();
+    if changes.is_empty() {
+        return Err("no pending virtual changes to apply".into());
+    }
+    
+    info!("Applying {} change(s) to {}", changes.len(), sandbox.working_dir.display());
+    sandbox.virtual_fs.flush_to_disk(&sandbox.working_dir)?;
+    sandbox.virtual_fs.commit();
+    
+    let mut summary = ApplySummary {
+        written: Vec::new(),
+        deleted: Vec::new(),
+    };
+    for change in changes {
+        match change.operation {
+            DiffOperation::Added | DiffOperation::Modified | DiffOperation::Symlinked { .. } => {
+                summary.written.push(change.path)
+            }
+            DiffOperation::Renamed { from } => {
+                summary.written.push(change.path);
+                summary.deleted.push(from);
+            }
+            DiffOperation::Deleted => summary.deleted.push(change.path),
+            DiffOperation::DirectoryAdded | DiffOperation::DirectoryRemoved => {}
+        }
+    }
+    summary.written.sort();
+    summary.deleted.sort();
+    
+    if format == OutputFormat::Json {
+        return print_json(&summary);
+    }
+    
+    println!("\n{}", "=".repeat(60));
+    println!("Changes Applied");
+    println!("{}", "=".repeat(60));
+    println!("Written: {}", summary.written.len());
+    for path in &summary.written {
+        println!("  + {}", path.display());
+    }
+    println!("Deleted: {}", summary.deleted.len());
+    for path in &summary.deleted {
+        println!("  - {}", path.display());
+    }
+    println!("{}", "=".repeat(60));
+    
+    Ok(())
+}
+
+fn show_history(history: &[&ExecutionResult], format: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    if format == OutputFormat::Json {
+        return print_json(&history);
+    }
     
     println!("\n{}", "=".repeat(60));
     println!("Execution History");
@@ -276,6 +913,7 @@ fn show_history(sandbox: &Sandbox) -> Result<(), Box<dyn std::error::Error>> {
             if let Some(code) = result.exit_code {
                 println!("    Exit Code: {}", code);
             }
+            println!("    Duration: {} ms", result.duration_ms);
         }
     }
     
@@ -299,22 +937,36 @@ Usage:
     agent-sandbox [OPTIONS] <COMMAND>
 
 Options:
-    -v, --verbose       Enable verbose output
+    -v, --verbose       Log debug messages, such as permission checks
+    -q, --quiet         Only log warnings and errors
+    --log-format <fmt>  Log format: text (default) or json
     -d, --working-dir   Set working directory (default: .)
+    --gitignore         Skip files ignored by .gitignore when mounting
     --allow-all         Allow all commands (bypass permissions)
     --simulate          Simulation mode - preview only
     --diff              Diff mode - show changes without executing
+    --timeout <secs>    Kill commands that run longer than this
     --allow             Allow specific tools (comma-separated)
+    --format <fmt>      Output format: text (default) or json
+    --session <file>    Resume from and save to a session file
 
 Commands:
     run <command>       Run a command in the sandbox
     sim <command>      Simulate a command (preview only)
-    diff <command>     Show diff of a command
+    diff <command>     Show diff of a command (--stat for per-file line counts)
+    check <command>     Report whether a command would be allowed
     status              Show sandbox status
     reset               Reset the sandbox
     list-tools          List available tools
-    approve <id>       Approve a pending execution
-    history             Show execution history
+    approve <id>       Approve a pending execution (--hash <sha256> to confirm it)
+    reject <id>         Reject a pending execution (--reason <text>)
+    pending             List executions waiting for approval
+    report              Summarize the session (--markdown for Markdown)
+    apply [<command>..] Stage the commands' predicted changes and write them to disk
+    export-diff         Save pending virtual changes as a patch (--output <file>)
+    history             Show execution history (--tool, --status, --since)
+    repl                Run commands from stdin in one session (:status, :history,
+                        :diff, :approve <id>, :quit)
 
 Examples:
     # Run in simulation mode
@@ -1,12 +1,15 @@
 use crate::error::{SandboxError, SandboxResult};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::warn;
 
-/// Permission level for a tool
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Permission level for a tool, ordered from least to most access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub enum PermissionLevel {
     /// No access
+    #[default]
     Denied,
     /// Read-only access
     ReadOnly,
@@ -16,21 +19,113 @@ pub enum PermissionLevel {
     Full,
 }
 
-impl Default for PermissionLevel {
-    fn default() -> Self {
-        PermissionLevel::Denied
+impl std::str::FromStr for PermissionLevel {
+    type Err = SandboxError;
+    
+    /// Parse `denied`, `readonly`, `execute` or `full`, ignoring case
+    fn from_str(s: &str) -> SandboxResult<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "denied" => Ok(PermissionLevel::Denied),
+            "readonly" | "read-only" => Ok(PermissionLevel::ReadOnly),
+            "execute" => Ok(PermissionLevel::Execute),
+            "full" => Ok(PermissionLevel::Full),
+            _ => Err(SandboxError::InvalidConfig(format!("unknown permission level '{}'", s))),
+        }
     }
 }
 
+/// What the permission gate does with a tool that is not registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum UnknownToolPolicy {
+    /// Refuse to run it
+    #[default]
+    Deny,
+    /// Run it with the given level
+    Allow(PermissionLevel),
+    /// Run it with the default level once a human approves it
+    RequireApproval,
+}
+
+/// Decides at run time whether a command must be approved before it runs
+///
+/// Consulted in addition to each tool's `requires_approval` flag, so a policy
+/// can only add approvals, e.g. for `rm -rf` or a `git push` to `main`.
+pub trait ApprovalPolicy: Send + Sync {
+    fn needs_approval(&self, tool: &str, args: &[String], level: PermissionLevel) -> bool;
+}
+
 /// Tool permission configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolPermission {
     pub name: String,
     pub level: PermissionLevel,
+    /// Path prefixes, or globs such as `src/**/*.rs` when they contain `*`, `?` or `[`
     pub allowed_paths: Vec<PathBuf>,
+    /// Like `allowed_paths`; a denied match wins over any allowed one
     pub denied_paths: Vec<PathBuf>,
     pub allowed_args: Vec<String>,
     pub requires_approval: bool,
+    /// Exit codes reported as success in live mode, e.g. `[0, 1]` for `grep`
+    #[serde(default = "default_success_exit_codes")]
+    pub success_exit_codes: Vec<i32>,
+    /// `allowed_paths` and `denied_paths`, compiled on the first `check_path`
+    ///
+    /// `allow_path` and `deny_path` reset it; editing the fields directly
+    /// after a check does not.
+    #[serde(skip)]
+    path_rules: OnceLock<PathRules>,
+}
+
+fn default_success_exit_codes() -> Vec<i32> {
+    vec![0]
+}
+
+/// One entry of `allowed_paths` or `denied_paths`
+#[derive(Debug, Clone)]
+enum PathRule {
+    Prefix(PathBuf),
+    Glob(glob::Pattern),
+}
+
+impl PathRule {
+    /// A glob if `path` contains glob metacharacters and parses as one, else a prefix
+    fn compile(path: &Path) -> Self {
+        let text = path.to_string_lossy();
+        if text.contains(['*', '?', '[']) {
+            if let Ok(pattern) = glob::Pattern::new(&text) {
+                return PathRule::Glob(pattern);
+            }
+        }
+        PathRule::Prefix(path.to_path_buf())
+    }
+    
+    /// Whether the rule names absolute paths rather than paths under a root
+    fn is_absolute(&self) -> bool {
+        match self {
+            PathRule::Prefix(prefix) => prefix.is_absolute(),
+            PathRule::Glob(pattern) => pattern.as_str().starts_with('/'),
+        }
+    }
+    
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            PathRule::Prefix(prefix) => path.starts_with(prefix),
+            PathRule::Glob(pattern) => pattern.matches_path_with(
+                path,
+                glob::MatchOptions {
+                    require_literal_separator: true,
+                    ..Default::default()
+                },
+            ),
+        }
+    }
+}
+
+/// Compiled path rules of a `ToolPermission`
+#[derive(Debug, Clone)]
+struct PathRules {
+    allowed: Vec<PathRule>,
+    denied: Vec<PathRule>,
 }
 
 impl ToolPermission {
@@ -42,6 +137,8 @@ impl ToolPermission {
             denied_paths: Vec::new(),
             allowed_args: Vec::new(),
             requires_approval: false,
+            success_exit_codes: default_success_exit_codes(),
+            path_rules: OnceLock::new(),
         }
     }
     
@@ -52,11 +149,13 @@ impl ToolPermission {
     
     pub fn allow_path(mut self, path: PathBuf) -> Self {
         self.allowed_paths.push(path);
+        self.path_rules = OnceLock::new();
         self
     }
     
     pub fn deny_path(mut self, path: PathBuf) -> Self {
         self.denied_paths.push(path);
+        self.path_rules = OnceLock::new();
         self
     }
     
@@ -70,6 +169,17 @@ impl ToolPermission {
         self
     }
     
+    /// Replace the exit codes that count as success
+    pub fn with_success_exit_codes(mut self, codes: &[i32]) -> Self {
+        self.success_exit_codes = codes.to_vec();
+        self
+    }
+    
+    /// Whether `code` counts as a successful exit for this tool
+    pub fn is_success(&self, code: i32) -> bool {
+        self.success_exit_codes.contains(&code)
+    }
+    
     /// Check if this tool is allowed to run with the given arguments
     pub fn check_args(&self, args: &[String]) -> bool {
         if self.allowed_args.is_empty() {
@@ -84,31 +194,96 @@ impl ToolPermission {
     }
     
     /// Check if a path is allowed
-    pub fn check_path(&self, path: &std::path::Path) -> bool {
-        // Check denied paths first
-        for denied in &self.denied_paths {
-            if path.starts_with(denied) {
-                return false;
+    ///
+    /// Globs match the whole path, with `*` staying within one component and
+    /// `**` spanning directories; other rules match as prefixes. Every rule is
+    /// matched against `path` as given; see `check_path_in` for paths under a root.
+    pub fn check_path(&self, path: &Path) -> bool {
+        self.path_allowed(|rule| rule.matches(path))
+    }
+    
+    /// Check an absolute `path` against rules that may be relative to `root`
+    ///
+    /// Absolute rules match `path` itself; relative rules such as `src/**` match
+    /// it relative to `root`, and never match a path outside `root`. Both
+    /// `root` and `path` should be absolute and free of `..`.
+    pub fn check_path_in(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).ok();
+        self.path_allowed(|rule| {
+            if rule.is_absolute() {
+                rule.matches(path)
+            } else {
+                relative.is_some_and(|relative| rule.matches(relative))
             }
+        })
+    }
+    
+    /// Apply the denied rules, then the allowed ones, using `matches` to test each rule
+    fn path_allowed(&self, matches: impl Fn(&PathRule) -> bool) -> bool {
+        let rules = self.path_rules.get_or_init(|| PathRules {
+            allowed: self.allowed_paths.iter().map(|p| PathRule::compile(p)).collect(),
+            denied: self.denied_paths.iter().map(|p| PathRule::compile(p)).collect(),
+        });
+        
+        // Check denied paths first
+        if rules.denied.iter().any(&matches) {
+            return false;
         }
         
         // If no allowed paths specified, allow all
-        if self.allowed_paths.is_empty() {
+        if rules.allowed.is_empty() {
             return true;
         }
         
         // Check allowed paths
-        self.allowed_paths.iter().any(|allowed| path.starts_with(allowed))
+        rules.allowed.iter().any(matches)
     }
 }
 
 /// Permission gate for tools
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredPermissionGate")]
 pub struct PermissionGate {
     tools: HashSet<String>,
     permissions: std::collections::HashMap<String, ToolPermission>,
     default_level: PermissionLevel,
+    unknown_tools: UnknownToolPolicy,
+    /// Environment variables that may be expanded in command lines
+    env_allowlist: HashSet<String>,
+}
+
+/// A permission gate as stored in a session, current or older
+///
+/// Sessions written before `UnknownToolPolicy` carry `allow_unknown: bool`
+/// instead, which allowed unknown tools at the default level.
+#[derive(Deserialize)]
+struct StoredPermissionGate {
+    tools: HashSet<String>,
+    permissions: std::collections::HashMap<String, ToolPermission>,
+    default_level: PermissionLevel,
+    #[serde(default)]
+    unknown_tools: Option<UnknownToolPolicy>,
+    #[serde(default)]
     allow_unknown: bool,
+    #[serde(default)]
+    env_allowlist: HashSet<String>,
+}
+
+impl From<StoredPermissionGate> for PermissionGate {
+    fn from(stored: StoredPermissionGate) -> Self {
+        let unknown_tools = stored.unknown_tools.unwrap_or(if stored.allow_unknown {
+            UnknownToolPolicy::Allow(stored.default_level)
+        } else {
+            UnknownToolPolicy::Deny
+        });
+        Self {
+            tools: stored.tools,
+            permissions: stored.permissions,
+            default_level: stored.default_level,
+            unknown_tools,
+            env_allowlist: stored.env_allowlist,
+        }
+    }
 }
 
 impl PermissionGate {
@@ -117,7 +292,8 @@ impl PermissionGate {
             tools: HashSet::new(),
             permissions: std::collections::HashMap::new(),
             default_level: PermissionLevel::Execute,
-            allow_unknown: false,
+            unknown_tools: UnknownToolPolicy::Deny,
+            env_allowlist: HashSet::new(),
         }
     }
     
@@ -186,39 +362,174 @@ impl PermissionGate {
         gate
     }
     
+    /// The default permissions, adjusted by `SANDBOX_*` environment variables
+    ///
+    /// See `apply_env` for the variables read.
+    pub fn from_env() -> SandboxResult<Self> {
+        let mut gate = Self::default_permissions();
+        gate.apply_env()?;
+        Ok(gate)
+    }
+    
+    /// Adjust this gate from `SANDBOX_*` environment variables
+    ///
+    /// - `SANDBOX_ALLOW=git,npm` lets each tool run, as with `allow_tool`
+    /// - `SANDBOX_DENY=sudo` denies each tool, winning over `SANDBOX_ALLOW`
+    /// - `SANDBOX_APPROVE=rm,chmod` makes each tool require approval
+    /// - `SANDBOX_DEFAULT_LEVEL=readonly` sets the default level
+    ///
+    /// Fails with `InvalidConfig` on an unknown level, before changing anything.
+    pub fn apply_env(&mut self) -> SandboxResult<()> {
+        self.apply_env_with(|name| std::env::var(name).ok())
+    }
+    
+    /// `apply_env`, reading each variable through `lookup` instead of the process environment
+    pub fn apply_env_with(&mut self, lookup: impl Fn(&str) -> Option<String>) -> SandboxResult<()> {
+        let tools = |name: &str| -> Vec<String> {
+            lookup(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|tool| !tool.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        let default_level = match lookup("SANDBOX_DEFAULT_LEVEL") {
+            Some(level) => Some(level.parse::<PermissionLevel>()?),
+            None => None,
+        };
+        
+        for tool in tools("SANDBOX_ALLOW") {
+            self.allow_tool(&tool);
+        }
+        for tool in tools("SANDBOX_DENY") {
+            self.register_tool(ToolPermission::new(&tool).with_level(PermissionLevel::Denied));
+        }
+        for tool in tools("SANDBOX_APPROVE") {
+            if !self.permissions.contains_key(&tool) {
+                self.allow_tool(&tool);
+            }
+            if let Some(permission) = self.permissions.get_mut(&tool) {
+                permission.requires_approval = true;
+            }
+        }
+        if let Some(level) = default_level {
+            self.set_default_level(level);
+        }
+        
+        Ok(())
+    }
+    
+    /// Let `tool` run, registering it at `Execute` if it is unknown or denied
+    ///
+    /// A tool that is already allowed keeps its registration.
+    pub fn allow_tool(&mut self, tool: &str) {
+        match self.permissions.get_mut(tool) {
+            Some(permission) if permission.level != PermissionLevel::Denied => {}
+            _ => self.register_tool(ToolPermission::new(tool).with_level(PermissionLevel::Execute)),
+        }
+    }
+    
     /// Register a tool with the permission gate
     pub fn register_tool(&mut self, permission: ToolPermission) {
         self.tools.insert(permission.name.clone());
         self.permissions.insert(permission.name.clone(), permission);
     }
     
+    /// The permission level unknown tools run with, when they may run at all
+    pub fn default_level(&self) -> PermissionLevel {
+        self.default_level
+    }
+    
     /// Set the default permission level for unknown tools
+    ///
+    /// Unknown tools run with it under `RequireApproval`, and an `Allow` policy
+    /// already in place is moved to it. Logs a warning for levels above
+    /// `Execute`, which hand full access to any tool nobody registered.
     pub fn set_default_level(&mut self, level: PermissionLevel) {
+        if level > PermissionLevel::Execute {
+            warn!(?level, "default permission level above Execute applies to every unregistered tool");
+        }
         self.default_level = level;
+        if let UnknownToolPolicy::Allow(_) = self.unknown_tools {
+            self.unknown_tools = UnknownToolPolicy::Allow(level);
+        }
+    }
+    
+    /// Like `set_default_level`, but fails while unknown tools are denied
+    ///
+    /// Under `UnknownToolPolicy::Deny` the default level is never used, so
+    /// setting it is most likely a mistake.
+    pub fn try_set_default_level(&mut self, level: PermissionLevel) -> SandboxResult<()> {
+        if self.unknown_tools == UnknownToolPolicy::Deny {
+            return Err(SandboxError::InvalidConfig(format!(
+                "default level {:?} has no effect while unknown tools are denied",
+                level
+            )));
+        }
+        self.set_default_level(level);
+        Ok(())
     }
     
     /// Allow unknown tools (not in the registry)
+    #[deprecated(note = "use `set_unknown_tool_policy(UnknownToolPolicy::Allow(level))`")]
     pub fn allow_unknown(&mut self) {
-        self.allow_unknown = true;
+        self.unknown_tools = UnknownToolPolicy::Allow(self.default_level);
+    }
+    
+    /// Decide what happens to tools that are not registered
+    pub fn set_unknown_tool_policy(&mut self, policy: UnknownToolPolicy) {
+        self.unknown_tools = policy;
+    }
+    
+    /// What happens to tools that are not registered
+    pub fn unknown_tool_policy(&self) -> UnknownToolPolicy {
+        self.unknown_tools
+    }
+    
+    /// Allow `$name` to be expanded when parsing command lines, and a
+    /// `name=value` prefix to set it for a command
+    pub fn allow_env(&mut self, name: &str) {
+        self.env_allowlist.insert(name.to_string());
+    }
+    
+    /// Whether `$name` may be expanded, or set by a `name=value` prefix
+    pub fn is_env_allowed(&self, name: &str) -> bool {
+        self.env_allowlist.contains(name)
+    }
+    
+    /// Names allowed by `allow_env`, sorted
+    pub fn allowed_env(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.env_allowlist.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        names
     }
     
     /// Check if a tool is allowed
     pub fn check_tool(&self, tool: &str) -> SandboxResult<PermissionLevel> {
         if let Some(permission) = self.permissions.get(tool) {
-            Ok(permission.level)
-        } else if self.allow_unknown {
-            Ok(self.default_level)
-        } else {
-            Err(SandboxError::PermissionDenied(format!(
+            return Ok(permission.level);
+        }
+        
+        match self.unknown_tools {
+            UnknownToolPolicy::Allow(level) => Ok(level),
+            UnknownToolPolicy::RequireApproval => Ok(self.default_level),
+            UnknownToolPolicy::Deny => Err(SandboxError::PermissionDenied(format!(
                 "Tool '{}' is not registered in the permission gate",
                 tool
-            )))
+            ))),
         }
     }
     
     /// Check if a tool is allowed with specific arguments
+    ///
+    /// Fails for tools whose level is `Denied`, as well as for unknown tools and
+    /// refused arguments.
     pub fn check_command(&self, tool: &str, args: &[String]) -> SandboxResult<PermissionLevel> {
         let level = self.check_tool(tool)?;
+        if level == PermissionLevel::Denied {
+            return Err(SandboxError::PermissionDenied(format!("Tool '{}' is denied", tool)));
+        }
         
         if let Some(permission) = self.permissions.get(tool) {
             if !permission.check_args(args) {
@@ -233,31 +544,67 @@ impl PermissionGate {
     }
     
     /// Check if a tool can access a specific path
-    pub fn check_path(&self, tool: &str, path: &std::path::Path) -> SandboxResult<bool> {
+    ///
+    /// Fails with `PathNotAllowed` when the tool's allowed and denied paths
+    /// exclude `path`, and with `PermissionDenied` when the tool itself may not run.
+    pub fn check_path(&self, tool: &str, path: &std::path::Path) -> SandboxResult<()> {
+        self.check_path_with(tool, path, |permission| permission.check_path(path))
+    }
+    
+    /// Like `check_path` for an absolute `path`, matching relative rules against `root`
+    ///
+    /// See `ToolPermission::check_path_in`.
+    pub fn check_path_in(&self, tool: &str, root: &Path, path: &Path) -> SandboxResult<()> {
+        self.check_path_with(tool, path, |permission| permission.check_path_in(root, path))
+    }
+    
+    fn check_path_with(
+        &self,
+        tool: &str,
+        path: &Path,
+        allowed: impl Fn(&ToolPermission) -> bool,
+    ) -> SandboxResult<()> {
         let level = self.check_tool(tool)?;
         
         if level == PermissionLevel::Denied {
-            return Ok(false);
+            return Err(SandboxError::PermissionDenied(format!("Tool '{}' is denied", tool)));
         }
         
-        if let Some(permission) = self.permissions.get(tool) {
-            Ok(permission.check_path(path))
-        } else {
-            Ok(true)
+        match self.permissions.get(tool) {
+            Some(permission) if !allowed(permission) => Err(SandboxError::PathNotAllowed {
+                tool: tool.to_string(),
+                path: path.to_path_buf(),
+            }),
+            _ => Ok(()),
         }
     }
     
     /// Check if a tool requires approval
+    ///
+    /// Unregistered tools do under `UnknownToolPolicy::RequireApproval`.
     pub fn requires_approval(&self, tool: &str) -> bool {
         self.permissions
             .get(tool)
             .map(|p| p.requires_approval)
-            .unwrap_or(false)
+            .unwrap_or(self.unknown_tools == UnknownToolPolicy::RequireApproval)
     }
     
-    /// Get all registered tools
+    /// Get all registered tools, sorted by name
     pub fn list_tools(&self) -> Vec<String> {
-        self.tools.iter().cloned().collect()
+        let mut tools: Vec<String> = self.tools.iter().cloned().collect();
+        tools.sort();
+        tools
+    }
+    
+    /// Registered tools grouped by their level, each group sorted by name
+    pub fn list_tools_by_level(&self) -> BTreeMap<PermissionLevel, Vec<String>> {
+        let mut levels: BTreeMap<PermissionLevel, Vec<String>> = BTreeMap::new();
+        for tool in self.list_tools() {
+            if let Some(permission) = self.permissions.get(&tool) {
+                levels.entry(permission.level).or_default().push(tool);
+            }
+        }
+        levels
     }
     
     /// Get permission for a specific tool
@@ -0,0 +1,573 @@
+use crate::virtual_fs::{DiffOperation, FileDiff, VirtualFilesystem};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Predicts the file changes a command would make without running it
+///
+/// The sandbox consults every registered predictor in Simulation and Diff
+/// mode and reports all of their predictions together.
+pub trait SideEffectPredictor: Send + Sync {
+    /// File changes `tool args` would make, or nothing if the tool is not recognized
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff>;
+    
+    /// Like `predict`, for a command run in `cwd` relative to the mounted root
+    ///
+    /// Predictors that report paths relative to where the command runs should
+    /// resolve them against `cwd`. The default ignores it.
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        let _ = cwd;
+        self.predict(tool, args, vfs)
+    }
+    
+    /// Like `predict_in`, knowing which arguments were quoted in the command line
+    ///
+    /// `quoted` runs parallel to `args` and may be shorter, with missing
+    /// entries taken as unquoted. A quoted `'>'` is a plain word rather than a
+    /// redirection. The default ignores it.
+    fn predict_quoted(
+        &self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        cwd: &Path,
+        vfs: &VirtualFilesystem,
+    ) -> Vec<FileDiff> {
+        let _ = quoted;
+        self.predict_in(tool, args, cwd, vfs)
+    }
+}
+
+/// The predictors every sandbox starts with
+pub fn default_predictors() -> Vec<Box<dyn SideEffectPredictor>> {
+    vec![
+        Box::new(GitPredictor),
+        Box::new(NpmPredictor),
+        Box::new(RedirectPredictor),
+        Box::new(RmPredictor),
+        Box::new(LnPredictor),
+        Box::new(CompilerOutputPredictor::default()),
+    ]
+}
+
+/// `git add` and `git commit` stage every tracked file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GitPredictor;
+
+impl SideEffectPredictor for GitPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        if tool != "git" || !args.iter().any(|a| a == "add" || a == "commit") {
+            return Vec::new();
+        }
+        
+        // Would stage/commit files
+        vfs.list_files()
+            .into_iter()
+            .map(|file| FileDiff {
+                path: file,
+                operation: DiffOperation::Modified,
+                old_content: None,
+                new_content: Some("(staged)".to_string()),
+                binary: None,
+                unified: None,
+                mode: None,
+            })
+            .collect()
+    }
+}
+
+/// `npm install` updates the lockfile and populates `node_modules`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NpmPredictor;
+
+impl SideEffectPredictor for NpmPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_in(tool, args, Path::new(""), vfs)
+    }
+    
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        if tool != "npm" || !args.iter().any(|a| a == "install") {
+            return Vec::new();
+        }
+        
+        let mut changes = vec![FileDiff {
+            path: cwd.join("package-lock.json"),
+            operation: DiffOperation::Modified,
+            old_content: None,
+            new_content: Some("(would be updated)".to_string()),
+            binary: None,
+            unified: None,
+            mode: None,
+        }];
+        
+        let node_modules = cwd.join("node_modules");
+        if vfs.is_dir(&node_modules) {
+            changes.push(FileDiff {
+                path: node_modules,
+                operation: DiffOperation::Modified,
+                old_content: None,
+                new_content: Some("(would be populated)".to_string()),
+                binary: None,
+                unified: None,
+                mode: None,
+            });
+        } else {
+            changes.push(FileDiff {
+                path: node_modules,
+                operation: DiffOperation::DirectoryAdded,
+                old_content: None,
+                new_content: None,
+                binary: None,
+                unified: None,
+                mode: None,
+            });
+        }
+        
+        changes
+    }
+}
+
+/// Output redirections (`>`, `>>`, `2>`, `&>`) create or overwrite their target
+///
+/// When the command's output is known without running it, as for `echo`, the
+/// prediction carries the real before and after contents and a unified diff.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RedirectPredictor;
+
+impl SideEffectPredictor for RedirectPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_in(tool, args, Path::new(""), vfs)
+    }
+    
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_quoted(tool, args, &[], cwd, vfs)
+    }
+    
+    fn predict_quoted(
+        &self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        cwd: &Path,
+        vfs: &VirtualFilesystem,
+    ) -> Vec<FileDiff> {
+        let (words, redirections) = split_redirections(args, quoted);
+        let output = static_output(tool, &words, heredoc_body(args));
+        // Like a shell, only the last redirection of a descriptor receives its output
+        let last_stdout = redirections.iter().rposition(|r| r.fd.is_none_or(|fd| fd == 1));
+        
+        redirections
+            .into_iter()
+            .enumerate()
+            .map(|(i, redirection)| {
+                let path = cwd.join(&redirection.target);
+                let exists = vfs.is_file(&path);
+                let operation = if exists {
+                    DiffOperation::Modified
+                } else {
+                    DiffOperation::Added
+                };
+                
+                // Real contents need the written output and, for an existing file, its text
+                let old = if exists {
+                    vfs.read(&path).ok().and_then(|bytes| String::from_utf8(bytes).ok())
+                } else {
+                    Some(String::new())
+                };
+                if let (Some(output), Some(old)) = (&output, old) {
+                    let written = if Some(i) == last_stdout { output.as_str() } else { "" };
+                    let new = if redirection.append {
+                        vfs.appended(&path, written.as_bytes())
+                            .ok()
+                            .and_then(|bytes| String::from_utf8(bytes).ok())
+                    } else {
+                        Some(written.to_string())
+                    };
+                    if let Some(new) = new {
+                        return FileDiff::text(path, operation, exists.then_some(old.as_str()), &new);
+                    }
+                }
+                
+                let note = if redirection.append {
+                    "(would be appended)"
+                } else {
+                    "(would be written)"
+                };
+                
+                FileDiff {
+                    path,
+                    operation,
+                    old_content: None,
+                    new_content: Some(note.to_string()),
+                    binary: None,
+                    unified: None,
+                    mode: None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// `rm` deletes each operand; directories only with `-r`
+///
+/// Operands missing from the virtual filesystem are still predicted as deleted
+/// so their paths can be permission-checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RmPredictor;
+
+impl SideEffectPredictor for RmPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_in(tool, args, Path::new(""), vfs)
+    }
+    
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        if tool != "rm" {
+            return Vec::new();
+        }
+        
+        let mut recursive = false;
+        let mut operands = Vec::new();
+        let mut flags_done = false;
+        for arg in args {
+            if flags_done || !arg.starts_with('-') || arg == "-" {
+                operands.push(arg);
+            } else if arg == "--" {
+                flags_done = true;
+            } else if arg == "--recursive" || (!arg.starts_with("--") && arg.contains(['r', 'R'])) {
+                recursive = true;
+            }
+        }
+        
+        operands
+            .into_iter()
+            .filter_map(|operand| {
+                let path = cwd.join(operand);
+                let operation = if vfs.is_dir(&path) {
+                    if !recursive {
+                        return None;
+                    }
+                    DiffOperation::DirectoryRemoved
+                } else {
+                    DiffOperation::Deleted
+                };
+                let old_content = vfs.read(&path).ok().and_then(|bytes| String::from_utf8(bytes).ok());
+                Some(FileDiff {
+                    path,
+                    operation,
+                    old_content,
+                    new_content: None,
+                    binary: None,
+                    unified: None,
+                    mode: None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// `ln -s target link` creates a symbolic link
+///
+/// With a single operand the link is named after the target in the current
+/// directory, and a link path naming an existing directory puts the link
+/// inside it. A link path that is already taken is only replaced with `-f`.
+/// Hard links are not predicted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LnPredictor;
+
+impl SideEffectPredictor for LnPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_in(tool, args, Path::new(""), vfs)
+    }
+    
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        if tool != "ln" {
+            return Vec::new();
+        }
+        
+        let mut symbolic = false;
+        let mut force = false;
+        let mut operands = Vec::new();
+        let mut flags_done = false;
+        for arg in args {
+            if flags_done || !arg.starts_with('-') || arg == "-" {
+                operands.push(arg);
+            } else if arg == "--" {
+                flags_done = true;
+            } else if arg.starts_with("--") {
+                symbolic |= arg == "--symbolic";
+                force |= arg == "--force";
+            } else {
+                symbolic |= arg.contains('s');
+                force |= arg.contains('f');
+            }
+        }
+        
+        let (target, link) = match operands.as_slice() {
+            [target] => (Path::new(target.as_str()), None),
+            [target, link] => (Path::new(target.as_str()), Some(cwd.join(link.as_str()))),
+            _ => return Vec::new(),
+        };
+        let Some(name) = target.file_name() else {
+            return Vec::new();
+        };
+        if !symbolic {
+            return Vec::new();
+        }
+        
+        let link = match link {
+            Some(link) if vfs.is_dir(&link) => link.join(name),
+            Some(link) => link,
+            None => cwd.join(name),
+        };
+        // Without `-f`, ln refuses to replace what is already there
+        if !force && (vfs.is_file(&link) || vfs.is_symlink(&link)) {
+            return Vec::new();
+        }
+        vec![FileDiff::symlink(link, target.to_path_buf())]
+    }
+}
+
+/// A flag through which a compiler is told where to write its output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputFlag {
+    /// The flag's value is the output file, as in `gcc -o a.out`
+    File(String),
+    /// The flag's value is a directory outputs are written into, as in `rustc --out-dir build`
+    Dir(String),
+}
+
+impl OutputFlag {
+    fn name(&self) -> &str {
+        match self {
+            OutputFlag::File(name) | OutputFlag::Dir(name) => name,
+        }
+    }
+    
+    /// The flag's value if `arg` is this flag, taking it from `next` when it is a separate word
+    ///
+    /// Accepts `-o out`, `--out-dir=build` and, for single-letter flags, `-oout`.
+    fn value<'a>(&self, arg: &'a str, next: Option<&'a str>) -> Option<(&'a str, bool)> {
+        let name = self.name();
+        let rest = arg.strip_prefix(name)?;
+        if rest.is_empty() {
+            return next.map(|value| (value, true));
+        }
+        if let Some(value) = rest.strip_prefix('=') {
+            return Some((value, false));
+        }
+        let short = name.len() == 2 && name.starts_with('-') && !name.starts_with("--");
+        short.then_some((rest, false))
+    }
+}
+
+/// Compilers writing the files named by their output flags
+///
+/// The default recognizes `gcc`, `g++`, `cc`, `clang`, `clang++`, `rustc` and
+/// `tsc`. Other tools can be added with `with_tool` and the predictor registered
+/// through `Sandbox::register_predictor`.
+#[derive(Debug, Clone)]
+pub struct CompilerOutputPredictor {
+    tools: HashMap<String, Vec<OutputFlag>>,
+}
+
+impl CompilerOutputPredictor {
+    /// A predictor that recognizes no tools yet
+    pub fn empty() -> Self {
+        Self {
+            tools: HashMap::new(),
+        }
+    }
+    
+    /// Recognize `tool`, replacing any flags it was already configured with
+    pub fn with_tool(mut self, tool: &str, flags: impl IntoIterator<Item = OutputFlag>) -> Self {
+        self.tools.insert(tool.to_string(), flags.into_iter().collect());
+        self
+    }
+}
+
+impl Default for CompilerOutputPredictor {
+    fn default() -> Self {
+        let c_flags = || [OutputFlag::File("-o".to_string())];
+        Self::empty()
+            .with_tool("gcc", c_flags())
+            .with_tool("g++", c_flags())
+            .with_tool("cc", c_flags())
+            .with_tool("clang", c_flags())
+            .with_tool("clang++", c_flags())
+            .with_tool(
+                "rustc",
+                [OutputFlag::File("-o".to_string()), OutputFlag::Dir("--out-dir".to_string())],
+            )
+            .with_tool(
+                "tsc",
+                [OutputFlag::File("--outFile".to_string()), OutputFlag::Dir("--outDir".to_string())],
+            )
+    }
+}
+
+impl SideEffectPredictor for CompilerOutputPredictor {
+    fn predict(&self, tool: &str, args: &[String], vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        self.predict_in(tool, args, Path::new(""), vfs)
+    }
+    
+    fn predict_in(&self, tool: &str, args: &[String], cwd: &Path, vfs: &VirtualFilesystem) -> Vec<FileDiff> {
+        let Some(flags) = self.tools.get(tool) else {
+            return Vec::new();
+        };
+        
+        let mut changes = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let next = args.get(i + 1).map(String::as_str);
+            let found = flags
+                .iter()
+                .find_map(|flag| flag.value(&args[i], next).map(|(value, separate)| (flag, value, separate)));
+            
+            let Some((flag, value, separate)) = found else {
+                i += 1;
+                continue;
+            };
+            i += if separate { 2 } else { 1 };
+            
+            let path = cwd.join(value);
+            let (operation, note) = match flag {
+                OutputFlag::File(_) if vfs.is_file(&path) => (DiffOperation::Modified, Some("(would be rebuilt)")),
+                OutputFlag::File(_) => (DiffOperation::Added, Some("(would be built)")),
+                OutputFlag::Dir(_) if vfs.is_dir(&path) => (DiffOperation::Modified, Some("(would be populated)")),
+                OutputFlag::Dir(_) => (DiffOperation::DirectoryAdded, None),
+            };
+            changes.push(FileDiff {
+                path,
+                operation,
+                old_content: None,
+                new_content: note.map(str::to_string),
+                binary: None,
+                unified: None,
+                mode: None,
+            });
+        }
+        
+        changes
+    }
+}
+
+/// An output redirection parsed from a command's arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirection {
+    /// Redirected file descriptor; `None` for `&>`, which redirects stdout and stderr
+    pub fd: Option<u32>,
+    pub target: PathBuf,
+    /// `>>` appends to the target instead of truncating it
+    pub append: bool,
+}
+
+/// Find the file redirections in `args`, in both `> file` and glued `>file` forms
+///
+/// Descriptor duplications such as `2>&1` are not file writes and are skipped.
+pub fn parse_redirections(args: &[String]) -> Vec<Redirection> {
+    split_redirections(args, &[]).1
+}
+
+/// Body of a `<<EOF` here-document in `args`, as the command would read it on stdin
+///
+/// The command parser leaves a here-document as a `<<` word followed by its
+/// body, with variables already expanded unless the delimiter was quoted.
+pub fn heredoc_body(args: &[String]) -> Option<&str> {
+    args.iter()
+        .position(|arg| arg == HEREDOC)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// Word marking a here-document body in parsed arguments
+pub(crate) const HEREDOC: &str = "<<";
+
+/// Separate the command's own words from its file redirections
+///
+/// Descriptor duplications are dropped from both. Words marked in `quoted`
+/// are never redirections; missing entries count as unquoted.
+pub(crate) fn split_redirections<'a>(args: &'a [String], quoted: &[bool]) -> (Vec<&'a str>, Vec<Redirection>) {
+    let mut words = Vec::new();
+    let mut redirections = Vec::new();
+    let mut args = args.iter().enumerate();
+    
+    while let Some((i, arg)) = args.next() {
+        if quoted.get(i).copied().unwrap_or(false) {
+            words.push(arg.as_str());
+            continue;
+        }
+        
+        // Here-document bodies are input, not words or file writes
+        if arg == HEREDOC {
+            args.next();
+            continue;
+        }
+        
+        let (fd, rest) = if let Some(rest) = arg.strip_prefix('&') {
+            (None, rest)
+        } else {
+            let digits = arg.len() - arg.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let fd = if digits == 0 { 1 } else { arg[..digits].parse().unwrap_or(1) };
+            (Some(fd), &arg[digits..])
+        };
+        
+        let Some(rest) = rest.strip_prefix('>') else {
+            words.push(arg.as_str());
+            continue;
+        };
+        let (append, rest) = match rest.strip_prefix('>') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        
+        // `>&2` and friends duplicate a descriptor rather than naming a file
+        if rest.starts_with('&') {
+            continue;
+        }
+        
+        let target = if rest.is_empty() {
+            match args.next() {
+                Some((_, target)) => target.as_str(),
+                None => continue,
+            }
+        } else {
+            rest
+        };
+        
+        redirections.push(Redirection {
+            fd,
+            target: PathBuf::from(target),
+            append,
+        });
+    }
+    
+    (words, redirections)
+}
+
+/// Stdout of `tool words` when it can be known without running it
+///
+/// `stdin` is a here-document fed to the command, which `cat` without file
+/// arguments copies to its output.
+fn static_output(tool: &str, words: &[&str], stdin: Option<&str>) -> Option<String> {
+    if tool == "cat" {
+        return match words {
+            [] | ["-"] => stdin.map(str::to_string),
+            _ => None,
+        };
+    }
+    if tool != "echo" {
+        return None;
+    }
+    
+    let (newline, words) = match words.split_first() {
+        Some((&"-n", rest)) => (false, rest),
+        // Escape interpretation is left to the real echo
+        Some((flag, _)) if flag.starts_with('-') && flag.len() > 1 => return None,
+        _ => (true, words),
+    };
+    
+    let mut output = words.join(" ");
+    if newline {
+        output.push('\n');
+    }
+    Some(output)
+}
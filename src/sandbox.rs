@@ -1,11 +1,26 @@
-use crate::diff_engine::DiffSummary;
+#[cfg(feature = "async")]
+use crate::backend::native_command;
+use crate::backend::{BackendOutput, CapturedStream, ExecutionBackend, Invocation, KillReason, NativeBackend};
+use crate::diff_engine::{DiffEngine, DiffSummary};
 use crate::error::{SandboxError, SandboxResult};
-use crate::permissions::{PermissionGate, PermissionLevel};
-use crate::virtual_fs::{VirtualFilesystem, FileDiff};
+use crate::permissions::{ApprovalPolicy, PermissionGate, PermissionLevel};
+use crate::predictor::{
+    default_predictors, heredoc_body, split_redirections, Redirection,
+    SideEffectPredictor, HEREDOC,
+};
+use crate::virtual_fs::{merge_file_changes, DiffOperation, FileDiff, FsSnapshot, VirtualFilesystem};
+use regex_automata::meta::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, debug_span, error, warn};
 use uuid::Uuid;
 
 /// Sandbox execution mode
@@ -20,7 +35,7 @@ pub enum ExecutionMode {
 }
 
 /// Result of a sandboxed execution
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ExecutionResult {
     pub id: String,
     pub command: String,
@@ -30,12 +45,253 @@ pub struct ExecutionResult {
     pub status: ExecutionStatus,
     pub stdout: String,
     pub stderr: String,
+    /// Raw stdout when it was not valid UTF-8, in which case `stdout` is a lossy copy
+    ///
+    /// Serialized as base64.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_bytes")]
+    pub stdout_bytes: Option<Vec<u8>>,
+    /// Raw stderr when it was not valid UTF-8, like `stdout_bytes`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_bytes")]
+    pub stderr_bytes: Option<Vec<u8>>,
     pub exit_code: Option<i32>,
     pub file_changes: Vec<FileDiff>,
     pub diff_summary: Option<DiffSummary>,
     pub permission_level: PermissionLevel,
     pub approved: bool,
     pub executed_at: i64,
+    /// Wall-clock run time in milliseconds; 0 for results that did not run a process
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// Whether stdout or stderr was cut off at `Sandbox::max_output_bytes`
+    #[serde(default)]
+    pub truncated: bool,
+    /// Per-stage results when the command was a pipeline or `&&`/`||`/`;` chain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<ExecutionResult>,
+    /// How this stage connects to the next one, for results inside `stages`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_operator: Option<ChainOperator>,
+    /// When the result was created; for pending approvals, when the command was queued
+    #[serde(default)]
+    pub created_at: i64,
+    /// After this timestamp a pending approval can no longer be approved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Directory the command ran in, after any earlier `cd`
+    #[serde(default)]
+    pub cwd: PathBuf,
+    /// Times the command was run; above 1 when `Sandbox::retry_policy` retried it
+    ///
+    /// For a chain, each stage records its own attempts.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// Set on simulated results for commands `Sandbox::is_read_only` found to have no side effects
+    #[serde(default)]
+    pub read_only: bool,
+    /// Whether the command was queued for approval before being approved or rejected
+    #[serde(default)]
+    pub awaited_approval: bool,
+    /// Program and arguments after quoting and expansion, as handed to the OS in live mode
+    ///
+    /// Empty for chains, whose stages each carry their own.
+    #[serde(default)]
+    pub resolved_argv: Vec<String>,
+    /// `NAME=value` assignments that prefixed the command, set in its environment in live mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<(String, String)>,
+    /// `content_hash` at the time the command was queued for approval
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval_hash: Option<String>,
+}
+
+fn default_attempts() -> u32 {
+    1
+}
+
+/// How a stage of a command chain connects to the stage after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChainOperator {
+    /// `|`: the next stage reads this stage's stdout
+    Pipe,
+    /// `&&`: the next stage runs only if this one succeeded
+    And,
+    /// `||`: the next stage runs only if this one failed
+    Or,
+    /// `;`: the next stage runs regardless
+    Sequence,
+}
+
+impl ExecutionResult {
+    /// A result for `tool args` with no output, changes or timing recorded yet
+    fn new(
+        tool: &str,
+        args: &[String],
+        mode: ExecutionMode,
+        status: ExecutionStatus,
+        permission_level: PermissionLevel,
+    ) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            command: format!("{} {}", tool, args.join(" ")),
+            tool: tool.to_string(),
+            args: args.to_vec(),
+            mode,
+            status,
+            stdout: String::new(),
+            stderr: String::new(),
+            stdout_bytes: None,
+            stderr_bytes: None,
+            exit_code: None,
+            file_changes: Vec::new(),
+            diff_summary: None,
+            permission_level,
+            approved: true,
+            executed_at: now,
+            duration_ms: 0,
+            truncated: false,
+            stages: Vec::new(),
+            chain_operator: None,
+            created_at: now,
+            expires_at: None,
+            cwd: PathBuf::new(),
+            attempts: default_attempts(),
+            read_only: false,
+            awaited_approval: false,
+            approval_hash: None,
+            env: Vec::new(),
+            resolved_argv: if tool.is_empty() {
+                Vec::new()
+            } else {
+                std::iter::once(tool.to_string()).chain(split_heredoc(args).0).collect()
+            },
+        }
+    }
+    
+    /// Combine the results of a chain's stages into one result for the whole command
+    ///
+    /// Status and exit code come from the last stage, as in a shell. Stdout holds
+    /// only output that was not piped into another stage.
+    fn from_stages(command: &str, mode: ExecutionMode, stages: Vec<ExecutionResult>) -> Self {
+        let (first, last) = match (stages.first(), stages.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                return Self {
+                    command: command.to_string(),
+                    ..Self::new("", &[], mode, ExecutionStatus::Failed, PermissionLevel::Denied)
+                }
+            }
+        };
+        
+        let file_changes =
+            merge_file_changes(stages.iter().flat_map(|s| s.file_changes.iter().cloned()));
+        
+        Self {
+            command: command.to_string(),
+            stdout: stages
+                .iter()
+                .filter(|s| s.chain_operator != Some(ChainOperator::Pipe))
+                .map(|s| s.stdout.as_str())
+                .collect(),
+            stderr: stages.iter().map(|s| s.stderr.as_str()).collect(),
+            exit_code: last.exit_code,
+            diff_summary: summarize_changes(&file_changes),
+            file_changes,
+            duration_ms: stages.iter().map(|s| s.duration_ms).sum(),
+            truncated: stages.iter().any(|s| s.truncated),
+            read_only: stages.iter().all(|s| s.read_only),
+            cwd: first.cwd.clone(),
+            resolved_argv: Vec::new(),
+            ..Self::new(&first.tool, &first.args, mode, last.status, first.permission_level)
+        }
+        .with_stages(stages)
+    }
+    
+    fn with_stages(mut self, stages: Vec<ExecutionResult>) -> Self {
+        self.stages = stages;
+        self
+    }
+    
+    /// A result for a command line that was never run, with `reason` in stderr
+    fn unrun(command: &str, mode: ExecutionMode, status: ExecutionStatus, reason: String) -> Self {
+        let tool = command.split_whitespace().next().unwrap_or_default();
+        Self {
+            command: command.to_string(),
+            stderr: reason,
+            ..Self::new(tool, &[], mode, status, PermissionLevel::Denied)
+        }
+    }
+    
+    /// Stdout exactly as the command wrote it, after redaction
+    pub fn stdout_raw(&self) -> &[u8] {
+        self.stdout_bytes.as_deref().unwrap_or(self.stdout.as_bytes())
+    }
+    
+    /// Stderr exactly as the command wrote it, after redaction
+    pub fn stderr_raw(&self) -> &[u8] {
+        self.stderr_bytes.as_deref().unwrap_or(self.stderr.as_bytes())
+    }
+    
+    /// Hex SHA-256 of what would run: each stage's tool, arguments and chain operator
+    ///
+    /// Stdin given to the command is not included.
+    pub fn content_hash(&self) -> String {
+        let stages = if self.stages.is_empty() {
+            std::slice::from_ref(self)
+        } else {
+            &self.stages
+        };
+        
+        let mut hasher = Sha256::new();
+        for stage in stages {
+            for (name, value) in &stage.env {
+                hasher.update(format!("{}={}", name, value).as_bytes());
+                hasher.update([0]);
+            }
+            for word in std::iter::once(&stage.tool).chain(&stage.args) {
+                hasher.update(word.as_bytes());
+                hasher.update([0]);
+            }
+            let operator = match stage.chain_operator {
+                None => "",
+                Some(ChainOperator::Pipe) => "|",
+                Some(ChainOperator::And) => "&&",
+                Some(ChainOperator::Or) => "||",
+                Some(ChainOperator::Sequence) => ";",
+            };
+            hasher.update(operator.as_bytes());
+            hasher.update([0xff]);
+        }
+        hex::encode(hasher.finalize())
+    }
+    
+    /// Whether this pending approval is past its expiry
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| chrono::Utc::now().timestamp() >= expires_at)
+    }
+    
+    /// Whether `other` is the same result apart from its id and timing
+    ///
+    /// Compares every field except those `redact_nondeterministic` clears.
+    pub fn equivalent(&self, other: &Self) -> bool {
+        self.redact_nondeterministic() == other.redact_nondeterministic()
+    }
+    
+    /// A copy with the id, timestamps and run time cleared, for snapshot tests
+    ///
+    /// Stages are redacted too. `expires_at` is kept, since it reflects the
+    /// approval TTL rather than when the command ran.
+    pub fn redact_nondeterministic(&self) -> ExecutionResult {
+        ExecutionResult {
+            id: String::new(),
+            executed_at: 0,
+            created_at: 0,
+            duration_ms: 0,
+            stages: self.stages.iter().map(Self::redact_nondeterministic).collect(),
+            ..self.clone()
+        }
+    }
 }
 
 /// Status of execution
@@ -51,10 +307,68 @@ pub enum ExecutionStatus {
     Simulated,
     /// Command requires approval
     PendingApproval,
+    /// Command was killed after exceeding the execution timeout
+    TimedOut,
+    /// Command was not run because an earlier command in its batch stopped the batch
+    Skipped,
+    /// Command was killed because its `CancellationToken` was cancelled
+    Cancelled,
+}
+
+/// When and how often a live command that failed transiently is run again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Total runs including the first; 1 never retries
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled before each one after it
+    pub backoff: Duration,
+    /// Exit codes worth retrying; any other exit is final
+    pub retryable_exit_codes: Vec<i32>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Duration, retryable_exit_codes: &[i32]) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            retryable_exit_codes: retryable_exit_codes.to_vec(),
+        }
+    }
+    
+    /// Delay before running again after `attempts` runs ended with `output`, or `None` to stop
+    ///
+    /// Commands that could not start, timed out or were cancelled are not retried.
+    fn retry_delay(&self, attempts: u32, output: &SandboxResult<BackendOutput>) -> Option<Duration> {
+        let output = output.as_ref().ok()?;
+        let retryable = output.killed.is_none()
+            && output.exit_code.is_some_and(|code| self.retryable_exit_codes.contains(&code));
+        (retryable && attempts < self.max_attempts)
+            .then(|| self.backoff.saturating_mul(1 << (attempts - 1).min(16)))
+    }
+}
+
+/// Cheap, cloneable flag for cancelling an in-flight execution from another thread
+///
+/// All clones share one flag; once cancelled, a token stays cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Ask every execution watching this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 /// A sandbox session
-#[derive(Debug)]
 pub struct Sandbox {
     pub id: String,
     pub virtual_fs: VirtualFilesystem,
@@ -62,11 +376,134 @@ pub struct Sandbox {
     pub mode: ExecutionMode,
     pub execution_history: Vec<ExecutionResult>,
     pub pending_approvals: HashMap<String, ExecutionResult>,
+    /// Stdin for pending approvals that were submitted with input, keyed by execution id
+    pub pending_stdin: HashMap<String, Vec<u8>>,
     pub working_dir: PathBuf,
+    /// Directory `cd` moved to, relative to `working_dir` unless it lies outside it
+    ///
+    /// Empty means `working_dir` itself.
+    pub cwd: PathBuf,
     pub allow_all: bool,
+    /// Permission checks cannot be bypassed; `allow_all` is ignored
+    pub strict: bool,
+    pub checkpoints: HashMap<String, FsSnapshot>,
+    /// Live commands running longer than this are killed
+    pub execution_timeout: Option<Duration>,
+    /// Captured stdout and stderr are each cut off after this many bytes
+    pub max_output_bytes: Option<usize>,
+    /// Re-runs live commands that exit with a retryable code; `None` runs each once
+    pub retry_policy: Option<RetryPolicy>,
+    /// Address-space limit for live commands
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub max_memory_bytes: Option<u64>,
+    /// CPU time limit for live commands, rounded up to whole seconds
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub cpu_time_limit: Option<Duration>,
+    /// Consulted in Simulation and Diff mode to predict file changes
+    pub predictors: Vec<Box<dyn SideEffectPredictor>>,
+    /// How long a pending approval stays approvable; `None` keeps it indefinitely
+    pub approval_ttl: Option<Duration>,
+    /// Oldest history entries are dropped once there are more than this many
+    pub max_history: Option<usize>,
+    /// Runs live commands for tools without their own backend; `None` spawns native processes
+    pub backend: Option<Arc<dyn ExecutionBackend>>,
+    /// Backends for specific tools, taking precedence over `backend`
+    pub tool_backends: HashMap<String, Arc<dyn ExecutionBackend>>,
+    /// Token watched by the `execute_cancellable` call in progress
+    cancellation: Option<CancellationToken>,
+    /// Asked about every permitted command, alongside each tool's `requires_approval`
+    pub approval_policy: Option<Box<dyn ApprovalPolicy>>,
+    /// Called with each parsed command before its permission check
+    pub before_hook: Option<BeforeHook>,
+    /// Called with every result `execute`, `execute_tool` and `approve` produce
+    pub after_hook: Option<AfterHook>,
+    /// Channels registered with `subscribe`
+    subscribers: Vec<Sender<SandboxEvent>>,
+    /// Matches in captured live output are replaced with `REDACTED` (`***REDACTED***`) before being stored
+    redaction_patterns: Vec<(String, Regex)>,
+    /// Every recorded result is appended here as one JSON line
+    trace_file: Option<File>,
+    /// Every command line passed to `execute` is appended here, for `replay`
+    record_file: Option<File>,
+}
+
+/// Callback deciding whether a parsed command may go on to its permission check
+pub type BeforeHook = Box<dyn FnMut(&str, &[String]) -> HookDecision + Send>;
+
+/// Callback observing each execution result
+pub type AfterHook = Box<dyn FnMut(&ExecutionResult) + Send>;
+
+/// Sandbox activity delivered to `Sandbox::subscribe` receivers
+#[derive(Debug, Clone)]
+pub enum SandboxEvent {
+    /// A permitted command line is about to run (or be simulated)
+    CommandStarted(String),
+    /// A command produced its final result
+    CommandFinished(Box<ExecutionResult>),
+    /// A command was queued for approval under this execution id
+    ApprovalRequested(String),
+    /// A pending execution was approved and is about to run
+    Approved(String),
+    /// A pending execution was rejected
+    Rejected(String),
+}
+
+/// What a before-hook decided about a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookDecision {
+    /// Continue with the permission check and execution
+    Proceed,
+    /// Return a `Blocked` result carrying the reason instead of running the command
+    Block(String),
+}
+
+impl std::fmt::Debug for Sandbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("Sandbox");
+        debug
+            .field("id", &self.id)
+            .field("virtual_fs", &self.virtual_fs)
+            .field("permissions", &self.permissions)
+            .field("mode", &self.mode)
+            .field("execution_history", &self.execution_history)
+            .field("pending_approvals", &self.pending_approvals)
+            .field("pending_stdin", &self.pending_stdin)
+            .field("working_dir", &self.working_dir)
+            .field("cwd", &self.cwd)
+            .field("allow_all", &self.allow_all)
+            .field("strict", &self.strict)
+            .field("checkpoints", &self.checkpoints)
+            .field("execution_timeout", &self.execution_timeout)
+            .field("max_output_bytes", &self.max_output_bytes)
+            .field("retry_policy", &self.retry_policy);
+        #[cfg(all(unix, feature = "rlimits"))]
+        debug
+            .field("max_memory_bytes", &self.max_memory_bytes)
+            .field("cpu_time_limit", &self.cpu_time_limit);
+        debug
+            .field("predictors", &self.predictors.len())
+            .field("approval_ttl", &self.approval_ttl)
+            .field("max_history", &self.max_history)
+            .field("backend", &self.backend.is_some())
+            .field("tool_backends", &self.tool_backends.keys().collect::<Vec<_>>())
+            .field("cancellation", &self.cancellation)
+            .field("approval_policy", &self.approval_policy.is_some())
+            .field("before_hook", &self.before_hook.is_some())
+            .field("after_hook", &self.after_hook.is_some())
+            .field("subscribers", &self.subscribers.len())
+            .field("redaction_patterns", &self.redaction_patterns.len())
+            .field("trace_file", &self.trace_file.is_some())
+            .field("record_file", &self.record_file.is_some())
+            .finish()
+    }
 }
 
 impl Sandbox {
+    /// Start configuring a sandbox
+    pub fn builder() -> SandboxBuilder {
+        SandboxBuilder::default()
+    }
+    
     /// Create a new sandbox
     pub fn new() -> Self {
         Self {
@@ -76,13 +513,61 @@ impl Sandbox {
             mode: ExecutionMode::Live,
             execution_history: Vec::new(),
             pending_approvals: HashMap::new(),
+            pending_stdin: HashMap::new(),
             working_dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+            cwd: PathBuf::new(),
             allow_all: false,
+            strict: false,
+            checkpoints: HashMap::new(),
+            execution_timeout: None,
+            max_output_bytes: None,
+            retry_policy: None,
+            #[cfg(all(unix, feature = "rlimits"))]
+            max_memory_bytes: None,
+            #[cfg(all(unix, feature = "rlimits"))]
+            cpu_time_limit: None,
+            predictors: default_predictors(),
+            approval_ttl: None,
+            max_history: None,
+            backend: None,
+            tool_backends: HashMap::new(),
+            cancellation: None,
+            approval_policy: None,
+            before_hook: None,
+            after_hook: None,
+            subscribers: Vec::new(),
+            redaction_patterns: DEFAULT_REDACTIONS
+                .iter()
+                .map(|p| (p.to_string(), Regex::new(p).expect("default redaction pattern is valid")))
+                .collect(),
+            trace_file: None,
+            record_file: None,
         }
     }
     
     /// Create a sandbox with a specific working directory
+    ///
+    /// Fails with `FileSystemError` if `path` does not exist or is not a directory.
     pub fn with_working_dir(path: PathBuf) -> SandboxResult<Self> {
+        if !path.exists() {
+            return Err(SandboxError::FileSystemError(format!(
+                "Working directory does not exist: {}",
+                path.display()
+            )));
+        }
+        if !path.is_dir() {
+            return Err(SandboxError::FileSystemError(format!(
+                "Working directory is not a directory: {}",
+                path.display()
+            )));
+        }
+        
+        Self::with_working_dir_lenient(path)
+    }
+    
+    /// Create a sandbox with a specific working directory, leaving the virtual
+    /// filesystem empty if it does not exist
+    pub fn with_working_dir_lenient(path: PathBuf) -> SandboxResult<Self> {
         let mut sandbox = Self::new();
         sandbox.working_dir = path;
         
@@ -94,166 +579,1325 @@ impl Sandbox {
         Ok(sandbox)
     }
     
+    /// Honor the working directory's `.gitignore` when mounting it
+    ///
+    /// The working directory is re-mounted so the virtual filesystem reflects
+    /// the setting; its other mounts and options are kept. Changing the setting
+    /// fails with `InvalidConfig` while there are pending virtual changes, since
+    /// re-mounting would discard them.
+    pub fn set_respect_gitignore(&mut self, enabled: bool) -> SandboxResult<()> {
+        if self.virtual_fs.respects_gitignore() == enabled {
+            return Ok(());
+        }
+        if !self.virtual_fs.get_diff().is_empty() {
+            return Err(SandboxError::InvalidConfig(
+                "Cannot change .gitignore handling with pending virtual changes".to_string(),
+            ));
+        }
+        
+        self.virtual_fs.set_respect_gitignore(enabled);
+        if self.working_dir.exists() {
+            // A lenient sandbox may not have mounted it
+            let _ = self.virtual_fs.unmount(&self.working_dir);
+            self.virtual_fs.mount(&self.working_dir)?;
+        }
+        Ok(())
+    }
+    
     /// Set the execution mode
     pub fn set_mode(&mut self, mode: ExecutionMode) {
         self.mode = mode;
     }
     
+    /// Kill live commands that run longer than `timeout`; `None` waits indefinitely
+    pub fn set_execution_timeout(&mut self, timeout: Option<Duration>) {
+        self.execution_timeout = timeout;
+    }
+    
+    /// Keep at most `limit` bytes of each output stream; `None` keeps everything
+    pub fn set_max_output_bytes(&mut self, limit: Option<usize>) {
+        self.max_output_bytes = limit;
+    }
+    
+    /// Expire pending approvals `ttl` after they are queued; `None` never expires them
+    ///
+    /// Expiry is tracked in whole seconds. Only approvals queued afterwards are affected.
+    pub fn set_approval_ttl(&mut self, ttl: Option<Duration>) {
+        self.approval_ttl = ttl;
+    }
+    
+    /// Keep at most `limit` history entries, dropping the oldest; `None` keeps everything
+    pub fn set_max_history(&mut self, limit: Option<usize>) {
+        self.max_history = limit;
+        self.trim_history();
+    }
+    
+    /// Append every result recorded in history to `path` as JSON lines
+    ///
+    /// The file is created if needed and flushed after each line, so the trace
+    /// survives a crash. Approvals and rejections are traced like any other run.
+    pub fn set_trace_file(&mut self, path: &Path) -> SandboxResult<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.trace_file = Some(file);
+        Ok(())
+    }
+    
+    /// Append every command line executed from now on to `path`, for `replay`
+    ///
+    /// Each command is written as a JSON string on its own line, so commands
+    /// spanning several lines survive. Only commands that parse and pass their
+    /// permission checks are recorded, once they have run or been queued for
+    /// approval; commands run by `replay` are not. Stdin passed with
+    /// `execute_with_stdin` is not recorded.
+    pub fn record(&mut self, path: &Path) -> SandboxResult<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.record_file = Some(file);
+        Ok(())
+    }
+    
+    /// Re-run the commands recorded at `path` in order, in Simulation mode
+    ///
+    /// Every command runs even if an earlier one failed or is awaiting
+    /// approval; commands that could not run at all come back as `Blocked` or
+    /// `Failed` results. The sandbox's own mode is left as it was.
+    pub fn replay(&mut self, path: &Path) -> SandboxResult<Vec<ExecutionResult>> {
+        let commands = std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<String>)
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        // Replayed commands are not recorded again, even when replaying the recording itself
+        let record_file = self.record_file.take();
+        let previous = std::mem::replace(&mut self.mode, ExecutionMode::Simulation);
+        let results = commands
+            .iter()
+            .map(|command| {
+                let result = self.execute(command);
+                self.or_unrun(command, result)
+            })
+            .collect();
+        self.mode = previous;
+        self.record_file = record_file;
+        Ok(results)
+    }
+    
+    /// Run live commands through `backend` unless their tool has its own
+    pub fn set_backend(&mut self, backend: impl ExecutionBackend + 'static) {
+        self.backend = Some(Arc::new(backend));
+    }
+    
+    /// Run live `tool` commands through `backend`
+    pub fn set_tool_backend(&mut self, tool: &str, backend: impl ExecutionBackend + 'static) {
+        self.tool_backends.insert(tool.to_string(), Arc::new(backend));
+    }
+    
+    /// Consult `hook` with each parsed command before its permission check
+    ///
+    /// Every stage of a chain is passed to the hook; blocking any of them
+    /// blocks the whole chain.
+    pub fn set_before_hook(
+        &mut self,
+        hook: impl FnMut(&str, &[String]) -> HookDecision + Send + 'static,
+    ) {
+        self.before_hook = Some(Box::new(hook));
+    }
+    
+    /// Show every execution result to `hook` once it is produced
+    pub fn set_after_hook(&mut self, hook: impl FnMut(&ExecutionResult) + Send + 'static) {
+        self.after_hook = Some(Box::new(hook));
+    }
+    
+    /// Limit the address space of live commands to `bytes`; `None` removes the limit
+    ///
+    /// This is `RLIMIT_AS`, not a limit on resident memory: Linux does not
+    /// enforce `RLIMIT_RSS`, so every mapping counts, including reserved but
+    /// untouched memory. Runtimes that reserve large heaps up front may need a
+    /// limit well above what they actually use.
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub fn set_max_memory_bytes(&mut self, bytes: Option<u64>) {
+        self.max_memory_bytes = bytes;
+    }
+    
+    /// Limit the CPU time of live commands; `None` removes the limit
+    #[cfg(all(unix, feature = "rlimits"))]
+    pub fn set_cpu_time_limit(&mut self, limit: Option<Duration>) {
+        self.cpu_time_limit = limit;
+    }
+    
+    /// Redact matches of `pattern` from captured output, alongside the defaults
+    ///
+    /// Only the output reported in results is redacted; a pipeline still passes
+    /// each stage's original output to the next one.
+    ///
+    /// Fails with `InvalidConfig` if `pattern` is not a valid regex.
+    pub fn add_redaction_pattern(&mut self, pattern: &str) -> SandboxResult<()> {
+        let regex = Regex::new(pattern).map_err(|e| {
+            SandboxError::InvalidConfig(format!("Invalid redaction pattern '{}': {}", pattern, e))
+        })?;
+        self.redaction_patterns.push((pattern.to_string(), regex));
+        Ok(())
+    }
+    
+    /// Drop every redaction pattern, including the defaults
+    pub fn clear_redaction_patterns(&mut self) {
+        self.redaction_patterns.clear();
+    }
+    
+    /// Replace every redaction pattern match in `text` with `REDACTED` (`***REDACTED***`)
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (_, regex) in &self.redaction_patterns {
+            let mut redacted = String::with_capacity(text.len());
+            let mut last = 0;
+            for m in regex.find_iter(&text) {
+                redacted.push_str(&text[last..m.start()]);
+                redacted.push_str(REDACTED);
+                last = m.end();
+            }
+            redacted.push_str(&text[last..]);
+            text = redacted;
+        }
+        text
+    }
+    
+    /// Like `redact`, for output that is not valid UTF-8
+    fn redact_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        for (_, regex) in &self.redaction_patterns {
+            let mut redacted = Vec::with_capacity(bytes.len());
+            let mut last = 0;
+            for m in regex.find_iter(&bytes) {
+                redacted.extend_from_slice(&bytes[last..m.start()]);
+                redacted.extend_from_slice(REDACTED.as_bytes());
+                last = m.end();
+            }
+            redacted.extend_from_slice(&bytes[last..]);
+            bytes = redacted;
+        }
+        bytes
+    }
+    
+    /// Redacted raw bytes of `stream`, kept only if they are not valid UTF-8
+    fn raw_output(&self, stream: &CapturedStream) -> Option<Vec<u8>> {
+        match std::str::from_utf8(&stream.bytes) {
+            Ok(_) => None,
+            Err(_) => Some(self.redact_bytes(&stream.bytes)),
+        }
+    }
+    
+    /// Receive an event for every command, approval and rejection from now on
+    ///
+    /// Each call returns its own receiver; every subscriber sees every event.
+    /// Dropping the receiver unsubscribes it.
+    pub fn subscribe(&mut self) -> Receiver<SandboxEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+    
+    /// Add a predictor consulted alongside the built-in ones
+    pub fn register_predictor(&mut self, predictor: impl SideEffectPredictor + 'static) {
+        self.predictors.push(Box::new(predictor));
+    }
+    
     /// Enable allow all mode (bypass permissions for testing)
+    ///
+    /// A strict sandbox keeps checking permissions regardless.
     pub fn allow_all(&mut self) {
+        if self.strict {
+            warn!("allow_all has no effect on a strict sandbox");
+        }
         self.allow_all = true;
     }
     
-    /// Execute a command in the sandbox
-    pub fn execute(&mut self, command: &str) -> SandboxResult<ExecutionResult> {
-        // Parse command into tool and arguments
-        let parts: Vec<String> = shell_words::split(command)
-            .map_err(|e| SandboxError::InvalidCommand(e.to_string()))?;
-        
-        if parts.is_empty() {
-            return Err(SandboxError::InvalidCommand("Empty command".to_string()));
+    /// Whether permission checks are skipped: `allow_all` is set and the sandbox is not strict
+    fn bypasses_permissions(&self) -> bool {
+        self.allow_all && !self.strict
+    }
+    
+    /// Split `command` into its stages, separating each stage's redirections and
+    /// leading `NAME=value` environment assignments from its arguments
+    ///
+    /// Allowlisted variables are expanded as they are for execution. Fails with
+    /// `InvalidCommand` on malformed input such as an unclosed quote.
+    pub fn parse(&self, command: &str) -> SandboxResult<Vec<ParsedCommand>> {
+        parse_command(command, &self.permissions)?
+            .into_iter()
+            .map(ParsedCommand::from_stage)
+            .collect()
+    }
+    
+    /// Report whether `command` would be allowed, without running or simulating it
+    ///
+    /// Every stage of a chain is checked; the outcome describes the first stage
+    /// and lists the others in `stages`. Hooks are not consulted and no state
+    /// changes. Fails only if the command cannot be parsed.
+    pub fn check(&self, command: &str) -> SandboxResult<CheckOutcome> {
+        let stages = parse_command(command, &self.permissions)?;
+        // A `cd` moves where later stages' paths are checked, as it would when they run
+        let mut cwd = self.cwd.clone();
+        let mut checks = Vec::with_capacity(stages.len());
+        for stage in &stages {
+            let (tool, args) = (&stage.words[0], &stage.words[1..]);
+            checks.push(self.check_stage(&stage.env, tool, args, &stage.quoted[1..], &cwd));
+            if tool == CD_BUILTIN {
+                if let Ok(target) = self.resolve_cd(&cwd, args) {
+                    cwd = target;
+                }
+            }
         }
         
-        let tool = &parts[0];
-        let args = &parts[1..];
+        if checks.len() == 1 {
+            return Ok(checks.remove(0));
+        }
         
-        self.execute_tool(tool, args)
+        let mut outcome = checks[0].clone();
+        outcome.requires_approval = checks.iter().any(|c| c.requires_approval);
+        outcome.violations = checks.iter().flat_map(|c| c.violations.iter().cloned()).collect();
+        outcome.stages = checks;
+        Ok(outcome)
     }
     
-    /// Execute a specific tool with arguments
-    pub fn execute_tool(&mut self, tool: &str, args: &[String]) -> SandboxResult<ExecutionResult> {
-        // Check permissions
-        let permission_level = if self.allow_all {
-            PermissionLevel::Full
+    /// The status `command` would get if executed now, without executing it
+    ///
+    /// Refused commands are `Blocked` and those needing approval are
+    /// `PendingApproval`; the rest are `Simulated` in Simulation and Diff mode
+    /// and `Success` in Live mode, though a live run may still fail. Like
+    /// `check`, hooks are not consulted and nothing is queued.
+    pub fn classify(&self, command: &str) -> SandboxResult<ExecutionStatus> {
+        let outcome = self.check(command)?;
+        let status = if !outcome.is_allowed() {
+            ExecutionStatus::Blocked
+        } else if outcome.requires_approval {
+            ExecutionStatus::PendingApproval
+        } else if self.mode == ExecutionMode::Live {
+            ExecutionStatus::Success
         } else {
-            self.permissions.check_command(tool, args)?
+            ExecutionStatus::Simulated
+        };
+        Ok(status)
+    }
+    
+    /// Permission check of a single stage for `check`
+    fn check_stage(
+        &self,
+        env: &[(String, String)],
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        cwd: &Path,
+    ) -> CheckOutcome {
+        let mut outcome = CheckOutcome {
+            tool: tool.to_string(),
+            args: args.to_vec(),
+            permission_level: PermissionLevel::ReadOnly,
+            requires_approval: false,
+            violations: Vec::new(),
+            stages: Vec::new(),
         };
+        if tool == CD_BUILTIN {
+            return outcome;
+        }
         
-        // Check if approval is required
-        if self.permissions.requires_approval(tool) && !self.allow_all {
-            // Create a pending approval result
-            let result = ExecutionResult {
-                id: Uuid::new_v4().to_string(),
-                command: format!("{} {}", tool, args.join(" ")),
-                tool: tool.to_string(),
-                args: args.to_vec(),
-                mode: self.mode,
-                status: ExecutionStatus::PendingApproval,
-                stdout: String::new(),
-                stderr: String::new(),
-                exit_code: None,
-                file_changes: Vec::new(),
-                diff_summary: None,
-                permission_level,
-                approved: false,
-                executed_at: chrono::Utc::now().timestamp(),
-            };
+        match self.check_permission(tool, args) {
+            Ok(level) => outcome.permission_level = level,
+            Err(e) => {
+                // Report the tool's own level even when its arguments are refused
+                outcome.permission_level = self.permissions.check_tool(tool).unwrap_or(PermissionLevel::Denied);
+                outcome.violations.push(e.to_string());
+            }
+        }
+        if let Err(e) = self.check_env(env) {
+            outcome.violations.push(e.to_string());
+        }
+        outcome.violations.extend(self.path_violations(tool, args, quoted, cwd).iter().map(ToString::to_string));
+        outcome.requires_approval = self.needs_approval(tool, args, outcome.permission_level);
+        outcome
+    }
+    
+    /// `PathNotAllowed` errors for the files `tool args` is predicted to touch
+    ///
+    /// Predicted paths are made absolute under `root_dir`; relative path rules
+    /// match them relative to it, so the outcome does not depend on whether
+    /// the working directory was given as an absolute path. Nothing is refused
+    /// under `allow_all`. The command runs in `cwd`, relative to the root.
+    fn path_violations(&self, tool: &str, args: &[String], quoted: &[bool], cwd: &Path) -> Vec<SandboxError> {
+        if self.bypasses_permissions() {
+            return Vec::new();
+        }
+        
+        let root = self.root_dir();
+        self.predict_file_changes(tool, args, quoted, cwd)
+            .iter()
+            .map(|change| normalize_path(&root.join(&change.path)))
+            .filter_map(|path| self.permissions.check_path_in(tool, &root, &path).err())
+            .collect()
+    }
+    
+    /// The working directory as an absolute path, without `.` or `..`
+    fn root_dir(&self) -> PathBuf {
+        let root = std::path::absolute(&self.working_dir).unwrap_or_else(|_| self.working_dir.clone());
+        normalize_path(&root)
+    }
+    
+    /// Execute a command in the sandbox
+    ///
+    /// Pipelines and `&&`/`||`/`;` chains are split into stages; every stage is
+    /// permission-checked before any of them runs, and the result lists each
+    /// stage in `stages`. In a pipeline each stage runs to completion before the
+    /// next one reads its output.
+    pub fn execute(&mut self, command: &str) -> SandboxResult<ExecutionResult> {
+        self.execute_command(command, None)
+    }
+    
+    /// Execute a command in `mode`, leaving the sandbox's own mode as it was
+    ///
+    /// Useful for running one read-only command live, like `git status`, while
+    /// the sandbox otherwise stays in Diff or Simulation mode.
+    pub fn execute_with_mode(&mut self, command: &str, mode: ExecutionMode) -> SandboxResult<ExecutionResult> {
+        let previous = std::mem::replace(&mut self.mode, mode);
+        let result = self.execute(command);
+        self.mode = previous;
+        result
+    }
+    
+    /// Execute a command, writing `input` to its stdin and then closing it
+    pub fn execute_with_stdin(&mut self, command: &str, input: &[u8]) -> SandboxResult<ExecutionResult> {
+        self.execute_command(command, Some(input))
+    }
+    
+    /// Execute a command, killing it as soon as `cancel` is cancelled
+    ///
+    /// A cancelled command reports `ExecutionStatus::Cancelled` with whatever
+    /// output it produced; later stages of a chain are not started.
+    pub fn execute_cancellable(&mut self, command: &str, cancel: CancellationToken) -> SandboxResult<ExecutionResult> {
+        self.cancellation = Some(cancel);
+        let result = self.execute_command(command, None);
+        self.cancellation = None;
+        result
+    }
+    
+    /// Execute commands in order, returning one result per command
+    ///
+    /// Commands that cannot run at all, such as those denied by permissions,
+    /// are reported as `Blocked`. With `stop_on_error` the batch stops after the
+    /// first `Failed`, `TimedOut` or `Blocked` result; a command queued for approval
+    /// always stops it. Commands after the stopping point are reported as `Skipped`.
+    pub fn execute_batch(&mut self, commands: &[String], stop_on_error: bool) -> Vec<ExecutionResult> {
+        let mut results = Vec::with_capacity(commands.len());
+        let mut stopped = false;
+        
+        for command in commands {
+            if stopped {
+                results.push(ExecutionResult::unrun(command, self.mode, ExecutionStatus::Skipped, String::new()));
+                continue;
+            }
             
-            // Store for approval
-            self.pending_approvals.insert(result.id.clone(), result.clone());
+            let result = self.execute(command);
+            let result = self.or_unrun(command, result);
             
-            return Ok(result);
+            stopped = match result.status {
+                ExecutionStatus::PendingApproval => true,
+                ExecutionStatus::Failed | ExecutionStatus::TimedOut | ExecutionStatus::Blocked => stop_on_error,
+                _ => false,
+            };
+            results.push(result);
         }
         
-        // Execute based on mode
-        match self.mode {
-            ExecutionMode::Simulation => self.simulate_execution(tool, args, permission_level),
-            ExecutionMode::Diff => self.diff_execution(tool, args, permission_level),
-            ExecutionMode::Live => self.live_execution(tool, args, permission_level),
-        }
+        results
     }
     
-    /// Execute in live mode (actual execution with safeguards)
-    fn live_execution(
-        &mut self,
-        tool: &str,
-        args: &[String],
-        permission_level: PermissionLevel,
-    ) -> SandboxResult<ExecutionResult> {
-        // Build the command
-        let mut cmd = Command::new(tool);
-        cmd.args(args)
-            .current_dir(&self.working_dir)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-        
-        // Execute
-        let output = match cmd.output() {
-            Ok(o) => o,
-            Err(e) => {
-                return Ok(ExecutionResult {
-                    id: Uuid::new_v4().to_string(),
-                    command: format!("{} {}", tool, args.join(" ")),
-                    tool: tool.to_string(),
-                    args: args.to_vec(),
-                    mode: self.mode,
-                    status: ExecutionStatus::Failed,
-                    stdout: String::new(),
-                    stderr: e.to_string(),
-                    exit_code: Some(-1),
-                    file_changes: Vec::new(),
-                    diff_summary: None,
-                    permission_level,
-                    approved: true,
-                    executed_at: chrono::Utc::now().timestamp(),
-                });
+    /// `result`, or an unrun result for `command` carrying its error
+    ///
+    /// Permission and path refusals are `Blocked`; other errors are `Failed`.
+    fn or_unrun(&self, command: &str, result: SandboxResult<ExecutionResult>) -> ExecutionResult {
+        result.unwrap_or_else(|e| {
+            let status = match e {
+                SandboxError::PermissionDenied(_) | SandboxError::PathNotAllowed { .. } => ExecutionStatus::Blocked,
+                _ => ExecutionStatus::Failed,
+            };
+            ExecutionResult::unrun(command, self.mode, status, e.to_string())
+        })
+    }
+    
+    /// Execute a specific tool with arguments
+    pub fn execute_tool(&mut self, tool: &str, args: &[String]) -> SandboxResult<ExecutionResult> {
+        self.execute_tool_with_input(tool, args, &[], None)
+    }
+    
+    /// Parse a command line and execute it as a single tool or a chain
+    fn execute_command(&mut self, command: &str, input: Option<&[u8]>) -> SandboxResult<ExecutionResult> {
+        let _span = debug_span!("execute", command, mode = ?self.mode).entered();
+        let result = self.dispatch_command(command, input);
+        self.record_accepted(command, &result);
+        result
+    }
+    
+    /// Run a parsed command line as a single tool or a chain
+    fn dispatch_command(&mut self, command: &str, input: Option<&[u8]>) -> SandboxResult<ExecutionResult> {
+        let stages = parse_command(command, &self.permissions)?;
+        // Environment assignments are carried by chain stages, so only a bare command skips the chain
+        if let [stage] = stages.as_slice() {
+            if stage.env.is_empty() {
+                return self.execute_tool_with_input(&stage.words[0], &stage.words[1..], &stage.quoted[1..], input);
             }
-        };
+        }
         
-        let status = if output.status.success() {
-            ExecutionStatus::Success
-        } else {
-            ExecutionStatus::Failed
+        if let Some(blocked) = self.veto_chain(command, &stages) {
+            return Ok(blocked);
+        }
+        
+        let planned = match self.plan_chain(command, &stages, input)? {
+            ChainPlan::Ready(planned) => planned,
+            ChainPlan::Pending(result) => return Ok(self.finish(*result)),
         };
         
-        let result = ExecutionResult {
-            id: Uuid::new_v4().to_string(),
-            command: format!("{} {}", tool, args.join(" ")),
-            tool: tool.to_string(),
-            args: args.to_vec(),
-            mode: self.mode,
-            status,
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-            exit_code: output.status.code(),
-            file_changes: Vec::new(),
-            diff_summary: None,
-            permission_level,
-            approved: true,
-            executed_at: chrono::Utc::now().timestamp(),
+        self.emit(SandboxEvent::CommandStarted(command.to_string()));
+        debug!(command, stages = planned.len(), mode = ?self.mode, "dispatching chain");
+        
+        let result = match self.mode {
+            ExecutionMode::Simulation | ExecutionMode::Diff => self.preview_chain(command, planned, &stages)?,
+            ExecutionMode::Live => {
+                let results = self.run_chain(planned, input)?;
+                let result = ExecutionResult::from_stages(command, self.mode, results);
+                self.record_history(result.clone());
+                result
+            }
         };
         
-        self.execution_history.push(result.clone());
-        Ok(result)
+        Ok(self.finish(result))
     }
     
-    /// Execute in simulation mode (preview only)
-    fn simulate_execution(
-        &self,
-        tool: &str,
-        args: &[String],
-        permission_level: PermissionLevel,
-    ) -> SandboxResult<ExecutionResult> {
-        let command = format!("{} {}", tool, args.join(" "));
+    /// Run `command` live, comparing the Diff-mode prediction with what changed on disk
+    ///
+    /// The prediction is taken from the current state before anything runs and
+    /// has no side effects. Changes match when the same paths change with the
+    /// same operation; content is not compared. A command queued for approval
+    /// has no actual changes yet.
+    pub fn execute_verified(&mut self, command: &str) -> SandboxResult<VerifiedExecution> {
+        let predicted = self.predict_command(command)?;
         
-        // Analyze what would happen
-        let (stdout, stderr) = self.analyze_command(tool, args);
+        let result = self.execute_with_mode(command, ExecutionMode::Live)?;
         
-        Ok(ExecutionResult {
-            id: Uuid::new_v4().to_string(),
-            command,
+        let actual = result.file_changes.clone();
+        let mut mismatches: Vec<PathBuf> = predicted
+            .iter()
+            .chain(&actual)
+            .map(|change| change.path.clone())
+            .filter(|path| operation_at(&predicted, path) != operation_at(&actual, path))
+            .collect();
+        mismatches.sort();
+        mismatches.dedup();
+        
+        Ok(VerifiedExecution {
+            matched: mismatches.is_empty(),
+            result,
+            predicted,
+            actual,
+            mismatches,
+        })
+    }
+    
+    /// File changes Diff mode would predict for `command`, without running or recording it
+    fn predict_command(&mut self, command: &str) -> SandboxResult<Vec<FileDiff>> {
+        let stages = parse_command(command, &self.permissions)?;
+        let cwd = self.cwd.clone();
+        let mut predicted = Vec::new();
+        for stage in &stages {
+            let (tool, args) = (&stage.words[0], &stage.words[1..]);
+            if tool == CD_BUILTIN {
+                self.preview_cd(args);
+            } else {
+                predicted.extend(self.predict_file_changes(tool, args, &stage.quoted[1..], &self.cwd));
+            }
+        }
+        self.cwd = cwd;
+        
+        Ok(merge_file_changes(predicted))
+    }
+    
+    /// Execute a tool, feeding `input` to its stdin in live mode
+    ///
+    /// `quoted` marks the arguments that were quoted on the command line, which
+    /// previews never take for redirections.
+    fn execute_tool_with_input(
+        &mut self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        input: Option<&[u8]>,
+    ) -> SandboxResult<ExecutionResult> {
+        if let Some(blocked) = self.run_before_hook(tool, args) {
+            return Ok(self.finish(blocked));
+        }
+        
+        if tool == CD_BUILTIN {
+            let result = self.builtin_cd(args);
+            return Ok(self.finish(result));
+        }
+        
+        let permission_level = match self.authorize(tool, args, quoted, input)? {
+            Authorization::Granted(level) => level,
+            Authorization::Pending(result) => return Ok(self.finish(*result)),
+        };
+        
+        self.emit(SandboxEvent::CommandStarted(format!("{} {}", tool, args.join(" "))));
+        debug!(tool, mode = ?self.mode, "dispatching command");
+        
+        // Execute based on mode
+        let result = match self.mode {
+            ExecutionMode::Simulation => self.simulate_execution(tool, args, quoted, permission_level)?,
+            ExecutionMode::Diff => self.diff_execution(tool, args, quoted, permission_level)?,
+            ExecutionMode::Live => {
+                let run = self.live_execution(tool, args, &[], permission_level, input)?;
+                self.record_live(run)?
+            }
+        };
+        
+        Ok(self.finish(result))
+    }
+    
+    /// Execute a command without blocking the async runtime
+    #[cfg(feature = "async")]
+    pub async fn execute_async(&mut self, command: &str) -> SandboxResult<ExecutionResult> {
+        let result = self.dispatch_command_async(command).await;
+        self.record_accepted(command, &result);
+        result
+    }
+    
+    #[cfg(feature = "async")]
+    async fn dispatch_command_async(&mut self, command: &str) -> SandboxResult<ExecutionResult> {
+        let stages = parse_command(command, &self.permissions)?;
+        if let [stage] = stages.as_slice() {
+            if stage.env.is_empty() {
+                return self.execute_stage_async(&stage.words[0], &stage.words[1..], &stage.quoted[1..]).await;
+            }
+        }
+        
+        if let Some(blocked) = self.veto_chain(command, &stages) {
+            return Ok(blocked);
+        }
+        
+        let planned = match self.plan_chain(command, &stages, None)? {
+            ChainPlan::Ready(planned) => planned,
+            ChainPlan::Pending(result) => return Ok(self.finish(*result)),
+        };
+        
+        self.emit(SandboxEvent::CommandStarted(command.to_string()));
+        
+        let result = match self.mode {
+            ExecutionMode::Simulation | ExecutionMode::Diff => self.preview_chain(command, planned, &stages)?,
+            ExecutionMode::Live => {
+                let results = self.run_chain_async(planned).await?;
+                let result = ExecutionResult::from_stages(command, self.mode, results);
+                self.record_history(result.clone());
+                result
+            }
+        };
+        
+        Ok(self.finish(result))
+    }
+    
+    /// Execute a specific tool without blocking the async runtime
+    ///
+    /// Live runs use `tokio::process`; hooks, permission checks, approvals and
+    /// history behave exactly as in `execute_tool`.
+    #[cfg(feature = "async")]
+    pub async fn execute_tool_async(
+        &mut self,
+        tool: &str,
+        args: &[String],
+    ) -> SandboxResult<ExecutionResult> {
+        self.execute_stage_async(tool, args, &[]).await
+    }
+    
+    /// `execute_tool_async` for a parsed stage whose `quoted` arguments are never redirections
+    #[cfg(feature = "async")]
+    async fn execute_stage_async(
+        &mut self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+    ) -> SandboxResult<ExecutionResult> {
+        if let Some(blocked) = self.run_before_hook(tool, args) {
+            return Ok(self.finish(blocked));
+        }
+        
+        if tool == CD_BUILTIN {
+            let result = self.builtin_cd(args);
+            return Ok(self.finish(result));
+        }
+        
+        let permission_level = match self.authorize(tool, args, quoted, None)? {
+            Authorization::Granted(level) => level,
+            Authorization::Pending(result) => return Ok(self.finish(*result)),
+        };
+        
+        self.emit(SandboxEvent::CommandStarted(format!("{} {}", tool, args.join(" "))));
+        
+        let result = match self.mode {
+            ExecutionMode::Simulation => self.simulate_execution(tool, args, quoted, permission_level)?,
+            ExecutionMode::Diff => self.diff_execution(tool, args, quoted, permission_level)?,
+            ExecutionMode::Live => {
+                let run = self.live_execution_async(tool, args, &[], permission_level, None).await?;
+                self.record_live(run)?
+            }
+        };
+        
+        Ok(self.finish(result))
+    }
+    
+    /// Ask the before-hook about `tool args`, returning a `Blocked` result if it vetoes the command
+    fn run_before_hook(&mut self, tool: &str, args: &[String]) -> Option<ExecutionResult> {
+        let hook = self.before_hook.as_mut()?;
+        match hook(tool, args) {
+            HookDecision::Proceed => None,
+            HookDecision::Block(reason) => Some(ExecutionResult {
+                stderr: format!("Blocked by hook: {}", reason),
+                approved: false,
+                cwd: self.current_dir(),
+                ..ExecutionResult::new(tool, args, self.mode, ExecutionStatus::Blocked, PermissionLevel::Denied)
+            }),
+        }
+    }
+    
+    /// Run the before-hook on every stage of a chain; one veto blocks the whole chain
+    fn veto_chain(&mut self, command: &str, stages: &[shell_words::Stage]) -> Option<ExecutionResult> {
+        let blocked = stages
+            .iter()
+            .find_map(|stage| self.run_before_hook(&stage.words[0], &stage.words[1..]))?;
+        
+        Some(self.finish(ExecutionResult {
+            command: command.to_string(),
+            ..blocked
+        }))
+    }
+    
+    /// Show `result` to the after-hook and subscribers, then hand it back
+    ///
+    /// Results still waiting for approval were already announced by `queue_approval`.
+    fn finish(&mut self, result: ExecutionResult) -> ExecutionResult {
+        if let Some(hook) = self.after_hook.as_mut() {
+            hook(&result);
+        }
+        if result.status != ExecutionStatus::PendingApproval {
+            self.emit(SandboxEvent::CommandFinished(Box::new(result.clone())));
+        }
+        result
+    }
+    
+    /// Send `event` to every subscriber, forgetting those whose receiver was dropped
+    fn emit(&mut self, event: SandboxEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+    
+    /// Permission level `tool` runs with, or an error if it may not run at all
+    fn check_permission(&self, tool: &str, args: &[String]) -> SandboxResult<PermissionLevel> {
+        let checked = if self.bypasses_permissions() {
+            Ok(PermissionLevel::Full)
+        } else {
+            self.permissions.check_command(tool, args)
+        };
+        debug!(tool, allow_all = self.allow_all, outcome = ?checked, "permission check");
+        checked
+    }
+    
+    /// Refuse `NAME=value` prefixes for variables that were not allowed with `allow_env`
+    ///
+    /// Setting a variable such as `LD_PRELOAD` can change what an allowed tool
+    /// does, so only allowlisted names may be assigned.
+    fn check_env(&self, env: &[(String, String)]) -> SandboxResult<()> {
+        if self.bypasses_permissions() {
+            return Ok(());
+        }
+        match env.iter().find(|(name, _)| !self.permissions.is_env_allowed(name)) {
+            Some((name, _)) => Err(SandboxError::PermissionDenied(format!(
+                "Environment variable '{}' may not be set",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+    
+    /// Whether `tool args` must be approved, by its tool's flag or the approval policy
+    ///
+    /// Nothing needs approval under `allow_all`.
+    fn needs_approval(&self, tool: &str, args: &[String], level: PermissionLevel) -> bool {
+        if self.bypasses_permissions() {
+            return false;
+        }
+        self.permissions.requires_approval(tool)
+            || self
+                .approval_policy
+                .as_ref()
+                .is_some_and(|policy| policy.needs_approval(tool, args, level))
+    }
+    
+    /// Check permissions, queueing the command if it needs approval first
+    ///
+    /// Paths the predictors expect the command to touch must pass the tool's
+    /// path rules, or the first one that does not is returned as `PathNotAllowed`.
+    fn authorize(
+        &mut self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        input: Option<&[u8]>,
+    ) -> SandboxResult<Authorization> {
+        let permission_level = self.check_permission(tool, args)?;
+        if let Some(violation) = self.path_violations(tool, args, quoted, &self.cwd).into_iter().next() {
+            return Err(violation);
+        }
+        
+        // Check if approval is required
+        if self.needs_approval(tool, args, permission_level) {
+            // Create a pending approval result
+            let result = ExecutionResult {
+                cwd: self.current_dir(),
+                ..ExecutionResult::new(
+                    tool,
+                    args,
+                    self.mode,
+                    ExecutionStatus::PendingApproval,
+                    permission_level,
+                )
+            };
+            
+            return Ok(Authorization::Pending(Box::new(self.queue_approval(result, input))));
+        }
+        
+        Ok(Authorization::Granted(permission_level))
+    }
+    
+    /// Permission-check every stage of a chain before anything runs
+    ///
+    /// A denied stage fails the whole chain. If any stage needs approval, the
+    /// chain is queued as a single pending result carrying every stage.
+    fn plan_chain(
+        &mut self,
+        command: &str,
+        stages: &[shell_words::Stage],
+        input: Option<&[u8]>,
+    ) -> SandboxResult<ChainPlan> {
+        let mut planned = Vec::with_capacity(stages.len());
+        // Later stages are checked in the directory an earlier `cd` moves to
+        let mut cwd = self.cwd.clone();
+        for stage in stages {
+            let (tool, args) = (&stage.words[0], &stage.words[1..]);
+            let started = cwd.clone();
+            let permission_level = if tool == CD_BUILTIN {
+                if let Ok(target) = self.resolve_cd(&cwd, args) {
+                    cwd = target;
+                }
+                PermissionLevel::ReadOnly
+            } else {
+                let level = self.check_permission(tool, args)?;
+                self.check_env(&stage.env)?;
+                let violations = self.path_violations(tool, args, &stage.quoted[1..], &cwd);
+                if let Some(violation) = violations.into_iter().next() {
+                    return Err(violation);
+                }
+                level
+            };
+            planned.push(ExecutionResult {
+                chain_operator: stage.operator,
+                cwd: self.dir_at(&started),
+                env: stage.env.clone(),
+                ..ExecutionResult::new(
+                    tool,
+                    args,
+                    self.mode,
+                    ExecutionStatus::PendingApproval,
+                    permission_level,
+                )
+            });
+        }
+        
+        let needs_approval = planned
+            .iter()
+            .any(|stage| self.needs_approval(&stage.tool, &stage.args, stage.permission_level));
+        if !needs_approval {
+            return Ok(ChainPlan::Ready(planned));
+        }
+        
+        let result = ExecutionResult::from_stages(command, self.mode, planned);
+        Ok(ChainPlan::Pending(Box::new(self.queue_approval(result, input))))
+    }
+    
+    /// Store `result` as a pending approval, stamping its expiry from `approval_ttl`
+    fn queue_approval(&mut self, mut result: ExecutionResult, input: Option<&[u8]>) -> ExecutionResult {
+        result.approved = false;
+        result.awaited_approval = true;
+        result.approval_hash = Some(result.content_hash());
+        result.expires_at = self
+            .approval_ttl
+            .map(|ttl| result.created_at + ttl.as_secs() as i64);
+        
+        if let Some(input) = input {
+            self.pending_stdin.insert(result.id.clone(), input.to_vec());
+        }
+        self.pending_approvals.insert(result.id.clone(), result.clone());
+        debug!(id = %result.id, command = %result.command, "queued for approval");
+        self.emit(SandboxEvent::ApprovalRequested(result.id.clone()));
+        
+        result
+    }
+    
+    /// Simulate or diff every stage of a checked chain
+    ///
+    /// A `cd` in the chain moves the directory later stages are previewed in,
+    /// but the tracked `cwd` is restored afterwards.
+    fn preview_chain(
+        &mut self,
+        command: &str,
+        planned: Vec<ExecutionResult>,
+        parsed: &[shell_words::Stage],
+    ) -> SandboxResult<ExecutionResult> {
+        let cwd = self.cwd.clone();
+        let mut stages = Vec::with_capacity(planned.len());
+        for (stage, parsed) in planned.into_iter().zip(parsed) {
+            let quoted = &parsed.quoted[1..];
+            let preview = match self.mode {
+                _ if stage.tool == CD_BUILTIN => self.preview_cd(&stage.args),
+                ExecutionMode::Diff => self.diff_execution(&stage.tool, &stage.args, quoted, stage.permission_level)?,
+                _ => self.simulate_execution(&stage.tool, &stage.args, quoted, stage.permission_level)?,
+            };
+            stages.push(ExecutionResult {
+                chain_operator: stage.chain_operator,
+                ..preview
+            });
+        }
+        self.cwd = cwd;
+        
+        Ok(ExecutionResult::from_stages(command, self.mode, stages))
+    }
+    
+    /// Run a checked chain with shell semantics, returning the stages that ran
+    ///
+    /// Each stage of a pipeline runs to completion before the next one reads
+    /// its captured stdout, as raw bytes rather than the redacted text in its
+    /// result. Stages skipped by `&&` or `||` are left out.
+    fn run_chain(&mut self, planned: Vec<ExecutionResult>, input: Option<&[u8]>) -> SandboxResult<Vec<ExecutionResult>> {
+        let mut ran: Vec<ExecutionResult> = Vec::with_capacity(planned.len());
+        let mut previous = None;
+        let mut previous_ran = true;
+        let mut last_stdout = Vec::new();
+        
+        for stage in planned {
+            if self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                break;
+            }
+            
+            let runs = should_run(previous, previous_ran, ran.last());
+            if runs {
+                let piped = match previous {
+                    None => input.map(<[u8]>::to_vec),
+                    Some(ChainOperator::Pipe) => Some(std::mem::take(&mut last_stdout)),
+                    Some(_) => None,
+                };
+                let result = if stage.tool == CD_BUILTIN {
+                    last_stdout.clear();
+                    self.change_directory(&stage.args)
+                } else {
+                    let run = self.live_execution(
+                        &stage.tool,
+                        &stage.args,
+                        &stage.env,
+                        stage.permission_level,
+                        piped.as_deref(),
+                    )?;
+                    last_stdout = run.stdout;
+                    run.result
+                };
+                ran.push(ExecutionResult {
+                    chain_operator: stage.chain_operator,
+                    ..result
+                });
+            }
+            previous = stage.chain_operator;
+            previous_ran = runs;
+        }
+        
+        Ok(ran)
+    }
+    
+    /// Async counterpart of `run_chain`
+    #[cfg(feature = "async")]
+    async fn run_chain_async(&mut self, planned: Vec<ExecutionResult>) -> SandboxResult<Vec<ExecutionResult>> {
+        let mut ran: Vec<ExecutionResult> = Vec::with_capacity(planned.len());
+        let mut previous = None;
+        let mut previous_ran = true;
+        let mut last_stdout = Vec::new();
+        
+        for stage in planned {
+            let runs = should_run(previous, previous_ran, ran.last());
+            if runs {
+                let piped = match previous {
+                    Some(ChainOperator::Pipe) => Some(std::mem::take(&mut last_stdout)),
+                    _ => None,
+                };
+                let result = if stage.tool == CD_BUILTIN {
+                    last_stdout.clear();
+                    self.change_directory(&stage.args)
+                } else {
+                    let run = self
+                        .live_execution_async(
+                            &stage.tool,
+                            &stage.args,
+                            &stage.env,
+                            stage.permission_level,
+                            piped.as_deref(),
+                        )
+                        .await?;
+                    last_stdout = run.stdout;
+                    run.result
+                };
+                ran.push(ExecutionResult {
+                    chain_operator: stage.chain_operator,
+                    ..result
+                });
+            }
+            previous = stage.chain_operator;
+            previous_ran = runs;
+        }
+        
+        Ok(ran)
+    }
+    
+    /// Directory commands currently run in
+    pub fn current_dir(&self) -> PathBuf {
+        self.dir_at(&self.cwd)
+    }
+    
+    /// Directory on disk for `cwd`, relative to the working directory
+    fn dir_at(&self, cwd: &Path) -> PathBuf {
+        if cwd.as_os_str().is_empty() {
+            self.working_dir.clone()
+        } else {
+            self.working_dir.join(cwd)
+        }
+    }
+    
+    /// Run `cd` as a built-in for the current mode
+    ///
+    /// Live runs move the tracked `cwd` and are recorded in history; previews
+    /// only report whether the directory exists.
+    fn builtin_cd(&mut self, args: &[String]) -> ExecutionResult {
+        if self.mode != ExecutionMode::Live {
+            let cwd = self.cwd.clone();
+            let result = self.preview_cd(args);
+            self.cwd = cwd;
+            return result;
+        }
+        
+        let result = self.change_directory(args);
+        self.record_history(result.clone());
+        result
+    }
+    
+    /// `change_directory`, reporting success as `Simulated`
+    fn preview_cd(&mut self, args: &[String]) -> ExecutionResult {
+        let mut result = self.change_directory(args);
+        if result.status == ExecutionStatus::Success {
+            result.status = ExecutionStatus::Simulated;
+        }
+        result
+    }
+    
+    /// Move the tracked `cwd` to `args[0]`, or back to `working_dir` without an argument
+    ///
+    /// No process is spawned. The target must be a directory on disk or in the
+    /// virtual filesystem, and must stay inside the working directory, also
+    /// after resolving symbolic links. If `cd` is registered in the permission
+    /// gate, its path rules decide which directories may be entered.
+    fn change_directory(&mut self, args: &[String]) -> ExecutionResult {
+        let started = self.current_dir();
+        let result = |status: ExecutionStatus, stderr: String| ExecutionResult {
+            stderr,
+            exit_code: Some(if status == ExecutionStatus::Success { 0 } else { 1 }),
+            cwd: started.clone(),
+            ..ExecutionResult::new(CD_BUILTIN, args, self.mode, status, PermissionLevel::ReadOnly)
+        };
+        
+        match self.resolve_cd(&self.cwd, args) {
+            Ok(target) => {
+                self.cwd = target;
+                result(ExecutionStatus::Success, String::new())
+            }
+            Err((status, stderr)) => result(status, stderr),
+        }
+    }
+    
+    /// The `cwd` that `cd args` run from `cwd` moves to, or the status and stderr it fails with
+    ///
+    /// Follows the rules of `change_directory` without moving anything.
+    fn resolve_cd(&self, cwd: &Path, args: &[String]) -> Result<PathBuf, (ExecutionStatus, String)> {
+        let dir = args.first().map(String::as_str).unwrap_or_default();
+        let root = self.root_dir();
+        let target = match args.first() {
+            Some(dir) => normalize_path(&cwd.join(dir)),
+            None => PathBuf::new(),
+        };
+        let Some(target) = contained_path(&root, &target) else {
+            return Err((
+                ExecutionStatus::Blocked,
+                format!("cd: {}: outside the sandbox root\n", dir),
+            ));
+        };
+        let absolute = root.join(&target);
+        
+        if !self.bypasses_permissions() && self.permissions.get_permission(CD_BUILTIN).is_some() {
+            if let Err(e) = self.permissions.check_path_in(CD_BUILTIN, &root, &absolute) {
+                return Err((ExecutionStatus::Blocked, format!("cd: {}\n", e)));
+            }
+        }
+        
+        if !target.as_os_str().is_empty() && absolute.is_dir() {
+            // A symbolic link inside the root may still point out of it
+            let escapes = match (absolute.canonicalize(), root.canonicalize()) {
+                (Ok(resolved), Ok(root)) => !resolved.starts_with(root),
+                _ => true,
+            };
+            if escapes {
+                return Err((
+                    ExecutionStatus::Blocked,
+                    format!("cd: {}: outside the sandbox root\n", dir),
+                ));
+            }
+        } else if !target.as_os_str().is_empty() && !self.virtual_fs.is_dir(&target) {
+            return Err((
+                ExecutionStatus::Failed,
+                format!("cd: {}: No such file or directory\n", dir),
+            ));
+        }
+        
+        Ok(target)
+    }
+    
+    /// Execute in live mode (actual execution with safeguards)
+    ///
+    /// A here-document is fed to the command's stdin, in place of any piped
+    /// input, rather than passed as arguments. The result is not recorded;
+    /// callers add it to `execution_history`.
+    fn live_execution(
+        &self,
+        tool: &str,
+        args: &[String],
+        env: &[(String, String)],
+        permission_level: PermissionLevel,
+        input: Option<&[u8]>,
+    ) -> SandboxResult<LiveRun> {
+        let (args, heredoc) = split_heredoc(args);
+        let args = args.as_slice();
+        let input = heredoc.map(str::as_bytes).or(input);
+        let invocation = self.invocation(tool, args, env, input);
+        let baseline = tree_baseline(self.virtual_fs.clone());
+        
+        let mut attempts = 1;
+        let output = loop {
+            let output = match self.backend_for(tool) {
+                Some(backend) => backend.run(&invocation),
+                None => NativeBackend.run(&invocation),
+            };
+            match self.retry_delay(tool, attempts, &output) {
+                Some(delay) => std::thread::sleep(delay),
+                None => break output,
+            }
+            attempts += 1;
+        };
+        let file_changes = match output {
+            Ok(_) => changes_since(baseline),
+            Err(_) => Vec::new(),
+        };
+        
+        let result = ExecutionResult {
+            attempts,
+            env: env.to_vec(),
+            ..self.live_result(tool, args, permission_level, &output, file_changes)
+        };
+        check_resolved_argv(&result, &output);
+        Ok(LiveRun::from_output(result, output))
+    }
+    
+    /// Async counterpart of `live_execution`; tree scans and custom backends run on the blocking pool
+    #[cfg(feature = "async")]
+    async fn live_execution_async(
+        &self,
+        tool: &str,
+        args: &[String],
+        env: &[(String, String)],
+        permission_level: PermissionLevel,
+        input: Option<&[u8]>,
+    ) -> SandboxResult<LiveRun> {
+        let (args, heredoc) = split_heredoc(args);
+        let args = args.as_slice();
+        let input = heredoc.map(str::as_bytes).or(input);
+        let invocation = self.invocation(tool, args, env, input);
+        let vfs = self.virtual_fs.clone();
+        let baseline = tokio::task::spawn_blocking(move || tree_baseline(vfs))
+            .await
+            .unwrap_or(None);
+        
+        let mut attempts = 1;
+        let output = loop {
+            let output = match self.backend_for(tool) {
+                Some(backend) => {
+                    let invocation = invocation.clone();
+                    tokio::task::spawn_blocking(move || backend.run(&invocation))
+                        .await
+                        .unwrap_or_else(|e| Err(std::io::Error::other(e).into()))
+                }
+                None => crate::backend::run_with_timeout_async(native_command(&invocation), &invocation).await,
+            };
+            match self.retry_delay(tool, attempts, &output) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => break output,
+            }
+            attempts += 1;
+        };
+        let file_changes = match output {
+            Ok(_) => tokio::task::spawn_blocking(move || changes_since(baseline))
+                .await
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        
+        let result = ExecutionResult {
+            attempts,
+            env: env.to_vec(),
+            ..self.live_result(tool, args, permission_level, &output, file_changes)
+        };
+        check_resolved_argv(&result, &output);
+        Ok(LiveRun::from_output(result, output))
+    }
+    
+    /// Record a live run in history, failing with its spawn error if the command never started
+    ///
+    /// A command that could not be started is still shown to the after-hook and
+    /// subscribers as a `Failed` result before the error is returned.
+    fn record_live(&mut self, run: LiveRun) -> SandboxResult<ExecutionResult> {
+        self.record_history(run.result.clone());
+        match run.spawn_error {
+            None => Ok(run.result),
+            Some(error) => {
+                self.finish(run.result);
+                Err(error)
+            }
+        }
+    }
+    
+    /// Backend configured for `tool`; `None` runs it as a native process
+    fn backend_for(&self, tool: &str) -> Option<Arc<dyn ExecutionBackend>> {
+        self.tool_backends
+            .get(tool)
+            .or(self.backend.as_ref())
+            .cloned()
+    }
+    
+    /// What a backend needs to run `tool args` under this sandbox's limits
+    fn invocation(&self, tool: &str, args: &[String], env: &[(String, String)], input: Option<&[u8]>) -> Invocation {
+        Invocation {
             tool: tool.to_string(),
             args: args.to_vec(),
-            mode: self.mode,
-            status: ExecutionStatus::Simulated,
+            env: env.to_vec(),
+            cwd: self.current_dir(),
+            stdin: input.map(<[u8]>::to_vec),
+            timeout: self.execution_timeout,
+            max_output_bytes: self.max_output_bytes,
+            cancel: self.cancellation.clone(),
+            #[cfg(all(unix, feature = "rlimits"))]
+            max_memory_bytes: self.max_memory_bytes,
+            #[cfg(all(unix, feature = "rlimits"))]
+            cpu_time_limit: self.cpu_time_limit,
+        }
+    }
+    
+    /// How long to wait before running `tool` again, or `None` if its last run is final
+    ///
+    /// Exits that count as success for the tool are never retried.
+    fn retry_delay(&self, tool: &str, attempts: u32, output: &SandboxResult<BackendOutput>) -> Option<Duration> {
+        let policy = self.retry_policy.as_ref()?;
+        let succeeded = matches!(output, Ok(o) if o.exit_code.is_some_and(|code| self.is_success_code(tool, code)));
+        if succeeded || self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return None;
+        }
+        policy.retry_delay(attempts, output)
+    }
+    
+    /// Whether `code` counts as success for `tool`; unregistered tools only accept 0
+    fn is_success_code(&self, tool: &str, code: i32) -> bool {
+        self.permissions
+            .get_permission(tool)
+            .map_or(code == 0, |p| p.is_success(code))
+    }
+    
+    /// Turn the outcome of a live run into an `ExecutionResult`
+    fn live_result(
+        &self,
+        tool: &str,
+        args: &[String],
+        permission_level: PermissionLevel,
+        output: &SandboxResult<BackendOutput>,
+        file_changes: Vec<FileDiff>,
+    ) -> ExecutionResult {
+        let output = match output {
+            Ok(o) => o,
+            Err(e) => {
+                let exit_code = match e {
+                    SandboxError::CommandNotFound(_) => 127,
+                    _ => -1,
+                };
+                return ExecutionResult {
+                    stderr: e.to_string(),
+                    exit_code: Some(exit_code),
+                    cwd: self.current_dir(),
+                    ..ExecutionResult::new(tool, args, self.mode, ExecutionStatus::Failed, permission_level)
+                };
+            }
+        };
+        
+        let status = match (output.killed, output.exit_code) {
+            (Some(KillReason::TimedOut), _) => ExecutionStatus::TimedOut,
+            (Some(KillReason::Cancelled), _) => ExecutionStatus::Cancelled,
+            (None, Some(code)) if self.is_success_code(tool, code) => ExecutionStatus::Success,
+            (None, _) => ExecutionStatus::Failed,
+        };
+        let diff_summary = summarize_changes(&file_changes);
+        
+        ExecutionResult {
+            stdout: self.redact(&output.stdout.to_text()),
+            stderr: self.redact(&output.stderr.to_text()),
+            stdout_bytes: self.raw_output(&output.stdout),
+            stderr_bytes: self.raw_output(&output.stderr),
+            exit_code: output.exit_code,
+            file_changes,
+            diff_summary,
+            duration_ms: output.duration.as_millis() as u64,
+            truncated: output.stdout.dropped > 0 || output.stderr.dropped > 0,
+            cwd: self.current_dir(),
+            ..ExecutionResult::new(tool, args, self.mode, status, permission_level)
+        }
+    }
+    
+    /// Execute in simulation mode (preview only)
+    fn simulate_execution(
+        &self,
+        tool: &str,
+        args: &[String],
+        quoted: &[bool],
+        permission_level: PermissionLevel,
+    ) -> SandboxResult<ExecutionResult> {
+        // Analyze what would happen
+        let read_only = self.is_read_only_quoted(tool, args, quoted);
+        let (stdout, stderr) = self.analyze_command(tool, args, quoted, read_only);
+        
+        Ok(ExecutionResult {
             stdout,
             stderr,
-            exit_code: None,
-            file_changes: Vec::new(),
-            diff_summary: None,
-            permission_level,
-            approved: true,
-            executed_at: chrono::Utc::now().timestamp(),
+            read_only,
+            cwd: self.current_dir(),
+            ..ExecutionResult::new(tool, args, self.mode, ExecutionStatus::Simulated, permission_level)
         })
     }
     
@@ -262,50 +1906,27 @@ impl Sandbox {
         &self,
         tool: &str,
         args: &[String],
+        quoted: &[bool],
         permission_level: PermissionLevel,
     ) -> SandboxResult<ExecutionResult> {
-        let command = format!("{} {}", tool, args.join(" "));
-        
         // Get file changes
-        let file_changes = self.predict_file_changes(tool, args);
+        let file_changes = self.predict_file_changes(tool, args, quoted, &self.cwd);
         
         // Generate diff summary
-        let diff_summary = if !file_changes.is_empty() {
-            let total_added: usize = file_changes
-                .iter()
-                .filter(|d| d.new_content.is_some())
-                .map(|d| d.new_content.as_ref().unwrap().lines().count())
-                .sum();
-            let total_deleted: usize = file_changes
-                .iter()
-                .filter(|d| d.old_content.is_some())
-                .map(|d| d.old_content.as_ref().unwrap().lines().count())
-                .sum();
-            
-            Some(DiffSummary {
-                added: total_added,
-                deleted: total_deleted,
-                unchanged: 0,
-            })
-        } else {
-            None
-        };
+        let diff_summary = summarize_changes(&file_changes);
+        
+        let mut stdout = self.describe_context();
+        for diff in &file_changes {
+            stdout.push_str(&format!("  - {}\n", self.resolve_predicted(&diff.path).display()));
+        }
         
         Ok(ExecutionResult {
-            id: Uuid::new_v4().to_string(),
-            command,
-            tool: tool.to_string(),
-            args: args.to_vec(),
-            mode: self.mode,
-            status: ExecutionStatus::Simulated,
-            stdout: String::new(),
+            stdout,
             stderr: format!("Diff preview for {} file(s)", file_changes.len()),
-            exit_code: None,
             file_changes,
             diff_summary,
-            permission_level,
-            approved: true,
-            executed_at: chrono::Utc::now().timestamp(),
+            cwd: self.current_dir(),
+            ..ExecutionResult::new(tool, args, self.mode, ExecutionStatus::Simulated, permission_level)
         })
     }
     
@@ -314,31 +1935,125 @@ impl Sandbox {
         let result = self.pending_approvals
             .remove(execution_id)
             .ok_or_else(|| SandboxError::InvalidCommand("Execution not found".to_string()))?;
+        let input = self.pending_stdin.remove(execution_id);
+        
+        if result.is_expired() {
+            return Err(SandboxError::ApprovalExpired(result.id));
+        }
+        
+        self.emit(SandboxEvent::Approved(result.id.clone()));
+        self.emit(SandboxEvent::CommandStarted(result.command.clone()));
         
         // Execute the command in live mode
-        let live_result = self.live_execution(
-            &result.tool,
-            &result.args,
-            result.permission_level,
-        )?;
+        let mut run = if result.stages.is_empty() {
+            self.live_execution(
+                &result.tool,
+                &result.args,
+                &result.env,
+                result.permission_level,
+                input.as_deref(),
+            )?
+        } else {
+            let stages = self.run_chain(result.stages, input.as_deref())?;
+            let combined = ExecutionResult::from_stages(&result.command, ExecutionMode::Live, stages);
+            LiveRun::new(combined, Ok(()))
+        };
         
-        Ok(live_result)
+        // Keep the pending id so callers can correlate the approval with its outcome
+        run.result.id = result.id;
+        run.result.approval_hash = result.approval_hash;
+        run.result.approved = true;
+        run.result.awaited_approval = true;
+        let live_result = self.record_live(run)?;
+        
+        Ok(self.finish(live_result))
+    }
+    
+    /// Approve a pending execution only if it still hashes to `expected_hash`
+    ///
+    /// The hash is recomputed from the stored command, so one changed after
+    /// the human saw its `approval_hash` is refused with `PermissionDenied` and
+    /// stays pending.
+    pub fn approve_checked(&mut self, execution_id: &str, expected_hash: &str) -> SandboxResult<ExecutionResult> {
+        let pending = self
+            .pending_approvals
+            .get(execution_id)
+            .ok_or_else(|| SandboxError::InvalidCommand("Execution not found".to_string()))?;
+        let actual = pending.content_hash();
+        if !actual.eq_ignore_ascii_case(expected_hash.trim()) {
+            return Err(SandboxError::PermissionDenied(format!(
+                "Approval hash mismatch for {}: expected {}, command hashes to {}",
+                execution_id, expected_hash, actual
+            )));
+        }
+        
+        self.approve(execution_id)
+    }
+    
+    /// Reject a pending execution, recording it in history as blocked
+    ///
+    /// The command never runs, and its id can no longer be approved. Like
+    /// other blocked commands, the result is shown to the after-hook and sent
+    /// to subscribers as `CommandFinished`, after `Rejected`.
+    pub fn reject(&mut self, execution_id: &str, reason: Option<String>) -> SandboxResult<ExecutionResult> {
+        let mut result = self.pending_approvals
+            .remove(execution_id)
+            .ok_or_else(|| SandboxError::InvalidCommand("Execution not found".to_string()))?;
+        self.pending_stdin.remove(execution_id);
+        
+        result.status = ExecutionStatus::Blocked;
+        result.approved = false;
+        result.stderr = match reason {
+            Some(reason) => format!("Rejected: {}", reason),
+            None => "Rejected".to_string(),
+        };
+        self.record_history(result.clone());
+        self.emit(SandboxEvent::Rejected(result.id.clone()));
+        
+        Ok(self.finish(result))
+    }
+    
+    /// Drop pending approvals that are past their expiry, returning how many were dropped
+    pub fn prune_expired_approvals(&mut self) -> usize {
+        let expired: Vec<String> = self
+            .pending_approvals
+            .values()
+            .filter(|result| result.is_expired())
+            .map(|result| result.id.clone())
+            .collect();
+        
+        for id in &expired {
+            self.pending_approvals.remove(id);
+            self.pending_stdin.remove(id);
+        }
+        
+        expired.len()
     }
     
     /// Analyze what a command would do
-    fn analyze_command(&self, tool: &str, args: &[String]) -> (String, String) {
-        let mut stdout = format!("[SIMULATION] Would execute: {} {}\n\n", tool, args.join(" "));
-        let mut stderr = String::new();
+    ///
+    /// File changes are not predicted for read-only commands.
+    fn analyze_command(&self, tool: &str, args: &[String], quoted: &[bool], read_only: bool) -> (String, String) {
+        let mut stdout = format!("[SIMULATION] Would execute: {} {}\n", tool, args.join(" "));
+        stdout.push_str(&self.describe_context());
+        stdout.push('\n');
+        let stderr = String::new();
         
         // Check what files would be affected
-        let file_changes = self.predict_file_changes(tool, args);
+        let file_changes = if read_only {
+            Vec::new()
+        } else {
+            self.predict_file_changes(tool, args, quoted, &self.cwd)
+        };
         
-        if file_changes.is_empty() {
+        if read_only {
+            stdout.push_str("Read-only command, no file changes.\n");
+        } else if file_changes.is_empty() {
             stdout.push_str("No file changes detected.\n");
         } else {
             stdout.push_str(&format!("Would affect {} file(s):\n", file_changes.len()));
             for diff in &file_changes {
-                stdout.push_str(&format!("  - {}\n", diff.path.display()));
+                stdout.push_str(&format!("  - {}\n", self.resolve_predicted(&diff.path).display()));
             }
         }
         
@@ -353,74 +2068,267 @@ impl Sandbox {
         (stdout, stderr)
     }
     
-    /// Predict what files would be changed by a command
-    fn predict_file_changes(&self, tool: &str, args: &[String]) -> Vec<FileDiff> {
-        let mut changes = Vec::new();
-        
-        // Git commands
-        if tool == "git" {
-            if args.iter().any(|a| a == "add" || a == "commit") {
-                // Would stage/commit files
-                for file in self.virtual_fs.list_files() {
-                    changes.push(FileDiff {
-                        path: file,
-                        operation: crate::virtual_fs::DiffOperation::Modified,
-                        old_content: None,
-                        new_content: Some("(staged)".to_string()),
-                    });
-                }
-            }
+    /// Whether `tool args` has no side effects
+    ///
+    /// True for tools registered at `PermissionLevel::ReadOnly` and for a small
+    /// builtin set of commands such as `ls` and `git status`, unless the command
+    /// redirects output to a file.
+    pub fn is_read_only(&self, tool: &str, args: &[String]) -> bool {
+        self.is_read_only_quoted(tool, args, &[])
+    }
+    
+    /// `is_read_only` for a parsed stage, whose `quoted` arguments are never redirections
+    fn is_read_only_quoted(&self, tool: &str, args: &[String], quoted: &[bool]) -> bool {
+        if !split_redirections(args, quoted).1.is_empty() {
+            return false;
         }
         
-        // npm commands
-        if tool == "npm" && args.iter().any(|a| a == "install") {
-            changes.push(FileDiff {
-                path: PathBuf::from("package-lock.json"),
-                operation: crate::virtual_fs::DiffOperation::Modified,
-                old_content: None,
-                new_content: Some("(would be updated)".to_string()),
-            });
-            changes.push(FileDiff {
-                path: PathBuf::from("node_modules/"),
-                operation: crate::virtual_fs::DiffOperation::Modified,
-                old_content: None,
-                new_content: Some("(would be populated)".to_string()),
-            });
+        let level = self.permissions.get_permission(tool).map(|p| p.level);
+        if level == Some(PermissionLevel::ReadOnly) {
+            return true;
         }
         
-        // File write operations
-        if tool == "echo" || tool == "tee" || tool == "cat" {
-            for arg in args {
-                if arg.starts_with('>') {
-                    let path = arg.trim_start_matches(">").trim();
-                    changes.push(FileDiff {
-                        path: PathBuf::from(path),
-                        operation: crate::virtual_fs::DiffOperation::Modified,
-                        old_content: None,
-                        new_content: Some("(would be written)".to_string()),
-                    });
-                }
+        READ_ONLY_COMMANDS.iter().any(|(name, subcommands)| {
+            *name == tool
+                && (subcommands.is_empty()
+                    || args.first().is_some_and(|arg| subcommands.contains(&arg.as_str())))
+        })
+    }
+    
+    /// Working directory and visible environment lines for previews
+    ///
+    /// Only variables on the permission env allowlist that are actually set are
+    /// listed, with their values passed through the redaction patterns.
+    fn describe_context(&self) -> String {
+        let mut context = format!("Working directory: {}\n", self.current_dir().display());
+        let visible: Vec<String> = self
+            .permissions
+            .allowed_env()
+            .into_iter()
+            .filter_map(|name| {
+                std::env::var(name)
+                    .ok()
+                    .map(|value| format!("{}={}", name, self.redact(&value)))
+            })
+            .collect();
+        
+        if visible.is_empty() {
+            context.push_str("Environment: (none)\n");
+        } else {
+            context.push_str("Environment:\n");
+            for entry in &visible {
+                context.push_str(&format!("  {}\n", entry));
             }
         }
         
-        changes
+        context
+    }
+    
+    /// Absolute path on disk for a predicted change
+    ///
+    /// Predictions are relative to the mounted root, which is the sandbox's
+    /// working directory.
+    fn resolve_predicted(&self, path: &Path) -> PathBuf {
+        normalize_path(&self.working_dir.join(path))
+    }
+    
+    /// Predict what files would be changed by a command
+    ///
+    /// `quoted` marks the arguments that were quoted, as for `SideEffectPredictor::predict_quoted`,
+    /// and the command runs in `cwd`, relative to the root.
+    fn predict_file_changes(&self, tool: &str, args: &[String], quoted: &[bool], cwd: &Path) -> Vec<FileDiff> {
+        merge_file_changes(
+            self.predictors
+                .iter()
+                .flat_map(|p| p.predict_quoted(tool, args, quoted, cwd, &self.virtual_fs)),
+        )
+    }
+    
+    /// Checkpoint the virtual filesystem, returning an id for `rollback`
+    pub fn checkpoint(&mut self) -> String {
+        let id = Uuid::new_v4().to_string();
+        self.checkpoints.insert(id.clone(), self.virtual_fs.snapshot());
+        id
+    }
+    
+    /// Roll the virtual filesystem back to a previous checkpoint
+    pub fn rollback(&mut self, checkpoint_id: &str) -> SandboxResult<()> {
+        let snapshot = self.checkpoints
+            .get(checkpoint_id)
+            .ok_or_else(|| SandboxError::InvalidCommand("Checkpoint not found".to_string()))?;
+        
+        self.virtual_fs.restore(snapshot);
+        Ok(())
+    }
+    
+    /// Get execution history
+    pub fn history(&self) -> &[ExecutionResult] {
+        &self.execution_history
+    }
+    
+    /// Append `result` to the history, evicting the oldest entries beyond `max_history`
+    fn record_history(&mut self, result: ExecutionResult) {
+        if let Err(err) = self.write_trace(&result) {
+            warn!(id = %result.id, error = %err, "failed to write execution trace");
+        }
+        self.execution_history.push(result);
+        self.trim_history();
+    }
+    
+    fn write_trace(&mut self, result: &ExecutionResult) -> SandboxResult<()> {
+        if let Some(file) = self.trace_file.as_mut() {
+            let mut line = serde_json::to_vec(result)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+    
+    /// Append `command` to the recording if it parsed and passed its permission checks
+    fn record_accepted(&mut self, command: &str, result: &SandboxResult<ExecutionResult>) {
+        if !matches!(result, Ok(result) if result.status != ExecutionStatus::Blocked) {
+            return;
+        }
+        if let Err(err) = self.write_record(command) {
+            warn!(command, error = %err, "failed to record command");
+        }
+    }
+    
+    fn write_record(&mut self, command: &str) -> SandboxResult<()> {
+        if let Some(file) = self.record_file.as_mut() {
+            let mut line = serde_json::to_vec(command)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+    
+    fn trim_history(&mut self) {
+        if let Some(limit) = self.max_history {
+            let excess = self.execution_history.len().saturating_sub(limit);
+            self.execution_history.drain(..excess);
+        }
+    }
+    
+    /// History entries matching `f`, oldest first
+    pub fn history_filter(&self, f: impl Fn(&ExecutionResult) -> bool) -> Vec<&ExecutionResult> {
+        self.execution_history.iter().filter(|result| f(result)).collect()
+    }
+    
+    /// History entries that ran `tool`
+    pub fn history_by_tool(&self, tool: &str) -> Vec<&ExecutionResult> {
+        self.history_filter(|result| result.tool == tool)
+    }
+    
+    /// History entries with the given status
+    pub fn history_by_status(&self, status: ExecutionStatus) -> Vec<&ExecutionResult> {
+        self.history_filter(|result| result.status == status)
+    }
+    
+    /// History entries executed at or after `timestamp` (Unix seconds)
+    pub fn history_since(&self, timestamp: i64) -> Vec<&ExecutionResult> {
+        self.history_filter(|result| result.executed_at >= timestamp)
+    }
+    
+    /// Get pending approvals
+    pub fn pending_approvals(&self) -> HashMap<String, ExecutionResult> {
+        self.pending_approvals.clone()
+    }
+    
+    /// Pending approvals, oldest first; entries queued in the same second are ordered by id
+    pub fn list_pending(&self) -> Vec<&ExecutionResult> {
+        let mut pending: Vec<&ExecutionResult> = self.pending_approvals.values().collect();
+        pending.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        pending
+    }
+    
+    /// Reject every pending approval, returning the rejected results oldest first
+    ///
+    /// Each one is recorded in history as blocked, like `reject`.
+    pub fn clear_pending(&mut self) -> Vec<ExecutionResult> {
+        let ids: Vec<String> = self.list_pending().into_iter().map(|r| r.id.clone()).collect();
+        ids.iter()
+            .filter_map(|id| self.reject(id, Some("pending approvals cleared".to_string())).ok())
+            .collect()
+    }
+    
+    /// Reset the sandbox
+    pub fn reset(&mut self) {
+        self.virtual_fs.reset();
+        self.execution_history.clear();
+        self.pending_approvals.clear();
+        self.pending_stdin.clear();
+        self.cwd = PathBuf::new();
+    }
+    
+    /// Capture the session state of this sandbox
+    pub fn session_snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            id: self.id.clone(),
+            virtual_fs: self.virtual_fs.clone(),
+            permissions: self.permissions.clone(),
+            mode: self.mode,
+            execution_history: self.execution_history.clone(),
+            pending_approvals: self.pending_approvals.clone(),
+            pending_stdin: self.pending_stdin.clone(),
+            working_dir: self.working_dir.clone(),
+            cwd: self.cwd.clone(),
+            allow_all: self.allow_all,
+            strict: self.strict,
+            execution_timeout: self.execution_timeout,
+            max_output_bytes: self.max_output_bytes,
+            approval_ttl: self.approval_ttl,
+            max_history: self.max_history,
+            retry_policy: self.retry_policy.clone(),
+            redaction_patterns: self.redaction_patterns.iter().map(|(pattern, _)| pattern.clone()).collect(),
+        }
     }
     
-    /// Get execution history
-    pub fn history(&self) -> &[ExecutionResult] {
-        &self.execution_history
+    /// Rebuild a sandbox from a captured session
+    pub fn from_session(session: SessionSnapshot) -> Self {
+        let mut sandbox = Self {
+            id: session.id,
+            virtual_fs: session.virtual_fs,
+            permissions: session.permissions,
+            mode: session.mode,
+            execution_history: session.execution_history,
+            pending_approvals: session.pending_approvals,
+            pending_stdin: session.pending_stdin,
+            working_dir: session.working_dir,
+            cwd: session.cwd,
+            allow_all: session.allow_all,
+            strict: session.strict,
+            execution_timeout: session.execution_timeout,
+            max_output_bytes: session.max_output_bytes,
+            approval_ttl: session.approval_ttl,
+            max_history: session.max_history,
+            retry_policy: session.retry_policy,
+            ..Self::new()
+        };
+        
+        sandbox.clear_redaction_patterns();
+        for pattern in &session.redaction_patterns {
+            if let Err(err) = sandbox.add_redaction_pattern(pattern) {
+                warn!(error = %err, "dropping redaction pattern from session");
+            }
+        }
+        sandbox
     }
     
-    /// Get pending approvals
-    pub fn pending_approvals(&self) -> HashMap<String, ExecutionResult> {
-        self.pending_approvals.clone()
+    /// Write the session state to `path` as JSON
+    pub fn save_session(&self, path: &Path) -> SandboxResult<()> {
+        let json = serde_json::to_string_pretty(&self.session_snapshot())?;
+        std::fs::write(path, json)?;
+        Ok(())
     }
     
-    /// Reset the sandbox
-    pub fn reset(&mut self) {
-        self.virtual_fs.reset();
-        self.execution_history.clear();
-        self.pending_approvals.clear();
+    /// Restore a sandbox from a session written by `save_session`
+    pub fn load_session(path: &Path) -> SandboxResult<Self> {
+        let json = std::fs::read_to_string(path)?;
+        let session: SessionSnapshot = serde_json::from_str(&json)?;
+        Ok(Self::from_session(session))
     }
     
     /// Get sandbox status
@@ -432,7 +2340,137 @@ impl Sandbox {
             execution_count: self.execution_history.len(),
             pending_approval_count: self.pending_approvals.len(),
             working_dir: self.working_dir.clone(),
+            pending_changes: DiffEngine::summarize_file_diffs(&self.virtual_fs.get_diff()),
+        }
+    }
+    
+    /// Net file changes of the whole session, one entry per path, sorted by path
+    ///
+    /// The changes of every live command in history are folded together in
+    /// order, followed by the virtual filesystem's diff, using the rules of
+    /// `FileDiff::then`: a file added and then modified is Added, and one
+    /// added and then deleted does not appear at all.
+    pub fn cumulative_diff(&self) -> Vec<FileDiff> {
+        let mut changes = merge_file_changes(
+            self.execution_history
+                .iter()
+                .filter(|result| result.mode == ExecutionMode::Live)
+                .flat_map(|result| result.file_changes.iter().cloned())
+                .chain(self.virtual_fs.get_diff()),
+        );
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        changes
+    }
+    
+    /// Digest of the session so far, from the history and pending virtual changes
+    ///
+    /// Touched files and line counts come from `cumulative_diff`, so a file
+    /// edited by several commands counts once.
+    pub fn report(&self) -> SessionReport {
+        let history = &self.execution_history;
+        let count = |f: &dyn Fn(&ExecutionResult) -> bool| history.iter().filter(|r| f(r)).count();
+        
+        let changes = self.cumulative_diff();
+        let summary = DiffEngine::summarize_file_diffs(&changes);
+        let mut files_touched: Vec<PathBuf> = changes.into_iter().map(|change| change.path).collect();
+        files_touched.sort();
+        files_touched.dedup();
+        
+        SessionReport {
+            commands: history.len(),
+            succeeded: count(&|r| r.status == ExecutionStatus::Success),
+            failed: count(&|r| {
+                matches!(
+                    r.status,
+                    ExecutionStatus::Failed | ExecutionStatus::TimedOut | ExecutionStatus::Cancelled
+                )
+            }),
+            blocked: count(&|r| r.status == ExecutionStatus::Blocked),
+            approved: count(&|r| r.awaited_approval && r.approved),
+            rejected: count(&|r| r.awaited_approval && !r.approved),
+            pending: self.pending_approvals.len(),
+            files_touched,
+            lines_added: summary.added,
+            lines_removed: summary.deleted,
+        }
+    }
+}
+
+/// Digest of a session, as returned by `Sandbox::report`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionReport {
+    /// Entries in the execution history
+    pub commands: usize,
+    pub succeeded: usize,
+    /// Commands that failed, timed out or were cancelled
+    pub failed: usize,
+    /// Commands refused by a hook or rejected
+    pub blocked: usize,
+    pub approved: usize,
+    pub rejected: usize,
+    /// Commands still waiting for approval
+    pub pending: usize,
+    /// Files changed on disk by live commands or in the virtual filesystem, sorted
+    pub files_touched: Vec<PathBuf>,
+    pub lines_added: usize,
+    pub lines_removed: usize,
+}
+
+impl SessionReport {
+    /// The report as pretty-printed JSON
+    pub fn to_json(&self) -> SandboxResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    
+    /// The report as a Markdown document
+    pub fn to_markdown(&self) -> String {
+        let mut output = String::from("# Session report\n\n");
+        output.push_str("| Commands | Succeeded | Failed | Blocked | Approved | Rejected | Pending |\n");
+        output.push_str("|---|---|---|---|---|---|---|\n");
+        output.push_str(&format!(
+            "| {} | {} | {} | {} | {} | {} | {} |\n\n",
+            self.commands,
+            self.succeeded,
+            self.failed,
+            self.blocked,
+            self.approved,
+            self.rejected,
+            self.pending
+        ));
+        
+        output.push_str(&format!(
+            "## Files touched ({})\n\n**+{} -{}** lines\n\n",
+            self.files_touched.len(),
+            self.lines_added,
+            self.lines_removed
+        ));
+        for path in &self.files_touched {
+            output.push_str(&format!("- `{}`\n", path.display()));
+        }
+        output
+    }
+}
+
+impl std::fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Commands: {}", self.commands)?;
+        writeln!(f, "  Succeeded: {}", self.succeeded)?;
+        writeln!(f, "  Failed: {}", self.failed)?;
+        writeln!(f, "  Blocked: {}", self.blocked)?;
+        writeln!(f, "  Approved: {}", self.approved)?;
+        writeln!(f, "  Rejected: {}", self.rejected)?;
+        writeln!(f, "  Pending: {}", self.pending)?;
+        writeln!(
+            f,
+            "Files touched: {} (+{} -{} lines)",
+            self.files_touched.len(),
+            self.lines_added,
+            self.lines_removed
+        )?;
+        for path in &self.files_touched {
+            writeln!(f, "  {}", path.display())?;
         }
+        Ok(())
     }
 }
 
@@ -445,6 +2483,139 @@ pub struct SandboxStatus {
     pub execution_count: usize,
     pub pending_approval_count: usize,
     pub working_dir: PathBuf,
+    /// Virtual filesystem changes not yet applied or committed
+    #[serde(default)]
+    pub pending_changes: DiffSummary,
+}
+
+/// Predicted and actual file changes of a live run, from `Sandbox::execute_verified`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedExecution {
+    pub result: ExecutionResult,
+    /// What Diff mode predicted before the command ran
+    pub predicted: Vec<FileDiff>,
+    /// What changed on disk, as reported in `result.file_changes`
+    pub actual: Vec<FileDiff>,
+    /// Paths whose predicted and actual operations differ, sorted
+    pub mismatches: Vec<PathBuf>,
+    pub matched: bool,
+}
+
+/// One stage of a command line, as returned by `Sandbox::parse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedCommand {
+    pub tool: String,
+    /// Arguments without redirections or here-documents
+    pub args: Vec<String>,
+    pub redirections: Vec<Redirection>,
+    /// Leading `NAME=value` words, in order
+    pub env_assignments: Vec<(String, String)>,
+    /// Body of a `<<` here-document fed to the command's stdin
+    pub heredoc: Option<String>,
+    /// How this stage connects to the next one; `None` for the last stage
+    pub operator: Option<ChainOperator>,
+}
+
+impl ParsedCommand {
+    fn from_stage(stage: shell_words::Stage) -> SandboxResult<Self> {
+        let (words, redirections) = split_redirections(&stage.words, &stage.quoted);
+        let mut words = words.into_iter();
+        
+        let tool = words.next().ok_or_else(|| {
+            SandboxError::InvalidCommand("No command besides redirections".to_string())
+        })?;
+        
+        Ok(Self {
+            tool: tool.to_string(),
+            args: words.map(str::to_string).collect(),
+            redirections,
+            env_assignments: stage.env,
+            heredoc: heredoc_body(&stage.words).map(str::to_string),
+            operator: stage.operator,
+        })
+    }
+}
+
+/// `args` without their here-document, and the here-document's body
+fn split_heredoc(args: &[String]) -> (Vec<String>, Option<&str>) {
+    let Some(at) = args.iter().position(|arg| arg == HEREDOC) else {
+        return (args.to_vec(), None);
+    };
+    
+    let mut rest = args[..at].to_vec();
+    rest.extend(args.iter().skip(at + 2).cloned());
+    (rest, args.get(at + 1).map(String::as_str))
+}
+
+/// Name and value of a `NAME=value` word
+fn env_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric());
+    valid.then_some((name, value))
+}
+
+/// Whether a command would be allowed, as reported by `Sandbox::check`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckOutcome {
+    pub tool: String,
+    pub args: Vec<String>,
+    /// Level the tool runs with; `Denied` if it may not run at all
+    pub permission_level: PermissionLevel,
+    pub requires_approval: bool,
+    /// Why the command would be refused, e.g. a disallowed argument or path
+    pub violations: Vec<String>,
+    /// One check per stage of a chain; empty for a single command
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<CheckOutcome>,
+}
+
+impl CheckOutcome {
+    /// Whether the command would run, possibly after approval
+    pub fn is_allowed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Serializable state of a sandbox session, as written by `Sandbox::save_session`
+///
+/// Checkpoints, registered predictors, hooks and backends are not part of a
+/// session; a loaded sandbox starts with the defaults. Sessions saved before
+/// redaction patterns were stored load with the default patterns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub id: String,
+    pub virtual_fs: VirtualFilesystem,
+    pub permissions: PermissionGate,
+    pub mode: ExecutionMode,
+    pub execution_history: Vec<ExecutionResult>,
+    pub pending_approvals: HashMap<String, ExecutionResult>,
+    #[serde(default)]
+    pub pending_stdin: HashMap<String, Vec<u8>>,
+    pub working_dir: PathBuf,
+    #[serde(default)]
+    pub cwd: PathBuf,
+    pub allow_all: bool,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub execution_timeout: Option<Duration>,
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    #[serde(default)]
+    pub approval_ttl: Option<Duration>,
+    #[serde(default)]
+    pub max_history: Option<usize>,
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
+    /// Sources of the redaction patterns, recompiled on load
+    #[serde(default = "default_redaction_patterns")]
+    pub redaction_patterns: Vec<String>,
+}
+
+fn default_redaction_patterns() -> Vec<String> {
+    DEFAULT_REDACTIONS.iter().map(|p| p.to_string()).collect()
 }
 
 impl Default for Sandbox {
@@ -453,55 +2624,709 @@ impl Default for Sandbox {
     }
 }
 
+/// Chainable configuration for a `Sandbox`, created by `Sandbox::builder`
+#[derive(Debug, Default)]
+pub struct SandboxBuilder {
+    mode: Option<ExecutionMode>,
+    working_dir: Option<PathBuf>,
+    permissions: Option<PermissionGate>,
+    timeout: Option<Duration>,
+    max_output_bytes: Option<usize>,
+    allow_all: bool,
+    strict: bool,
+}
+
+impl SandboxBuilder {
+    /// Execution mode; defaults to `Live`
+    pub fn mode(mut self, mode: ExecutionMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+    
+    /// Directory to mount and run commands in; defaults to the process's current directory
+    pub fn working_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.working_dir = Some(path.into());
+        self
+    }
+    
+    /// Permission gate to use instead of `PermissionGate::default_permissions`
+    pub fn permissions(mut self, permissions: PermissionGate) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+    
+    /// Kill live commands that run longer than `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    
+    /// Keep at most `limit` bytes of each output stream
+    pub fn max_output_bytes(mut self, limit: usize) -> Self {
+        self.max_output_bytes = Some(limit);
+        self
+    }
+    
+    /// Bypass permission checks
+    pub fn allow_all(mut self, allow_all: bool) -> Self {
+        self.allow_all = allow_all;
+        self
+    }
+    
+    /// Refuse to build a sandbox whose permission checks can be bypassed
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    
+    /// Validate the options and create the sandbox, mounting its working directory
+    pub fn build(self) -> SandboxResult<Sandbox> {
+        if self.strict && self.allow_all {
+            return Err(SandboxError::InvalidConfig(
+                "allow_all bypasses permission checks and cannot be combined with strict".to_string(),
+            ));
+        }
+        
+        let mut sandbox = match self.working_dir {
+            Some(path) => Sandbox::with_working_dir(path)?,
+            None => Sandbox::new(),
+        };
+        
+        if let Some(permissions) = self.permissions {
+            sandbox.permissions = permissions;
+        }
+        if let Some(mode) = self.mode {
+            sandbox.set_mode(mode);
+        }
+        sandbox.allow_all = self.allow_all;
+        sandbox.strict = self.strict;
+        sandbox.set_execution_timeout(self.timeout);
+        sandbox.set_max_output_bytes(self.max_output_bytes);
+        
+        Ok(sandbox)
+    }
+}
+
+/// Outcome of the permission check for a command
+enum Authorization {
+    Granted(PermissionLevel),
+    /// The command was queued for approval
+    Pending(Box<ExecutionResult>),
+}
+
+/// Outcome of the permission check for every stage of a chain
+enum ChainPlan {
+    /// One not-yet-run placeholder result per stage
+    Ready(Vec<ExecutionResult>),
+    /// The chain was queued for approval
+    Pending(Box<ExecutionResult>),
+}
+
+/// A live command's result, plus the error if it could not be started at all
+struct LiveRun {
+    result: ExecutionResult,
+    /// `CommandNotFound` or `SpawnFailed`; other backend errors are only reported in the result
+    spawn_error: Option<SandboxError>,
+    /// Captured stdout before redaction or decoding, which is what a pipe passes on
+    stdout: Vec<u8>,
+}
+
+impl LiveRun {
+    fn new<T>(result: ExecutionResult, output: SandboxResult<T>) -> Self {
+        let spawn_error = output.err().filter(|e| {
+            matches!(e, SandboxError::CommandNotFound(_) | SandboxError::SpawnFailed { .. })
+        });
+        Self {
+            result,
+            spawn_error,
+            stdout: Vec::new(),
+        }
+    }
+    
+    fn from_output(result: ExecutionResult, output: SandboxResult<BackendOutput>) -> Self {
+        let stdout = output.as_ref().map(|o| o.stdout.bytes.clone()).unwrap_or_default();
+        Self {
+            stdout,
+            ..Self::new(result, output)
+        }
+    }
+}
+
+/// Whether the stage after `previous` runs, given the last stage that did run
+fn should_run(previous: Option<ChainOperator>, previous_ran: bool, last_ran: Option<&ExecutionResult>) -> bool {
+    let succeeded = last_ran.is_some_and(|r| r.status == ExecutionStatus::Success);
+    match previous {
+        None | Some(ChainOperator::Sequence) => true,
+        Some(ChainOperator::Pipe) => previous_ran,
+        Some(ChainOperator::And) => succeeded,
+        Some(ChainOperator::Or) => !succeeded,
+    }
+}
+
+/// Split a command line into a non-empty list of stages, each with at least one word
+///
+/// Only environment variables allowed by `permissions` are expanded; others are
+/// left as written so permission checks see exactly what was asked for.
+fn parse_command(command: &str, permissions: &PermissionGate) -> SandboxResult<Vec<shell_words::Stage>> {
+    let lookup = |name: &str| {
+        if permissions.is_env_allowed(name) {
+            std::env::var(name).ok()
+        } else {
+            None
+        }
+    };
+    let mut stages = shell_words::split_chain(command, lookup)
+        .map_err(|e| SandboxError::InvalidCommand(e.to_string()))?;
+    
+    if stages.is_empty() {
+        return Err(SandboxError::InvalidCommand("Empty command".to_string()));
+    }
+    
+    // `NAME=value cmd` runs `cmd`, so that is the tool every check sees
+    for stage in &mut stages {
+        let assignments = stage.words.iter().take_while(|word| env_assignment(word).is_some()).count();
+        if assignments == stage.words.len() {
+            return Err(SandboxError::InvalidCommand(
+                "No command after environment assignments".to_string(),
+            ));
+        }
+        stage.quoted.drain(..assignments);
+        stage.env = stage
+            .words
+            .drain(..assignments)
+            .filter_map(|word| env_assignment(&word).map(|(name, value)| (name.to_string(), value.to_string())))
+            .collect();
+    }
+    
+    Ok(stages)
+}
+
+/// Name of the built-in that changes the tracked working directory
+const CD_BUILTIN: &str = "cd";
+
+/// Commands without side effects whatever their tool's level, with the
+/// subcommands that qualify; an empty list means every invocation does
+const READ_ONLY_COMMANDS: &[(&str, &[&str])] = &[
+    ("ls", &[]),
+    ("cat", &[]),
+    ("pwd", &[]),
+    ("echo", &[]),
+    ("head", &[]),
+    ("tail", &[]),
+    ("wc", &[]),
+    ("grep", &[]),
+    ("which", &[]),
+    ("git", &["status", "diff", "log", "show"]),
+];
+
+/// Replacement for secrets found in captured output
+pub const REDACTED: &str = "***REDACTED***";
+
+/// Token formats redacted from captured output unless cleared
+const DEFAULT_REDACTIONS: &[&str] = &[
+    // AWS access key ids and secret keys
+    r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+    r"(?i)aws_secret_access_key\s*[=:]\s*[A-Za-z0-9/+=]{40}",
+    // Bearer tokens, e.g. in an `Authorization` header
+    r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*",
+    // GitHub personal access and app tokens
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}\b",
+];
+
+/// Log an error when `result` reports a different argv than the backend started
+///
+/// The command has already run, so the result is left as is; a mismatch
+/// points at a parsing or quoting bug in how `resolved_argv` is built.
+fn check_resolved_argv(result: &ExecutionResult, output: &SandboxResult<BackendOutput>) {
+    if let Ok(BackendOutput { argv: Some(argv), .. }) = output {
+        if result.resolved_argv != *argv {
+            error!(
+                id = %result.id,
+                reported = ?result.resolved_argv,
+                started = ?argv,
+                "reported argv differs from the one run"
+            );
+        }
+    }
+}
+
+/// `path` relative to `root`, or `None` if it leads outside it
+///
+/// `path` is normalized and either relative to `root` or absolute.
+fn contained_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = if path.is_absolute() {
+        path.strip_prefix(root).ok()?.to_path_buf()
+    } else {
+        path.to_path_buf()
+    };
+    match relative.components().next() {
+        Some(Component::ParentDir) => None,
+        _ => Some(relative),
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem
+///
+/// Leading `..` components of a relative path are kept.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Re-read the mounted tree so a later `changes_since` can compare against it
+fn tree_baseline(mut vfs: VirtualFilesystem) -> Option<VirtualFilesystem> {
+    if let Err(err) = vfs.rescan() {
+        warn!(error = %err, "failed to scan mounts before a live command; its file changes are not reported");
+        return None;
+    }
+    vfs.commit();
+    Some(vfs)
+}
+
+/// Files changed on disk since `baseline` was taken
+fn changes_since(baseline: Option<VirtualFilesystem>) -> Vec<FileDiff> {
+    let Some(mut vfs) = baseline else {
+        return Vec::new();
+    };
+    
+    match vfs.rescan() {
+        Ok(()) => vfs.get_diff(),
+        Err(err) => {
+            warn!(error = %err, "failed to scan mounts after a live command; its file changes are not reported");
+            Vec::new()
+        }
+    }
+}
+
+/// Operation `changes` records for `path`, if any
+fn operation_at<'a>(changes: &'a [FileDiff], path: &Path) -> Option<&'a DiffOperation> {
+    changes
+        .iter()
+        .find(|change| change.path == path)
+        .map(|change| &change.operation)
+}
+
+/// Line and file counts across `file_changes`, if there are any
+fn summarize_changes(file_changes: &[FileDiff]) -> Option<DiffSummary> {
+    if file_changes.is_empty() {
+        return None;
+    }
+    
+    Some(DiffEngine::summarize_file_diffs(file_changes))
+}
+
+/// Serde helpers storing optional raw bytes as standard padded base64
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_str(&encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(text) => decode(&text).map(Some).ok_or_else(|| serde::de::Error::custom("invalid base64")),
+            None => Ok(None),
+        }
+    }
+    
+    fn encode(bytes: &[u8]) -> String {
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+    
+    fn decode(text: &str) -> Option<Vec<u8>> {
+        let text = text.trim_end_matches('=').as_bytes();
+        let mut out = Vec::with_capacity(text.len() * 3 / 4);
+        for chunk in text.chunks(4) {
+            if chunk.len() == 1 {
+                return None;
+            }
+            let mut n = 0u32;
+            for (i, &c) in chunk.iter().enumerate() {
+                let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+                n |= value << (18 - 6 * i);
+            }
+            for i in 0..chunk.len() - 1 {
+                out.push((n >> (16 - 8 * i)) as u8);
+            }
+        }
+        Some(out)
+    }
+}
+
 // Simple shell words parser
 mod shell_words {
+    use super::ChainOperator;
+    use crate::predictor::HEREDOC;
     use std::borrow::Cow;
+    use std::iter::Peekable;
+    use std::str::Chars;
+    
+    /// One command of a chain and the operator that follows it
+    pub struct Stage {
+        pub words: Vec<String>,
+        /// Whether each word was quoted or escaped, which keeps it from being read as a redirection
+        pub quoted: Vec<bool>,
+        /// Leading `NAME=value` words, moved out of `words` by `parse_command`
+        pub env: Vec<(String, String)>,
+        pub operator: Option<ChainOperator>,
+    }
     
-    pub fn split(input: &str) -> Result<Vec<String>, Cow<'static, str>> {
+    /// Split a command line into stages of words, expanding `$VAR` and `${VAR}` via `lookup`
+    ///
+    /// Variables `lookup` returns `None` for are kept verbatim. Single quotes are
+    /// literal, backslash escapes follow POSIX rules inside and outside double
+    /// quotes, and an unquoted `#` at the start of a word begins a comment.
+    /// Unquoted `|`, `&&`, `||` and `;` separate stages; a lone `&` is kept as
+    /// part of a word so redirections like `2>&1` survive.
+    ///
+    /// A `<<EOF` here-document becomes a `<<` word followed by its body, read
+    /// from the lines after the command. Its body is expanded unless the
+    /// delimiter is quoted, and `<<-` strips leading tabs.
+    pub fn split_chain(
+        input: &str,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<Stage>, Cow<'static, str>> {
+        let mut stages = Vec::new();
         let mut words = Vec::new();
+        let mut quoted = Vec::new();
         let mut current = String::new();
-        let mut in_quotes = false;
-        let mut quote_char = ' ';
-        let mut escaped = false;
-        
-        for c in input.chars() {
-            if escaped {
-                current.push(c);
-                escaped = false;
-                continue;
-            }
-            
+        // Distinguishes an empty quoted word ("") from no word at all
+        let mut in_word = false;
+        let mut current_quoted = false;
+        // Here-documents whose bodies start on the next line, with the stage and word they fill
+        let mut heredocs: Vec<(Heredoc, usize, usize)> = Vec::new();
+        let mut chars = input.chars().peekable();
+        
+        while let Some(c) = chars.next() {
             match c {
-                '\\' if !in_quotes => {
-                    escaped = true;
+                '\\' => {
+                    match chars.next() {
+                        // Line continuation
+                        Some('\n') => {}
+                        Some(escaped) => current.push(escaped),
+                        None => {}
+                    }
+                    in_word = true;
+                    current_quoted = true;
                 }
-                '\'' | '"' if in_quotes && c == quote_char => {
-                    in_quotes = false;
+                '\'' => {
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => current.push(c),
+                            None => return Err("Unclosed quote".into()),
+                        }
+                    }
+                    in_word = true;
+                    current_quoted = true;
+                }
+                '"' => {
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\' | '$' | '`')) => current.push(c),
+                                Some('\n') => {}
+                                Some(c) => {
+                                    current.push('\\');
+                                    current.push(c);
+                                }
+                                None => return Err("Unclosed quote".into()),
+                            },
+                            Some('$') => expand_variable(&mut chars, &mut current, &lookup),
+                            Some(c) => current.push(c),
+                            None => return Err("Unclosed quote".into()),
+                        }
+                    }
+                    in_word = true;
+                    current_quoted = true;
+                }
+                '$' => {
+                    expand_variable(&mut chars, &mut current, &lookup);
+                    in_word = true;
+                }
+                '#' if !in_word => {
+                    // Comment runs to the end of the line
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                    read_heredocs(&mut chars, &mut heredocs, &mut stages, &mut words, &lookup)?;
+                }
+                '<' if chars.peek() == Some(&'<') => {
+                    chars.next();
+                    // A `<<<` here-string is left to the command as a word
+                    if chars.next_if_eq(&'<').is_some() {
+                        current.push_str("<<<");
+                        in_word = true;
+                        continue;
+                    }
+                    if in_word {
+                        words.push(std::mem::take(&mut current));
+                        quoted.push(std::mem::take(&mut current_quoted));
+                        in_word = false;
+                    }
+                    let heredoc = heredoc_delimiter(&mut chars)?;
+                    words.push(HEREDOC.to_string());
+                    words.push(String::new());
+                    quoted.extend([false, false]);
+                    heredocs.push((heredoc, stages.len(), words.len() - 1));
                 }
-                '\'' | '"' if !in_quotes => {
-                    in_quotes = true;
-                    quote_char = c;
+                ' ' | '\t' | '\n' | '\r' => {
+                    if in_word {
+                        words.push(std::mem::take(&mut current));
+                        quoted.push(std::mem::take(&mut current_quoted));
+                        in_word = false;
+                    }
+                    if c == '\n' {
+                        read_heredocs(&mut chars, &mut heredocs, &mut stages, &mut words, &lookup)?;
+                    }
                 }
-                ' ' | '\t' | '\n' | '\r' if !in_quotes => {
-                    if !current.is_empty() {
-                        words.push(current.clone());
-                        current.clear();
+                '|' | ';' | '&' if c != '&' || chars.peek() == Some(&'&') => {
+                    let operator = match c {
+                        '|' if chars.next_if_eq(&'|').is_some() => ChainOperator::Or,
+                        '|' => ChainOperator::Pipe,
+                        '&' => {
+                            chars.next();
+                            ChainOperator::And
+                        }
+                        _ => ChainOperator::Sequence,
+                    };
+                    if in_word {
+                        words.push(std::mem::take(&mut current));
+                        quoted.push(std::mem::take(&mut current_quoted));
+                        in_word = false;
+                    }
+                    if words.is_empty() {
+                        return Err("Empty command in chain".into());
                     }
+                    stages.push(Stage {
+                        words: std::mem::take(&mut words),
+                        quoted: std::mem::take(&mut quoted),
+                        env: Vec::new(),
+                        operator: Some(operator),
+                    });
                 }
                 _ => {
                     current.push(c);
+                    in_word = true;
                 }
             }
         }
         
-        if !current.is_empty() {
+        if !heredocs.is_empty() {
+            return Err("Unterminated here-document".into());
+        }
+        
+        if in_word {
             words.push(current);
+            quoted.push(current_quoted);
+        }
+        
+        if !words.is_empty() {
+            stages.push(Stage {
+                words,
+                quoted,
+                env: Vec::new(),
+                operator: None,
+            });
+        } else if let Some(last) = stages.last_mut() {
+            // A trailing `;` just ends the line; any other operator needs a command after it
+            if last.operator != Some(ChainOperator::Sequence) {
+                return Err("Empty command in chain".into());
+            }
+            last.operator = None;
+        }
+        
+        Ok(stages)
+    }
+    
+    /// A `<<` redirection whose body has not been read yet
+    struct Heredoc {
+        delimiter: String,
+        /// Quoting any part of the delimiter turns off expansion in the body
+        quoted: bool,
+        /// `<<-` strips leading tabs from body lines and the delimiter line
+        strip_tabs: bool,
+    }
+    
+    /// Parse the delimiter word following `<<`
+    fn heredoc_delimiter(chars: &mut Peekable<Chars<'_>>) -> Result<Heredoc, Cow<'static, str>> {
+        let strip_tabs = chars.next_if_eq(&'-').is_some();
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        
+        let mut delimiter = String::new();
+        let mut quoted = false;
+        while let Some(&c) = chars.peek() {
+            match c {
+                '\'' | '"' => {
+                    chars.next();
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some(q) if q == c => break,
+                            Some(q) => delimiter.push(q),
+                            None => return Err("Unclosed quote".into()),
+                        }
+                    }
+                }
+                '\\' => {
+                    chars.next();
+                    quoted = true;
+                    delimiter.extend(chars.next());
+                }
+                ' ' | '\t' | '\n' | '\r' | ';' | '|' | '&' | '<' | '>' => break,
+                _ => {
+                    chars.next();
+                    delimiter.push(c);
+                }
+            }
+        }
+        
+        if delimiter.is_empty() {
+            return Err("Missing here-document delimiter".into());
+        }
+        Ok(Heredoc {
+            delimiter,
+            quoted,
+            strip_tabs,
+        })
+    }
+    
+    /// Read the bodies of `pending` here-documents, in order, from the following lines
+    fn read_heredocs(
+        chars: &mut Peekable<Chars<'_>>,
+        pending: &mut Vec<(Heredoc, usize, usize)>,
+        stages: &mut [Stage],
+        words: &mut [String],
+        lookup: &impl Fn(&str) -> Option<String>,
+    ) -> Result<(), Cow<'static, str>> {
+        for (heredoc, stage, word) in pending.drain(..) {
+            let body = heredoc_body(chars, &heredoc, lookup)?;
+            let target = match stages.get_mut(stage) {
+                Some(stage) => &mut stage.words,
+                None => &mut *words,
+            };
+            target[word] = body;
+        }
+        Ok(())
+    }
+    
+    /// Read lines up to the delimiter line, expanding variables unless the delimiter was quoted
+    fn heredoc_body(
+        chars: &mut Peekable<Chars<'_>>,
+        heredoc: &Heredoc,
+        lookup: &impl Fn(&str) -> Option<String>,
+    ) -> Result<String, Cow<'static, str>> {
+        let mut body = String::new();
+        loop {
+            if chars.peek().is_none() {
+                return Err("Unterminated here-document".into());
+            }
+            let line: String = chars.by_ref().take_while(|c| *c != '\n').collect();
+            let line = if heredoc.strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line.as_str()
+            };
+            if line == heredoc.delimiter {
+                return Ok(body);
+            }
+            
+            if heredoc.quoted {
+                body.push_str(line);
+            } else {
+                let mut line = line.chars().peekable();
+                while let Some(c) = line.next() {
+                    match c {
+                        '\\' => match line.next_if(|c| matches!(c, '$' | '\\' | '`')) {
+                            Some(escaped) => body.push(escaped),
+                            None => body.push('\\'),
+                        },
+                        '$' => expand_variable(&mut line, &mut body, lookup),
+                        c => body.push(c),
+                    }
+                }
+            }
+            body.push('\n');
+        }
+    }
+    
+    /// Expand the variable following a `$`, keeping the text as written if it is unknown
+    fn expand_variable(
+        chars: &mut Peekable<Chars<'_>>,
+        output: &mut String,
+        lookup: &impl Fn(&str) -> Option<String>,
+    ) {
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        }
+        
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            let valid = c == '_' || c.is_ascii_alphabetic() || (!name.is_empty() && c.is_ascii_digit());
+            if !valid {
+                break;
+            }
+            name.push(c);
+            chars.next();
         }
         
-        if in_quotes {
-            return Err("Unclosed quote".into());
+        let closed = braced && chars.peek() == Some(&'}');
+        if closed {
+            chars.next();
         }
         
-        Ok(words)
+        let value = if name.is_empty() || (braced && !closed) {
+            None
+        } else {
+            lookup(&name)
+        };
+        
+        match value {
+            Some(value) => output.push_str(&value),
+            None => {
+                output.push('$');
+                if braced {
+                    output.push('{');
+                }
+                output.push_str(&name);
+                if closed {
+                    output.push('}');
+                }
+            }
+        }
     }
 }
@@ -1,19 +1,35 @@
+use crate::diff_engine::{DiffEngine, PathMasker, UnifiedDiff};
 use crate::error::{SandboxError, SandboxResult};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::debug;
 
 /// Represents a file in the virtual filesystem
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VirtualFile {
     pub path: PathBuf,
+    /// In-memory content; empty for stubs and lazy files, use `load` to read those
     pub content: Vec<u8>,
     pub permissions: u16,
     pub is_executable: bool,
     pub hash: String,
     pub created_at: i64,
     pub modified_at: i64,
+    /// Size of the file in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// Metadata-only entry whose content was too large to load
+    #[serde(default)]
+    pub is_stub: bool,
+    /// File on disk the content is read from on demand, for lazily mounted files
+    ///
+    /// Cleared once the content is written and held in memory.
+    #[serde(default)]
+    pub source: Option<PathBuf>,
 }
 
 impl VirtualFile {
@@ -23,30 +39,126 @@ impl VirtualFile {
         
         Self {
             path,
+            size: content.len() as u64,
             content,
             permissions: 0o644,
             is_executable: false,
             hash,
             created_at: now,
             modified_at: now,
+            is_stub: false,
+            source: None,
         }
     }
     
+    /// Create a metadata-only entry for a file whose content is not held in memory
+    pub fn stub(path: PathBuf, size: u64, hash: String) -> Self {
+        let mut file = Self::new(path, Vec::new());
+        file.size = size;
+        file.hash = hash;
+        file.is_stub = true;
+        file
+    }
+    
+    /// Create a metadata-only entry whose content is read from `source` when needed
+    pub fn lazy(path: PathBuf, source: PathBuf, size: u64, hash: String) -> Self {
+        let mut file = Self::new(path, Vec::new());
+        file.size = size;
+        file.hash = hash;
+        file.source = Some(source);
+        file
+    }
+    
+    /// Whether the content still lives on disk rather than in memory
+    pub fn is_lazy(&self) -> bool {
+        self.source.is_some()
+    }
+    
+    /// The file's content, read from its source on disk if it is lazy
+    ///
+    /// Fails for stubs, and for lazy files whose source can no longer be read or
+    /// changed on disk since it was mounted.
+    pub fn load(&self) -> SandboxResult<Cow<'_, [u8]>> {
+        if self.is_stub {
+            return Err(SandboxError::FileSystemError(format!(
+                "File is too large to load: {} ({} bytes)",
+                self.path.display(),
+                self.size
+            )));
+        }
+        
+        let Some(source) = &self.source else {
+            return Ok(Cow::Borrowed(&self.content));
+        };
+        
+        let content = std::fs::read(source)?;
+        if Self::compute_hash(&content) != self.hash {
+            return Err(SandboxError::FileSystemError(format!(
+                "File changed on disk since it was mounted: {}",
+                self.path.display()
+            )));
+        }
+        Ok(Cow::Owned(content))
+    }
+    
     pub fn new_executable(path: PathBuf, content: Vec<u8>) -> Self {
         let mut file = Self::new(path, content);
-        file.is_executable = true;
-        file.permissions = 0o755;
+        file.set_mode(0o755);
         file
     }
     
+    /// Permission bits the file is written with, including execute bits for `is_executable`
+    pub fn mode(&self) -> u32 {
+        let mut mode = self.permissions as u32;
+        if self.is_executable {
+            mode |= 0o111;
+        }
+        mode
+    }
+    
+    /// Set the permission bits, keeping `is_executable` in sync
+    pub fn set_mode(&mut self, mode: u16) {
+        self.permissions = mode & 0o7777;
+        self.is_executable = mode & 0o111 != 0;
+    }
+    
     fn compute_hash(content: &[u8]) -> String {
         let mut hasher = Sha256::new();
         hasher.update(content);
         hex::encode(hasher.finalize())
     }
     
+    /// Hash a file on disk without reading it into memory at once
+    fn compute_hash_streaming(path: &Path) -> SandboxResult<String> {
+        use std::io::Read;
+        
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 8192];
+        
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        
+        Ok(hex::encode(hasher.finalize()))
+    }
+    
+    /// Whether the content looks binary (contains NUL bytes or is not valid UTF-8)
+    ///
+    /// Fails where `load` does, since the content has to be read to tell.
+    pub fn is_binary(&self) -> SandboxResult<bool> {
+        Ok(is_binary_content(&self.load()?))
+    }
+    
     pub fn update_content(&mut self, content: Vec<u8>) {
         self.hash = Self::compute_hash(&content);
+        self.size = content.len() as u64;
+        self.is_stub = false;
+        self.source = None;
         self.content = content;
         self.modified_at = chrono::Utc::now().timestamp();
     }
@@ -55,9 +167,45 @@ impl VirtualFile {
 /// Virtual filesystem with diff tracking
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct VirtualFilesystem {
-    files: HashMap<PathBuf, VirtualFile>,
-    deleted_files: HashMap<PathBuf, VirtualFile>,
+    files: HashMap<PathBuf, Arc<VirtualFile>>,
+    deleted_files: HashMap<PathBuf, Arc<VirtualFile>>,
+    original_files: HashMap<PathBuf, Arc<VirtualFile>>,
+    renamed_files: HashMap<PathBuf, PathBuf>,
+    /// Paths of current files, keyed by content hash
+    hash_index: HashMap<String, BTreeSet<PathBuf>>,
+    directories: HashSet<PathBuf>,
+    original_dirs: HashSet<PathBuf>,
+    /// Symbolic links, mapped to their targets
+    symlinks: HashMap<PathBuf, PathBuf>,
+    /// Links created with `symlink` since the last commit
+    #[serde(default)]
+    created_symlinks: HashSet<PathBuf>,
+    /// Targets of existing links replaced with `symlink_force`, restored by `reset`
+    #[serde(default)]
+    original_symlinks: HashMap<PathBuf, PathBuf>,
     mount_points: Vec<PathBuf>,
+    /// Mount root each mounted file or directory came from, keyed by its original path
+    origins: HashMap<PathBuf, PathBuf>,
+    /// Entries of earlier mounts hidden by a later mount of the same path, oldest first
+    #[serde(default)]
+    shadowed: HashMap<PathBuf, Vec<MountLayer>>,
+    respect_gitignore: bool,
+    max_file_size: Option<u64>,
+    max_total_size: Option<u64>,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    /// Mount roots whose files are read from disk on demand
+    #[serde(default)]
+    lazy_mounts: HashSet<PathBuf>,
+    /// Ignore globs each mount root was mounted with, so `rescan` skips the same paths
+    #[serde(default)]
+    mount_ignores: HashMap<PathBuf, Vec<String>>,
+    /// Paths differing only in case name the same file; keys are stored lowercased
+    #[serde(default)]
+    case_insensitive: bool,
+    /// Directories keyed by lowercased path, in the case they were created or mounted with
+    #[serde(default)]
+    dir_names: HashMap<PathBuf, PathBuf>,
 }
 
 impl VirtualFilesystem {
@@ -65,8 +213,132 @@ impl VirtualFilesystem {
         Self {
             files: HashMap::new(),
             deleted_files: HashMap::new(),
+            original_files: HashMap::new(),
+            renamed_files: HashMap::new(),
+            hash_index: HashMap::new(),
+            directories: HashSet::new(),
+            original_dirs: HashSet::new(),
+            symlinks: HashMap::new(),
+            created_symlinks: HashSet::new(),
+            original_symlinks: HashMap::new(),
             mount_points: Vec::new(),
+            origins: HashMap::new(),
+            shadowed: HashMap::new(),
+            respect_gitignore: false,
+            max_file_size: None,
+            max_total_size: None,
+            max_depth: None,
+            follow_symlinks: false,
+            lazy_mounts: HashSet::new(),
+            mount_ignores: HashMap::new(),
+            case_insensitive: false,
+            dir_names: HashMap::new(),
+        }
+    }
+    
+    /// Files larger than this are mounted as metadata-only stubs
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+    
+    /// Abort mounting once the loaded content would exceed this many bytes
+    pub fn with_max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = Some(bytes);
+        self
+    }
+    
+    /// Limit how many directory levels below the mount root are walked
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+    
+    /// Follow symbolic links while mounting instead of recording them as links
+    pub fn with_follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+    
+    /// Treat paths that differ only in case as the same file, as on macOS and Windows
+    ///
+    /// Lookups ignore case while each file keeps the case it was first written or
+    /// mounted with. Set this before mounting or writing anything.
+    pub fn with_case_insensitive(mut self, enabled: bool) -> Self {
+        self.case_insensitive = enabled;
+        self
+    }
+    
+    /// Whether paths that differ only in case name the same file
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+    
+    /// Map key for `path`, lowercased when the filesystem is case-insensitive
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if self.case_insensitive {
+            Cow::Owned(PathBuf::from(path.to_string_lossy().to_lowercase()))
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+    
+    /// Directory `key` in the case it was created or mounted with
+    fn dir_name<'a>(&'a self, key: &'a PathBuf) -> &'a PathBuf {
+        self.dir_names.get(key).unwrap_or(key)
+    }
+    
+    /// `path` with its parent directory spelled the way that directory was named
+    fn display_path(&self, path: &Path) -> PathBuf {
+        let (Some(parent), Some(leaf)) = (path.parent(), path.file_name()) else {
+            return path.to_path_buf();
+        };
+        match self.dir_names.get(self.key(parent).as_ref()) {
+            Some(parent) => parent.join(leaf),
+            None => path.to_path_buf(),
+        }
+    }
+    
+    /// Record the case `path` was given in for a directory that is being created
+    fn name_dir(&mut self, key: &Path, path: &Path) {
+        if self.case_insensitive && !self.directories.contains(key) {
+            let name = self.display_path(path);
+            self.dir_names.insert(key.to_path_buf(), name);
+        }
+    }
+    
+    /// Refuse a mount whose entries differ only in case from one another
+    ///
+    /// A case-insensitive filesystem could only keep one of them, so the
+    /// others would silently disappear.
+    fn check_case_collisions<'a>(&self, root: &Path, paths: impl Iterator<Item = &'a Path>) -> SandboxResult<()> {
+        if !self.case_insensitive {
+            return Ok(());
         }
+        
+        let mut seen: HashMap<PathBuf, &Path> = HashMap::new();
+        for path in paths {
+            if let Some(other) = seen.insert(self.key(path).into_owned(), path) {
+                return Err(SandboxError::FileSystemError(format!(
+                    "Cannot mount {} case-insensitively: {} and {} differ only in case",
+                    root.display(),
+                    other.display(),
+                    path.display()
+                )));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Honor the mounted root's `.gitignore` (and skip `.git`) when mounting
+    pub fn set_respect_gitignore(&mut self, enabled: bool) {
+        self.respect_gitignore = enabled;
+    }
+    
+    /// Whether mounts honor `.gitignore`
+    pub fn respects_gitignore(&self) -> bool {
+        self.respect_gitignore
     }
     
     /// Create a new virtual filesystem from a real directory
@@ -77,7 +349,30 @@ impl VirtualFilesystem {
     }
     
     /// Mount a real directory into the virtual filesystem
+    ///
+    /// Mounts are layered: a path present in several mounted directories shows
+    /// the copy from the latest mount, and unmounting that directory reveals the
+    /// copy beneath it again.
     pub fn mount(&mut self, path: &Path) -> SandboxResult<()> {
+        self.mount_with_ignores(path, &[])
+    }
+    
+    /// Mount a real directory, skipping any path matching one of the ignore globs
+    pub fn mount_with_ignores(&mut self, path: &Path, ignores: &[String]) -> SandboxResult<()> {
+        self.mount_from(path, ignores, false)
+    }
+    
+    /// Mount a real directory holding only each file's metadata in memory
+    ///
+    /// Content is read from disk when a file is read or diffed, and held in
+    /// memory once the file is written. Lazy files do not count towards the total
+    /// size limit. `original_content` reads lazy originals from disk and fails
+    /// if they can no longer be read.
+    pub fn mount_lazy(&mut self, path: &Path) -> SandboxResult<()> {
+        self.mount_from(path, &[], true)
+    }
+    
+    fn mount_from(&mut self, path: &Path, ignores: &[String], lazy: bool) -> SandboxResult<()> {
         if !path.exists() {
             return Err(SandboxError::FileSystemError(format!(
                 "Directory does not exist: {}",
@@ -85,34 +380,285 @@ impl VirtualFilesystem {
             )));
         }
         
-        self.mount_points.push(path.to_path_buf());
+        let mut rules = IgnoreRules::default();
+        for pattern in ignores {
+            rules.add_pattern(pattern);
+        }
         
-        for entry in walkdir::WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.file_type().is_file() {
+        if self.respect_gitignore {
+            rules.add_pattern(".git/");
+            if let Ok(gitignore) = std::fs::read_to_string(path.join(".gitignore")) {
+                for line in gitignore.lines() {
+                    rules.add_pattern(line);
+                }
+            }
+        }
+        
+        let mut walkdir = walkdir::WalkDir::new(path).follow_links(self.follow_symlinks);
+        if let Some(depth) = self.max_depth {
+            walkdir = walkdir.max_depth(depth);
+        }
+        
+        let walker = walkdir.into_iter().filter_entry(|e| {
+            let relative_path = e.path().strip_prefix(path).unwrap_or(e.path());
+            relative_path.as_os_str().is_empty()
+                || !rules.is_ignored(relative_path, e.file_type().is_dir())
+        });
+        
+        // Stage everything first so an aborted mount leaves the filesystem untouched
+        let mut total_size: u64 = self.files.values().map(|f| f.content.len() as u64).sum();
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        
+        for entry in walker.filter_map(|e| e.ok()) {
+            if entry.file_type().is_dir() {
+                let relative_path = entry
+                    .path()
+                    .strip_prefix(path)
+                    .unwrap()
+                    .to_path_buf();
+                
+                // Skip the mount root itself
+                if !relative_path.as_os_str().is_empty() {
+                    dirs.push(relative_path);
+                }
+            } else if entry.file_type().is_file() {
                 let relative_path = entry
                     .path()
                     .strip_prefix(path)
                     .unwrap()
                     .to_path_buf();
                 
-                let content = std::fs::read(entry.path())?;
-                let is_executable = {
+                let metadata = entry.metadata().map_err(|e| {
+                    SandboxError::FileSystemError(format!("{}: {}", entry.path().display(), e))
+                })?;
+                // Re-check the type reported by the metadata itself; reading a FIFO would block forever
+                if !metadata.file_type().is_file() {
+                    debug!(
+                        path = %entry.path().display(),
+                        kind = special_file_kind(&metadata.file_type()),
+                        "skipping special file"
+                    );
+                    continue;
+                }
+                let mode = {
                     use std::os::unix::fs::PermissionsExt;
-                    entry.metadata()
-                        .map(|m| m.permissions().mode() & 0o111 != 0)
-                        .unwrap_or(false)
+                    metadata.permissions().mode()
                 };
                 
-                let file = if is_executable {
-                    VirtualFile::new_executable(relative_path.clone(), content)
+                let oversized = self
+                    .max_file_size
+                    .map(|max| metadata.len() > max)
+                    .unwrap_or(false);
+                
+                let mut file = if oversized {
+                    let hash = VirtualFile::compute_hash_streaming(entry.path())?;
+                    VirtualFile::stub(relative_path.clone(), metadata.len(), hash)
+                } else if lazy {
+                    let hash = VirtualFile::compute_hash_streaming(entry.path())?;
+                    let mut file = VirtualFile::lazy(
+                        relative_path.clone(),
+                        entry.path().to_path_buf(),
+                        metadata.len(),
+                        hash,
+                    );
+                    if let Ok(modified) = metadata.modified() {
+                        file.modified_at = chrono::DateTime::<chrono::Utc>::from(modified).timestamp();
+                    }
+                    file
                 } else {
-                    VirtualFile::new(relative_path.clone(), content)
+                    total_size += metadata.len();
+                    if let Some(max) = self.max_total_size {
+                        if total_size > max {
+                            return Err(SandboxError::FileSystemError(format!(
+                                "Mounting {} exceeds the total size limit of {} bytes",
+                                path.display(),
+                                max
+                            )));
+                        }
+                    }
+                    
+                    VirtualFile::new(relative_path.clone(), std::fs::read(entry.path())?)
                 };
                 
-                self.files.insert(relative_path, file);
+                file.set_mode((mode & 0o7777) as u16);
+                files.push(file);
+            } else if entry.path_is_symlink() {
+                let relative_path = entry
+                    .path()
+                    .strip_prefix(path)
+                    .unwrap()
+                    .to_path_buf();
+                let target = std::fs::read_link(entry.path())?;
+                links.push((relative_path, target));
+            } else {
+                debug!(
+                    path = %entry.path().display(),
+                    kind = special_file_kind(&entry.file_type()),
+                    "skipping special file"
+                );
+            }
+        }
+        
+        self.check_case_collisions(
+            path,
+            dirs.iter()
+                .map(PathBuf::as_path)
+                .chain(files.iter().map(|file| file.path.as_path()))
+                .chain(links.iter().map(|(link, _)| link.as_path())),
+        )?;
+        
+        self.mount_points.push(path.to_path_buf());
+        if lazy {
+            self.lazy_mounts.insert(path.to_path_buf());
+        }
+        if !ignores.is_empty() {
+            self.mount_ignores.insert(path.to_path_buf(), ignores.to_vec());
+        }
+        
+        for name in dirs {
+            let dir = self.key(&name).into_owned();
+            self.shadow(&dir, path);
+            self.name_dir(&dir, &name);
+            self.directories.insert(dir.clone());
+            self.original_dirs.insert(dir);
+        }
+        
+        for (link, target) in links {
+            let link = self.key(&link).into_owned();
+            self.shadow(&link, path);
+            self.symlinks.insert(link, target);
+        }
+        
+        for file in files {
+            let key = self.key(&file.path).into_owned();
+            self.shadow(&key, path);
+            let file = Arc::new(file);
+            self.original_files.insert(key.clone(), file.clone());
+            self.insert_file(key, file);
+        }
+        
+        Ok(())
+    }
+    
+    /// Record that `root` now provides `key`, keeping any earlier mount's entry beneath it
+    fn shadow(&mut self, key: &Path, root: &Path) {
+        let previous = self.origins.insert(key.to_path_buf(), root.to_path_buf());
+        let Some(previous) = previous.filter(|previous| previous != root) else {
+            return;
+        };
+        
+        let entry = if let Some(file) = self.original_files.get(key) {
+            LayerEntry::File(file.clone())
+        } else if let Some(target) = self.symlinks.get(key) {
+            LayerEntry::Symlink(target.clone())
+        } else {
+            LayerEntry::Dir
+        };
+        self.shadowed
+            .entry(key.to_path_buf())
+            .or_default()
+            .push(MountLayer { root: previous, entry });
+    }
+    
+    /// Mount root the visible copy of `path` came from, if it was mounted
+    pub fn mount_origin(&self, path: &Path) -> Option<&Path> {
+        self.origins.get(self.key(path).as_ref()).map(PathBuf::as_path)
+    }
+    
+    /// Unmount a previously mounted directory
+    ///
+    /// Every file and directory that came from the mount is removed, including
+    /// edited or renamed copies. Where the mount shadowed an earlier mount, the
+    /// earlier copy becomes visible again. Files from other mounts and newly
+    /// written files are left in place.
+    pub fn unmount(&mut self, path: &Path) -> SandboxResult<()> {
+        let index = self
+            .mount_points
+            .iter()
+            .position(|p| p == path)
+            .ok_or_else(|| {
+                SandboxError::FileSystemError(format!("Not mounted: {}", path.display()))
+            })?;
+        self.mount_points.remove(index);
+        self.lazy_mounts.remove(path);
+        self.mount_ignores.remove(path);
+        
+        let owned: HashSet<PathBuf> = self
+            .origins
+            .iter()
+            .filter(|(_, root)| root.as_path() == path)
+            .map(|(p, _)| p.clone())
+            .collect();
+        
+        // Renamed copies of mounted files go with them
+        let renamed: Vec<PathBuf> = self
+            .renamed_files
+            .iter()
+            .filter(|(_, from)| owned.contains(*from))
+            .map(|(to, _)| to.clone())
+            .collect();
+        for to in renamed {
+            self.renamed_files.remove(&to);
+            self.remove_file(&to);
+        }
+        
+        for relative_path in &owned {
+            self.origins.remove(relative_path);
+            self.remove_file(relative_path);
+            self.original_files.remove(relative_path);
+            self.deleted_files.remove(relative_path);
+            self.symlinks.remove(relative_path);
+            self.original_symlinks.remove(relative_path);
+        }
+        
+        // Layers of this mount that were already hidden simply go away
+        for layers in self.shadowed.values_mut() {
+            layers.retain(|layer| layer.root != path);
+        }
+        self.shadowed.retain(|_, layers| !layers.is_empty());
+        
+        // Reveal what this mount was hiding
+        let mut revealed = HashSet::new();
+        for relative_path in &owned {
+            let Some(layer) = self.shadowed.get_mut(relative_path).and_then(Vec::pop) else {
+                continue;
+            };
+            if self.shadowed.get(relative_path).is_some_and(Vec::is_empty) {
+                self.shadowed.remove(relative_path);
+            }
+            
+            self.origins.insert(relative_path.clone(), layer.root);
+            match layer.entry {
+                LayerEntry::File(file) => {
+                    self.original_files.insert(relative_path.clone(), file.clone());
+                    self.insert_file(relative_path.clone(), file);
+                }
+                LayerEntry::Symlink(target) => {
+                    self.symlinks.insert(relative_path.clone(), target);
+                }
+                LayerEntry::Dir => {
+                    self.directories.insert(relative_path.clone());
+                    self.original_dirs.insert(relative_path.clone());
+                }
+            }
+            revealed.insert(relative_path.clone());
+        }
+        
+        // Drop the mount's directories unless something else still lives inside
+        let mut dirs: Vec<&PathBuf> = owned
+            .iter()
+            .filter(|p| !revealed.contains(*p))
+            .filter(|p| self.original_dirs.contains(*p) || self.directories.contains(*p))
+            .collect();
+        dirs.sort_by(|a, b| b.cmp(a));
+        for dir in dirs {
+            let occupied = self.files.keys().any(|p| p.starts_with(dir))
+                || self.directories.iter().any(|d| d != dir && d.starts_with(dir));
+            if !occupied {
+                self.directories.remove(dir);
+                self.original_dirs.remove(dir);
             }
         }
         
@@ -121,131 +667,1648 @@ impl VirtualFilesystem {
     
     /// Read a file from the virtual filesystem
     pub fn read(&self, path: &Path) -> SandboxResult<Vec<u8>> {
-        self.files
-            .get(path)
-            .map(|f| f.content.clone())
-            .ok_or_else(|| SandboxError::VirtualFileNotFound(path.display().to_string()))
+        let file = self
+            .files
+            .get(self.key(path).as_ref())
+            .ok_or_else(|| SandboxError::VirtualFileNotFound(path.display().to_string()))?;
+        
+        Ok(file.load()?.into_owned())
     }
     
     /// Write a file to the virtual filesystem
+    ///
+    /// Overwriting an existing file keeps its mode bits and creation time.
     pub fn write(&mut self, path: PathBuf, content: Vec<u8>) {
-        let file = VirtualFile::new(path.clone(), content);
+        let key = self.key(&path).into_owned();
+        let file = match self.remove_file(&key) {
+            Some(mut existing) => {
+                Arc::make_mut(&mut existing).update_content(content);
+                existing
+            }
+            None => Arc::new(VirtualFile::new(self.display_path(&path), content)),
+        };
+        self.insert_file(key, file);
+    }
+    
+    /// Find a file by the SHA-256 hash of its content
+    ///
+    /// When several paths share the same content the first by path order is returned;
+    /// use `paths_by_hash` to get all of them.
+    pub fn read_by_hash(&self, hash: &str) -> Option<&VirtualFile> {
+        self.hash_index
+            .get(hash)
+            .and_then(|paths| paths.iter().next())
+            .and_then(|path| self.files.get(path))
+            .map(|f| f.as_ref())
+    }
+    
+    /// List every path whose content has the given SHA-256 hash
+    pub fn paths_by_hash(&self, hash: &str) -> Vec<PathBuf> {
+        self.hash_index
+            .get(hash)
+            .map(|paths| paths.iter().filter_map(|key| self.files.get(key)).map(|f| f.path.clone()).collect())
+            .unwrap_or_default()
+    }
+    
+    fn insert_file(&mut self, path: PathBuf, file: Arc<VirtualFile>) {
+        self.remove_file(&path);
+        self.hash_index
+            .entry(file.hash.clone())
+            .or_default()
+            .insert(path.clone());
         self.files.insert(path, file);
     }
     
+    fn remove_file(&mut self, path: &Path) -> Option<Arc<VirtualFile>> {
+        let file = self.files.remove(path)?;
+        if let Some(paths) = self.hash_index.get_mut(&file.hash) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.hash_index.remove(&file.hash);
+            }
+        }
+        Some(file)
+    }
+    
+    fn rebuild_hash_index(&mut self) {
+        self.hash_index.clear();
+        for (path, file) in &self.files {
+            self.hash_index
+                .entry(file.hash.clone())
+                .or_default()
+                .insert(path.clone());
+        }
+    }
+    
+    /// The change `write` would make, without making it
+    ///
+    /// Text changes carry a unified diff; binary or stubbed content is
+    /// compared by size and hash.
+    pub fn preview_write(&self, path: &Path, new_content: &[u8]) -> FileDiff {
+        let existing = self.files.get(self.key(path).as_ref());
+        let path = existing.map_or_else(|| path.to_path_buf(), |file| file.path.clone());
+        let operation = if existing.is_some() {
+            DiffOperation::Modified
+        } else {
+            DiffOperation::Added
+        };
+        
+        // `Some(None)` is a text change to a file that does not exist yet
+        let old_content = existing.map(|file| file.load().ok());
+        let old_text = match &old_content {
+            None => Some(None),
+            Some(Some(content)) if !content.contains(&0) => std::str::from_utf8(content).ok().map(Some),
+            Some(_) => None,
+        };
+        match (old_text, std::str::from_utf8(new_content)) {
+            (Some(old), Ok(new)) if !new.contains('\0') => FileDiff::text(path, operation, old, new),
+            _ => {
+                let new = VirtualFile::new(path.clone(), new_content.to_vec());
+                FileDiff::from_files(path, operation, existing.map(|f| f.as_ref()), Some(&new))
+            }
+        }
+    }
+    
+    /// Write a file to the virtual filesystem with explicit mode bits
+    pub fn write_with_mode(&mut self, path: PathBuf, content: Vec<u8>, mode: u16) {
+        let key = self.key(&path).into_owned();
+        self.write(path, content);
+        if let Some(file) = self.files.get_mut(&key) {
+            Arc::make_mut(file).set_mode(mode);
+        }
+    }
+    
+    /// Append to a file, creating it if absent
+    ///
+    /// Fails if `path` is a directory or a stub too large to load.
+    pub fn append(&mut self, path: &Path, content: &[u8]) -> SandboxResult<()> {
+        let bytes = self.appended(path, content)?;
+        self.write(path.to_path_buf(), bytes);
+        Ok(())
+    }
+    
+    /// What `path` would contain after `append`, without changing anything
+    pub fn appended(&self, path: &Path, content: &[u8]) -> SandboxResult<Vec<u8>> {
+        if self.directories.contains(self.key(path).as_ref()) {
+            return Err(SandboxError::FileSystemError(format!(
+                "Is a directory: {}",
+                path.display()
+            )));
+        }
+        
+        let mut bytes = match self.read(path) {
+            Ok(bytes) => bytes,
+            Err(SandboxError::VirtualFileNotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        bytes.extend_from_slice(content);
+        Ok(bytes)
+    }
+    
     /// Delete a file from the virtual filesystem
     pub fn delete(&mut self, path: &Path) -> SandboxResult<()> {
-        if let Some(file) = self.files.remove(path) {
-            self.deleted_files.insert(path.to_path_buf(), file);
+        let key = self.key(path).into_owned();
+        if let Some(file) = self.remove_file(&key) {
+            self.renamed_files.remove(&key);
+            self.deleted_files.insert(key, file);
             Ok(())
         } else {
             Err(SandboxError::VirtualFileNotFound(path.display().to_string()))
         }
     }
     
+    /// Move a file to a new path, replacing any file already there
+    pub fn rename(&mut self, from: &Path, to: &Path) -> SandboxResult<()> {
+        let (from_key, to_key) = (self.key(from).into_owned(), self.key(to).into_owned());
+        if !self.files.contains_key(&from_key) {
+            return Err(SandboxError::VirtualFileNotFound(from.display().to_string()));
+        }
+        
+        if self.directories.contains(&to_key) {
+            return Err(SandboxError::FileSystemError(format!(
+                "Is a directory: {}",
+                to.display()
+            )));
+        }
+        
+        // Same file; at most the displayed case changes
+        if from_key == to_key {
+            if let Some(file) = self.files.get_mut(&to_key) {
+                Arc::make_mut(file).path = to.to_path_buf();
+            }
+            return Ok(());
+        }
+        
+        let file = self.remove_file(&from_key).unwrap();
+        self.deleted_files.insert(from_key.clone(), file.clone());
+        
+        if let Some(replaced) = self.remove_file(&to_key) {
+            self.deleted_files.insert(to_key.clone(), replaced);
+        }
+        
+        // Follow chained renames back to the original source
+        let source = self
+            .renamed_files
+            .remove(&from_key)
+            .unwrap_or(from_key);
+        if source != to_key {
+            self.renamed_files.insert(to_key.clone(), source);
+        }
+        
+        let mut file = file;
+        let moved = Arc::make_mut(&mut file);
+        moved.path = to.to_path_buf();
+        moved.modified_at = chrono::Utc::now().timestamp();
+        self.insert_file(to_key, file);
+        
+        Ok(())
+    }
+    
     /// Check if a file exists
     pub fn exists(&self, path: &Path) -> bool {
-        self.files.contains_key(path) || self.deleted_files.contains_key(path)
+        let key = self.key(path);
+        self.files.contains_key(key.as_ref()) || self.deleted_files.contains_key(key.as_ref())
+    }
+    
+    /// Create a directory, along with any missing parent directories
+    pub fn mkdir(&mut self, path: &Path) -> SandboxResult<()> {
+        let name = path;
+        let path = self.key(path).into_owned();
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            
+            if self.files.contains_key(ancestor) {
+                return Err(SandboxError::FileSystemError(format!(
+                    "Not a directory: {}",
+                    ancestor.display()
+                )));
+            }
+        }
+        
+        // Outermost first, so each directory is named under its parent's name
+        let ancestors: Vec<(&Path, &Path)> = path.ancestors().zip(name.ancestors()).collect();
+        for (ancestor, named) in ancestors.into_iter().rev() {
+            if ancestor.as_os_str().is_empty() {
+                continue;
+            }
+            self.name_dir(ancestor, named);
+            self.directories.insert(ancestor.to_path_buf());
+        }
+        
+        Ok(())
+    }
+    
+    /// Remove an empty directory
+    pub fn rmdir(&mut self, path: &Path) -> SandboxResult<()> {
+        if !self.is_dir(path) {
+            return Err(SandboxError::VirtualFileNotFound(path.display().to_string()));
+        }
+        
+        let key = self.key(path).into_owned();
+        let has_children = self.files.keys().any(|p| *p != key && p.starts_with(&key))
+            || self.directories.iter().any(|d| *d != key && d.starts_with(&key));
+        
+        if has_children {
+            return Err(SandboxError::FileSystemError(format!(
+                "Directory not empty: {}",
+                path.display()
+            )));
+        }
+        
+        self.directories.remove(&key);
+        Ok(())
+    }
+    
+    /// Check if a path is a live file, unlike `exists` which also counts deleted ones
+    pub fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(self.key(path).as_ref())
+    }
+    
+    /// Check if a path is a directory, either explicitly created or implied by the files beneath it
+    pub fn is_dir(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        let key = key.as_ref();
+        self.directories.contains(key)
+            || self.files.keys().any(|p| p != key && p.starts_with(key))
     }
     
     /// Get a file's metadata
     pub fn get_metadata(&self, path: &Path) -> SandboxResult<VirtualFile> {
         self.files
-            .get(path)
-            .cloned()
+            .get(self.key(path).as_ref())
+            .map(|f| VirtualFile::clone(f))
             .ok_or_else(|| SandboxError::VirtualFileNotFound(path.display().to_string()))
     }
     
+    /// Content of `path` as captured when it was mounted
+    ///
+    /// `None` for files that were not mounted and for stubs too large to load.
+    /// Lazily mounted originals are read from disk, failing like
+    /// `VirtualFile::load` if that is no longer possible. The original stays
+    /// available after the file is edited, renamed or deleted.
+    pub fn original_content(&self, path: &Path) -> SandboxResult<Option<Cow<'_, [u8]>>> {
+        self.original_files
+            .get(self.key(path).as_ref())
+            .filter(|file| !file.is_stub)
+            .map(|file| file.load())
+            .transpose()
+    }
+    
+    /// Whether `path` differs from its mounted original
+    ///
+    /// Files created or deleted since mounting count as modified.
+    pub fn is_modified(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        let current = self.files.get(key.as_ref()).map(|f| &f.hash);
+        let original = self.original_files.get(key.as_ref()).map(|f| &f.hash);
+        current != original
+    }
+    
     /// List all files in the virtual filesystem
     pub fn list_files(&self) -> Vec<PathBuf> {
-        self.files.keys().cloned().collect()
+        self.files.values().map(|f| f.path.clone()).collect()
     }
     
-    /// Get the diff between current state and original state
-    pub fn get_diff(&self) -> Vec<FileDiff> {
-        let mut diffs = Vec::new();
-        
-        // New and modified files
-        for (path, file) in &self.files {
-            diffs.push(FileDiff {
-                path: path.clone(),
-                operation: DiffOperation::Modified,
-                old_content: None,
-                new_content: Some(String::from_utf8_lossy(&file.content).to_string()),
-            });
-        }
+    /// List files matching a glob such as `src/**/*.rs`, sorted
+    ///
+    /// `*` stays within one path component while `**` spans directories. Only
+    /// virtual state is consulted, so deleted files never match. An invalid
+    /// pattern matches nothing.
+    pub fn glob(&self, pattern: &str) -> Vec<PathBuf> {
+        let Ok(pattern) = glob::Pattern::new(pattern) else {
+            return Vec::new();
+        };
+        let options = glob::MatchOptions {
+            case_sensitive: !self.case_insensitive,
+            require_literal_separator: true,
+            ..Default::default()
+        };
         
-        // Deleted files
-        for (path, file) in &self.deleted_files {
-            diffs.push(FileDiff {
-                path: path.clone(),
-                operation: DiffOperation::Deleted,
-                old_content: Some(String::from_utf8_lossy(&file.content).to_string()),
-                new_content: None,
-            });
+        let mut paths: Vec<PathBuf> = self
+            .files
+            .values()
+            .filter(|f| pattern.matches_path_with(&f.path, options))
+            .map(|f| f.path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+    
+    /// List files at or below `prefix`, sorted
+    pub fn list_files_under(&self, prefix: &Path) -> Vec<PathBuf> {
+        let prefix = self.key(prefix);
+        let mut paths: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix.as_ref()))
+            .map(|(_, f)| f.path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+    
+    /// Check if a path is a symbolic link
+    pub fn is_symlink(&self, path: &Path) -> bool {
+        self.symlinks.contains_key(self.key(path).as_ref())
+    }
+    
+    /// Get the target of a symbolic link
+    pub fn read_link(&self, path: &Path) -> SandboxResult<PathBuf> {
+        self.symlinks
+            .get(self.key(path).as_ref())
+            .cloned()
+            .ok_or_else(|| SandboxError::VirtualFileNotFound(path.display().to_string()))
+    }
+    
+    /// List all symbolic links in the virtual filesystem
+    pub fn list_symlinks(&self) -> Vec<PathBuf> {
+        self.symlinks.keys().cloned().collect()
+    }
+    
+    /// Create a symbolic link at `link` pointing to `target`
+    ///
+    /// The target is stored as given and need not exist, but it must be
+    /// relative and stay under the root when resolved from the link's
+    /// directory. Fails if anything already lives at `link`.
+    pub fn symlink(&mut self, link: &Path, target: &Path) -> SandboxResult<()> {
+        let key = self.key(link).into_owned();
+        if self.files.contains_key(&key) || self.directories.contains(&key) || self.symlinks.contains_key(&key) {
+            return Err(SandboxError::FileSystemError(format!(
+                "File exists: {}",
+                key.display()
+            )));
         }
         
-        diffs
+        self.insert_symlink(key, link, target)
     }
     
-    /// Reset the virtual filesystem to its original state
-    pub fn reset(&mut self) {
-        // Restore deleted files
-        for (path, file) in self.deleted_files.drain() {
-            self.files.insert(path, file);
+    /// Like `symlink`, but replace a file or link already at `link`, as `ln -sf` does
+    pub fn symlink_force(&mut self, link: &Path, target: &Path) -> SandboxResult<()> {
+        Self::check_link_target(link, target)?;
+        
+        let key = self.key(link).into_owned();
+        if self.directories.contains(&key) {
+            return Err(SandboxError::FileSystemError(format!(
+                "Is a directory: {}",
+                key.display()
+            )));
         }
+        if self.files.contains_key(&key) {
+            self.delete(link)?;
+        }
+        if !self.created_symlinks.contains(&key) {
+            if let Some(original) = self.symlinks.get(&key) {
+                self.original_symlinks.insert(key.clone(), original.clone());
+            }
+        }
+        
+        self.insert_symlink(key, link, target)
+    }
+    
+    fn insert_symlink(&mut self, key: PathBuf, link: &Path, target: &Path) -> SandboxResult<()> {
+        Self::check_link_target(link, target)?;
+        self.symlinks.insert(key.clone(), target.to_path_buf());
+        self.created_symlinks.insert(key);
+        Ok(())
+    }
+    
+    /// Refuse a link target that is absolute or climbs out of the root
+    ///
+    /// `target` is resolved lexically from the directory `link` lives in.
+    fn check_link_target(link: &Path, target: &Path) -> SandboxResult<()> {
+        use std::path::Component;
+        
+        Self::ensure_relative(link)?;
+        let mut depth = link.parent().map_or(0, |parent| {
+            parent.components().filter(|c| matches!(c, Component::Normal(_))).count()
+        });
+        let escapes = target.components().any(|component| match component {
+            Component::Normal(_) => {
+                depth += 1;
+                false
+            }
+            Component::CurDir => false,
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => {
+                    depth = parent;
+                    false
+                }
+                None => true,
+            },
+            Component::RootDir | Component::Prefix(_) => true,
+        });
+        
+        if escapes {
+            return Err(SandboxError::PermissionDenied(format!(
+                "Symbolic link {} -> {} points outside the root",
+                link.display(),
+                target.display()
+            )));
+        }
+        Ok(())
+    }
+    
+    /// Bytes of current and original file content held in memory
+    ///
+    /// Lazy files and stubs count as nothing until they are written.
+    pub fn resident_bytes(&self) -> u64 {
+        self.files
+            .values()
+            .chain(self.original_files.values())
+            .map(|file| file.content.len() as u64)
+            .sum()
+    }
+    
+    /// List all directories in the virtual filesystem
+    pub fn list_dirs(&self) -> Vec<PathBuf> {
+        self.directories.iter().map(|dir| self.dir_name(dir).clone()).collect()
+    }
+    
+    /// Re-read every mount from disk into the current state, keeping the original state
+    ///
+    /// Afterwards `get_diff` reports what changed on disk since the mounts were
+    /// taken. Virtual edits that were never flushed are discarded.
+    pub fn rescan(&mut self) -> SandboxResult<()> {
+        let mut fresh = Self {
+            respect_gitignore: self.respect_gitignore,
+            max_file_size: self.max_file_size,
+            max_total_size: self.max_total_size,
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            case_insensitive: self.case_insensitive,
+            ..Self::new()
+        };
+        for mount in &self.mount_points {
+            let ignores = self.mount_ignores.get(mount).map_or(&[][..], Vec::as_slice);
+            fresh.mount_from(mount, ignores, self.lazy_mounts.contains(mount))?;
+        }
+        
+        // Files that disappeared from disk become tombstones
+        for (path, file) in std::mem::take(&mut self.files) {
+            if !fresh.files.contains_key(&path) {
+                self.deleted_files.insert(path, file);
+            }
+        }
+        
+        self.files = fresh.files;
+        self.rebuild_hash_index();
+        self.renamed_files.clear();
+        self.directories = fresh.directories;
+        self.symlinks = fresh.symlinks;
+        self.created_symlinks.clear();
+        self.original_symlinks.clear();
+        self.origins.extend(fresh.origins);
+        self.shadowed = fresh.shadowed;
+        
+        Ok(())
+    }
+    
+    /// Get the diff between current state and original state
+    pub fn get_diff(&self) -> Vec<FileDiff> {
+        let mut diffs = Vec::new();
+        
+        // New, modified and renamed files
+        for (path, file) in &self.files {
+            if let Some(from) = self.renamed_files.get(path) {
+                let original = self.original_files.get(from);
+                let from = original.map_or_else(|| from.clone(), |f| f.path.clone());
+                diffs.push(FileDiff::from_files(
+                    file.path.clone(),
+                    DiffOperation::Renamed { from },
+                    original.map(|f| f.as_ref()),
+                    Some(file),
+                ));
+                continue;
+            }
+            
+            match self.original_files.get(path) {
+                None => diffs.push(FileDiff::from_files(
+                    file.path.clone(),
+                    DiffOperation::Added,
+                    None,
+                    Some(file),
+                )),
+                Some(original) if original.hash != file.hash => diffs.push(FileDiff::from_files(
+                    file.path.clone(),
+                    DiffOperation::Modified,
+                    Some(original),
+                    Some(file),
+                )),
+                Some(_) => {}
+            }
+        }
+        
+        // Deleted files, excluding those that were renamed or re-created
+        for (path, file) in &self.deleted_files {
+            let renamed = self.renamed_files.values().any(|from| from == path);
+            if self.files.contains_key(path) || renamed || !self.original_files.contains_key(path) {
+                continue;
+            }
+            
+            diffs.push(FileDiff::from_files(
+                file.path.clone(),
+                DiffOperation::Deleted,
+                Some(file),
+                None,
+            ));
+        }
+        
+        // Created and removed directories
+        for path in self.directories.difference(&self.original_dirs) {
+            diffs.push(FileDiff {
+                path: self.dir_name(path).clone(),
+                operation: DiffOperation::DirectoryAdded,
+                old_content: None,
+                new_content: None,
+                binary: None,
+                unified: None,
+                mode: None,
+            });
+        }
+        
+        for path in self.original_dirs.difference(&self.directories) {
+            diffs.push(FileDiff {
+                path: self.dir_name(path).clone(),
+                operation: DiffOperation::DirectoryRemoved,
+                old_content: None,
+                new_content: None,
+                binary: None,
+                unified: None,
+                mode: None,
+            });
+        }
+        
+        for path in &self.created_symlinks {
+            if let Some(target) = self.symlinks.get(path) {
+                diffs.push(FileDiff::symlink(path.clone(), target.clone()));
+            }
+        }
+        
+        diffs
+    }
+    
+    /// Changes that turn `other` into `self`, sorted by path
+    ///
+    /// Files are compared by hash: a file only in `self` is Added, one only in
+    /// `other` is Deleted, and one whose hash differs is Modified. Each
+    /// filesystem's own origin is ignored.
+    pub fn diff_against(&self, other: &VirtualFilesystem) -> Vec<FileDiff> {
+        let mut diffs = Vec::new();
+        
+        for file in self.files.values() {
+            match other.files.get(other.key(&file.path).as_ref()) {
+                None => diffs.push(FileDiff::from_files(
+                    file.path.clone(),
+                    DiffOperation::Added,
+                    None,
+                    Some(file),
+                )),
+                Some(theirs) if theirs.hash != file.hash => diffs.push(FileDiff::from_files(
+                    file.path.clone(),
+                    DiffOperation::Modified,
+                    Some(theirs),
+                    Some(file),
+                )),
+                Some(_) => {}
+            }
+        }
+        
+        for theirs in other.files.values() {
+            if !self.files.contains_key(self.key(&theirs.path).as_ref()) {
+                diffs.push(FileDiff::from_files(
+                    theirs.path.clone(),
+                    DiffOperation::Deleted,
+                    Some(theirs),
+                    None,
+                ));
+            }
+        }
+        
+        diffs.sort_by(|a, b| a.path.cmp(&b.path));
+        diffs
+    }
+    
+    /// Write the accumulated changes to the real filesystem under `root`
+    ///
+    /// Added, modified and renamed files are written with their mode bits, new
+    /// symbolic links are created, and deleted files and removed directories
+    /// are removed. Returns the paths touched.
+    pub fn flush_to_disk(&self, root: &Path) -> SandboxResult<Vec<PathBuf>> {
+        use std::os::unix::fs::PermissionsExt;
+        
+        self.check_flush_paths()?;
+        for (path, include_leaf) in self.flush_targets()? {
+            Self::ensure_no_symlinks(root, &path, include_leaf)?;
+        }
+        
+        let mut touched = Vec::new();
+        
+        let mut created_dirs: Vec<&PathBuf> = self
+            .directories
+            .difference(&self.original_dirs)
+            .map(|dir| self.dir_name(dir))
+            .collect();
+        created_dirs.sort();
+        for dir in created_dirs {
+            let target = root.join(dir);
+            std::fs::create_dir_all(&target)?;
+            touched.push(target);
+        }
+        
+        for (path, file) in &self.files {
+            if !self.needs_flush(path, file)? {
+                continue;
+            }
+            
+            let target = root.join(&file.path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            
+            std::fs::write(&target, file.load()?)?;
+            std::fs::set_permissions(&target, std::fs::Permissions::from_mode(file.mode()))?;
+            
+            touched.push(target);
+        }
+        
+        for (path, file) in &self.deleted_files {
+            if self.files.contains_key(path) || !self.original_files.contains_key(path) {
+                continue;
+            }
+            
+            let target = root.join(&file.path);
+            if target.is_file() {
+                std::fs::remove_file(&target)?;
+                touched.push(target);
+            }
+        }
+        
+        // Links go in after deletions, so one may take the place of a deleted file
+        let mut created_links: Vec<&PathBuf> = self.created_symlinks.iter().collect();
+        created_links.sort();
+        for link in created_links {
+            let Some(target) = self.symlinks.get(link) else {
+                continue;
+            };
+            
+            let path = root.join(link);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // A mounted link that was replaced, as by `ln -sf`; removing it leaves its target alone
+            let replaced = self.origins.contains_key(link)
+                && path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink());
+            if replaced {
+                std::fs::remove_file(&path)?;
+            }
+            std::os::unix::fs::symlink(target, &path)?;
+            touched.push(path);
+        }
+        
+        // Remove directories deepest first
+        let mut removed_dirs: Vec<&PathBuf> = self
+            .original_dirs
+            .difference(&self.directories)
+            .map(|dir| self.dir_name(dir))
+            .collect();
+        removed_dirs.sort_by(|a, b| b.cmp(a));
+        for dir in removed_dirs {
+            let target = root.join(dir);
+            if target.is_dir() {
+                std::fs::remove_dir(&target)?;
+                touched.push(target);
+            }
+        }
+        
+        Ok(touched)
+    }
+    
+    /// Work out what `flush_to_disk` would do under `root` without touching it
+    ///
+    /// Each planned operation names the real path it affects. A file whose
+    /// content on disk no longer matches what was mounted, a new file or link
+    /// whose path is already taken on disk, or a path that is or passes
+    /// through a symbolic link on disk is reported as a conflict. A mounted
+    /// link replaced with `symlink_force` is not, as long as the link on disk
+    /// still points where it did when mounted.
+    pub fn flush_plan(&self, root: &Path) -> SandboxResult<FlushPlan> {
+        self.check_flush_paths()?;
+        
+        let mut plan = FlushPlan {
+            root: root.to_path_buf(),
+            operations: Vec::new(),
+            conflicts: Vec::new(),
+        };
+        
+        for dir in self.directories.difference(&self.original_dirs) {
+            plan.push(root.join(self.dir_name(dir)), FlushOperation::CreateDir);
+        }
+        
+        for (path, file) in &self.files {
+            if !self.needs_flush(path, file)? {
+                continue;
+            }
+            
+            let target = root.join(&file.path);
+            let expected = self.original_files.get(path).map(|original| original.hash.clone());
+            let found = Self::disk_hash(&target)?;
+            let operation = if target.symlink_metadata().is_ok() {
+                FlushOperation::Overwrite
+            } else {
+                FlushOperation::Create
+            };
+            
+            plan.check(&target, expected, found);
+            plan.push(target, operation);
+        }
+        
+        let mut replaced_links = HashSet::new();
+        for link in &self.created_symlinks {
+            let Some(target) = self.symlinks.get(link) else {
+                continue;
+            };
+            
+            let path = root.join(link);
+            if self.replaces_mounted_link(root, link) {
+                replaced_links.insert(path.clone());
+            } else if path.symlink_metadata().is_ok() {
+                plan.conflicts.push(FlushConflict {
+                    path: path.clone(),
+                    expected_hash: None,
+                    found_hash: Self::disk_hash(&path)?,
+                    symlink: None,
+                });
+            }
+            plan.push(path, FlushOperation::Symlink { target: target.clone() });
+        }
+        
+        for (path, file) in &self.deleted_files {
+            if self.files.contains_key(path) || !self.original_files.contains_key(path) {
+                continue;
+            }
+            
+            let target = root.join(&file.path);
+            plan.check(&target, Some(file.hash.clone()), Self::disk_hash(&target)?);
+            plan.push(target, FlushOperation::Delete);
+        }
+        
+        for dir in self.original_dirs.difference(&self.directories) {
+            plan.push(root.join(self.dir_name(dir)), FlushOperation::RemoveDir);
+        }
+        
+        let linked: Vec<(PathBuf, PathBuf)> = plan
+            .operations
+            .iter()
+            .filter_map(|step| {
+                let relative = step.path.strip_prefix(root).ok()?;
+                let include_leaf = !replaced_links.contains(&step.path);
+                Self::symlink_on_path(root, relative, include_leaf).map(|link| (step.path.clone(), link))
+            })
+            .collect();
+        for (path, link) in linked {
+            plan.flag_symlink(path, link);
+        }
+        
+        plan.operations.sort_by(|a, b| a.path.cmp(&b.path));
+        plan.conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(plan)
+    }
+    
+    /// Whether `link` replaces a mounted link that still points at its mounted target on disk
+    fn replaces_mounted_link(&self, root: &Path, link: &Path) -> bool {
+        self.origins.contains_key(link)
+            && self.original_symlinks.get(link).is_some_and(|mounted| {
+                std::fs::read_link(root.join(link)).is_ok_and(|found| found == *mounted)
+            })
+    }
+    
+    /// Flush to disk only if `plan` is conflict-free and still describes the changes
+    ///
+    /// The plan is recomputed against its root first, so a plan made before
+    /// further virtual edits or before the disk changed is refused. Changes
+    /// made to the disk between that check and the writes themselves are not
+    /// caught; `flush_to_disk` still refuses to write through symbolic links,
+    /// but a file edited in that window is overwritten.
+    pub fn flush_with_plan(&self, plan: &FlushPlan) -> SandboxResult<Vec<PathBuf>> {
+        if !plan.is_clean() {
+            let paths: Vec<String> = plan.conflicts.iter().map(|c| c.path.display().to_string()).collect();
+            return Err(SandboxError::FileSystemError(format!(
+                "Flush plan has {} conflict(s): {}",
+                paths.len(),
+                paths.join(", ")
+            )));
+        }
+        
+        if self.flush_plan(&plan.root)? != *plan {
+            return Err(SandboxError::FileSystemError(format!(
+                "Flush plan for {} is out of date",
+                plan.root.display()
+            )));
+        }
+        
+        self.flush_to_disk(&plan.root)
+    }
+    
+    /// Validate every path up front so nothing is written if one would escape
+    fn check_flush_paths(&self) -> SandboxResult<()> {
+        for link in &self.created_symlinks {
+            if let Some(target) = self.symlinks.get(link) {
+                Self::check_link_target(link, target)?;
+            }
+        }
+        
+        for path in self
+            .files
+            .keys()
+            .chain(self.deleted_files.keys())
+            .chain(self.directories.iter())
+            .chain(self.original_dirs.iter())
+            .chain(self.created_symlinks.iter())
+        {
+            Self::ensure_relative(path)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Relative paths `flush_to_disk` writes or removes, and whether the path
+    /// itself (not just its parents) must not be a symbolic link on disk
+    ///
+    /// Removing a link only removes the link, so removals check parents only.
+    fn flush_targets(&self) -> SandboxResult<Vec<(PathBuf, bool)>> {
+        let mut targets = Vec::new();
+        
+        for dir in self.directories.difference(&self.original_dirs) {
+            targets.push((self.dir_name(dir).clone(), true));
+        }
+        
+        for (path, file) in &self.files {
+            if self.needs_flush(path, file)? {
+                targets.push((file.path.clone(), true));
+            }
+        }
+        
+        for link in &self.created_symlinks {
+            targets.push((link.clone(), false));
+        }
+        
+        for (path, file) in &self.deleted_files {
+            if !self.files.contains_key(path) && self.original_files.contains_key(path) {
+                targets.push((file.path.clone(), false));
+            }
+        }
+        
+        for dir in self.original_dirs.difference(&self.directories) {
+            targets.push((self.dir_name(dir).clone(), false));
+        }
+        
+        Ok(targets)
+    }
+    
+    /// Refuse a path under `root` that passes through a symbolic link on disk
+    ///
+    /// Writing through an existing link lands wherever it points, which may be
+    /// outside `root`. `root` itself is trusted.
+    fn ensure_no_symlinks(root: &Path, path: &Path, include_leaf: bool) -> SandboxResult<()> {
+        match Self::symlink_on_path(root, path, include_leaf) {
+            Some(link) => Err(SandboxError::PermissionDenied(format!(
+                "Refusing to write through symbolic link: {}",
+                link.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+    
+    /// The first symbolic link on disk among `path`'s ancestors under `root`, and the path itself with `include_leaf`
+    fn symlink_on_path(root: &Path, path: &Path, include_leaf: bool) -> Option<PathBuf> {
+        let components: Vec<_> = path.components().collect();
+        let checked = if include_leaf {
+            components.len()
+        } else {
+            components.len().saturating_sub(1)
+        };
+        
+        let mut current = root.to_path_buf();
+        for component in &components[..checked] {
+            current.push(component);
+            match current.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => return Some(current),
+                Ok(_) => {}
+                // Nothing deeper can exist either
+                Err(_) => break,
+            }
+        }
+        
+        None
+    }
+    
+    /// Whether a file differs from what was mounted and has content that can be written
+    fn needs_flush(&self, path: &Path, file: &VirtualFile) -> SandboxResult<bool> {
+        let changed = self.renamed_files.contains_key(path)
+            || self
+                .original_files
+                .get(path)
+                .map(|original| original.hash != file.hash)
+                .unwrap_or(true);
+        
+        if changed && file.is_stub {
+            return Err(SandboxError::FileSystemError(format!(
+                "Cannot flush metadata-only file: {}",
+                path.display()
+            )));
+        }
+        
+        Ok(changed)
+    }
+    
+    /// Hash of the regular file at `path`, or None if there is none
+    ///
+    /// A symbolic link is not followed, so a link to a file has no hash.
+    fn disk_hash(path: &Path) -> SandboxResult<Option<String>> {
+        if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_file()) {
+            VirtualFile::compute_hash_streaming(path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+    
+    /// Export the current files and directories as a tar archive
+    ///
+    /// Paths, content and mode bits are preserved; deletion tombstones are not.
+    pub fn export_tar(&self, writer: impl std::io::Write) -> SandboxResult<()> {
+        let mut builder = tar::Builder::new(writer);
+        
+        let mut dirs: Vec<&PathBuf> = self.directories.iter().map(|dir| self.dir_name(dir)).collect();
+        dirs.sort();
+        for dir in dirs {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, dir, std::io::empty())?;
+        }
+        
+        let mut files: Vec<&Arc<VirtualFile>> = self.files.values().collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        for file in files {
+            if file.is_stub {
+                return Err(SandboxError::FileSystemError(format!(
+                    "Cannot export metadata-only file: {}",
+                    file.path.display()
+                )));
+            }
+            
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(file.mode());
+            let content = file.load()?;
+            header.set_size(content.len() as u64);
+            header.set_mtime(file.modified_at.max(0) as u64);
+            builder.append_data(&mut header, &file.path, content.as_ref())?;
+        }
+        
+        builder.into_inner()?;
+        Ok(())
+    }
+    
+    /// Load a virtual filesystem from a tar archive produced by `export_tar`
+    ///
+    /// The imported contents become the original state, as if they had been mounted.
+    pub fn import_tar(reader: impl std::io::Read) -> SandboxResult<Self> {
+        let mut vfs = Self::new();
+        let mut archive = tar::Archive::new(reader);
+        
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            Self::ensure_relative(&path)?;
+            
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => {
+                    vfs.directories.insert(path.clone());
+                    vfs.original_dirs.insert(path);
+                }
+                tar::EntryType::Regular => {
+                    let mode = entry.header().mode()?;
+                    let mut content = Vec::new();
+                    std::io::Read::read_to_end(&mut entry, &mut content)?;
+                    
+                    let mut file = VirtualFile::new(path.clone(), content);
+                    file.set_mode((mode & 0o7777) as u16);
+                    
+                    let file = Arc::new(file);
+                    vfs.original_files.insert(path.clone(), file.clone());
+                    vfs.insert_file(path, file);
+                }
+                _ => {}
+            }
+        }
+        
+        Ok(vfs)
+    }
+    
+    /// Ensure a virtual path stays beneath whatever root it is joined onto
+    fn ensure_relative(path: &Path) -> SandboxResult<()> {
+        use std::path::Component;
+        
+        if path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            Ok(())
+        } else {
+            Err(SandboxError::PermissionDenied(format!(
+                "Refusing to write outside the target root: {}",
+                path.display()
+            )))
+        }
+    }
+    
+    /// Capture the current state so it can be restored later
+    pub fn snapshot(&self) -> FsSnapshot {
+        FsSnapshot {
+            files: self.files.clone(),
+            deleted_files: self.deleted_files.clone(),
+            original_files: self.original_files.clone(),
+            renamed_files: self.renamed_files.clone(),
+            directories: self.directories.clone(),
+            original_dirs: self.original_dirs.clone(),
+            symlinks: self.symlinks.clone(),
+            created_symlinks: self.created_symlinks.clone(),
+            original_symlinks: self.original_symlinks.clone(),
+            mount_points: self.mount_points.clone(),
+            origins: self.origins.clone(),
+            shadowed: self.shadowed.clone(),
+            lazy_mounts: self.lazy_mounts.clone(),
+            mount_ignores: self.mount_ignores.clone(),
+            dir_names: self.dir_names.clone(),
+            taken_at: chrono::Utc::now().timestamp(),
+        }
+    }
+    
+    /// Restore the state captured by a snapshot
+    pub fn restore(&mut self, snapshot: &FsSnapshot) {
+        self.files = snapshot.files.clone();
+        self.rebuild_hash_index();
+        self.deleted_files = snapshot.deleted_files.clone();
+        self.original_files = snapshot.original_files.clone();
+        self.renamed_files = snapshot.renamed_files.clone();
+        self.directories = snapshot.directories.clone();
+        self.original_dirs = snapshot.original_dirs.clone();
+        self.symlinks = snapshot.symlinks.clone();
+        self.created_symlinks = snapshot.created_symlinks.clone();
+        self.original_symlinks = snapshot.original_symlinks.clone();
+        self.mount_points = snapshot.mount_points.clone();
+        self.origins = snapshot.origins.clone();
+        self.shadowed = snapshot.shadowed.clone();
+        self.lazy_mounts = snapshot.lazy_mounts.clone();
+        self.mount_ignores = snapshot.mount_ignores.clone();
+        self.dir_names = snapshot.dir_names.clone();
+    }
+    
+    /// Reset the virtual filesystem to its original state
+    pub fn reset(&mut self) {
+        // Undo renames before restoring their sources
+        for (to, _) in std::mem::take(&mut self.renamed_files) {
+            self.remove_file(&to);
+        }
+        
+        // Restore deleted files
+        for (path, file) in std::mem::take(&mut self.deleted_files) {
+            self.insert_file(path, file);
+        }
+        
+        self.directories = self.original_dirs.clone();
+        
+        for link in std::mem::take(&mut self.created_symlinks) {
+            self.symlinks.remove(&link);
+        }
+        self.symlinks.extend(std::mem::take(&mut self.original_symlinks));
+    }
+    
+    /// Drop the tombstones of deleted files that have since been re-created
+    ///
+    /// Unlike `commit`, deletions that still stand are kept, so `get_diff`
+    /// reports the same changes before and after. `reset` no longer brings
+    /// back the deleted version of a compacted file.
+    pub fn compact(&mut self) -> CompactStats {
+        let mut stats = CompactStats::default();
+        let stale: Vec<PathBuf> = self
+            .deleted_files
+            .keys()
+            .filter(|path| self.files.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in stale {
+            if let Some(file) = self.deleted_files.remove(&path) {
+                stats.tombstones_removed += 1;
+                // Content shared with the original state or a snapshot stays in memory
+                if Arc::strong_count(&file) == 1 {
+                    stats.bytes_reclaimed += file.content.len() as u64;
+                }
+            }
+        }
+        stats
     }
     
     /// Commit changes (apply deletions)
     pub fn commit(&mut self) {
         self.deleted_files.clear();
+        self.created_symlinks.clear();
+        self.original_symlinks.clear();
+        self.original_files = self.files.clone();
+        self.renamed_files.clear();
+        self.original_dirs = self.directories.clone();
     }
 }
 
-/// Represents a diff operation
+/// A point-in-time copy of a virtual filesystem's state
+///
+/// File entries are shared with the filesystem they were taken from, so holding
+/// several snapshots costs little more than the path maps themselves.
+#[derive(Debug, Clone)]
+pub struct FsSnapshot {
+    files: HashMap<PathBuf, Arc<VirtualFile>>,
+    deleted_files: HashMap<PathBuf, Arc<VirtualFile>>,
+    original_files: HashMap<PathBuf, Arc<VirtualFile>>,
+    renamed_files: HashMap<PathBuf, PathBuf>,
+    directories: HashSet<PathBuf>,
+    original_dirs: HashSet<PathBuf>,
+    symlinks: HashMap<PathBuf, PathBuf>,
+    created_symlinks: HashSet<PathBuf>,
+    original_symlinks: HashMap<PathBuf, PathBuf>,
+    mount_points: Vec<PathBuf>,
+    origins: HashMap<PathBuf, PathBuf>,
+    shadowed: HashMap<PathBuf, Vec<MountLayer>>,
+    lazy_mounts: HashSet<PathBuf>,
+    mount_ignores: HashMap<PathBuf, Vec<String>>,
+    dir_names: HashMap<PathBuf, PathBuf>,
+    pub taken_at: i64,
+}
+
+/// What an earlier mount provided at a path now shadowed by a later mount
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MountLayer {
+    root: PathBuf,
+    entry: LayerEntry,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+enum LayerEntry {
+    File(Arc<VirtualFile>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// A single ignore pattern, using a subset of gitignore semantics
+#[derive(Debug)]
+struct IgnorePattern {
+    pattern: glob::Pattern,
+    anchored: bool,
+    dir_only: bool,
+}
+
+/// Compiled set of ignore patterns applied while mounting
+#[derive(Debug, Default)]
+struct IgnoreRules {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    /// Add a pattern; blank lines, comments and negations (`!`) are skipped
+    fn add_pattern(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            return;
+        }
+        
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        // Patterns containing a slash match from the root, others match any file name
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        
+        if let Ok(pattern) = glob::Pattern::new(line) {
+            self.patterns.push(IgnorePattern {
+                pattern,
+                anchored,
+                dir_only,
+            });
+        }
+    }
+    
+    fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        
+        self.patterns.iter().any(|p| {
+            if p.dir_only && !is_dir {
+                return false;
+            }
+            
+            if p.anchored {
+                p.pattern.matches_path_with(relative_path, options)
+            } else {
+                relative_path
+                    .file_name()
+                    .map(|name| p.pattern.matches_with(&name.to_string_lossy(), options))
+                    .unwrap_or(false)
+            }
+        })
+    }
+}
+
+/// Represents a diff operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DiffOperation {
     Added,
     Modified,
     Deleted,
+    Renamed { from: PathBuf },
+    DirectoryAdded,
+    DirectoryRemoved,
+    Symlinked { target: PathBuf },
+}
+
+/// Name of a non-regular file type, for logging why it was not mounted
+fn special_file_kind(file_type: &std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    
+    if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else {
+        "unknown"
+    }
+}
+
+/// Check whether content should be treated as binary rather than text
+pub fn is_binary_content(content: &[u8]) -> bool {
+    content.contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+/// Merge changes to the same path into one entry each, keeping first-seen order
+///
+/// Changes are applied in sequence with `FileDiff::then`; paths whose changes
+/// cancel out are dropped.
+pub fn merge_file_changes(changes: impl IntoIterator<Item = FileDiff>) -> Vec<FileDiff> {
+    let mut order: Vec<PathBuf> = Vec::new();
+    let mut merged: HashMap<PathBuf, FileDiff> = HashMap::new();
+    
+    for change in changes {
+        let path = change.path.clone();
+        let combined = match merged.remove(&path) {
+            Some(earlier) => earlier.then(change),
+            None => {
+                if !order.contains(&path) {
+                    order.push(path.clone());
+                }
+                Some(change)
+            }
+        };
+        if let Some(combined) = combined {
+            merged.insert(path, combined);
+        }
+    }
+    
+    order.into_iter().filter_map(|path| merged.remove(&path)).collect()
+}
+
+/// What `VirtualFilesystem::compact` cleaned up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactStats {
+    pub tombstones_removed: usize,
+    /// File content freed along with the tombstones
+    pub bytes_reclaimed: u64,
+}
+
+/// A change `flush_to_disk` would make to one real path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlushOperation {
+    CreateDir,
+    /// Write a file where none exists yet
+    Create,
+    /// Replace whatever is at the path with new content
+    Overwrite,
+    Symlink { target: PathBuf },
+    Delete,
+    RemoveDir,
+}
+
+/// A planned operation on a path under the flush root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlushStep {
+    pub path: PathBuf,
+    pub operation: FlushOperation,
+}
+
+/// A path whose on-disk state is not what the virtual changes were based on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlushConflict {
+    pub path: PathBuf,
+    /// Hash of the file as mounted, or None if the path was expected to be free
+    pub expected_hash: Option<String>,
+    /// Hash of the regular file now on disk, or None if there is none
+    pub found_hash: Option<String>,
+    /// Symbolic link on disk at the path or one of its parents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<PathBuf>,
+}
+
+/// Dry run of `VirtualFilesystem::flush_to_disk`, from `VirtualFilesystem::flush_plan`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlushPlan {
+    pub root: PathBuf,
+    /// Operations sorted by path
+    pub operations: Vec<FlushStep>,
+    pub conflicts: Vec<FlushConflict>,
+}
+
+impl FlushPlan {
+    /// No conflicts were found, so flushing would not clobber outside changes
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+    
+    fn push(&mut self, path: PathBuf, operation: FlushOperation) {
+        self.operations.push(FlushStep { path, operation });
+    }
+    
+    fn check(&mut self, path: &Path, expected_hash: Option<String>, found_hash: Option<String>) {
+        if expected_hash != found_hash {
+            self.conflicts.push(FlushConflict {
+                path: path.to_path_buf(),
+                expected_hash,
+                found_hash,
+                symlink: None,
+            });
+        }
+    }
+    
+    /// Record that `path` is or passes through the symbolic link `link` on disk
+    fn flag_symlink(&mut self, path: PathBuf, link: PathBuf) {
+        match self.conflicts.iter_mut().find(|conflict| conflict.path == path) {
+            Some(conflict) => conflict.symlink = Some(link),
+            None => self.conflicts.push(FlushConflict {
+                path,
+                expected_hash: None,
+                found_hash: None,
+                symlink: Some(link),
+            }),
+        }
+    }
+}
+
+/// Size and hash details for a change to binary content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BinaryChange {
+    pub old_len: u64,
+    pub new_len: u64,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
 }
 
 /// Represents a file diff
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileDiff {
     pub path: PathBuf,
     pub operation: DiffOperation,
     pub old_content: Option<String>,
     pub new_content: Option<String>,
+    /// Set instead of the text contents when either side is binary
+    #[serde(default)]
+    pub binary: Option<BinaryChange>,
+    /// Line-level diff of the contents, when both sides are real text rather than a description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unified: Option<UnifiedDiff>,
+    /// Permission bits of the file after the change, or before it for a deletion, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
 }
 
 impl FileDiff {
+    /// A new symbolic link at `path` pointing to `target`
+    pub fn symlink(path: PathBuf, target: PathBuf) -> Self {
+        Self {
+            path,
+            operation: DiffOperation::Symlinked { target },
+            old_content: None,
+            new_content: None,
+            binary: None,
+            unified: None,
+            mode: None,
+        }
+    }
+    
+    /// Build a diff between two versions of a file, detecting binary content
+    pub fn from_files(
+        path: PathBuf,
+        operation: DiffOperation,
+        old: Option<&VirtualFile>,
+        new: Option<&VirtualFile>,
+    ) -> Self {
+        // Stubs and unreadable lazy files have no content, so only size and hash can be compared
+        let mode = new.or(old).map(VirtualFile::mode);
+        let old_content = old.map(|f| f.load().ok());
+        let new_content = new.map(|f| f.load().ok());
+        let is_binary = [&old_content, &new_content].into_iter().any(|side| match side {
+            Some(Some(content)) => is_binary_content(content),
+            Some(None) => true,
+            None => false,
+        });
+        
+        if is_binary {
+            return Self {
+                path,
+                operation,
+                old_content: None,
+                new_content: None,
+                binary: Some(BinaryChange {
+                    old_len: old.map(|f| f.size).unwrap_or(0),
+                    new_len: new.map(|f| f.size).unwrap_or(0),
+                    old_hash: old.map(|f| f.hash.clone()),
+                    new_hash: new.map(|f| f.hash.clone()),
+                }),
+                unified: None,
+                mode,
+            };
+        }
+        
+        Self {
+            path,
+            operation,
+            old_content: old_content.flatten().map(|c| String::from_utf8_lossy(&c).into_owned()),
+            new_content: new_content.flatten().map(|c| String::from_utf8_lossy(&c).into_owned()),
+            binary: None,
+            unified: None,
+            mode,
+        }
+    }
+    
+    /// A text change between known contents, with its unified diff attached
+    ///
+    /// `old` is `None` when the file does not exist yet.
+    pub fn text(path: PathBuf, operation: DiffOperation, old: Option<&str>, new: &str) -> Self {
+        let unified = DiffEngine::unified_diff(old.unwrap_or(""), new, &path, &path);
+        Self {
+            path,
+            operation,
+            old_content: old.map(str::to_string),
+            new_content: Some(new.to_string()),
+            binary: None,
+            unified: Some(unified),
+            mode: None,
+        }
+    }
+    
+    /// Whether this diff describes a binary change
+    pub fn is_binary(&self) -> bool {
+        self.binary.is_some()
+    }
+    
+    /// Combine this change with a `later` one to the same path
+    ///
+    /// The result spans from before `self` to after `later`: Added then Modified
+    /// stays Added, anything then Deleted is Deleted, Deleted then re-created is
+    /// Modified, and Added then Deleted cancels out to `None`.
+    pub fn then(self, later: FileDiff) -> Option<FileDiff> {
+        let operation = match (&self.operation, later.operation.clone()) {
+            (DiffOperation::Added, DiffOperation::Deleted)
+            | (DiffOperation::DirectoryAdded, DiffOperation::DirectoryRemoved) => return None,
+            (DiffOperation::Added, _) => DiffOperation::Added,
+            (_, DiffOperation::Deleted) => DiffOperation::Deleted,
+            (DiffOperation::Deleted, DiffOperation::Added) => DiffOperation::Modified,
+            (DiffOperation::Renamed { from }, DiffOperation::Modified) => {
+                DiffOperation::Renamed { from: from.clone() }
+            }
+            (_, operation) => operation,
+        };
+        let creates = matches!(operation, DiffOperation::Added);
+        let mode = later.mode.or(self.mode);
+        
+        let binary = match (&self.binary, &later.binary) {
+            (None, None) => None,
+            (earlier, after) => Some(BinaryChange {
+                old_len: match earlier {
+                    _ if creates => 0,
+                    Some(b) => b.old_len,
+                    None => self.old_content.as_ref().map_or(0, |c| c.len() as u64),
+                },
+                new_len: match after {
+                    Some(b) => b.new_len,
+                    None => later.new_content.as_ref().map_or(0, |c| c.len() as u64),
+                },
+                old_hash: earlier.as_ref().filter(|_| !creates).and_then(|b| b.old_hash.clone()),
+                new_hash: after.as_ref().and_then(|b| b.new_hash.clone()),
+            }),
+        };
+        if binary.is_some() {
+            return Some(Self {
+                path: later.path,
+                operation,
+                old_content: None,
+                new_content: None,
+                binary,
+                unified: None,
+                mode,
+            });
+        }
+        
+        let old_content = if creates { None } else { self.old_content };
+        let new_content = later.new_content;
+        // Only real text on both sides gets a line diff, as in `text`
+        let unified = (self.unified.is_some() || later.unified.is_some()).then(|| {
+            DiffEngine::unified_diff(
+                old_content.as_deref().unwrap_or(""),
+                new_content.as_deref().unwrap_or(""),
+                &later.path,
+                &later.path,
+            )
+        });
+        
+        Some(Self {
+            path: later.path,
+            operation,
+            old_content,
+            new_content,
+            binary: None,
+            unified,
+            mode,
+        })
+    }
+    
     /// Format the diff for display
     pub fn format(&self) -> String {
-        match self.operation {
+        self.format_masked(&PathMasker::default())
+    }
+    
+    /// Format the diff for display, with paths passed through `masker`
+    pub fn format_masked(&self, masker: &PathMasker) -> String {
+        let path = masker.mask(&self.path);
+        if self.is_binary() {
+            let header = match &self.operation {
+                DiffOperation::Added => format!("+++ {}", path),
+                DiffOperation::Modified => format!("M  {}", path),
+                DiffOperation::Deleted => format!("D  {}", path),
+                DiffOperation::Renamed { from } => {
+                    format!("R  {} -> {}", masker.mask(from), path)
+                }
+                DiffOperation::DirectoryAdded
+                | DiffOperation::DirectoryRemoved
+                | DiffOperation::Symlinked { .. } => path.clone(),
+            };
+            return format!("{}\nBinary files differ\n", header);
+        }
+        
+        match &self.operation {
             DiffOperation::Added => {
                 format!(
                     "+++ {}\n{}\n",
-                    self.path.display(),
+                    path,
                     self.new_content.as_deref().unwrap_or("")
                 )
             }
             DiffOperation::Modified => {
                 format!(
                     "M  {}\n--- a/{}\n+++ b/{}\n{}\n",
-                    self.path.display(),
-                    self.path.display(),
-                    self.path.display(),
+                    path,
+                    path,
+                    path,
                     self.new_content.as_deref().unwrap_or("")
                 )
             }
             DiffOperation::Deleted => {
                 format!(
                     "D  {}\n{}\n",
-                    self.path.display(),
+                    path,
                     self.old_content.as_deref().unwrap_or("")
                 )
             }
+            DiffOperation::Renamed { from } => {
+                format!(
+                    "R  {} -> {}\n",
+                    masker.mask(from),
+                    path
+                )
+            }
+            DiffOperation::DirectoryAdded => {
+                format!("+++ {}/\n", path)
+            }
+            DiffOperation::DirectoryRemoved => {
+                format!("D  {}/\n", path)
+            }
+            DiffOperation::Symlinked { target } => {
+                format!("L  {} -> {}\n", path, target.display())
+            }
         }
     }
 }
@@ -0,0 +1,47 @@
+#![cfg(feature = "async")]
+
+use agent_sandbox::{ExecutionStatus, PermissionLevel, Sandbox, ToolPermission};
+
+#[tokio::test]
+async fn execute_async_runs_and_records_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+
+    let result = sandbox.execute_async("echo from-tokio").await.unwrap();
+
+    assert_eq!(result.status, ExecutionStatus::Success);
+    assert_eq!(result.stdout, "from-tokio\n");
+    assert_eq!(sandbox.history().len(), 1);
+    assert_eq!(sandbox.history()[0].id, result.id);
+}
+
+#[tokio::test]
+async fn execute_async_queues_commands_needing_approval() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.permissions.register_tool(
+        ToolPermission::new("echo")
+            .with_level(PermissionLevel::Execute)
+            .requires_approval(),
+    );
+
+    let result = sandbox.execute_async("echo later").await.unwrap();
+
+    assert_eq!(result.status, ExecutionStatus::PendingApproval);
+    assert!(sandbox.history().is_empty());
+    assert_eq!(sandbox.pending_approvals().len(), 1);
+}
+
+#[tokio::test]
+async fn execute_async_runs_pipelines() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+
+    let result = sandbox.execute_async("printf 'b\\na\\n' | sort").await.unwrap();
+
+    assert_eq!(result.status, ExecutionStatus::Success);
+    assert_eq!(result.stdout, "a\nb\n");
+    assert_eq!(result.stages.len(), 2);
+}
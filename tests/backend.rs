@@ -0,0 +1,98 @@
+use agent_sandbox::backend::CapturedStream;
+use agent_sandbox::{
+    BackendOutput, ExecutionBackend, ExecutionStatus, Invocation, NativeBackend, Sandbox,
+    SandboxError, SandboxResult,
+};
+use std::time::Duration;
+
+/// Reports the invocation it was given instead of running anything
+struct EchoInvocation;
+
+impl ExecutionBackend for EchoInvocation {
+    fn run(&self, invocation: &Invocation) -> SandboxResult<BackendOutput> {
+        let mut stdout = CapturedStream::default();
+        let line = format!("{} {}\n", invocation.tool, invocation.args.join(" "));
+        stdout.push(line.as_bytes(), invocation.max_output_bytes);
+        Ok(BackendOutput {
+            exit_code: Some(0),
+            killed: None,
+            stdout,
+            stderr: CapturedStream::default(),
+            duration: Duration::ZERO,
+            argv: None,
+        })
+    }
+}
+
+struct Unavailable;
+
+impl ExecutionBackend for Unavailable {
+    fn run(&self, invocation: &Invocation) -> SandboxResult<BackendOutput> {
+        Err(SandboxError::WasiError(format!("no module for {}", invocation.tool)))
+    }
+}
+
+#[test]
+fn tool_backend_takes_precedence_over_native_execution() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+    sandbox.set_tool_backend("greet", EchoInvocation);
+
+    let result = sandbox.execute("greet hello world").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Success);
+    assert_eq!(result.stdout, "greet hello world\n");
+
+    // Other tools still run natively
+    assert_eq!(sandbox.execute("echo native").unwrap().stdout, "native\n");
+}
+
+#[test]
+fn global_backend_runs_every_tool_and_errors_fail_the_result() {
+    let mut sandbox = Sandbox::new();
+    sandbox.allow_all();
+    sandbox.set_backend(Unavailable);
+    sandbox.set_tool_backend("echo", NativeBackend);
+
+    let result = sandbox.execute("tool.wasm --flag").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Failed);
+    assert_eq!(result.stderr, "WASI error: no module for tool.wasm");
+
+    assert_eq!(sandbox.execute("echo still native").unwrap().stdout, "still native\n");
+}
+
+#[test]
+fn native_backend_runs_invocations_directly() {
+    let dir = tempfile::tempdir().unwrap();
+    let invocation = Invocation {
+        tool: "cat".to_string(),
+        args: Vec::new(),
+        cwd: dir.path().to_path_buf(),
+        stdin: Some(b"piped".to_vec()),
+        timeout: Some(Duration::from_secs(5)),
+        max_output_bytes: Some(3),
+        ..Default::default()
+    };
+
+    let output = NativeBackend.run(&invocation).unwrap();
+    assert_eq!(output.exit_code, Some(0));
+    assert_eq!(output.killed, None);
+    assert_eq!(output.stdout.bytes, b"pip");
+    assert_eq!(output.stdout.dropped, 2);
+    assert_eq!(output.argv, Some(vec!["cat".to_string()]));
+}
+
+#[test]
+fn native_backend_reports_the_argv_it_started() {
+    let dir = tempfile::tempdir().unwrap();
+    let invocation = Invocation {
+        tool: "echo".to_string(),
+        args: vec!["a b".to_string(), "c".to_string()],
+        cwd: dir.path().to_path_buf(),
+        ..Default::default()
+    };
+
+    let output = NativeBackend.run(&invocation).unwrap();
+    assert_eq!(output.argv, Some(invocation.argv()));
+    assert_eq!(output.stdout.bytes, b"a b c\n");
+}
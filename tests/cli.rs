@@ -0,0 +1,346 @@
+use agent_sandbox::{CheckOutcome, ExecutionResult, ExecutionStatus};
+use std::process::Command;
+
+fn agent_sandbox(dir: &std::path::Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_agent-sandbox"))
+        .arg("--working-dir")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn json_run_output_parses_as_an_execution_result() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["--allow-all", "--format", "json", "run", "echo hello"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let result: ExecutionResult = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(result.status, ExecutionStatus::Success);
+    assert_eq!(result.tool, "echo");
+    assert_eq!(result.stdout, "hello\n");
+}
+
+#[test]
+fn json_pending_result_carries_the_approval_notice() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["--format", "json", "run", "chmod +x script.sh"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let result: ExecutionResult = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(result.status, ExecutionStatus::PendingApproval);
+    let notice = json["approval_notice"].as_str().unwrap();
+    assert!(notice.contains(&result.id), "{}", notice);
+}
+
+#[test]
+fn json_status_is_a_single_document() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["--format", "json", "status"]);
+    assert!(output.status.success());
+
+    let status: agent_sandbox::SandboxStatus = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status.execution_count, 0);
+}
+
+#[test]
+fn apply_writes_staged_changes_to_disk() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = agent_sandbox(
+        dir.path(),
+        &[
+            "--allow-all",
+            "--format",
+            "json",
+            "apply",
+            "echo first > notes.txt",
+            "echo second >> notes.txt",
+        ],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("notes.txt")).unwrap(),
+        "first\nsecond\n"
+    );
+
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["written"], serde_json::json!(["notes.txt"]));
+}
+
+#[test]
+fn apply_skips_deletions_of_missing_files() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = agent_sandbox(dir.path(), &["--allow-all", "apply", "rm missing.txt", "echo x > a.txt"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_to_string(dir.path().join("a.txt")).unwrap(), "x\n");
+}
+
+#[test]
+fn apply_refuses_when_nothing_is_pending() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = agent_sandbox(dir.path(), &["--allow-all", "apply", "ls"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("no pending virtual changes"));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn sandbox_errors_exit_with_their_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["run", "whoami"]);
+    assert_eq!(output.status.code(), Some(13));
+}
+
+#[test]
+fn missing_working_dir_fails_fast() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(&dir.path().join("typo"), &["status"]);
+    assert_eq!(output.status.code(), Some(73));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("does not exist"));
+}
+
+/// Levels of the JSON log lines a CLI run wrote to stdout
+fn logged_levels(output: &std::process::Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|json| json["level"].as_str().map(str::to_string))
+        .collect()
+}
+
+#[test]
+fn verbose_and_quiet_change_the_log_level() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |flag: Option<&str>| {
+        let mut args = vec!["--allow-all", "--log-format", "json"];
+        args.extend(flag);
+        args.extend(["run", "echo hi"]);
+        logged_levels(&agent_sandbox(dir.path(), &args))
+    };
+
+    let default = run(None);
+    assert!(default.iter().any(|level| level == "INFO"), "{:?}", default);
+    assert!(!default.iter().any(|level| level == "DEBUG"), "{:?}", default);
+
+    let verbose = run(Some("--verbose"));
+    assert!(verbose.iter().any(|level| level == "DEBUG"), "{:?}", verbose);
+
+    let quiet = run(Some("--quiet"));
+    assert!(quiet.is_empty(), "{:?}", quiet);
+}
+
+#[test]
+fn json_logs_carry_the_current_span() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(
+        dir.path(),
+        &["--allow-all", "--log-format", "json", "--verbose", "run", "echo hi"],
+    );
+
+    let check = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|json| json["fields"]["message"] == "permission check")
+        .expect("no permission check logged");
+    assert_eq!(check["span"]["name"], "execute");
+    assert_eq!(check["span"]["command"], "echo hi");
+}
+
+#[test]
+fn check_prints_the_decision_as_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["--format", "json", "check", "npm publish"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let outcome: CheckOutcome = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(outcome.tool, "npm");
+    assert!(!outcome.is_allowed());
+}
+
+#[test]
+fn export_diff_writes_a_patch_of_session_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "one\ntwo\n").unwrap();
+    let session = dir.path().join("session.json");
+    let patch = dir.path().join("changes.patch");
+
+    let mut sandbox = agent_sandbox::Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox
+        .virtual_fs
+        .write("notes.txt".into(), b"one\nTWO\n".to_vec());
+    sandbox.save_session(&session).unwrap();
+
+    let session_arg = session.to_str().unwrap();
+    let output = agent_sandbox(
+        dir.path(),
+        &["--session", session_arg, "export-diff", "--output", patch.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let patch = std::fs::read_to_string(&patch).unwrap();
+    assert!(patch.contains("diff --git a/notes.txt b/notes.txt\n"), "{}", patch);
+    assert!(patch.contains("@@ -1,2 +1,2 @@\n one\n-two\n+TWO\n"), "{}", patch);
+    // Exporting does not touch the working directory
+    assert_eq!(std::fs::read_to_string(dir.path().join("notes.txt")).unwrap(), "one\ntwo\n");
+}
+
+#[test]
+fn export_diff_fails_without_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let patch = dir.path().join("changes.patch");
+    let output = agent_sandbox(dir.path(), &["export-diff", "--output", patch.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(!patch.exists());
+}
+
+#[test]
+fn pending_lists_queued_executions_from_the_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("session.json");
+    let session_arg = session.to_str().unwrap();
+
+    let output = agent_sandbox(
+        dir.path(),
+        &["--session", session_arg, "--format", "json", "run", "chmod +x script.sh"],
+    );
+    let queued: ExecutionResult = serde_json::from_slice(&output.stdout).unwrap();
+
+    let output = agent_sandbox(dir.path(), &["--session", session_arg, "--format", "json", "pending"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["id"], queued.id.as_str());
+    assert_eq!(entries[0]["command"], "chmod +x script.sh");
+    assert!(entries[0]["age_secs"].as_i64().unwrap() >= 0);
+}
+
+#[test]
+fn report_renders_the_session_as_markdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("session.json");
+
+    let mut sandbox = agent_sandbox::Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.virtual_fs.write("draft.txt".into(), b"one\ntwo\n".to_vec());
+    sandbox.save_session(&session).unwrap();
+
+    let session_arg = session.to_str().unwrap();
+    let output = agent_sandbox(dir.path(), &["--session", session_arg, "report", "--markdown"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let markdown = String::from_utf8(output.stdout).unwrap();
+    assert!(markdown.starts_with("# Session report\n"), "{}", markdown);
+    assert!(markdown.contains("- `draft.txt`\n"), "{}", markdown);
+
+    // Markdown is specific to `report`, not a global output format
+    assert!(!agent_sandbox(dir.path(), &["--format", "md", "status"]).status.success());
+    assert!(!agent_sandbox(dir.path(), &["--format", "json", "report", "--markdown"]).status.success());
+}
+
+#[test]
+fn environment_permissions_apply_under_explicit_flags() {
+    let dir = tempfile::tempdir().unwrap();
+    let check = |env: &[(&str, &str)], extra: &[&str]| {
+        let mut args = extra.to_vec();
+        args.extend(["--format", "json", "check", "whoami"]);
+        Command::new(env!("CARGO_BIN_EXE_agent-sandbox"))
+            .arg("--working-dir")
+            .arg(dir.path())
+            .args(&args)
+            .envs(env.iter().copied())
+            .output()
+            .unwrap()
+    };
+    let allowed = |output: std::process::Output| {
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice::<CheckOutcome>(&output.stdout).unwrap().is_allowed()
+    };
+
+    assert!(!allowed(check(&[], &[])));
+    assert!(allowed(check(&[("SANDBOX_ALLOW", "whoami")], &[])));
+    assert!(!allowed(check(&[("SANDBOX_DENY", "whoami")], &[])));
+    assert!(allowed(check(&[("SANDBOX_DENY", "whoami")], &["--allow", "whoami"])));
+
+    let output = check(&[("SANDBOX_DEFAULT_LEVEL", "superuser")], &[]);
+    assert_eq!(output.status.code(), Some(78));
+}
+
+#[test]
+fn repl_runs_lines_in_one_session() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_agent-sandbox"))
+        .arg("--working-dir")
+        .arg(dir.path())
+        .args(["--allow-all", "repl"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"sh -c 'echo one > a.txt'\n\
+              sh -c 'echo two >> a.txt'\n\
+              \n\
+              :status\n\
+              :diff\n\
+              :bogus\n\
+              :quit\n\
+              sh -c 'echo never > b.txt'\n",
+        )
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Executions: 2"), "{}", stdout);
+    assert!(stdout.contains("a.txt: Added"), "{}", stdout);
+    assert!(stdout.contains("unknown meta-command ':bogus'"), "{}", stdout);
+    assert_eq!(std::fs::read_to_string(dir.path().join("a.txt")).unwrap(), "one\ntwo\n");
+    assert!(!dir.path().join("b.txt").exists());
+}
+
+#[test]
+fn diff_stat_prints_per_file_line_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = agent_sandbox(dir.path(), &["--allow-all", "diff", "--stat", "echo hi > out.txt"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" out.txt | 1 +\n"), "{}", stdout);
+    assert!(stdout.contains(" 1 file changed, 1 insertion(+)\n"), "{}", stdout);
+    assert!(!dir.path().join("out.txt").exists());
+
+    let output = agent_sandbox(
+        dir.path(),
+        &["--allow-all", "--format", "json", "diff", "--stat", "echo hi > out.txt"],
+    );
+    let stat: agent_sandbox::MultiFileStat = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!((stat.files.len(), stat.insertions, stat.deletions), (1, 1, 0));
+}
+
+#[test]
+fn check_reports_path_rule_violations() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = agent_sandbox(dir.path(), &["--format", "json", "check", "rm /etc/passwd"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let outcome: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        outcome["violations"],
+        serde_json::json!(["Path not allowed: 'rm' may not access /etc/passwd"])
+    );
+
+    let output = agent_sandbox(dir.path(), &["--format", "json", "check", "rm /tmp/scratch"]);
+    let outcome: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(outcome["violations"], serde_json::json!([]));
+}
@@ -0,0 +1,541 @@
+use agent_sandbox::diff_engine::{DiffChangeType, DiffEngine};
+use agent_sandbox::{DiffOperation, FileDiff, VirtualFile};
+use std::path::Path;
+use std::process::Command;
+
+fn numbered_lines(count: usize) -> String {
+    (1..=count).map(|i| format!("line {}\n", i)).collect()
+}
+
+#[test]
+fn unified_diff_includes_context_and_splits_hunks() {
+    let old = numbered_lines(20);
+    let new = old
+        .replace("line 2\n", "line two\n")
+        .replace("line 18\n", "line eighteen\n");
+
+    let diff = DiffEngine::unified_diff(&old, &new, Path::new("f.txt"), Path::new("f.txt"));
+
+    assert_eq!(diff.hunks.len(), 2);
+    let first = &diff.hunks[0];
+    assert_eq!((first.old_start, first.old_lines), (1, 5));
+    assert_eq!((first.new_start, first.new_lines), (1, 5));
+    assert_eq!(first.changes[0].change_type, DiffChangeType::Equal);
+
+    let merged = DiffEngine::unified_diff_with_context(
+        &old,
+        &new,
+        Path::new("f.txt"),
+        Path::new("f.txt"),
+        10,
+    );
+    assert_eq!(merged.hunks.len(), 1);
+}
+
+#[test]
+fn unified_diff_applies_with_patch() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = numbered_lines(30);
+    let new = old
+        .replace("line 1\n", "")
+        .replace("line 15\n", "line 15\ninserted\n")
+        .replace("line 29\n", "line twenty-nine\n");
+    std::fs::write(dir.path().join("f.txt"), &old).unwrap();
+
+    let diff = DiffEngine::unified_diff(&old, &new, Path::new("f.txt"), Path::new("f.txt"));
+    std::fs::write(
+        dir.path().join("change.patch"),
+        DiffEngine::format_unified_diff(&diff),
+    )
+    .unwrap();
+
+    let status = Command::new("patch")
+        .args(["-p1", "-s", "-i", "change.patch"])
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read_to_string(dir.path().join("f.txt")).unwrap(), new);
+}
+
+#[test]
+fn side_by_side_diff_stays_aligned() {
+    use agent_sandbox::diff_engine::side_by_side_diff_with_width;
+    use unicode_width::UnicodeWidthStr;
+
+    let old = "fn main() {\n\tprintln!(\"hi\");\n}\n";
+    let new = "fn main() {\n\tprintln!(\"你好, 世界\");\n\t// a comment long enough that it cannot fit in the column\n}\n";
+
+    let output = side_by_side_diff_with_width(old, new, 60);
+    let widths: Vec<usize> = output.lines().map(|l| l.width()).collect();
+
+    assert!(widths.iter().all(|w| *w == 59), "{:?}\n{}", widths, output);
+    assert!(output.contains('…'));
+    assert!(!output.contains('\t'));
+}
+
+#[test]
+fn colored_diff_wraps_inserted_lines() {
+    let diff = DiffEngine::unified_diff("a\n", "a\nb\n", Path::new("f"), Path::new("f"));
+
+    let colored = DiffEngine::format_unified_diff_with_color(&diff, true);
+    assert!(colored.contains("\x1b[32m+b\x1b[0m\n"));
+    assert!(colored.contains("\x1b[36m@@"));
+
+    let plain = DiffEngine::format_unified_diff_with_color(&diff, false);
+    assert!(!plain.contains('\x1b'));
+    assert_eq!(plain, DiffEngine::format_unified_diff(&diff));
+}
+
+#[test]
+fn html_output_escapes_content_and_classes_rows() {
+    let old = "<p>keep</p>\nold line\n";
+    let new = "<p>keep</p>\n<script>alert(1)</script>\n";
+    let diff = DiffEngine::unified_diff(old, new, Path::new("a<b>.html"), Path::new("a<b>.html"));
+
+    let html = DiffEngine::to_html(&diff);
+
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    assert!(html.contains("a&lt;b&gt;.html"));
+    assert!(html.contains(
+        "<tr class=\"diff-add\"><td class=\"diff-line-old\"></td><td class=\"diff-line-new\">2</td>"
+    ));
+    assert!(html.contains(
+        "<tr class=\"diff-del\"><td class=\"diff-line-old\">2</td><td class=\"diff-line-new\"></td>"
+    ));
+    assert!(html.contains("<tr class=\"diff-ctx\"><td class=\"diff-line-old\">1</td>"));
+}
+
+#[test]
+fn json_round_trip_preserves_diff() {
+    use agent_sandbox::UnifiedDiff;
+
+    let diff = DiffEngine::unified_diff(
+        "one\ntwo\nthree\n",
+        "one\n2\nthree\nfour\n",
+        Path::new("n.txt"),
+        Path::new("n.txt"),
+    );
+
+    let json = DiffEngine::to_json(&diff).unwrap();
+    assert!(json.contains("\"change_type\": \"Insert\""));
+
+    let parsed: UnifiedDiff = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, diff);
+}
+
+#[test]
+fn apply_reproduces_new_content() {
+    let old = numbered_lines(25);
+    let new = old
+        .replace("line 3\n", "line three\n")
+        .replace("line 20\n", "");
+
+    let diff = DiffEngine::unified_diff(&old, &new, Path::new("f"), Path::new("f"));
+    assert_eq!(DiffEngine::apply(&old, &diff).unwrap(), new);
+}
+
+#[test]
+fn apply_reports_context_mismatch() {
+    use agent_sandbox::SandboxError;
+
+    let old = numbered_lines(10);
+    let new = old.replace("line 5\n", "line five\n");
+    let diff = DiffEngine::unified_diff(&old, &new, Path::new("f"), Path::new("f"));
+
+    let drifted = old.replace("line 4\n", "line four\n");
+    let err = DiffEngine::apply(&drifted, &diff).unwrap_err();
+    match err {
+        SandboxError::PatchError(msg) => {
+            assert!(msg.contains("hunk #1"), "{}", msg);
+            assert!(msg.contains("line 4"), "{}", msg);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn apply_inserts_into_empty_original() {
+    let diff = DiffEngine::unified_diff("", "hello\nworld\n", Path::new("f"), Path::new("f"));
+    assert_eq!(diff.hunks[0].old_start, 0);
+    assert_eq!(DiffEngine::apply("", &diff).unwrap(), "hello\nworld\n");
+}
+
+const GIT_PATCH: &str = "diff --git a/src/lib.rs b/src/lib.rs
+index 3b18e51..a9c2f4d 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,4 +1,4 @@
+-pub fn add(a: i32, b: i32) -> i32 {
++pub fn add(a: i64, b: i64) -> i64 {
+     a + b
+ }
+ 
+@@ -10,3 +10,5 @@ mod tests {
+     fn it_adds() {
+         assert_eq!(add(2, 2), 4);
+     }
++
++    // TODO: overflow
+";
+
+#[test]
+fn parse_unified_reads_git_patch() {
+    let diff = DiffEngine::parse_unified(GIT_PATCH).unwrap();
+
+    assert_eq!(diff.old_path, Path::new("src/lib.rs"));
+    assert_eq!(diff.new_path, Path::new("src/lib.rs"));
+    assert_eq!(diff.hunks.len(), 2);
+    assert_eq!(diff.hunks[1].new_start, 10);
+    assert_eq!(diff.hunks[1].new_lines, 5);
+    assert_eq!(diff.hunks[0].changes[0].change_type, DiffChangeType::Delete);
+    assert_eq!(diff.hunks[0].changes[1].change_type, DiffChangeType::Insert);
+    assert_eq!(diff.hunks[1].changes[4].content, "    // TODO: overflow\n");
+
+    let body = GIT_PATCH.split_once("--- ").unwrap().1;
+    assert_eq!(DiffEngine::format_unified_diff(&diff), format!("--- {}", body));
+}
+
+#[test]
+fn parse_unified_tolerates_missing_trailing_newline() {
+    let text = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-old\n\\ No newline at end of file\n+new";
+    let diff = DiffEngine::parse_unified(text).unwrap();
+
+    let changes = &diff.hunks[0].changes;
+    assert_eq!(changes[0].content, "old");
+    assert_eq!(changes[1].content, "new\n");
+    assert_eq!(DiffEngine::apply("old", &diff).unwrap(), "new\n");
+}
+
+#[test]
+fn word_diff_highlights_only_the_changed_word() {
+    let changes = DiffEngine::word_diff("keep\nthe quick fox\n", "keep\nthe slow fox\n");
+
+    assert_eq!(changes.len(), 3);
+    let (deleted, inserted) = (&changes[1], &changes[2]);
+    assert_eq!(deleted.change_type, DiffChangeType::Delete);
+    assert_eq!(inserted.change_type, DiffChangeType::Insert);
+    assert_eq!(deleted.highlights, vec![4..9]);
+    assert_eq!(inserted.highlights, vec![4..8]);
+    assert_eq!(&inserted.content[4..8], "slow");
+    assert!(changes[0].highlights.is_empty());
+}
+
+#[test]
+fn formatter_highlights_changed_spans_when_asked() {
+    use agent_sandbox::FormatOptions;
+
+    let diff = DiffEngine::unified_diff("the quick fox\n", "the slow fox\n", Path::new("f"), Path::new("f"));
+    let options = FormatOptions {
+        color: true,
+        word_diff: true,
+    };
+
+    let output = DiffEngine::format_unified_diff_with_options(&diff, &options);
+    assert!(output.contains("\x1b[32m+the \x1b[7mslow\x1b[27m fox\x1b[0m\n"), "{:?}", output);
+    assert!(!DiffEngine::format_unified_diff_colored(&diff).contains("\x1b[7m"));
+}
+
+#[test]
+fn multi_file_stat_counts_real_lines() {
+    use agent_sandbox::{DiffOperation, FileDiff};
+    use std::path::PathBuf;
+
+    let file = |path: &str, operation, old: Option<&str>, new: Option<&str>| FileDiff {
+        path: PathBuf::from(path),
+        operation,
+        old_content: old.map(str::to_string),
+        new_content: new.map(str::to_string),
+        binary: None,
+        unified: None,
+        mode: None,
+    };
+    let diffs = vec![
+        file("src/lib.rs", DiffOperation::Modified, Some("a\nb\nc\n"), Some("a\nB\nc\nd\n")),
+        file("new.txt", DiffOperation::Added, None, Some("1\n2\n3\n")),
+        file("old.txt", DiffOperation::Deleted, Some("x\ny\n"), None),
+    ];
+
+    let stat = DiffEngine::multi_file_stat(&diffs);
+
+    assert_eq!((stat.insertions, stat.deletions), (5, 3));
+    assert_eq!((stat.files[0].added, stat.files[0].deleted), (2, 1));
+    assert_eq!(stat.summary_line(), "3 files changed, 5 insertions(+), 3 deletions(-)");
+    assert_eq!(
+        stat.format(),
+        " src/lib.rs | 3 ++-\n new.txt    | 3 +++\n old.txt    | 2 --\n 3 files changed, 5 insertions(+), 3 deletions(-)\n"
+    );
+}
+
+const MOVED_BLOCK_OLD: &str = r#"#include <stdio.h>
+
+// Frobs foo heartily
+int frobnitz(int foo)
+{
+    int i;
+    for(i = 0; i < 10; i++)
+    {
+        printf("Your answer is: ");
+        printf("%d\n", foo);
+    }
+}
+
+int fact(int n)
+{
+    if(n > 1)
+    {
+        return fact(n-1) * n;
+    }
+    return 1;
+}
+
+int main(int argc, char **argv)
+{
+    frobnitz(fact(10));
+}
+"#;
+
+const MOVED_BLOCK_NEW: &str = r#"#include <stdio.h>
+
+int fib(int n)
+{
+    if(n > 2)
+    {
+        return fib(n-1) + fib(n-2);
+    }
+    return 1;
+}
+
+// Frobs foo heartily
+int frobnitz(int foo)
+{
+    int i;
+    for(i = 0; i < 10; i++)
+    {
+        printf("%d\n", foo);
+    }
+}
+
+int main(int argc, char **argv)
+{
+    frobnitz(fib(10));
+}
+"#;
+
+#[test]
+fn patience_keeps_moved_block_intact() {
+    use agent_sandbox::{DiffAlgorithm, DiffOptions};
+
+    let diff_with = |algorithm| {
+        DiffEngine::unified_diff_with_options(
+            MOVED_BLOCK_OLD,
+            MOVED_BLOCK_NEW,
+            Path::new("frob.c"),
+            Path::new("frob.c"),
+            &DiffOptions { context: 0, algorithm },
+        )
+    };
+
+    let myers = diff_with(DiffAlgorithm::Myers);
+    let patience = diff_with(DiffAlgorithm::Patience);
+
+    // Myers matches braces and blank lines across the two functions
+    assert_eq!(myers.hunks.len(), 9);
+    assert_eq!(patience.hunks.len(), 4);
+    assert!(patience
+        .hunks
+        .iter()
+        .flat_map(|h| &h.changes)
+        .all(|c| c.content != "// Frobs foo heartily\n"));
+
+    assert_eq!(DiffEngine::apply(MOVED_BLOCK_OLD, &patience).unwrap(), MOVED_BLOCK_NEW);
+    let default = DiffEngine::unified_diff_with_context(
+        MOVED_BLOCK_OLD,
+        MOVED_BLOCK_NEW,
+        Path::new("frob.c"),
+        Path::new("frob.c"),
+        0,
+    );
+    assert_eq!(myers, default);
+}
+
+#[test]
+fn dropping_the_final_newline_round_trips() {
+    let old = "one\ntwo\n";
+    let new = "one\ntwo";
+
+    let diff = DiffEngine::unified_diff(old, new, Path::new("f"), Path::new("f"));
+    let text = DiffEngine::format_unified_diff(&diff);
+
+    assert!(text.ends_with("-two\n+two\n\\ No newline at end of file\n"), "{}", text);
+    assert_eq!(DiffEngine::apply(old, &diff).unwrap().as_bytes(), new.as_bytes());
+
+    let parsed = DiffEngine::parse_unified(&text).unwrap();
+    assert_eq!(parsed, diff);
+    assert_eq!(DiffEngine::apply(old, &parsed).unwrap().as_bytes(), new.as_bytes());
+}
+
+#[test]
+fn adding_a_final_newline_round_trips() {
+    let old = "one\ntwo";
+    let new = "one\ntwo\nthree\n";
+
+    let diff = DiffEngine::unified_diff(old, new, Path::new("f"), Path::new("f"));
+    let text = DiffEngine::format_unified_diff(&diff);
+
+    assert!(text.contains("-two\n\\ No newline at end of file\n+two\n"), "{}", text);
+    assert_eq!(DiffEngine::apply(old, &diff).unwrap().as_bytes(), new.as_bytes());
+
+    let parsed = DiffEngine::parse_unified(&text).unwrap();
+    assert_eq!(DiffEngine::apply(old, &parsed).unwrap().as_bytes(), new.as_bytes());
+}
+
+#[test]
+fn format_patch_writes_git_style_headers_for_each_file() {
+    let changes = vec![
+        FileDiff::text("new.txt".into(), DiffOperation::Added, None, "hello\n"),
+        FileDiff::text("edit.txt".into(), DiffOperation::Modified, Some("a\nb\n"), "a\nc\n"),
+        FileDiff {
+            path: "gone.txt".into(),
+            operation: DiffOperation::Deleted,
+            old_content: Some("bye\n".into()),
+            new_content: None,
+            binary: None,
+            unified: None,
+            mode: None,
+        },
+    ];
+
+    let patch = DiffEngine::format_patch(&changes, 3);
+    assert_eq!(
+        patch,
+        "diff --git a/new.txt b/new.txt\n\
+         new file mode 100644\n\
+         --- /dev/null\n\
+         +++ b/new.txt\n\
+         @@ -0,0 +1,1 @@\n\
+         +hello\n\
+         diff --git a/edit.txt b/edit.txt\n\
+         --- a/edit.txt\n\
+         +++ b/edit.txt\n\
+         @@ -1,2 +1,2 @@\n \
+         a\n\
+         -b\n\
+         +c\n\
+         diff --git a/gone.txt b/gone.txt\n\
+         deleted file mode 100644\n\
+         --- a/gone.txt\n\
+         +++ /dev/null\n\
+         @@ -1,1 +0,0 @@\n\
+         -bye\n"
+    );
+    assert!(DiffEngine::format_patch(&[], 3).is_empty());
+}
+
+#[test]
+fn format_patch_keeps_the_exec_bit_of_new_files() {
+    let script = VirtualFile::new_executable("run.sh".into(), b"echo hi\n".to_vec());
+    let changes = vec![
+        FileDiff::from_files("run.sh".into(), DiffOperation::Added, None, Some(&script)),
+        FileDiff::text("notes.txt".into(), DiffOperation::Added, None, "hi\n"),
+    ];
+
+    let patch = DiffEngine::format_patch(&changes, 3);
+    assert!(patch.contains("diff --git a/run.sh b/run.sh\nnew file mode 100755\n"), "{}", patch);
+    assert!(patch.contains("diff --git a/notes.txt b/notes.txt\nnew file mode 100644\n"), "{}", patch);
+}
+
+#[test]
+fn summarize_file_diffs_counts_files_by_operation() {
+    let changes = vec![
+        FileDiff::text("new.txt".into(), DiffOperation::Added, None, "a\nb\n"),
+        FileDiff::text("edit.txt".into(), DiffOperation::Modified, Some("a\n"), "b\n"),
+        FileDiff::text(
+            "moved.txt".into(),
+            DiffOperation::Renamed { from: "old.txt".into() },
+            Some("x\n"),
+            "x\n",
+        ),
+        FileDiff {
+            path: "gone.txt".into(),
+            operation: DiffOperation::Deleted,
+            old_content: Some("bye\n".into()),
+            new_content: None,
+            binary: None,
+            unified: None,
+            mode: None,
+        },
+        FileDiff {
+            path: "dir".into(),
+            operation: DiffOperation::DirectoryAdded,
+            old_content: None,
+            new_content: None,
+            binary: None,
+            unified: None,
+            mode: None,
+        },
+    ];
+
+    let summary = DiffEngine::summarize_file_diffs(&changes);
+    assert_eq!(summary.files_added, 1);
+    assert_eq!(summary.files_modified, 2);
+    assert_eq!(summary.files_deleted, 1);
+    assert_eq!((summary.added, summary.deleted), (3, 2));
+    assert_eq!(summary.format_files(), "4 files changed (1 added, 2 modified, 1 deleted)");
+}
+
+#[test]
+fn deleting_an_empty_file_is_a_change() {
+    let changes = vec![FileDiff {
+        path: "empty.txt".into(),
+        operation: DiffOperation::Deleted,
+        old_content: Some(String::new()),
+        new_content: None,
+        binary: None,
+        unified: None,
+        mode: None,
+    }];
+
+    let summary = DiffEngine::summarize_file_diffs(&changes);
+    assert_eq!((summary.added, summary.deleted), (0, 0));
+    assert_eq!(summary.files_deleted, 1);
+    assert!(summary.has_changes());
+    assert!(!DiffEngine::summarize_file_diffs(&[]).has_changes());
+}
+
+#[test]
+fn path_masker_scrubs_home_prefixes_from_headers() {
+    use agent_sandbox::{FormatOptions, PathMasker};
+
+    let masker = PathMasker::new()
+        .with_rule("/home/alice", "$HOME")
+        .with_rule("/home/alice/project", "$PROJECT");
+    assert_eq!(masker.mask(Path::new("/home/alice/project/src/x.rs")), "$PROJECT/src/x.rs");
+    assert_eq!(masker.mask(Path::new("/home/alice/notes.txt")), "$HOME/notes.txt");
+    // Prefixes match whole components only
+    assert_eq!(masker.mask(Path::new("/home/alicia/x")), "/home/alicia/x");
+
+    let path = Path::new("/home/alice/project/src/x.rs");
+    let diff = DiffEngine::unified_diff("a\n", "b\n", path, path);
+    let formatted = DiffEngine::format_unified_diff_masked(&diff, &FormatOptions::default(), &masker);
+    assert!(
+        formatted.starts_with("--- a/$PROJECT/src/x.rs\n+++ b/$PROJECT/src/x.rs\n"),
+        "{}",
+        formatted
+    );
+    assert!(!formatted.contains("alice"));
+
+    let renamed = FileDiff {
+        path: path.to_path_buf(),
+        operation: DiffOperation::Renamed {
+            from: "/home/alice/project/src/old.rs".into(),
+        },
+        old_content: None,
+        new_content: None,
+        binary: None,
+        unified: None,
+        mode: None,
+    };
+    assert_eq!(renamed.format_masked(&masker), "R  $PROJECT/src/old.rs -> $PROJECT/src/x.rs\n");
+}
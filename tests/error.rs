@@ -0,0 +1,42 @@
+use agent_sandbox::SandboxError;
+use std::path::PathBuf;
+
+#[test]
+fn exit_codes_are_pinned_per_variant() {
+    let io = std::io::Error::other("disk");
+    let spawn = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+    let serde = serde_json::from_str::<u32>("not json").unwrap_err();
+
+    let cases = [
+        (SandboxError::PermissionDenied(String::new()), 13),
+        (
+            SandboxError::PathNotAllowed {
+                tool: "rm".to_string(),
+                path: PathBuf::from("/etc"),
+            },
+            14,
+        ),
+        (SandboxError::InvalidCommand(String::new()), 64),
+        (SandboxError::PatchError(String::new()), 65),
+        (SandboxError::VirtualFileNotFound(String::new()), 66),
+        (SandboxError::WasiError(String::new()), 70),
+        (SandboxError::SimulationError(String::new()), 71),
+        (SandboxError::FileSystemError(String::new()), 73),
+        (SandboxError::IoError(io), 74),
+        (SandboxError::ApprovalExpired(String::new()), 75),
+        (SandboxError::SerdeError(serde), 76),
+        (SandboxError::InvalidConfig(String::new()), 78),
+        (
+            SandboxError::SpawnFailed {
+                tool: "script.sh".to_string(),
+                source: spawn,
+            },
+            126,
+        ),
+        (SandboxError::CommandNotFound(String::new()), 127),
+    ];
+
+    for (error, code) in cases {
+        assert_eq!(error.code(), code, "{:?}", error);
+    }
+}
@@ -0,0 +1,231 @@
+use agent_sandbox::{PermissionGate, PermissionLevel, SandboxError, ToolPermission, UnknownToolPolicy};
+use std::path::Path;
+
+#[test]
+fn denied_path_is_reported_as_path_not_allowed() {
+    let gate = PermissionGate::default_permissions();
+
+    assert!(gate.check_path("rm", Path::new("/tmp/scratch")).is_ok());
+
+    let err = gate.check_path("rm", Path::new("/etc/passwd")).unwrap_err();
+    match &err {
+        SandboxError::PathNotAllowed { tool, path } => {
+            assert_eq!(tool, "rm");
+            assert_eq!(path, Path::new("/etc/passwd"));
+        }
+        other => panic!("expected PathNotAllowed, got {:?}", other),
+    }
+    assert_eq!(err.to_string(), "Path not allowed: 'rm' may not access /etc/passwd");
+}
+
+#[test]
+fn denied_tools_and_unknown_tools_stay_permission_denied() {
+    let mut gate = PermissionGate::new();
+    gate.register_tool(
+        ToolPermission::new("cat")
+            .with_level(PermissionLevel::ReadOnly)
+            .deny_path("secrets".into()),
+    );
+    gate.register_tool(ToolPermission::new("sudo").with_level(PermissionLevel::Denied));
+
+    assert!(matches!(
+        gate.check_path("cat", Path::new("secrets/key")),
+        Err(SandboxError::PathNotAllowed { .. })
+    ));
+    assert!(gate.check_path("cat", Path::new("src/lib.rs")).is_ok());
+    assert!(matches!(
+        gate.check_path("sudo", Path::new("src/lib.rs")),
+        Err(SandboxError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        gate.check_path("whoami", Path::new("src/lib.rs")),
+        Err(SandboxError::PermissionDenied(_))
+    ));
+}
+
+#[test]
+fn glob_path_rules_with_denied_globs_taking_precedence() {
+    let permission = ToolPermission::new("cat")
+        .with_level(PermissionLevel::ReadOnly)
+        .allow_path("src/**".into())
+        .allow_path("**/*.md".into())
+        .deny_path("src/secrets/**".into());
+
+    assert!(permission.check_path(Path::new("src/lib.rs")));
+    assert!(permission.check_path(Path::new("docs/guide/intro.md")));
+    assert!(!permission.check_path(Path::new("src/secrets/api.key")));
+    assert!(!permission.check_path(Path::new("src/secrets/README.md")));
+    assert!(!permission.check_path(Path::new("target/debug/app")));
+
+    let mut gate = PermissionGate::new();
+    gate.register_tool(permission);
+    assert!(matches!(
+        gate.check_path("cat", Path::new("src/secrets/api.key")),
+        Err(SandboxError::PathNotAllowed { .. })
+    ));
+}
+
+#[test]
+fn check_path_in_matches_relative_rules_under_the_root() {
+    let permission = ToolPermission::new("rm")
+        .allow_path("src/**".into())
+        .allow_path("/tmp".into())
+        .deny_path("src/secrets".into());
+    let root = Path::new("/work/repo");
+
+    assert!(permission.check_path_in(root, Path::new("/work/repo/src/lib.rs")));
+    assert!(permission.check_path_in(root, Path::new("/tmp/scratch")));
+    assert!(!permission.check_path_in(root, Path::new("/work/repo/src/secrets/api.key")));
+    assert!(!permission.check_path_in(root, Path::new("/work/repo/notes.txt")));
+    assert!(!permission.check_path_in(root, Path::new("/elsewhere/src/lib.rs")));
+}
+
+#[test]
+fn path_rules_without_metacharacters_stay_prefixes() {
+    let permission = ToolPermission::new("rm").allow_path("/tmp".into()).deny_path("/tmp/keep".into());
+
+    assert!(permission.check_path(Path::new("/tmp/a/b")));
+    assert!(!permission.check_path(Path::new("/tmp/keep/file")));
+    assert!(!permission.check_path(Path::new("/tmpfile")));
+}
+
+#[test]
+fn default_level_getter_and_guarded_setter() {
+    use agent_sandbox::UnknownToolPolicy;
+
+    let mut gate = PermissionGate::new();
+    assert_eq!(gate.default_level(), PermissionLevel::Execute);
+
+    let err = gate.try_set_default_level(PermissionLevel::ReadOnly).unwrap_err();
+    assert!(matches!(err, SandboxError::InvalidConfig(_)));
+    assert_eq!(gate.default_level(), PermissionLevel::Execute);
+
+    gate.set_unknown_tool_policy(UnknownToolPolicy::RequireApproval);
+    gate.try_set_default_level(PermissionLevel::ReadOnly).unwrap();
+    assert_eq!(gate.default_level(), PermissionLevel::ReadOnly);
+    assert_eq!(gate.check_tool("whoami").unwrap(), PermissionLevel::ReadOnly);
+}
+
+/// Collects formatted log output for assertions
+#[derive(Clone, Default)]
+struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn default_level_above_execute_logs_a_warning() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+
+    let mut gate = PermissionGate::new();
+    tracing::subscriber::with_default(subscriber, || {
+        gate.set_default_level(PermissionLevel::Execute);
+        assert!(logs.0.lock().unwrap().is_empty());
+
+        gate.set_default_level(PermissionLevel::Full);
+    });
+
+    let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("WARN"));
+    assert!(output.contains("above Execute"));
+    assert_eq!(gate.default_level(), PermissionLevel::Full);
+}
+
+#[test]
+fn apply_env_registers_tools_and_levels() {
+    let vars = |level: &'static str| {
+        move |name: &str| {
+            match name {
+                "SANDBOX_ALLOW" => Some("whoami, git"),
+                "SANDBOX_DENY" => Some("curl"),
+                "SANDBOX_APPROVE" => Some("npm,terraform"),
+                "SANDBOX_DEFAULT_LEVEL" => Some(level),
+                _ => None,
+            }
+            .map(String::from)
+        }
+    };
+    let mut gate = PermissionGate::default_permissions();
+    gate.apply_env_with(vars("ReadOnly")).unwrap();
+
+    assert_eq!(gate.check_tool("whoami").unwrap(), PermissionLevel::Execute);
+    // Already-allowed tools keep their registration
+    assert_eq!(gate.check_tool("git").unwrap(), PermissionLevel::Full);
+    assert_eq!(gate.check_tool("curl").unwrap(), PermissionLevel::Denied);
+    assert!(matches!(gate.check_command("curl", &[]), Err(SandboxError::PermissionDenied(_))));
+    assert!(gate.requires_approval("npm"));
+    assert!(gate.requires_approval("terraform"));
+    assert_eq!(gate.check_tool("terraform").unwrap(), PermissionLevel::Execute);
+    assert_eq!(gate.default_level(), PermissionLevel::ReadOnly);
+
+    // An invalid level fails before anything is registered
+    let mut invalid = PermissionGate::new();
+    assert!(matches!(invalid.apply_env_with(vars("admin")), Err(SandboxError::InvalidConfig(_))));
+    assert!(invalid.check_tool("whoami").is_err());
+
+    // Unset variables change nothing
+    let mut unchanged = PermissionGate::default_permissions();
+    unchanged.apply_env_with(|_| None).unwrap();
+    assert_eq!(unchanged.list_tools(), PermissionGate::default_permissions().list_tools());
+}
+
+#[test]
+fn list_tools_is_sorted_regardless_of_insertion_order() {
+    let mut forward = PermissionGate::new();
+    let mut backward = PermissionGate::new();
+    let names = ["zip", "awk", "make", "curl", "bash"];
+    for name in names {
+        forward.register_tool(ToolPermission::new(name).with_level(PermissionLevel::Execute));
+    }
+    for name in names.iter().rev() {
+        backward.register_tool(ToolPermission::new(name).with_level(PermissionLevel::Execute));
+    }
+
+    assert_eq!(forward.list_tools(), ["awk", "bash", "curl", "make", "zip"]);
+    assert_eq!(forward.list_tools(), backward.list_tools());
+
+    let gate = PermissionGate::default_permissions();
+    let by_level = gate.list_tools_by_level();
+    assert_eq!(by_level[&PermissionLevel::Denied], ["sudo"]);
+    assert_eq!(by_level[&PermissionLevel::ReadOnly], ["curl", "file_read"]);
+    assert_eq!(
+        by_level.keys().copied().collect::<Vec<_>>(),
+        [PermissionLevel::Denied, PermissionLevel::ReadOnly, PermissionLevel::Execute, PermissionLevel::Full]
+    );
+}
+
+#[test]
+fn gates_saved_with_allow_unknown_still_load() {
+    let mut json = serde_json::to_value(PermissionGate::default_permissions()).unwrap();
+    let fields = json.as_object_mut().unwrap();
+    fields.remove("unknown_tools");
+    fields.remove("env_allowlist");
+    fields.insert("allow_unknown".to_string(), serde_json::json!(true));
+
+    let gate: PermissionGate = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(gate.unknown_tool_policy(), UnknownToolPolicy::Allow(PermissionLevel::Execute));
+    assert_eq!(gate.check_tool("make").unwrap(), PermissionLevel::Execute);
+
+    // Saved again, the gate is written in the current format and keeps its policy
+    let saved = serde_json::to_value(&gate).unwrap();
+    assert!(saved.get("allow_unknown").is_none());
+    let reloaded: PermissionGate = serde_json::from_value(saved).unwrap();
+    assert_eq!(reloaded.unknown_tool_policy(), gate.unknown_tool_policy());
+
+    json.as_object_mut().unwrap().insert("allow_unknown".to_string(), serde_json::json!(false));
+    let gate: PermissionGate = serde_json::from_value(json).unwrap();
+    assert_eq!(gate.unknown_tool_policy(), UnknownToolPolicy::Deny);
+}
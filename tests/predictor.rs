@@ -0,0 +1,202 @@
+use agent_sandbox::predictor::{heredoc_body, parse_redirections, Redirection, RedirectPredictor};
+use agent_sandbox::{DiffOperation, SideEffectPredictor, VirtualFilesystem};
+use std::path::PathBuf;
+
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(str::to_string).collect()
+}
+
+fn redirect(fd: Option<u32>, target: &str, append: bool) -> Redirection {
+    Redirection {
+        fd,
+        target: PathBuf::from(target),
+        append,
+    }
+}
+
+#[test]
+fn parses_truncate_append_and_stderr_redirections() {
+    assert_eq!(
+        parse_redirections(&args("hi > out.txt")),
+        vec![redirect(Some(1), "out.txt", false)]
+    );
+    assert_eq!(
+        parse_redirections(&args("hi >> log.txt")),
+        vec![redirect(Some(1), "log.txt", true)]
+    );
+    assert_eq!(
+        parse_redirections(&args("build 2> err.log")),
+        vec![redirect(Some(2), "err.log", false)]
+    );
+}
+
+#[test]
+fn parses_glued_and_combined_forms() {
+    assert_eq!(
+        parse_redirections(&args("hi >out.txt 2>>err.log &> all.log")),
+        vec![
+            redirect(Some(1), "out.txt", false),
+            redirect(Some(2), "err.log", true),
+            redirect(None, "all.log", false),
+        ]
+    );
+    assert!(parse_redirections(&args("hi 2>&1 >&2 && true")).is_empty());
+}
+
+#[test]
+fn redirect_predictor_distinguishes_new_and_existing_targets() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("log.txt"), b"old\n".to_vec());
+
+    // `cat`'s output is not known without running it, so only placeholders are predicted
+    let changes = RedirectPredictor.predict("cat", &args("in.txt > new.txt 2>> log.txt"), &vfs);
+
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].path, PathBuf::from("new.txt"));
+    assert!(matches!(changes[0].operation, DiffOperation::Added));
+    assert_eq!(changes[1].path, PathBuf::from("log.txt"));
+    assert!(matches!(changes[1].operation, DiffOperation::Modified));
+    assert_eq!(changes[1].new_content.as_deref(), Some("(would be appended)"));
+}
+
+#[test]
+fn redirect_predictor_treats_deleted_files_as_new() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("out.txt"), b"old\n".to_vec());
+    vfs.delete(std::path::Path::new("out.txt")).unwrap();
+
+    let changes = RedirectPredictor.predict("echo", &args("x > out.txt"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert!(matches!(changes[0].operation, DiffOperation::Added));
+    assert_eq!(changes[0].old_content, None);
+    assert_eq!(changes[0].new_content.as_deref(), Some("x\n"));
+}
+
+#[test]
+fn redirect_predictor_resolves_targets_against_the_working_directory() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("sub/out.txt"), b"old\n".to_vec());
+
+    let changes = RedirectPredictor.predict_in("echo", &args("x > out.txt"), std::path::Path::new("sub"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("sub/out.txt"));
+    assert!(matches!(changes[0].operation, DiffOperation::Modified));
+
+    let changes = RedirectPredictor.predict("echo", &args("x > out.txt"), &vfs);
+    assert!(matches!(changes[0].operation, DiffOperation::Added));
+}
+
+#[test]
+fn redirect_predictor_carries_real_echo_output() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("log.txt"), b"old\n".to_vec());
+
+    let changes = RedirectPredictor.predict("echo", &args("-n hi there >> log.txt"), &vfs);
+    assert_eq!(changes[0].old_content.as_deref(), Some("old\n"));
+    assert_eq!(changes[0].new_content.as_deref(), Some("old\nhi there"));
+
+    // Only the last stdout redirection receives the output; earlier targets are truncated
+    let changes = RedirectPredictor.predict("echo", &args("hi > first.txt > second.txt"), &vfs);
+    assert_eq!(changes[0].new_content.as_deref(), Some(""));
+    assert_eq!(changes[1].new_content.as_deref(), Some("hi\n"));
+    assert!(changes[1].old_content.is_none());
+    assert!(changes[1].unified.is_some());
+}
+
+#[test]
+fn redirect_predictor_writes_heredoc_bodies_through_cat() {
+    let vfs = VirtualFilesystem::new();
+    let args: Vec<String> = vec![">".into(), "notes.txt".into(), "<<".into(), "one\ntwo\n".into()];
+    assert_eq!(heredoc_body(&args), Some("one\ntwo\n"));
+
+    let changes = RedirectPredictor.predict("cat", &args, &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("notes.txt"));
+    assert_eq!(changes[0].new_content.as_deref(), Some("one\ntwo\n"));
+}
+
+#[test]
+fn compiler_predictor_finds_output_files_and_dirs() {
+    use agent_sandbox::predictor::CompilerOutputPredictor;
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("main.c"), b"int main() {}\n".to_vec());
+    let predictor = CompilerOutputPredictor::default();
+
+    let changes = predictor.predict("gcc", &args("main.c -o a.out"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("a.out"));
+    assert!(matches!(changes[0].operation, DiffOperation::Added));
+
+    let changes = predictor.predict("rustc", &args("--out-dir build src/main.rs"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("build"));
+    assert!(matches!(changes[0].operation, DiffOperation::DirectoryAdded));
+
+    // Glued and `=` forms, and an existing output
+    let changes = predictor.predict("gcc", &args("-omain.c main.c"), &vfs);
+    assert!(matches!(changes[0].operation, DiffOperation::Modified));
+    let changes = predictor.predict("rustc", &args("--out-dir=target lib.rs"), &vfs);
+    assert_eq!(changes[0].path, PathBuf::from("target"));
+
+    assert!(predictor.predict("make", &args("-o out"), &vfs).is_empty());
+}
+
+#[test]
+fn compiler_predictor_accepts_configured_tools() {
+    use agent_sandbox::predictor::{CompilerOutputPredictor, OutputFlag};
+
+    let vfs = VirtualFilesystem::new();
+    let predictor = CompilerOutputPredictor::empty().with_tool("zig", [OutputFlag::File("-femit-bin".to_string())]);
+
+    let changes = predictor.predict("zig", &args("build-exe main.zig -femit-bin=app"), &vfs);
+    assert_eq!(changes[0].path, PathBuf::from("app"));
+    assert!(predictor.predict("gcc", &args("-o a.out"), &vfs).is_empty());
+}
+
+#[test]
+fn rm_predictor_deletes_operands_and_only_recurses_with_r() {
+    use agent_sandbox::predictor::RmPredictor;
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("notes.txt"), b"bye\n".to_vec());
+    vfs.write(PathBuf::from("build/out.o"), b"obj".to_vec());
+
+    let changes = RmPredictor.predict("rm", &args("-f notes.txt build"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("notes.txt"));
+    assert!(matches!(changes[0].operation, DiffOperation::Deleted));
+    assert_eq!(changes[0].old_content.as_deref(), Some("bye\n"));
+
+    let changes = RmPredictor.predict("rm", &args("-rf build /etc/hostname"), &vfs);
+    assert!(matches!(changes[0].operation, DiffOperation::DirectoryRemoved));
+    assert_eq!(changes[1].path, PathBuf::from("/etc/hostname"));
+    assert!(matches!(changes[1].operation, DiffOperation::Deleted));
+}
+
+#[test]
+fn ln_predictor_emits_symlinks_only_for_symbolic_links() {
+    use agent_sandbox::predictor::LnPredictor;
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mkdir(std::path::Path::new("bin")).unwrap();
+
+    let changes = LnPredictor.predict("ln", &args("-sf ../lib/tool.sh bin"), &vfs);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].path, PathBuf::from("bin/tool.sh"));
+    assert_eq!(
+        changes[0].operation,
+        DiffOperation::Symlinked { target: PathBuf::from("../lib/tool.sh") }
+    );
+
+    let changes = LnPredictor.predict_in("ln", &args("-s config.toml current"), std::path::Path::new("etc"), &vfs);
+    assert_eq!(changes[0].path, PathBuf::from("etc/current"));
+
+    assert!(LnPredictor.predict("ln", &args("a.txt b.txt"), &vfs).is_empty());
+
+    // An existing link is only replaced with -f
+    vfs.symlink(std::path::Path::new("current"), std::path::Path::new("v1")).unwrap();
+    assert!(LnPredictor.predict("ln", &args("-s v2 current"), &vfs).is_empty());
+    let changes = LnPredictor.predict("ln", &args("-s --force v2 current"), &vfs);
+    assert_eq!(changes[0].operation, DiffOperation::Symlinked { target: PathBuf::from("v2") });
+}
@@ -0,0 +1,33 @@
+#![cfg(all(unix, feature = "rlimits"))]
+
+use agent_sandbox::{ExecutionStatus, Sandbox};
+
+#[test]
+fn memory_limit_fails_hungry_commands() {
+    let mut sandbox = Sandbox::new();
+    sandbox.allow_all();
+    sandbox.set_max_memory_bytes(Some(64 * 1024 * 1024));
+
+    // `sort` has to hold the whole 256 MiB line in memory
+    let result = sandbox
+        .execute("sh -c 'head -c 268435456 /dev/zero | sort'")
+        .unwrap();
+    assert_eq!(result.status, ExecutionStatus::Failed);
+    assert!(result.stderr.contains("memory limit of 67108864 bytes"), "{}", result.stderr);
+
+    // Small commands still fit under the limit
+    let result = sandbox.execute("echo fits").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Success);
+    assert_eq!(result.stdout, "fits\n");
+}
+
+#[test]
+fn memory_limit_is_not_blamed_for_ordinary_failures() {
+    let mut sandbox = Sandbox::new();
+    sandbox.allow_all();
+    sandbox.set_max_memory_bytes(Some(64 * 1024 * 1024));
+
+    let result = sandbox.execute("sh -c 'echo nope >&2; exit 3'").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Failed);
+    assert_eq!(result.stderr, "nope\n");
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::assertions_on_constants)]
+
 #[test]
 fn smoke() {
     assert!(true);
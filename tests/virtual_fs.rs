@@ -0,0 +1,1164 @@
+use agent_sandbox::virtual_fs::merge_file_changes;
+use agent_sandbox::{DiffOperation, FileDiff, SandboxError, VirtualFile, VirtualFilesystem};
+use std::path::{Path, PathBuf};
+
+#[test]
+fn mkdir_creates_nested_directories() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mkdir(Path::new("a/b/c")).unwrap();
+
+    assert!(vfs.is_dir(Path::new("a")));
+    assert!(vfs.is_dir(Path::new("a/b")));
+    assert!(vfs.is_dir(Path::new("a/b/c")));
+
+    let mut dirs = vfs.list_dirs();
+    dirs.sort();
+    assert_eq!(
+        dirs,
+        vec![PathBuf::from("a"), PathBuf::from("a/b"), PathBuf::from("a/b/c")]
+    );
+
+    let added = vfs
+        .get_diff()
+        .into_iter()
+        .filter(|d| matches!(d.operation, DiffOperation::DirectoryAdded))
+        .count();
+    assert_eq!(added, 3);
+}
+
+#[test]
+fn rmdir_refuses_non_empty_directory() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mkdir(Path::new("src")).unwrap();
+    vfs.write(PathBuf::from("src/main.rs"), b"fn main() {}\n".to_vec());
+
+    let err = vfs.rmdir(Path::new("src")).unwrap_err();
+    assert!(matches!(err, SandboxError::FileSystemError(_)));
+    assert!(vfs.is_dir(Path::new("src")));
+
+    vfs.delete(Path::new("src/main.rs")).unwrap();
+    vfs.rmdir(Path::new("src")).unwrap();
+    assert!(!vfs.is_dir(Path::new("src")));
+}
+
+#[test]
+fn rename_over_existing_destination() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("a.txt"), b"from a\n".to_vec());
+    vfs.write(PathBuf::from("b.txt"), b"from b\n".to_vec());
+    let created_at = vfs.get_metadata(Path::new("a.txt")).unwrap().created_at;
+    vfs.commit();
+
+    vfs.rename(Path::new("a.txt"), Path::new("b.txt")).unwrap();
+
+    assert!(vfs.get_metadata(Path::new("a.txt")).is_err());
+    let moved = vfs.get_metadata(Path::new("b.txt")).unwrap();
+    assert_eq!(moved.content, b"from a\n");
+    assert_eq!(moved.path, PathBuf::from("b.txt"));
+    assert_eq!(moved.created_at, created_at);
+
+    let diffs = vfs.get_diff();
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].path, PathBuf::from("b.txt"));
+    assert!(matches!(
+        &diffs[0].operation,
+        DiffOperation::Renamed { from } if from == Path::new("a.txt")
+    ));
+}
+
+#[test]
+fn rename_missing_source_fails() {
+    let mut vfs = VirtualFilesystem::new();
+    let err = vfs
+        .rename(Path::new("missing.txt"), Path::new("other.txt"))
+        .unwrap_err();
+    assert!(matches!(err, SandboxError::VirtualFileNotFound(_)));
+    assert!(vfs.list_files().is_empty());
+}
+
+#[test]
+fn mount_with_ignores_skips_ignored_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("node_modules/pkg")).unwrap();
+    std::fs::write(dir.path().join("node_modules/pkg/index.js"), "x").unwrap();
+    std::fs::write(dir.path().join("main.js"), "y").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_with_ignores(dir.path(), &["node_modules/".to_string()])
+        .unwrap();
+
+    assert_eq!(vfs.list_files(), vec![PathBuf::from("main.js")]);
+    assert!(!vfs.is_dir(Path::new("node_modules")));
+}
+
+#[test]
+fn rescan_keeps_skipping_ignored_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("node_modules/pkg")).unwrap();
+    std::fs::write(dir.path().join("main.js"), "y").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_with_ignores(dir.path(), &["node_modules/".to_string()])
+        .unwrap();
+    std::fs::write(dir.path().join("node_modules/pkg/index.js"), "x").unwrap();
+    std::fs::write(dir.path().join("new.js"), "z").unwrap();
+    vfs.rescan().unwrap();
+
+    let diff = vfs.get_diff();
+    assert_eq!(diff.len(), 1, "{:?}", diff);
+    assert_eq!(diff[0].path, Path::new("new.js"));
+    assert!(!vfs.is_dir(Path::new("node_modules")));
+}
+
+#[test]
+fn mount_honors_gitignore_when_enabled() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join(".gitignore"), "target/\n*.log\n").unwrap();
+    std::fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+    std::fs::write(dir.path().join("target/debug/app"), "bin").unwrap();
+    std::fs::write(dir.path().join("build.log"), "log").unwrap();
+    std::fs::write(dir.path().join("lib.rs"), "src").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.set_respect_gitignore(true);
+    vfs.mount(dir.path()).unwrap();
+
+    let mut files = vfs.list_files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![PathBuf::from(".gitignore"), PathBuf::from("lib.rs")]
+    );
+}
+
+#[test]
+fn oversized_files_are_mounted_as_stubs() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("big.bin"), vec![7u8; 64]).unwrap();
+    std::fs::write(dir.path().join("small.txt"), "ok").unwrap();
+
+    let mut vfs = VirtualFilesystem::new().with_max_file_size(16);
+    vfs.mount(dir.path()).unwrap();
+
+    let big = vfs.get_metadata(Path::new("big.bin")).unwrap();
+    assert!(big.is_stub);
+    assert_eq!(big.size, 64);
+    assert!(big.content.is_empty());
+    assert_eq!(big.hash, VirtualFile::new(PathBuf::new(), vec![7u8; 64]).hash);
+    assert!(vfs.read(Path::new("big.bin")).is_err());
+
+    assert_eq!(vfs.read(Path::new("small.txt")).unwrap(), b"ok");
+}
+
+#[test]
+fn mount_aborts_when_total_size_exceeded() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), vec![b'a'; 10]).unwrap();
+    std::fs::write(dir.path().join("b.txt"), vec![b'b'; 10]).unwrap();
+
+    let mut vfs = VirtualFilesystem::new().with_max_total_size(15);
+    let err = vfs.mount(dir.path()).unwrap_err();
+
+    assert!(matches!(err, SandboxError::FileSystemError(ref msg) if msg.contains("total size")));
+    assert!(vfs.list_files().is_empty());
+}
+
+#[test]
+fn flush_to_disk_writes_changes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+    std::fs::set_permissions(
+        dir.path().join("run.sh"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("old.txt"), "stale\n").unwrap();
+    std::fs::write(dir.path().join("keep.txt"), "keep\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.mkdir(Path::new("bin")).unwrap();
+    vfs.rename(Path::new("run.sh"), Path::new("bin/run.sh")).unwrap();
+    vfs.write(PathBuf::from("new.txt"), b"fresh\n".to_vec());
+    vfs.delete(Path::new("old.txt")).unwrap();
+
+    let mut touched = vfs.flush_to_disk(dir.path()).unwrap();
+    touched.sort();
+
+    assert_eq!(
+        touched,
+        vec![
+            dir.path().join("bin"),
+            dir.path().join("bin/run.sh"),
+            dir.path().join("new.txt"),
+            dir.path().join("old.txt"),
+            dir.path().join("run.sh"),
+        ]
+    );
+    assert_eq!(std::fs::read_to_string(dir.path().join("new.txt")).unwrap(), "fresh\n");
+    assert!(!dir.path().join("old.txt").exists());
+    assert!(!dir.path().join("run.sh").exists());
+
+    let mode = std::fs::metadata(dir.path().join("bin/run.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+    let mode = std::fs::metadata(dir.path().join("new.txt"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o111, 0);
+}
+
+#[test]
+fn flush_to_disk_creates_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("config.toml"), "debug = true\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.symlink(Path::new("links/current"), Path::new("../config.toml")).unwrap();
+    assert!(vfs.symlink(Path::new("config.toml"), Path::new("other")).is_err());
+    assert!(vfs.is_symlink(Path::new("links/current")));
+
+    let diff = vfs.get_diff();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].path, PathBuf::from("links/current"));
+    assert_eq!(
+        diff[0].operation,
+        DiffOperation::Symlinked { target: PathBuf::from("../config.toml") }
+    );
+
+    let touched = vfs.flush_to_disk(dir.path()).unwrap();
+    assert_eq!(touched, vec![dir.path().join("links/current")]);
+
+    let link = dir.path().join("links/current");
+    assert_eq!(std::fs::read_link(&link).unwrap(), PathBuf::from("../config.toml"));
+    assert_eq!(std::fs::read_to_string(&link).unwrap(), "debug = true\n");
+}
+
+#[test]
+fn flush_to_disk_refuses_paths_outside_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("inside.txt"), b"ok".to_vec());
+    vfs.write(PathBuf::from("../escape.txt"), b"nope".to_vec());
+
+    let err = vfs.flush_to_disk(&root).unwrap_err();
+    assert!(matches!(err, SandboxError::PermissionDenied(_)));
+    assert!(!dir.path().join("escape.txt").exists());
+    assert!(!root.join("inside.txt").exists());
+}
+
+#[test]
+fn flush_plan_lists_operations_without_touching_disk() {
+    use agent_sandbox::{FlushOperation, FlushStep};
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("keep.txt"), "keep\n").unwrap();
+    std::fs::write(dir.path().join("edit.txt"), "old\n").unwrap();
+    std::fs::write(dir.path().join("old.txt"), "stale\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.write(PathBuf::from("edit.txt"), b"new\n".to_vec());
+    vfs.write(PathBuf::from("new.txt"), b"fresh\n".to_vec());
+    vfs.delete(Path::new("old.txt")).unwrap();
+
+    let plan = vfs.flush_plan(dir.path()).unwrap();
+    assert!(plan.is_clean(), "{:?}", plan.conflicts);
+    assert_eq!(
+        plan.operations,
+        vec![
+            FlushStep { path: dir.path().join("edit.txt"), operation: FlushOperation::Overwrite },
+            FlushStep { path: dir.path().join("new.txt"), operation: FlushOperation::Create },
+            FlushStep { path: dir.path().join("old.txt"), operation: FlushOperation::Delete },
+        ]
+    );
+    assert!(!dir.path().join("new.txt").exists());
+    assert_eq!(std::fs::read_to_string(dir.path().join("edit.txt")).unwrap(), "old\n");
+
+    let mut touched = vfs.flush_with_plan(&plan).unwrap();
+    touched.sort();
+    assert_eq!(
+        touched,
+        plan.operations.iter().map(|step| step.path.clone()).collect::<Vec<_>>()
+    );
+    assert_eq!(std::fs::read_to_string(dir.path().join("edit.txt")).unwrap(), "new\n");
+    assert!(!dir.path().join("old.txt").exists());
+}
+
+#[test]
+fn flush_plan_reports_symlinks_on_planned_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("edit.txt"), "old\n").unwrap();
+    std::fs::write(outside.path().join("edit.txt"), "old\n").unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.write(PathBuf::from("edit.txt"), b"new\n".to_vec());
+    vfs.write(PathBuf::from("sub/new.txt"), b"new\n".to_vec());
+    assert!(vfs.flush_plan(dir.path()).unwrap().is_clean());
+
+    // Same content behind a link, and a parent directory swapped for a link
+    std::fs::remove_file(dir.path().join("edit.txt")).unwrap();
+    std::os::unix::fs::symlink(outside.path().join("edit.txt"), dir.path().join("edit.txt")).unwrap();
+    std::fs::remove_dir(dir.path().join("sub")).unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("sub")).unwrap();
+
+    let plan = vfs.flush_plan(dir.path()).unwrap();
+    let conflicts: Vec<_> = plan.conflicts.iter().map(|c| (c.path.clone(), c.symlink.clone())).collect();
+    assert_eq!(
+        conflicts,
+        vec![
+            (dir.path().join("edit.txt"), Some(dir.path().join("edit.txt"))),
+            (dir.path().join("sub/new.txt"), Some(dir.path().join("sub"))),
+        ]
+    );
+    assert_eq!(plan.conflicts[0].found_hash, None);
+    assert!(vfs.flush_with_plan(&plan).is_err());
+    assert!(!outside.path().join("new.txt").exists());
+    assert_eq!(std::fs::read_to_string(outside.path().join("edit.txt")).unwrap(), "old\n");
+}
+
+#[test]
+fn flush_plan_reports_files_changed_on_disk_since_mount() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("edit.txt"), "old\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.write(PathBuf::from("edit.txt"), b"mine\n".to_vec());
+    vfs.write(PathBuf::from("new.txt"), b"mine\n".to_vec());
+    let clean = vfs.flush_plan(dir.path()).unwrap();
+    assert!(clean.is_clean());
+
+    std::fs::write(dir.path().join("edit.txt"), "theirs\n").unwrap();
+    std::fs::write(dir.path().join("new.txt"), "theirs\n").unwrap();
+
+    let plan = vfs.flush_plan(dir.path()).unwrap();
+    let conflicts: Vec<_> = plan.conflicts.iter().map(|c| c.path.clone()).collect();
+    assert_eq!(conflicts, vec![dir.path().join("edit.txt"), dir.path().join("new.txt")]);
+    assert!(plan.conflicts[0].expected_hash.is_some());
+    assert_ne!(plan.conflicts[0].expected_hash, plan.conflicts[0].found_hash);
+    assert_eq!(plan.conflicts[1].expected_hash, None);
+
+    let err = vfs.flush_with_plan(&plan).unwrap_err();
+    assert!(matches!(err, SandboxError::FileSystemError(ref msg) if msg.contains("conflict")));
+    let err = vfs.flush_with_plan(&clean).unwrap_err();
+    assert!(matches!(err, SandboxError::FileSystemError(ref msg) if msg.contains("out of date")));
+    assert_eq!(std::fs::read_to_string(dir.path().join("edit.txt")).unwrap(), "theirs\n");
+}
+
+#[test]
+fn symlink_targets_must_stay_under_the_root() {
+    let mut vfs = VirtualFilesystem::new();
+
+    for target in ["/etc/passwd", "../outside", "a/../../outside"] {
+        let err = vfs.symlink(Path::new("link"), Path::new(target)).unwrap_err();
+        assert!(matches!(err, SandboxError::PermissionDenied(_)), "{}: {:?}", target, err);
+    }
+    assert!(vfs.symlink(Path::new("deep/link"), Path::new("../../../etc")).is_err());
+    assert!(vfs.list_symlinks().is_empty());
+
+    vfs.symlink(Path::new("deep/link"), Path::new("../config.toml")).unwrap();
+    vfs.symlink(Path::new("here"), Path::new("./sub/../config.toml")).unwrap();
+}
+
+#[test]
+fn forced_symlinks_replace_existing_links_on_flush() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("v1"), "one\n").unwrap();
+    std::fs::write(dir.path().join("v2"), "two\n").unwrap();
+    std::fs::write(dir.path().join("plain.txt"), "file\n").unwrap();
+    std::os::unix::fs::symlink("v1", dir.path().join("current")).unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    assert!(vfs.symlink(Path::new("current"), Path::new("v2")).is_err());
+    vfs.symlink_force(Path::new("current"), Path::new("v2")).unwrap();
+    vfs.symlink_force(Path::new("plain.txt"), Path::new("v2")).unwrap();
+
+    vfs.flush_to_disk(dir.path()).unwrap();
+    assert_eq!(std::fs::read_link(dir.path().join("current")).unwrap(), Path::new("v2"));
+    assert_eq!(std::fs::read_link(dir.path().join("plain.txt")).unwrap(), Path::new("v2"));
+    assert_eq!(std::fs::read_to_string(dir.path().join("v1")).unwrap(), "one\n");
+
+    // The same replacement through a flush plan
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.symlink_force(Path::new("current"), Path::new("v1")).unwrap();
+    let plan = vfs.flush_plan(dir.path()).unwrap();
+    assert!(plan.is_clean(), "{:?}", plan.conflicts);
+    vfs.flush_with_plan(&plan).unwrap();
+    assert_eq!(std::fs::read_link(dir.path().join("current")).unwrap(), Path::new("v1"));
+
+    // A link retargeted on disk since the mount is still a conflict
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.symlink_force(Path::new("current"), Path::new("v3")).unwrap();
+    std::fs::remove_file(dir.path().join("current")).unwrap();
+    std::os::unix::fs::symlink("v2", dir.path().join("current")).unwrap();
+    let plan = vfs.flush_plan(dir.path()).unwrap();
+    assert_eq!(plan.conflicts.len(), 1);
+    assert!(vfs.flush_with_plan(&plan).is_err());
+}
+
+#[test]
+fn reset_restores_links_replaced_by_symlink_force() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("v1"), "one\n").unwrap();
+    std::fs::write(dir.path().join("plain.txt"), "file\n").unwrap();
+    std::os::unix::fs::symlink("v1", dir.path().join("current")).unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    vfs.symlink_force(Path::new("current"), Path::new("v2")).unwrap();
+    vfs.symlink_force(Path::new("current"), Path::new("v3")).unwrap();
+    vfs.symlink_force(Path::new("plain.txt"), Path::new("v1")).unwrap();
+    vfs.reset();
+
+    assert_eq!(vfs.read_link(Path::new("current")).unwrap(), Path::new("v1"));
+    assert!(!vfs.is_symlink(Path::new("plain.txt")));
+    assert_eq!(vfs.read(Path::new("plain.txt")).unwrap(), b"file\n");
+    assert!(vfs.get_diff().is_empty());
+}
+
+#[test]
+fn flush_to_disk_refuses_to_write_through_symlinked_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    let outside = dir.path().join("outside");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::create_dir(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("inside.txt"), b"ok".to_vec());
+    vfs.write(PathBuf::from("out/passwd"), b"nope".to_vec());
+
+    let err = vfs.flush_to_disk(&root).unwrap_err();
+    assert!(matches!(err, SandboxError::PermissionDenied(ref msg) if msg.contains("symbolic link")));
+    assert!(!outside.join("passwd").exists());
+    assert!(!root.join("inside.txt").exists());
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("out"), b"replaced".to_vec());
+    assert!(vfs.flush_to_disk(&root).is_err());
+    assert_eq!(std::fs::read_link(root.join("out")).unwrap(), outside);
+}
+
+#[test]
+fn tar_round_trip_preserves_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("src/bin")).unwrap();
+    std::fs::write(dir.path().join("src/lib.rs"), "pub fn f() {}\n").unwrap();
+    std::fs::write(dir.path().join("src/bin/tool.sh"), "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(
+        dir.path().join("src/bin/tool.sh"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("data.bin"), [0u8, 159, 146, 150]).unwrap();
+
+    let vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    let mut archive = Vec::new();
+    vfs.export_tar(&mut archive).unwrap();
+
+    let imported = VirtualFilesystem::import_tar(archive.as_slice()).unwrap();
+
+    let mut expected = vfs.list_files();
+    expected.sort();
+    let mut actual = imported.list_files();
+    actual.sort();
+    assert_eq!(actual, expected);
+
+    for path in &expected {
+        let original = vfs.get_metadata(path).unwrap();
+        let restored = imported.get_metadata(path).unwrap();
+        assert_eq!(restored.hash, original.hash);
+        assert_eq!(restored.is_executable, original.is_executable);
+    }
+    assert!(imported.is_dir(Path::new("src/bin")));
+    assert!(imported.get_diff().is_empty());
+}
+
+const PNG_BYTES: &[u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0xff];
+
+#[test]
+fn binary_changes_are_not_lossy_strings() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("logo.png"), PNG_BYTES).unwrap();
+
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    let mut edited = PNG_BYTES.to_vec();
+    edited.push(0x42);
+    vfs.write(PathBuf::from("logo.png"), edited.clone());
+
+    let diffs = vfs.get_diff();
+    assert_eq!(diffs.len(), 1);
+    let diff = &diffs[0];
+    assert!(matches!(diff.operation, DiffOperation::Modified));
+    assert!(diff.old_content.is_none());
+    assert!(diff.new_content.is_none());
+
+    let binary = diff.binary.as_ref().unwrap();
+    assert_eq!(binary.old_len, PNG_BYTES.len() as u64);
+    assert_eq!(binary.new_len, edited.len() as u64);
+    assert_ne!(binary.old_hash, binary.new_hash);
+
+    let formatted = diff.format();
+    assert!(formatted.contains("Binary files differ"));
+    assert!(!formatted.contains('\u{FFFD}'));
+}
+
+#[test]
+fn unmount_removes_only_that_mounts_files() {
+    let first = tempfile::tempdir().unwrap();
+    std::fs::create_dir(first.path().join("docs")).unwrap();
+    std::fs::write(first.path().join("docs/a.md"), "a").unwrap();
+    std::fs::write(first.path().join("one.txt"), "1").unwrap();
+    let second = tempfile::tempdir().unwrap();
+    std::fs::write(second.path().join("two.txt"), "2").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount(first.path()).unwrap();
+    vfs.mount(second.path()).unwrap();
+    vfs.write(PathBuf::from("scratch.txt"), b"new".to_vec());
+    vfs.write(PathBuf::from("one.txt"), b"edited".to_vec());
+
+    vfs.unmount(first.path()).unwrap();
+
+    let mut files = vfs.list_files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![PathBuf::from("scratch.txt"), PathBuf::from("two.txt")]
+    );
+    assert!(!vfs.is_dir(Path::new("docs")));
+
+    let err = vfs.unmount(first.path()).unwrap_err();
+    assert!(matches!(err, SandboxError::FileSystemError(_)));
+}
+
+#[test]
+fn mount_respects_max_depth() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+    std::fs::write(dir.path().join("top.txt"), "0").unwrap();
+    std::fs::write(dir.path().join("a/mid.txt"), "1").unwrap();
+    std::fs::write(dir.path().join("a/b/deep.txt"), "2").unwrap();
+
+    let mut vfs = VirtualFilesystem::new().with_max_depth(2);
+    vfs.mount(dir.path()).unwrap();
+
+    let mut files = vfs.list_files();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![PathBuf::from("a/mid.txt"), PathBuf::from("top.txt")]
+    );
+}
+
+#[test]
+fn mount_records_symlinks_without_following() {
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("real.txt"), "real").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("escape")).unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount(dir.path()).unwrap();
+
+    assert_eq!(vfs.list_files(), vec![PathBuf::from("real.txt")]);
+    assert!(vfs.is_symlink(Path::new("escape")));
+    assert_eq!(vfs.read_link(Path::new("escape")).unwrap(), outside.path());
+    assert!(!vfs.exists(Path::new("escape/secret.txt")));
+
+    let mut followed = VirtualFilesystem::new().with_follow_symlinks(true);
+    followed.mount(dir.path()).unwrap();
+    assert!(followed.exists(Path::new("escape/secret.txt")));
+}
+
+#[test]
+fn write_preserves_mode_of_existing_file() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write_with_mode(PathBuf::from("build.sh"), b"#!/bin/sh\n".to_vec(), 0o755);
+    vfs.write(PathBuf::from("build.sh"), b"#!/bin/sh\nmake\n".to_vec());
+
+    let file = vfs.get_metadata(Path::new("build.sh")).unwrap();
+    assert_eq!(file.content, b"#!/bin/sh\nmake\n");
+    assert_eq!(file.permissions, 0o755);
+    assert!(file.is_executable);
+}
+
+#[test]
+fn write_with_mode_honors_explicit_mode() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("key.pem"), b"key".to_vec());
+    vfs.write_with_mode(PathBuf::from("key.pem"), b"key".to_vec(), 0o600);
+
+    let file = vfs.get_metadata(Path::new("key.pem")).unwrap();
+    assert_eq!(file.permissions, 0o600);
+    assert!(!file.is_executable);
+}
+
+#[test]
+fn read_by_hash_finds_files_by_content() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("a.txt"), b"same".to_vec());
+    vfs.write(PathBuf::from("b.txt"), b"same".to_vec());
+    vfs.write(PathBuf::from("c.txt"), b"different".to_vec());
+
+    let same = vfs.get_metadata(Path::new("a.txt")).unwrap().hash;
+    let different = vfs.get_metadata(Path::new("c.txt")).unwrap().hash;
+
+    assert_eq!(
+        vfs.paths_by_hash(&same),
+        vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+    );
+    assert_eq!(vfs.read_by_hash(&same).unwrap().content, b"same");
+    assert_eq!(
+        vfs.read_by_hash(&different).unwrap().path,
+        PathBuf::from("c.txt")
+    );
+
+    vfs.delete(Path::new("a.txt")).unwrap();
+    vfs.write(PathBuf::from("b.txt"), b"changed".to_vec());
+    assert!(vfs.read_by_hash(&same).is_none());
+    assert!(vfs.paths_by_hash("0000").is_empty());
+}
+
+#[test]
+fn rescan_picks_up_changes_made_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("edit.txt"), "before\n").unwrap();
+    std::fs::write(dir.path().join("gone.txt"), "bye\n").unwrap();
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+
+    std::fs::write(dir.path().join("edit.txt"), "after\n").unwrap();
+    std::fs::remove_file(dir.path().join("gone.txt")).unwrap();
+    vfs.rescan().unwrap();
+
+    let mut diff = vfs.get_diff();
+    diff.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(diff.len(), 2);
+    assert!(matches!(diff[0].operation, DiffOperation::Modified));
+    assert_eq!(diff[0].new_content.as_deref(), Some("after\n"));
+    assert!(matches!(diff[1].operation, DiffOperation::Deleted));
+    assert_eq!(diff[1].path, Path::new("gone.txt"));
+}
+
+#[test]
+fn case_sensitive_by_default() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("Foo.txt"), b"hi".to_vec());
+
+    assert!(!vfs.is_case_insensitive());
+    assert!(vfs.read(Path::new("foo.txt")).is_err());
+    assert!(!vfs.exists(Path::new("foo.txt")));
+    assert!(vfs.delete(Path::new("foo.txt")).is_err());
+    assert_eq!(vfs.read(Path::new("Foo.txt")).unwrap(), b"hi");
+}
+
+#[test]
+fn case_insensitive_lookups_keep_display_case() {
+    let mut vfs = VirtualFilesystem::new().with_case_insensitive(true);
+    vfs.write(PathBuf::from("Foo.txt"), b"hi".to_vec());
+
+    assert_eq!(vfs.read(Path::new("foo.txt")).unwrap(), b"hi");
+    assert!(vfs.exists(Path::new("FOO.TXT")));
+
+    vfs.write(PathBuf::from("foo.TXT"), b"again".to_vec());
+    assert_eq!(vfs.list_files(), vec![PathBuf::from("Foo.txt")]);
+    assert_eq!(vfs.read(Path::new("Foo.txt")).unwrap(), b"again");
+
+    vfs.delete(Path::new("FOO.txt")).unwrap();
+    assert!(vfs.read(Path::new("Foo.txt")).is_err());
+    assert!(vfs.list_files().is_empty());
+}
+
+#[test]
+fn case_insensitive_mount_flushes_original_names() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("README.md"), "old\n").unwrap();
+    let mut vfs = VirtualFilesystem::new().with_case_insensitive(true);
+    vfs.mount(dir.path()).unwrap();
+
+    vfs.write(PathBuf::from("readme.md"), b"new\n".to_vec());
+    let diff = vfs.get_diff();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].path, Path::new("README.md"));
+
+    vfs.flush_to_disk(dir.path()).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.path().join("README.md")).unwrap(), "new\n");
+}
+
+#[test]
+fn case_insensitive_directories_keep_display_case() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("Docs")).unwrap();
+    let mut vfs = VirtualFilesystem::new().with_case_insensitive(true);
+    vfs.mount(dir.path()).unwrap();
+
+    assert_eq!(vfs.list_dirs(), vec![PathBuf::from("Docs")]);
+    assert!(vfs.is_dir(Path::new("docs")));
+
+    vfs.mkdir(Path::new("Src/Bin")).unwrap();
+    vfs.mkdir(Path::new("src/bin/Extra")).unwrap();
+    let mut added: Vec<PathBuf> = vfs.get_diff().into_iter().map(|change| change.path).collect();
+    added.sort();
+    assert_eq!(added, ["Src", "Src/Bin", "Src/Bin/Extra"].map(PathBuf::from));
+
+    // New files land in the directory as it was named
+    vfs.write(PathBuf::from("src/bin/main.rs"), b"fn main() {}\n".to_vec());
+    vfs.flush_to_disk(dir.path()).unwrap();
+    let names: Vec<String> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(names.contains(&"Src".to_string()) && !names.contains(&"src".to_string()), "{:?}", names);
+    assert!(dir.path().join("Src/Bin/Extra").is_dir());
+    assert!(dir.path().join("Src/Bin/main.rs").is_file());
+}
+
+#[test]
+fn case_insensitive_mount_refuses_names_differing_only_in_case() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("Notes.txt"), "a").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "b").unwrap();
+
+    let mut vfs = VirtualFilesystem::new().with_case_insensitive(true);
+    let err = vfs.mount(dir.path()).unwrap_err();
+    assert!(err.to_string().contains("differ only in case"), "{}", err);
+    assert!(vfs.list_files().is_empty());
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount(dir.path()).unwrap();
+    assert_eq!(vfs.list_files().len(), 2);
+}
+
+fn glob_fixture() -> VirtualFilesystem {
+    let mut vfs = VirtualFilesystem::new();
+    for path in ["a.txt", "b.txt", "src/lib.rs", "src/bin/main.rs", "src/notes.txt", "docs/a.txt"] {
+        vfs.write(PathBuf::from(path), b"x".to_vec());
+    }
+    vfs
+}
+
+#[test]
+fn glob_double_star_spans_directories() {
+    let mut vfs = glob_fixture();
+    assert_eq!(
+        vfs.glob("src/**/*.rs"),
+        vec![PathBuf::from("src/bin/main.rs"), PathBuf::from("src/lib.rs")]
+    );
+    assert_eq!(vfs.glob("**/*.rs").len(), 2);
+
+    vfs.delete(Path::new("src/lib.rs")).unwrap();
+    assert_eq!(vfs.glob("**/*.rs"), vec![PathBuf::from("src/bin/main.rs")]);
+}
+
+#[test]
+fn glob_single_star_stays_in_one_directory() {
+    let vfs = glob_fixture();
+    assert_eq!(vfs.glob("*.txt"), vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]);
+    assert!(vfs.glob("[").is_empty());
+}
+
+#[test]
+fn list_files_under_scopes_to_a_directory() {
+    let mut vfs = glob_fixture();
+    assert_eq!(
+        vfs.list_files_under(Path::new("src")),
+        vec![
+            PathBuf::from("src/bin/main.rs"),
+            PathBuf::from("src/lib.rs"),
+            PathBuf::from("src/notes.txt"),
+        ]
+    );
+
+    vfs.delete(Path::new("src/notes.txt")).unwrap();
+    assert_eq!(vfs.list_files_under(Path::new("src/bin")), vec![PathBuf::from("src/bin/main.rs")]);
+    assert_eq!(vfs.list_files_under(Path::new("src")).len(), 2);
+    assert!(vfs.list_files_under(Path::new("sr")).is_empty());
+}
+
+#[test]
+fn diff_against_compares_two_filesystems() {
+    let mut before = VirtualFilesystem::new();
+    before.write(PathBuf::from("kept.txt"), b"same\n".to_vec());
+    before.write(PathBuf::from("changed.txt"), b"old\n".to_vec());
+    before.write(PathBuf::from("removed.txt"), b"bye\n".to_vec());
+
+    let mut after = VirtualFilesystem::new();
+    after.write(PathBuf::from("kept.txt"), b"same\n".to_vec());
+    after.write(PathBuf::from("changed.txt"), b"new\n".to_vec());
+    after.write(PathBuf::from("added.txt"), b"hi\n".to_vec());
+
+    let diff = after.diff_against(&before);
+    assert_eq!(diff.len(), 3);
+
+    assert_eq!(diff[0].path, Path::new("added.txt"));
+    assert!(matches!(diff[0].operation, DiffOperation::Added));
+    assert_eq!(diff[0].old_content, None);
+    assert_eq!(diff[0].new_content.as_deref(), Some("hi\n"));
+
+    assert_eq!(diff[1].path, Path::new("changed.txt"));
+    assert!(matches!(diff[1].operation, DiffOperation::Modified));
+    assert_eq!(diff[1].old_content.as_deref(), Some("old\n"));
+    assert_eq!(diff[1].new_content.as_deref(), Some("new\n"));
+
+    assert_eq!(diff[2].path, Path::new("removed.txt"));
+    assert!(matches!(diff[2].operation, DiffOperation::Deleted));
+    assert_eq!(diff[2].old_content.as_deref(), Some("bye\n"));
+    assert_eq!(diff[2].new_content, None);
+}
+
+#[test]
+fn diff_against_identical_filesystem_is_empty() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("a.txt"), b"a".to_vec());
+    vfs.write(PathBuf::from("src/b.rs"), b"b".to_vec());
+
+    assert!(vfs.diff_against(&vfs.clone()).is_empty());
+    assert!(VirtualFilesystem::new().diff_against(&VirtualFilesystem::new()).is_empty());
+}
+
+fn change(path: &str, operation: DiffOperation, old: Option<&str>, new: Option<&str>) -> FileDiff {
+    FileDiff {
+        path: PathBuf::from(path),
+        operation,
+        old_content: old.map(str::to_string),
+        new_content: new.map(str::to_string),
+        binary: None,
+        unified: None,
+        mode: None,
+    }
+}
+
+#[test]
+fn merge_added_then_modified_stays_added() {
+    let merged = merge_file_changes(vec![
+        change("a.txt", DiffOperation::Added, None, Some("one")),
+        change("a.txt", DiffOperation::Modified, Some("one"), Some("two")),
+    ]);
+    assert_eq!(merged.len(), 1);
+    assert!(matches!(merged[0].operation, DiffOperation::Added));
+    assert_eq!(merged[0].old_content, None);
+    assert_eq!(merged[0].new_content.as_deref(), Some("two"));
+}
+
+#[test]
+fn merge_modified_then_deleted_is_deleted() {
+    let merged = merge_file_changes(vec![
+        change("a.txt", DiffOperation::Modified, Some("orig"), Some("edited")),
+        change("a.txt", DiffOperation::Deleted, Some("edited"), None),
+    ]);
+    assert_eq!(merged.len(), 1);
+    assert!(matches!(merged[0].operation, DiffOperation::Deleted));
+    assert_eq!(merged[0].old_content.as_deref(), Some("orig"));
+    assert_eq!(merged[0].new_content, None);
+}
+
+#[test]
+fn merge_added_then_deleted_cancels_out() {
+    let merged = merge_file_changes(vec![
+        change("tmp.txt", DiffOperation::Added, None, Some("scratch")),
+        change("keep.txt", DiffOperation::Added, None, Some("kept")),
+        change("tmp.txt", DiffOperation::Deleted, Some("scratch"), None),
+    ]);
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].path, Path::new("keep.txt"));
+}
+
+#[test]
+fn merge_leaves_unique_paths_in_first_seen_order() {
+    let merged = merge_file_changes(vec![
+        change("b.txt", DiffOperation::Modified, Some("1"), Some("2")),
+        change("a.txt", DiffOperation::Added, None, Some("x")),
+        change("b.txt", DiffOperation::Modified, Some("2"), Some("3")),
+        change("c.txt", DiffOperation::Deleted, Some("gone"), None),
+        change("c.txt", DiffOperation::Added, None, Some("back")),
+    ]);
+    let paths: Vec<_> = merged.iter().map(|d| d.path.clone()).collect();
+    assert_eq!(paths, vec![PathBuf::from("b.txt"), PathBuf::from("a.txt"), PathBuf::from("c.txt")]);
+
+    assert_eq!(merged[0].old_content.as_deref(), Some("1"));
+    assert_eq!(merged[0].new_content.as_deref(), Some("3"));
+    assert!(matches!(merged[2].operation, DiffOperation::Modified));
+    assert_eq!(merged[2].old_content.as_deref(), Some("gone"));
+    assert_eq!(merged[2].new_content.as_deref(), Some("back"));
+}
+
+#[test]
+fn append_extends_an_existing_file() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("log.txt"), b"one\n".to_vec());
+    let before = vfs.get_metadata(Path::new("log.txt")).unwrap().hash;
+
+    vfs.append(Path::new("log.txt"), b"two\n").unwrap();
+    assert_eq!(vfs.read(Path::new("log.txt")).unwrap(), b"one\ntwo\n");
+    assert_ne!(vfs.get_metadata(Path::new("log.txt")).unwrap().hash, before);
+}
+
+#[test]
+fn append_creates_a_missing_file() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.append(Path::new("new.txt"), b"first\n").unwrap();
+    assert_eq!(vfs.read(Path::new("new.txt")).unwrap(), b"first\n");
+
+    vfs.mkdir(Path::new("dir")).unwrap();
+    assert!(matches!(
+        vfs.append(Path::new("dir"), b"x"),
+        Err(SandboxError::FileSystemError(_))
+    ));
+}
+
+#[test]
+fn original_content_survives_edits() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("edited.txt"), "before\n").unwrap();
+    std::fs::write(dir.path().join("untouched.txt"), "same\n").unwrap();
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+
+    vfs.write(PathBuf::from("edited.txt"), b"after\n".to_vec());
+    assert!(vfs.is_modified(Path::new("edited.txt")));
+    assert_eq!(vfs.original_content(Path::new("edited.txt")).unwrap().as_deref(), Some(&b"before\n"[..]));
+    assert_eq!(vfs.read(Path::new("edited.txt")).unwrap(), b"after\n");
+
+    assert!(!vfs.is_modified(Path::new("untouched.txt")));
+    assert_eq!(vfs.original_content(Path::new("untouched.txt")).unwrap().as_deref(), Some(&b"same\n"[..]));
+}
+
+#[test]
+fn created_and_deleted_files_count_as_modified() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("gone.txt"), "bye\n").unwrap();
+    let mut vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+
+    vfs.delete(Path::new("gone.txt")).unwrap();
+    vfs.write(PathBuf::from("new.txt"), b"hi\n".to_vec());
+
+    assert!(vfs.is_modified(Path::new("gone.txt")));
+    assert_eq!(vfs.original_content(Path::new("gone.txt")).unwrap().as_deref(), Some(&b"bye\n"[..]));
+    assert!(vfs.is_modified(Path::new("new.txt")));
+    assert_eq!(vfs.original_content(Path::new("new.txt")).unwrap().as_deref(), None);
+    assert!(!vfs.is_modified(Path::new("never.txt")));
+}
+
+fn overlay_fixture() -> (tempfile::TempDir, tempfile::TempDir) {
+    let base = tempfile::tempdir().unwrap();
+    std::fs::write(base.path().join("shared.txt"), "base\n").unwrap();
+    std::fs::write(base.path().join("only_base.txt"), "b\n").unwrap();
+    let overlay = tempfile::tempdir().unwrap();
+    std::fs::write(overlay.path().join("shared.txt"), "overlay\n").unwrap();
+    std::fs::write(overlay.path().join("only_overlay.txt"), "o\n").unwrap();
+    (base, overlay)
+}
+
+#[test]
+fn later_mounts_take_precedence() {
+    let (base, overlay) = overlay_fixture();
+    let mut vfs = VirtualFilesystem::from_directory(base.path()).unwrap();
+    vfs.mount(overlay.path()).unwrap();
+
+    assert_eq!(vfs.read(Path::new("shared.txt")).unwrap(), b"overlay\n");
+    assert_eq!(vfs.original_content(Path::new("shared.txt")).unwrap().as_deref(), Some(&b"overlay\n"[..]));
+    assert_eq!(vfs.mount_origin(Path::new("shared.txt")), Some(overlay.path()));
+    assert_eq!(vfs.mount_origin(Path::new("only_base.txt")), Some(base.path()));
+    assert_eq!(vfs.list_files().len(), 3);
+    assert!(vfs.get_diff().is_empty());
+}
+
+#[test]
+fn unmounting_an_overlay_reveals_the_base() {
+    let (base, overlay) = overlay_fixture();
+    let mut vfs = VirtualFilesystem::from_directory(base.path()).unwrap();
+    vfs.mount(overlay.path()).unwrap();
+    vfs.write(PathBuf::from("shared.txt"), b"edited\n".to_vec());
+
+    vfs.unmount(overlay.path()).unwrap();
+    assert_eq!(vfs.read(Path::new("shared.txt")).unwrap(), b"base\n");
+    assert_eq!(vfs.original_content(Path::new("shared.txt")).unwrap().as_deref(), Some(&b"base\n"[..]));
+    assert_eq!(vfs.mount_origin(Path::new("shared.txt")), Some(base.path()));
+    assert!(!vfs.exists(Path::new("only_overlay.txt")));
+    assert!(vfs.get_diff().is_empty());
+}
+
+#[test]
+fn unmounting_the_base_keeps_the_overlay() {
+    let (base, overlay) = overlay_fixture();
+    let mut vfs = VirtualFilesystem::from_directory(base.path()).unwrap();
+    vfs.mount(overlay.path()).unwrap();
+
+    vfs.unmount(base.path()).unwrap();
+    assert_eq!(vfs.read(Path::new("shared.txt")).unwrap(), b"overlay\n");
+    assert!(!vfs.exists(Path::new("only_base.txt")));
+
+    vfs.unmount(overlay.path()).unwrap();
+    assert!(vfs.list_files().is_empty());
+}
+
+#[test]
+fn preview_write_to_a_new_path_is_added() {
+    let vfs = VirtualFilesystem::new();
+    let diff = vfs.preview_write(Path::new("new.txt"), b"hello\n");
+
+    assert!(matches!(diff.operation, DiffOperation::Added));
+    assert_eq!(diff.old_content, None);
+    assert_eq!(diff.new_content.as_deref(), Some("hello\n"));
+    let hunks = &diff.unified.unwrap().hunks;
+    assert_eq!(hunks.len(), 1);
+    assert_eq!((hunks[0].old_lines, hunks[0].new_lines), (0, 1));
+    assert!(!vfs.exists(Path::new("new.txt")));
+}
+
+#[test]
+fn preview_write_to_an_existing_path_is_modified() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("a.txt"), b"one\ntwo\nthree\n".to_vec());
+    let diff = vfs.preview_write(Path::new("a.txt"), b"one\nTWO\nthree\n");
+
+    assert!(matches!(diff.operation, DiffOperation::Modified));
+    assert_eq!(diff.old_content.as_deref(), Some("one\ntwo\nthree\n"));
+    let hunks = &diff.unified.unwrap().hunks;
+    assert_eq!(hunks.len(), 1);
+    assert_eq!((hunks[0].old_start, hunks[0].old_lines, hunks[0].new_lines), (1, 3, 3));
+    assert_eq!(vfs.read(Path::new("a.txt")).unwrap(), b"one\ntwo\nthree\n");
+
+    let binary = vfs.preview_write(Path::new("a.txt"), &[0, 1, 2]);
+    assert!(binary.is_binary());
+    assert_eq!(binary.binary.unwrap().new_len, 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn mount_skips_fifos() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("regular.txt"), "hi").unwrap();
+    let status = std::process::Command::new("mkfifo")
+        .arg(dir.path().join("pipe"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let vfs = VirtualFilesystem::from_directory(dir.path()).unwrap();
+    assert_eq!(vfs.list_files(), vec![PathBuf::from("regular.txt")]);
+    assert!(!vfs.exists(Path::new("pipe")));
+
+    // A followed symlink to the FIFO is skipped too
+    std::os::unix::fs::symlink(dir.path().join("pipe"), dir.path().join("link")).unwrap();
+    let mut vfs = VirtualFilesystem::new().with_follow_symlinks(true);
+    vfs.mount(dir.path()).unwrap();
+    assert_eq!(vfs.list_files(), vec![PathBuf::from("regular.txt")]);
+}
+
+#[test]
+fn lazy_mount_reads_content_on_demand() {
+    let dir = tempfile::tempdir().unwrap();
+    let big: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.path().join("big.bin"), &big).unwrap();
+    std::fs::write(dir.path().join("small.txt"), "ok\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_lazy(dir.path()).unwrap();
+
+    assert_eq!(vfs.resident_bytes(), 0);
+    let metadata = vfs.get_metadata(Path::new("big.bin")).unwrap();
+    assert!(metadata.is_lazy());
+    assert_eq!(metadata.size, big.len() as u64);
+
+    assert_eq!(vfs.read(Path::new("big.bin")).unwrap(), big);
+    assert_eq!(vfs.read(Path::new("small.txt")).unwrap(), b"ok\n");
+    assert_eq!(vfs.resident_bytes(), 0);
+
+    vfs.write(PathBuf::from("small.txt"), b"changed\n".to_vec());
+    assert!(!vfs.get_metadata(Path::new("small.txt")).unwrap().is_lazy());
+    assert_eq!(vfs.resident_bytes(), 8);
+
+    // A restored snapshot keeps the mount lazy when it is re-read
+    let snapshot = vfs.snapshot();
+    vfs.unmount(dir.path()).unwrap();
+    vfs.restore(&snapshot);
+    vfs.rescan().unwrap();
+    assert!(vfs.get_metadata(Path::new("big.bin")).unwrap().is_lazy());
+    assert_eq!(vfs.resident_bytes(), 0);
+}
+
+#[test]
+fn lazy_mount_diffs_against_originals_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "before\n").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "untouched\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_lazy(dir.path()).unwrap();
+    vfs.write(PathBuf::from("a.txt"), b"after\n".to_vec());
+
+    let diff = vfs.get_diff();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].path, Path::new("a.txt"));
+    assert!(matches!(diff[0].operation, DiffOperation::Modified));
+    assert_eq!(diff[0].old_content.as_deref(), Some("before\n"));
+    assert_eq!(diff[0].new_content.as_deref(), Some("after\n"));
+}
+
+#[test]
+fn lazy_originals_are_loaded_or_fail_loudly() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "before\n").unwrap();
+
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_lazy(dir.path()).unwrap();
+    vfs.write(PathBuf::from("a.txt"), b"after\n".to_vec());
+    assert_eq!(
+        vfs.original_content(Path::new("a.txt")).unwrap().as_deref(),
+        Some(&b"before\n"[..])
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("gone.bin"), [0u8, 1, 2]).unwrap();
+    let mut vfs = VirtualFilesystem::new();
+    vfs.mount_lazy(dir.path()).unwrap();
+    let file = vfs.get_metadata(Path::new("gone.bin")).unwrap();
+    assert!(file.is_binary().unwrap());
+
+    std::fs::remove_file(dir.path().join("gone.bin")).unwrap();
+    assert!(vfs.original_content(Path::new("gone.bin")).is_err());
+    assert!(file.is_binary().is_err());
+}
+
+#[test]
+fn compact_drops_tombstones_of_recreated_files_only() {
+    let mut vfs = VirtualFilesystem::new();
+    vfs.write(PathBuf::from("notes.txt"), b"first draft\n".to_vec());
+    vfs.write(PathBuf::from("old.txt"), b"stale\n".to_vec());
+    vfs.commit();
+
+    vfs.delete(Path::new("notes.txt")).unwrap();
+    vfs.write(PathBuf::from("notes.txt"), b"second draft\n".to_vec());
+    vfs.delete(Path::new("old.txt")).unwrap();
+    let before = vfs.get_diff().len();
+
+    let stats = vfs.compact();
+    assert_eq!(stats.tombstones_removed, 1);
+    // The deleted version is still shared with the original state
+    assert_eq!(stats.bytes_reclaimed, 0);
+
+    assert_eq!(vfs.get_diff().len(), before);
+    assert!(vfs.exists(Path::new("old.txt")));
+    assert!(vfs.read(Path::new("old.txt")).is_err());
+    assert_eq!(vfs.read(Path::new("notes.txt")).unwrap(), b"second draft\n");
+
+    assert_eq!(vfs.compact(), agent_sandbox::CompactStats::default());
+
+    // Uncommitted content held only by the tombstone is freed
+    vfs.write(PathBuf::from("scratch.txt"), b"12345".to_vec());
+    vfs.delete(Path::new("scratch.txt")).unwrap();
+    vfs.write(PathBuf::from("scratch.txt"), b"6".to_vec());
+    assert_eq!(vfs.compact().bytes_reclaimed, 5);
+}
@@ -0,0 +1,108 @@
+#![cfg(feature = "wasi")]
+
+use agent_sandbox::{ExecutionStatus, Sandbox, WasiBackend};
+use std::path::{Path, PathBuf};
+
+/// Writes `hello from wasm` to `out.txt` in its preopened directory and to stdout
+const WRITE_FILE: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "out.txt")
+  (data (i32.const 16) "hello from wasm\n")
+  (func (export "_start")
+    (i32.store (i32.const 64) (i32.const 16))
+    (i32.store (i32.const 68) (i32.const 16))
+    ;; fd 3 is the first preopen; O_CREAT | O_TRUNC with the fd_write right
+    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 7)
+          (i32.const 9) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 80))
+      (then (call $proc_exit (i32.const 2))))
+    (drop (call $fd_write (i32.load (i32.const 80)) (i32.const 64) (i32.const 1) (i32.const 84)))
+    (drop (call $fd_write (i32.const 1) (i32.const 64) (i32.const 1) (i32.const 84)))))
+"#;
+
+/// Never returns
+const SPIN: &str = r#"(module (func (export "_start") (loop $spin (br $spin))))"#;
+
+/// Traps as soon as it starts
+const TRAP: &str = r#"(module (func (export "_start") unreachable))"#;
+
+fn module(dir: &Path, name: &str, wat: &str) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+    path
+}
+
+#[test]
+fn wasi_tool_writes_into_the_preopened_working_dir() {
+    let modules = tempfile::tempdir().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+    sandbox.set_tool_backend(
+        "write-file",
+        WasiBackend::new().with_module("write-file", module(modules.path(), "write.wasm", WRITE_FILE)),
+    );
+
+    let result = sandbox.execute("write-file").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Success, "{}", result.stderr);
+    assert_eq!(result.exit_code, Some(0));
+    assert_eq!(result.stdout, "hello from wasm\n");
+    assert_eq!(std::fs::read_to_string(dir.path().join("out.txt")).unwrap(), "hello from wasm\n");
+    assert_eq!(result.file_changes.len(), 1);
+    assert_eq!(result.file_changes[0].path, Path::new("out.txt"));
+}
+
+#[test]
+fn wasi_runtime_failures_are_wasi_errors() {
+    let modules = tempfile::tempdir().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+    sandbox.set_tool_backend(
+        "trap",
+        WasiBackend::new().with_module("trap", module(modules.path(), "trap.wasm", TRAP)),
+    );
+    sandbox.set_tool_backend("missing", WasiBackend::new());
+
+    let result = sandbox.execute("trap").unwrap();
+    assert_eq!(result.status, ExecutionStatus::Failed);
+    assert!(result.stderr.starts_with("WASI error:"), "{}", result.stderr);
+
+    assert!(sandbox.execute("missing").is_err());
+}
+
+#[test]
+fn wasi_tool_is_interrupted_at_the_timeout() {
+    let modules = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(modules.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+    sandbox.set_execution_timeout(Some(std::time::Duration::from_millis(200)));
+    module(modules.path(), "spin.wasm", SPIN);
+    sandbox.set_backend(WasiBackend::new());
+
+    let result = sandbox.execute("spin.wasm").unwrap();
+    assert_eq!(result.status, ExecutionStatus::TimedOut);
+    assert_eq!(result.exit_code, None);
+}
+
+#[test]
+fn wasi_output_is_capped_at_the_limit() {
+    let modules = tempfile::tempdir().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut sandbox = Sandbox::with_working_dir(dir.path().to_path_buf()).unwrap();
+    sandbox.allow_all();
+    sandbox.set_max_output_bytes(Some(5));
+    sandbox.set_tool_backend(
+        "write-file",
+        WasiBackend::new().with_module("write-file", module(modules.path(), "write.wasm", WRITE_FILE)),
+    );
+
+    let result = sandbox.execute("write-file").unwrap();
+    assert!(result.truncated);
+    assert!(result.stdout.starts_with("hello\n"), "{}", result.stdout);
+}